@@ -1,39 +1,221 @@
+use std::{
+	os::unix::io::{FromRawFd, IntoRawFd, OwnedFd},
+	rc::Rc
+};
+
 use anyhow::Context as AnyhowContext;
 
+use drm::buffer::{DrmFourcc, DrmModifier};
 use gbm::{AsRaw, Format};
 
 use khronos_egl as egl;
 use egl::{
 	DynamicInstance,
 	Display,
-	Context
+	Config,
+	Context,
+	Image,
+	Surface
 };
 
-use crate::kms::{KmsContext, FrameBufferObject};
+use crate::kms::{FormatTable, KmsContext, KmsSurface, FrameBufferObject, SyncFile};
 
-pub struct EglContext {
-	format: Format,
+// void glEGLImageTargetRenderbufferStorageOES(GLenum target, GLeglImageOES image);
+type GlEglImageTargetRenderbufferStorageOesFn = extern "system" fn(gl::types::GLenum, *const std::ffi::c_void);
+// EGLint eglDupNativeFenceFDANDROID(EGLDisplay dpy, EGLSyncKHR sync);
+type EglDupNativeFenceFdAndroidFn = extern "system" fn(*mut std::ffi::c_void, *mut std::ffi::c_void) -> egl::Int;
+// EGLBoolean eglQueryDmaBufFormatsEXT(EGLDisplay dpy, EGLint max_formats, EGLint *formats, EGLint *num_formats);
+type EglQueryDmaBufFormatsExtFn = extern "system" fn(*mut std::ffi::c_void, egl::Int, *mut egl::Int, *mut egl::Int) -> egl::Boolean;
+// EGLBoolean eglQueryDmaBufModifiersEXT(EGLDisplay dpy, EGLint format, EGLint max_modifiers, EGLuint64KHR *modifiers, EGLBoolean *external_only, EGLint *num_modifiers);
+type EglQueryDmaBufModifiersExtFn = extern "system" fn(*mut std::ffi::c_void, egl::Int, egl::Int, *mut u64, *mut egl::Boolean, *mut egl::Int) -> egl::Boolean;
+
+// #define EGL_SYNC_NATIVE_FENCE_ANDROID          0x3144
+// #define EGL_SYNC_NATIVE_FENCE_FD_ANDROID       0x3145
+// #define EGL_NO_NATIVE_FENCE_FD_ANDROID         -1
+const EGL_SYNC_NATIVE_FENCE_ANDROID: egl::Enum = 0x3144;
+const EGL_SYNC_NATIVE_FENCE_FD_ANDROID: egl::Attrib = 0x3145;
+const EGL_NO_NATIVE_FENCE_FD_ANDROID: egl::Int = -1;
+
+// #define EGL_DMA_BUF_PLANE0_FD_EXT         0x3272 ... EGL_DMA_BUF_PLANE2_PITCH_EXT 0x327A
+// #define EGL_DMA_BUF_PLANE3_FD_EXT         0x3440 ... EGL_DMA_BUF_PLANE3_PITCH_EXT 0x3442
+/// Fd, offset and pitch attributes of each dma-buf plane.
+const EGL_DMA_BUF_PLANE_ATTRIBS: [[egl::Attrib; 3]; 4] = [
+	[0x3272, 0x3273, 0x3274],
+	[0x3275, 0x3276, 0x3277],
+	[0x3278, 0x3279, 0x327A],
+	[0x3440, 0x3441, 0x3442]
+];
+// #define EGL_DMA_BUF_PLANE0_MODIFIER_LO_EXT 0x3443 ... EGL_DMA_BUF_PLANE3_MODIFIER_HI_EXT 0x344A
+/// Low and high modifier attributes of each dma-buf plane, from `EGL_EXT_image_dma_buf_import_modifiers`.
+const EGL_DMA_BUF_PLANE_MODIFIER_ATTRIBS: [[egl::Attrib; 2]; 4] = [
+	[0x3443, 0x3444],
+	[0x3445, 0x3446],
+	[0x3447, 0x3448],
+	[0x3449, 0x344A]
+];
+
+/// State shared between the context and the render targets it creates so that they can clean up after themselves.
+struct EglShared {
 	instance: DynamicInstance<egl::EGL1_5>,
 	display: Display,
-	context: Context
+	/// destroyed once the last render target is dropped, GL objects can only be deleted while it exists
+	context: Context,
+	image_target_renderbuffer_storage: GlEglImageTargetRenderbufferStorageOesFn,
+	/// `None` without `EGL_ANDROID_native_fence_sync`
+	dup_native_fence_fd: Option<EglDupNativeFenceFdAndroidFn>,
+	/// `None` without `EGL_EXT_image_dma_buf_import_modifiers`
+	dma_buf_modifiers: Option<(EglQueryDmaBufFormatsExtFn, EglQueryDmaBufModifiersExtFn)>
+}
+impl EglShared {
+	/// Makes the context current unless it already is, so that GL objects can be deleted.
+	///
+	/// Keeps a bound window surface, GL objects are shared by all surfaces of the context.
+	fn make_current(&self) -> anyhow::Result<()> {
+		if self.instance.get_current_context() == Some(self.context) {
+			return Ok(())
+		}
+
+		self.instance.make_current(self.display, None, None, Some(self.context)).context("Failed to bind EGL current context")
+	}
+
+	/// Deletes a framebuffer and its renderbuffer, leaking them if the context cannot be made current.
+	fn delete_framebuffer(&self, framebuffer: gl::types::GLuint, renderbuffer: gl::types::GLuint) {
+		if let Err(err) = self.make_current() {
+			log::error!("Failed to delete GL framebuffer {}: {:#}", framebuffer, err);
+			return
+		}
+
+		unsafe {
+			gl::DeleteFramebuffers(1, &framebuffer);
+			gl::DeleteRenderbuffers(1, &renderbuffer);
+		}
+	}
+}
+impl Drop for EglShared {
+	fn drop(&mut self) {
+		if let Err(err) = self.instance.destroy_context(self.display, self.context) {
+			log::error!("Failed to destroy EGL context: {}", err);
+		}
+	}
+}
+
+/// GL framebuffer rendering into an EGLImage imported from a kms buffer object.
+///
+/// Cached on the [`FrameBufferObject`] it was created for and destroyed together with it.
+pub struct EglFramebuffer {
+	shared: Rc<EglShared>,
+	image: Image,
+	renderbuffer: gl::types::GLuint,
+	framebuffer: gl::types::GLuint
+}
+impl Drop for EglFramebuffer {
+	fn drop(&mut self) {
+		self.shared.delete_framebuffer(self.framebuffer, self.renderbuffer);
+
+		if let Err(err) = self.shared.instance.destroy_image(self.shared.display, self.image) {
+			log::error!("Failed to destroy EGL image: {}", err);
+		}
+	}
+}
+
+/// EGL window surface rendering into a [`KmsSurface`].
+pub struct EglSurface {
+	shared: Rc<EglShared>,
+	surface: Surface,
+	size: [u32; 2]
+}
+impl Drop for EglSurface {
+	fn drop(&mut self) {
+		if let Err(err) = self.shared.instance.destroy_surface(self.shared.display, self.surface) {
+			log::error!("Failed to destroy EGL surface: {}", err);
+		}
+	}
+}
+
+/// Offscreen GL framebuffer not backed by any kms buffer, used for headless rendering.
+pub struct EglOffscreen {
+	shared: Rc<EglShared>,
+	renderbuffer: gl::types::GLuint,
+	framebuffer: gl::types::GLuint,
+	size: [u32; 2]
+}
+impl EglOffscreen {
+	pub fn size(&self) -> [u32; 2] {
+		self.size
+	}
+}
+impl Drop for EglOffscreen {
+	fn drop(&mut self) {
+		self.shared.delete_framebuffer(self.framebuffer, self.renderbuffer);
+	}
+}
+
+pub struct EglContext {
+	format: Format,
+	shared: Rc<EglShared>,
+	config: Config,
+	/// internal format of offscreen renderbuffers, `GL_RGBA8_OES` if supported
+	offscreen_format: gl::types::GLenum
 }
 impl EglContext {
+	fn load_instance() -> anyhow::Result<DynamicInstance<egl::EGL1_5>> {
+		// SAFETY: *eyeroll*
+		unsafe {
+			egl::DynamicInstance::<egl::EGL1_5>::load_required().context("Failed to load libEGL.so.1")
+		}
+	}
+
 	pub fn new(
 		kms: &KmsContext,
 		format: Format
 	) -> anyhow::Result<Self> {
-		// SAFETY: *eyeroll*
-		let instance = unsafe {
-			egl::DynamicInstance::<egl::EGL1_5>::load_required().context("Failed to load libEGL.so.1")?
-		};
+		let instance = Self::load_instance()?;
 
 		// #define EGL_PLATFORM_GBM_KHR              0x31D7
 		let display = instance.get_platform_display(
 			0x31D7, kms.device().as_raw() as *mut _, &[egl::ATTRIB_NONE]
 		).context("Failed to get platform display")?;
 
+		Self::from_display(instance, display, egl::WINDOW_BIT, format, true)
+	}
+
+	/// Creates a context which does not need any display hardware, rendering only into offscreen targets.
+	///
+	/// Uses `EGL_MESA_platform_surfaceless`, so on a machine without a GPU Mesa falls back to llvmpipe
+	/// (this can also be forced with `LIBGL_ALWAYS_SOFTWARE=1`).
+	pub fn new_surfaceless(
+		format: Format
+	) -> anyhow::Result<Self> {
+		let instance = Self::load_instance()?;
+
+		let client_extensions = instance.query_string(None, egl::EXTENSIONS).context("Failed to query EGL client extensions")?;
+		if !client_extensions.to_string_lossy().split(' ').any(|ext| ext == "EGL_MESA_platform_surfaceless") {
+			anyhow::bail!("EGL_MESA_platform_surfaceless is not supported");
+		}
+
+		// #define EGL_PLATFORM_SURFACELESS_MESA     0x31DD
+		let display = instance.get_platform_display(
+			0x31DD, std::ptr::null_mut(), &[egl::ATTRIB_NONE]
+		).context("Failed to get surfaceless platform display")?;
+
+		// surfaceless platform has no native visuals, so any config will do
+		Self::from_display(instance, display, egl::PBUFFER_BIT, format, false)
+	}
+
+	fn from_display(
+		instance: DynamicInstance<egl::EGL1_5>,
+		display: Display,
+		surface_type: egl::Int,
+		format: Format,
+		match_visual: bool
+	) -> anyhow::Result<Self> {
 		instance.initialize(display).context("Failed to initialize EGL")?;
 
+		match instance.query_string(Some(display), egl::VENDOR) {
+			Ok(vendor) => log::debug!("EGL vendor: {:?}", vendor),
+			Err(err) => log::warn!("Failed to query EGL vendor: {}", err)
+		}
+
 		instance.bind_api(egl::OPENGL_ES_API).context("Failed to bind EGL API")?;
 
 		let mut configs = Vec::with_capacity(
@@ -42,7 +224,7 @@ impl EglContext {
 		instance.choose_config(
 			display,
 			&[
-				egl::SURFACE_TYPE, egl::WINDOW_BIT,
+				egl::SURFACE_TYPE, surface_type,
 				egl::RED_SIZE, 1,
 				egl::GREEN_SIZE, 1,
 				egl::BLUE_SIZE, 1,
@@ -56,6 +238,11 @@ impl EglContext {
 
 		let mut chosen_config = None;
 		for config in configs {
+			if !match_visual {
+				chosen_config = Some(config);
+				break;
+			}
+
 			let visual_id = instance.get_config_attrib(display, config, egl::NATIVE_VISUAL_ID).context("Failed to get config visual id")?;
 
 			if visual_id as u32 == format as u32 {
@@ -75,44 +262,436 @@ impl EglContext {
 			]
 		).context("Failed to create EGL contex")?;
 
+		// we render into our own framebuffers so no surface is bound
+		let display_extensions = instance.query_string(Some(display), egl::EXTENSIONS).context("Failed to query EGL display extensions")?;
+		if !display_extensions.to_string_lossy().split(' ').any(|ext| ext == "EGL_KHR_surfaceless_context") {
+			anyhow::bail!("EGL_KHR_surfaceless_context is not supported");
+		}
 		instance.make_current(display, None, None, Some(context)).context("Failed to bind EGL current context")?;
 
+		gl::load_with(
+			|name| instance.get_proc_address(name).map_or(std::ptr::null(), |f| f as *const _)
+		);
+		let image_target_renderbuffer_storage = instance.get_proc_address(
+			"glEGLImageTargetRenderbufferStorageOES"
+		).context("Failed to load glEGLImageTargetRenderbufferStorageOES, is GL_OES_EGL_image supported?")?;
+		// SAFETY: the signature is given by the GL_OES_EGL_image extension
+		let image_target_renderbuffer_storage = unsafe {
+			std::mem::transmute::<extern "system" fn(), GlEglImageTargetRenderbufferStorageOesFn>(image_target_renderbuffer_storage)
+		};
+
+		// SAFETY: the context is current, the string is static and nul-terminated
+		let gl_extensions = unsafe {
+			let extensions = gl::GetString(gl::EXTENSIONS);
+			if extensions.is_null() {
+				anyhow::bail!("Failed to query GL extensions");
+			}
+			std::ffi::CStr::from_ptr(extensions as *const _).to_string_lossy().into_owned()
+		};
+		// GLES2 only has 16 bit renderbuffer formats in core
+		let offscreen_format = if gl_extensions.split(' ').any(|ext| ext == "GL_OES_rgb8_rgba8") {
+			gl::RGBA8
+		} else {
+			log::warn!("GL_OES_rgb8_rgba8 is not supported, offscreen targets only keep 4 bits per channel");
+			gl::RGBA4
+		};
+
+		let dup_native_fence_fd = if display_extensions.to_string_lossy().split(' ').any(|ext| ext == "EGL_ANDROID_native_fence_sync") {
+			instance.get_proc_address("eglDupNativeFenceFDANDROID").map(
+				// SAFETY: the signature is given by the EGL_ANDROID_native_fence_sync extension
+				|f| unsafe { std::mem::transmute::<extern "system" fn(), EglDupNativeFenceFdAndroidFn>(f) }
+			)
+		} else {
+			None
+		};
+		if dup_native_fence_fd.is_none() {
+			log::info!("EGL_ANDROID_native_fence_sync is not supported, relying on implicit synchronization");
+		}
+
+		let dma_buf_modifiers = if display_extensions.to_string_lossy().split(' ').any(|ext| ext == "EGL_EXT_image_dma_buf_import_modifiers") {
+			let query_formats = instance.get_proc_address("eglQueryDmaBufFormatsEXT").map(
+				// SAFETY: the signature is given by the EGL_EXT_image_dma_buf_import_modifiers extension
+				|f| unsafe { std::mem::transmute::<extern "system" fn(), EglQueryDmaBufFormatsExtFn>(f) }
+			);
+			let query_modifiers = instance.get_proc_address("eglQueryDmaBufModifiersEXT").map(
+				// SAFETY: the signature is given by the EGL_EXT_image_dma_buf_import_modifiers extension
+				|f| unsafe { std::mem::transmute::<extern "system" fn(), EglQueryDmaBufModifiersExtFn>(f) }
+			);
+
+			query_formats.zip(query_modifiers)
+		} else {
+			None
+		};
+		if dma_buf_modifiers.is_none() {
+			log::info!("EGL_EXT_image_dma_buf_import_modifiers is not supported, importing buffers with implicit modifiers");
+		}
+
 		Ok(
 			EglContext {
 				format,
-				instance,
-				display,
-				context
+				shared: Rc::new(
+					EglShared {
+						instance,
+						display,
+						context,
+						image_target_renderbuffer_storage,
+						dup_native_fence_fd,
+						dma_buf_modifiers
+					}
+				),
+				config: chosen_config,
+				offscreen_format
 			}
 		)
 	}
 
-	fn create_framebuffer(&self, fbo: &FrameBufferObject) -> anyhow::Result<()> {
-		let fd = fbo.buffer().fd().context("Failed to get buffer object DMA fd")?;
+	/// Formats and modifiers which can be imported as render targets, `None` without `EGL_EXT_image_dma_buf_import_modifiers`.
+	///
+	/// Modifiers which can only be sampled as external textures are left out.
+	pub fn dma_buf_formats(&self) -> anyhow::Result<Option<FormatTable>> {
+		let (query_formats, query_modifiers) = match self.shared.dma_buf_modifiers {
+			None => return Ok(None),
+			Some(functions) => functions
+		};
+		let display = self.shared.display.as_ptr();
+
+		let mut count = 0;
+		if query_formats(display, 0, std::ptr::null_mut(), &mut count) != egl::TRUE {
+			anyhow::bail!("Failed to query EGL dma-buf format count");
+		}
+		let mut formats = vec![0; count as usize];
+		if query_formats(display, count, formats.as_mut_ptr(), &mut count) != egl::TRUE {
+			anyhow::bail!("Failed to query EGL dma-buf formats");
+		}
+		formats.truncate(count as usize);
+
+		let mut table = FormatTable::new();
+		for format in formats {
+			let fourcc = match DrmFourcc::try_from(format as u32) {
+				Err(_) => {
+					log::trace!("Skipping unknown EGL dma-buf format {:#x}", format);
+					continue
+				}
+				Ok(fourcc) => fourcc
+			};
+
+			let mut count = 0;
+			if query_modifiers(display, format, 0, std::ptr::null_mut(), std::ptr::null_mut(), &mut count) != egl::TRUE {
+				anyhow::bail!("Failed to query EGL dma-buf modifier count of {:?}", fourcc);
+			}
+			let mut modifiers = vec![0; count as usize];
+			let mut external_only = vec![egl::FALSE; count as usize];
+			if query_modifiers(display, format, count, modifiers.as_mut_ptr(), external_only.as_mut_ptr(), &mut count) != egl::TRUE {
+				anyhow::bail!("Failed to query EGL dma-buf modifiers of {:?}", fourcc);
+			}
+
+			for (&modifier, &external_only) in modifiers.iter().zip(external_only.iter()).take(count as usize) {
+				if external_only == egl::FALSE {
+					table.insert(fourcc, DrmModifier::from(modifier));
+				}
+			}
+		}
+
+		if table.is_empty() {
+			return Ok(None)
+		}
+
+		Ok(Some(table))
+	}
+
+	fn create_framebuffer(&self, fbo: &FrameBufferObject) -> anyhow::Result<EglFramebuffer> {
+		let buffer = fbo.buffer();
+		let modifier = buffer.modifier().context("Failed to get buffer object modifier")?;
+		let planes = buffer.plane_count().context("Failed to get buffer object plane count")? as usize;
+		if planes > EGL_DMA_BUF_PLANE_ATTRIBS.len() {
+			anyhow::bail!("Buffer object has {} planes, EGL imports at most {}", planes, EGL_DMA_BUF_PLANE_ATTRIBS.len());
+		}
 
 		// #define EGL_LINUX_DMA_BUF_EXT          0x3270
 		// #define EGL_LINUX_DRM_FOURCC_EXT        0x3271
-		// #define EGL_DMA_BUF_PLANE0_FD_EXT       0x3272
-        // #define EGL_DMA_BUF_PLANE0_OFFSET_EXT   0x3273
-        // #define EGL_DMA_BUF_PLANE0_PITCH_EXT    0x3274
-		let image = self.instance.create_image(
-			self.display,
-			self.context,
+		let mut attribs = vec![
+			egl::WIDTH as _, buffer.width().context("Failed to get buffer object width")? as _,
+			egl::HEIGHT as _, buffer.height().context("Failed to get buffer object height")? as _,
+			0x3271, buffer.format().context("Failed to get buffer object format")? as _
+		];
+		// EGL keeps its own references to the dma-bufs, so our fds are closed when this goes out of scope
+		let mut fds = Vec::with_capacity(planes);
+		for plane in 0 .. planes {
+			let fd = buffer.fd_for_plane(plane as i32).context("Failed to get buffer object plane DMA fd")?;
+			// SAFETY: gbm_bo_get_fd_for_plane returns a new fd owned by the caller
+			let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+			let [fd_attrib, offset_attrib, pitch_attrib] = EGL_DMA_BUF_PLANE_ATTRIBS[plane];
+			attribs.extend_from_slice(&[
+				fd_attrib, std::os::unix::io::AsRawFd::as_raw_fd(&fd) as _,
+				offset_attrib, buffer.offset(plane as i32).context("Failed to get buffer object plane offset")? as _,
+				pitch_attrib, buffer.stride_for_plane(plane as i32).context("Failed to get buffer object plane stride")? as _
+			]);
+			// implicit modifiers cannot be passed, and drivers without the extension only know linear and implicit layouts
+			if modifier != DrmModifier::Invalid && self.shared.dma_buf_modifiers.is_some() {
+				let [lo_attrib, hi_attrib] = EGL_DMA_BUF_PLANE_MODIFIER_ATTRIBS[plane];
+				let modifier = u64::from(modifier);
+				attribs.extend_from_slice(&[
+					lo_attrib, (modifier & 0xFFFF_FFFF) as _,
+					hi_attrib, (modifier >> 32) as _
+				]);
+			}
+
+			fds.push(fd);
+		}
+		attribs.push(egl::ATTRIB_NONE);
+
+		let image = self.shared.instance.create_image(
+			self.shared.display,
+			// EGL_LINUX_DMA_BUF_EXT requires EGL_NO_CONTEXT
+			unsafe { Context::from_ptr(egl::NO_CONTEXT) },
 			0x3270,
 			unsafe { egl::ClientBuffer::from_ptr(std::ptr::null_mut()) },
-			&[
-				egl::WIDTH as _, fbo.buffer().width().unwrap() as _,
-				egl::HEIGHT as _, fbo.buffer().height().unwrap() as _,
-				0x3271, fbo.buffer().format().unwrap() as _,
-				0x3272, fd as _,
-				0x3273, 0,
-				0x3274, fbo.buffer().stride().unwrap() as _,
-				egl::ATTRIB_NONE
-			]
+			&attribs
 		).context("Failed to create EGL image")?;
+		std::mem::drop(fds);
+
+		let mut renderbuffer = 0;
+		let mut framebuffer = 0;
+		unsafe {
+			gl::GenRenderbuffers(1, &mut renderbuffer);
+			gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer);
+			(self.shared.image_target_renderbuffer_storage)(gl::RENDERBUFFER, image.as_ptr());
+			gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+
+			gl::GenFramebuffers(1, &mut framebuffer);
+			gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+			gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::RENDERBUFFER, renderbuffer);
+		}
+		// constructed before checking the status so that everything is cleaned up on error
+		let result = EglFramebuffer {
+			shared: self.shared.clone(),
+			image,
+			renderbuffer,
+			framebuffer
+		};
+
+		let status = unsafe { gl::CheckFramebufferStatus(gl::FRAMEBUFFER) };
+		if status != gl::FRAMEBUFFER_COMPLETE {
+			anyhow::bail!("Framebuffer is not complete: 0x{:X}", status);
+		}
+
+		Ok(result)
+	}
+
+	/// Binds the GL framebuffer for `fbo` as the current draw target, creating and caching it on first use.
+	pub fn bind_framebuffer(&self, fbo: &mut FrameBufferObject) -> anyhow::Result<()> {
+		let framebuffer = match fbo.render_target::<EglFramebuffer>() {
+			Some(target) => target.framebuffer,
+			None => {
+				let target = self.create_framebuffer(fbo).context("Failed to create EGL framebuffer")?;
+				let framebuffer = target.framebuffer;
+				fbo.set_render_target(target);
+
+				framebuffer
+			}
+		};
+
+		let width = fbo.buffer().width().context("Failed to get buffer object width")?;
+		let height = fbo.buffer().height().context("Failed to get buffer object height")?;
+		unsafe {
+			gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+			gl::Viewport(0, 0, width as _, height as _);
+		}
+
+		Ok(())
+	}
+
+	/// Creates an offscreen render target backed by a plain GL renderbuffer.
+	///
+	/// Without `GL_OES_rgb8_rgba8` the renderbuffer only has 4 bits per channel, reading it back still gives 8 bit RGBA.
+	pub fn create_offscreen(&self, width: u32, height: u32) -> anyhow::Result<EglOffscreen> {
+		let mut renderbuffer = 0;
+		let mut framebuffer = 0;
+		unsafe {
+			gl::GenRenderbuffers(1, &mut renderbuffer);
+			gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer);
+			gl::RenderbufferStorage(gl::RENDERBUFFER, self.offscreen_format, width as _, height as _);
+			gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+
+			gl::GenFramebuffers(1, &mut framebuffer);
+			gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+			gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::RENDERBUFFER, renderbuffer);
+		}
+		let result = EglOffscreen {
+			shared: self.shared.clone(),
+			renderbuffer,
+			framebuffer,
+			size: [width, height]
+		};
+
+		let status = unsafe { gl::CheckFramebufferStatus(gl::FRAMEBUFFER) };
+		if status != gl::FRAMEBUFFER_COMPLETE {
+			anyhow::bail!("Offscreen framebuffer is not complete: 0x{:X}", status);
+		}
+
+		Ok(result)
+	}
+
+	pub fn bind_offscreen(&self, target: &EglOffscreen) {
+		unsafe {
+			gl::BindFramebuffer(gl::FRAMEBUFFER, target.framebuffer);
+			gl::Viewport(0, 0, target.size[0] as _, target.size[1] as _);
+		}
+	}
+
+	/// Reads back the contents of `target` as tightly packed RGBA rows, bottom row first.
+	pub fn read_pixels(&self, target: &EglOffscreen, pixels: &mut [u8]) -> anyhow::Result<()> {
+		let [width, height] = target.size;
+		let required = width as usize * height as usize * 4;
+		if pixels.len() < required {
+			anyhow::bail!("Pixel buffer too small: {} < {}", pixels.len(), required);
+		}
+
+		unsafe {
+			gl::BindFramebuffer(gl::FRAMEBUFFER, target.framebuffer);
+			gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+			gl::ReadPixels(
+				0, 0, width as _, height as _,
+				gl::RGBA, gl::UNSIGNED_BYTE,
+				pixels.as_mut_ptr() as *mut _
+			);
+		}
+
+		let error = unsafe { gl::GetError() };
+		if error != gl::NO_ERROR {
+			anyhow::bail!("Failed to read pixels: 0x{:X}", error);
+		}
+
+		Ok(())
+	}
+
+	/// Creates an EGL window surface over `surface` for the gbm surface presentation path.
+	///
+	/// # Safety
+	///
+	/// The returned surface renders into the `gbm_surface` of `surface` without borrowing it, so `surface` must not be
+	/// dropped before the returned [`EglSurface`].
+	pub unsafe fn create_surface(&self, surface: &KmsSurface) -> anyhow::Result<EglSurface> {
+		// SAFETY: the caller keeps the gbm surface alive for as long as the EGL surface
+		let egl_surface = unsafe {
+			self.shared.instance.create_platform_window_surface(
+				self.shared.display,
+				self.config,
+				surface.native_window(),
+				&[egl::ATTRIB_NONE]
+			)
+		}.context("Failed to create EGL window surface")?;
+
+		Ok(
+			EglSurface {
+				shared: self.shared.clone(),
+				surface: egl_surface,
+				size: surface.size()
+			}
+		)
+	}
+
+	/// Makes `surface` current so that rendering goes into its back buffer.
+	pub fn bind_surface(&self, surface: &EglSurface) -> anyhow::Result<()> {
+		self.shared.instance.make_current(
+			self.shared.display,
+			Some(surface.surface),
+			Some(surface.surface),
+			Some(self.shared.context)
+		).context("Failed to bind EGL surface")?;
+
+		unsafe {
+			gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+			gl::Viewport(0, 0, surface.size[0] as _, surface.size[1] as _);
+		}
+
+		Ok(())
+	}
+
+	/// Posts the back buffer of `surface` to its gbm surface, after this the front buffer can be locked.
+	pub fn swap_buffers(&self, surface: &EglSurface) -> anyhow::Result<()> {
+		self.shared.instance.swap_buffers(self.shared.display, surface.surface).context("Failed to swap EGL buffers")
+	}
+
+	/// Waits for all rendering to finish so that the buffer can be safely scanned out.
+	pub fn finish(&self) {
+		unsafe { gl::Finish(); }
+	}
+
+	/// Exports a sync_file signaled once all rendering submitted so far has completed, and flushes it to the gpu.
+	///
+	/// Returns `None` without `EGL_ANDROID_native_fence_sync`, then use [`Self::finish`] instead.
+	pub fn create_render_fence(&self) -> anyhow::Result<Option<SyncFile>> {
+		let dup_native_fence_fd = match self.shared.dup_native_fence_fd {
+			None => return Ok(None),
+			Some(f) => f
+		};
+
+		// SAFETY: the attribute list is terminated and contains no pointers
+		let sync = unsafe {
+			self.shared.instance.create_sync(
+				self.shared.display,
+				EGL_SYNC_NATIVE_FENCE_ANDROID,
+				&[
+					EGL_SYNC_NATIVE_FENCE_FD_ANDROID, EGL_NO_NATIVE_FENCE_FD_ANDROID as _,
+					egl::ATTRIB_NONE
+				]
+			)
+		}.context("Failed to create native fence sync")?;
+
+		// the fence only gets an fd once it has been flushed
+		unsafe { gl::Flush(); }
+		let fd = dup_native_fence_fd(self.shared.display.as_ptr(), sync.as_ptr());
+
+		// SAFETY: the sync was created above and is not used afterwards
+		if let Err(err) = unsafe { self.shared.instance.destroy_sync(self.shared.display, sync) } {
+			log::error!("Failed to destroy EGL sync: {}", err);
+		}
+
+		if fd == EGL_NO_NATIVE_FENCE_FD_ANDROID {
+			anyhow::bail!("Failed to export native fence fd");
+		}
+
+		// SAFETY: eglDupNativeFenceFDANDROID returns a new fd owned by the caller
+		Ok(Some(SyncFile::from(unsafe { OwnedFd::from_raw_fd(fd) })))
+	}
+
+	/// Makes the gpu wait for `fence` before executing further commands, or waits on the cpu without `EGL_ANDROID_native_fence_sync`.
+	pub fn wait_fence(&self, fence: SyncFile) -> anyhow::Result<()> {
+		if self.shared.dup_native_fence_fd.is_none() {
+			fence.wait(None)?;
+			return Ok(())
+		}
+
+		// EGL takes ownership of the fd once the sync is created
+		let fd = fence.into_raw_fd();
+		// SAFETY: the attribute list is terminated and contains no pointers
+		let sync = unsafe {
+			self.shared.instance.create_sync(
+				self.shared.display,
+				EGL_SYNC_NATIVE_FENCE_ANDROID,
+				&[
+					EGL_SYNC_NATIVE_FENCE_FD_ANDROID, fd as _,
+					egl::ATTRIB_NONE
+				]
+			)
+		};
+		let sync = match sync {
+			Ok(sync) => sync,
+			Err(err) => {
+				// SAFETY: the fd was not consumed
+				std::mem::drop(unsafe { OwnedFd::from_raw_fd(fd) });
+				return Err(err).context("Failed to import native fence")
+			}
+		};
+
+		// SAFETY: the sync was created above and is not used afterwards
+		let result = unsafe { self.shared.instance.wait_sync(self.shared.display, sync, 0) }.context("Failed to wait for native fence");
+		if let Err(err) = unsafe { self.shared.instance.destroy_sync(self.shared.display, sync) } {
+			log::error!("Failed to destroy EGL sync: {}", err);
+		}
 
-		// TODO: Close fd?
-		
-		todo!()
+		result
 	}
 }
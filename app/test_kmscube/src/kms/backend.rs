@@ -0,0 +1,515 @@
+use std::{
+	fmt,
+	os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+	time::Duration
+};
+
+use anyhow::Context;
+
+use drm::control::{
+	Device as ControlDevice,
+	Event,
+	Mode,
+	PageFlipFlags,
+	RawResourceHandle,
+	ResourceHandles,
+	atomic::{AtomicCommitFlags, AtomicModeReq},
+	connector::{Handle as ConnectorHandle, Interface as ConnectorInterface, State as ConnectorState},
+	encoder::{Handle as EncoderHandle, Kind as EncoderKind},
+	crtc::Handle as CrtcHandle,
+	framebuffer::Handle as FramebufferHandle,
+	plane::Handle as PlaneHandle,
+	property::{Handle as PropertyHandle, Value as PropertyValue, ValueType as PropertyValueType}
+};
+
+use drm_ffi::result::SystemError;
+
+use super::{device::DrmDevice, format::FormatTable, sync_file::SyncFile};
+
+/// Handle of a DRM object which can have properties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectHandle {
+	Connector(ConnectorHandle),
+	Crtc(CrtcHandle),
+	Plane(PlaneHandle)
+}
+
+impl ObjectHandle {
+	pub fn raw(&self) -> u32 {
+		match *self {
+			ObjectHandle::Connector(handle) => handle.into(),
+			ObjectHandle::Crtc(handle) => handle.into(),
+			ObjectHandle::Plane(handle) => handle.into()
+		}
+	}
+}
+
+/// Error of a nonblocking atomic commit the kernel refused with `EBUSY`, as a previous commit has not been applied yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitBusy;
+impl fmt::Display for CommitBusy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Previous atomic commit has not been applied yet")
+	}
+}
+impl std::error::Error for CommitBusy {}
+
+/// Property assignments of an atomic commit.
+///
+/// Mirrors [`AtomicModeReq`], but can be inspected, compared and recorded.
+/// Fence properties are kept apart since their values are only meaningful within the running process.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitRequest {
+	pub properties: Vec<(ObjectHandle, PropertyHandle, u64)>,
+	/// `IN_FENCE_FD` assignments, the sync_file fds must stay open until the commit returns
+	pub in_fences: Vec<(ObjectHandle, PropertyHandle, RawFd)>,
+	/// `OUT_FENCE_PTR` properties, the backend returns one fence for each
+	pub out_fences: Vec<(ObjectHandle, PropertyHandle)>
+}
+impl CommitRequest {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn add(&mut self, object: ObjectHandle, property: PropertyHandle, value: u64) {
+		self.properties.push((object, property, value));
+	}
+
+	/// Makes `object` wait for the sync_file `fence` before using its new state.
+	pub fn add_in_fence(&mut self, object: ObjectHandle, property: PropertyHandle, fence: RawFd) {
+		self.in_fences.push((object, property, fence));
+	}
+
+	/// Requests a fence signaled once the new state of `object` is applied, i.e. the previous buffers are released.
+	pub fn add_out_fence(&mut self, object: ObjectHandle, property: PropertyHandle) {
+		self.out_fences.push((object, property));
+	}
+
+	/// Value assigned to `property` of `object`, if any.
+	pub fn get(&self, object: ObjectHandle, property: PropertyHandle) -> Option<u64> {
+		self.properties.iter().rev().find_map(
+			|&(o, p, value)| if o == object && p == property {
+				Some(value)
+			} else {
+				None
+			}
+		)
+	}
+}
+
+/// Mode setting resources of a device.
+#[derive(Debug, Clone)]
+pub struct ResourcesDesc {
+	pub connectors: Vec<ConnectorHandle>,
+	pub encoders: Vec<EncoderHandle>,
+	/// in kernel order, the index of a crtc in this list is used in possible crtc masks
+	pub crtcs: Vec<CrtcHandle>
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectorDesc {
+	pub handle: ConnectorHandle,
+	pub interface: ConnectorInterface,
+	pub interface_id: u32,
+	pub state: ConnectorState,
+	pub modes: Vec<Mode>,
+	pub encoders: Vec<EncoderHandle>,
+	pub current_encoder: Option<EncoderHandle>
+}
+
+/// Kernel names of connector types, indexed by `DRM_MODE_CONNECTOR_*`.
+const CONNECTOR_TYPE_NAMES: [&str; 21] = [
+	"Unknown", "VGA", "DVI-I", "DVI-D", "DVI-A", "Composite", "SVIDEO", "LVDS", "Component", "DIN",
+	"DP", "HDMI-A", "HDMI-B", "TV", "eDP", "Virtual", "DSI", "DPI", "Writeback", "SPI", "USB"
+];
+
+impl ConnectorDesc {
+	/// Name as used by the kernel and compositors, e.g. `HDMI-A-2`.
+	pub fn name(&self) -> String {
+		let type_name = CONNECTOR_TYPE_NAMES.get(Self::interface_type(self.interface) as usize).copied().unwrap_or("Unknown");
+
+		format!("{}-{}", type_name, self.interface_id)
+	}
+
+	/// Kernel connector type value, `DRM_MODE_CONNECTOR_*`.
+	pub fn interface_type(interface: ConnectorInterface) -> u32 {
+		#[allow(unreachable_patterns)]
+		match interface {
+			ConnectorInterface::Unknown => 0,
+			ConnectorInterface::VGA => 1,
+			ConnectorInterface::DVII => 2,
+			ConnectorInterface::DVID => 3,
+			ConnectorInterface::DVIA => 4,
+			ConnectorInterface::Composite => 5,
+			ConnectorInterface::SVideo => 6,
+			ConnectorInterface::LVDS => 7,
+			ConnectorInterface::Component => 8,
+			ConnectorInterface::NinePinDIN => 9,
+			ConnectorInterface::DisplayPort => 10,
+			ConnectorInterface::HDMIA => 11,
+			ConnectorInterface::HDMIB => 12,
+			ConnectorInterface::TV => 13,
+			ConnectorInterface::EmbeddedDisplayPort => 14,
+			ConnectorInterface::Virtual => 15,
+			ConnectorInterface::DSI => 16,
+			ConnectorInterface::DPI => 17,
+			_ => 0
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct EncoderDesc {
+	pub handle: EncoderHandle,
+	pub kind: EncoderKind,
+	/// crtc currently driven by this encoder
+	pub crtc: Option<CrtcHandle>,
+	pub possible_crtcs: Vec<CrtcHandle>
+}
+
+#[derive(Debug, Clone)]
+pub struct CrtcDesc {
+	pub handle: CrtcHandle,
+	pub mode: Option<Mode>,
+	pub framebuffer: Option<FramebufferHandle>,
+	pub position: (u32, u32)
+}
+
+#[derive(Debug, Clone)]
+pub struct PlaneDesc {
+	pub handle: PlaneHandle,
+	pub crtc: Option<CrtcHandle>,
+	pub framebuffer: Option<FramebufferHandle>,
+	pub possible_crtcs: Vec<CrtcHandle>,
+	/// fourcc codes
+	pub formats: Vec<u32>
+}
+
+/// Value type of a property, see [`super::property::TypedValue`] for the values of each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PropertyKind {
+	/// type not reported, raw values are passed through unchecked
+	#[default]
+	Unknown,
+	Boolean,
+	Range { min: u64, max: u64 },
+	SignedRange { min: i64, max: i64 },
+	/// one of [`PropertyDesc::enum_values`]
+	Enum,
+	/// combination of [`PropertyDesc::enum_values`], whose values are bit indices
+	Bitmask,
+	/// id of a property blob, 0 for none
+	Blob,
+	/// id of a mode object such as a crtc or framebuffer, 0 for none
+	Object
+}
+
+#[derive(Debug, Clone)]
+pub struct PropertyDesc {
+	pub handle: PropertyHandle,
+	pub name: String,
+	pub kind: PropertyKind,
+	/// current raw value
+	pub value: u64,
+	/// value and name pairs if this is an enum or bitmask property
+	pub enum_values: Vec<(u64, String)>
+}
+
+/// Event read from the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrmEvent {
+	/// a commit requested with `PAGE_FLIP_EVENT` has been applied on `crtc`
+	PageFlip {
+		crtc: CrtcHandle,
+		/// vblank counter of the crtc
+		frame: u32,
+		/// `CLOCK_MONOTONIC` timestamp of the vblank
+		time: Duration
+	}
+}
+
+/// Queries and commits needed to choose and drive a display configuration.
+///
+/// Implemented by [`DrmDevice`] for real hardware, by `FakeDevice` for scripted topologies in tests
+/// and by [`super::record`] for capturing and replaying traffic of real devices.
+pub trait DrmBackend {
+	/// Whether atomic commits can be used, otherwise only [`Self::set_crtc`] and [`Self::page_flip`].
+	fn is_atomic(&self) -> bool;
+
+	fn resources(&self) -> anyhow::Result<ResourcesDesc>;
+
+	fn connector(&self, handle: ConnectorHandle) -> anyhow::Result<ConnectorDesc>;
+
+	fn encoder(&self, handle: EncoderHandle) -> anyhow::Result<EncoderDesc>;
+
+	fn crtc(&self, handle: CrtcHandle) -> anyhow::Result<CrtcDesc>;
+
+	/// Lists all planes, including primary and cursor planes if the universal planes capability is set.
+	fn planes(&self) -> anyhow::Result<Vec<PlaneHandle>>;
+
+	fn plane(&self, handle: PlaneHandle) -> anyhow::Result<PlaneDesc>;
+
+	fn properties(&self, object: ObjectHandle) -> anyhow::Result<Vec<PropertyDesc>>;
+
+	fn property_blob(&self, blob: u64) -> anyhow::Result<Vec<u8>>;
+
+	/// Creates a property blob containing `mode` and returns its id.
+	fn create_mode_blob(&self, mode: &Mode) -> anyhow::Result<u64>;
+
+	/// Destroys a blob created by [`Self::create_mode_blob`], commits already using it keep their reference.
+	fn destroy_property_blob(&self, blob: u64) -> anyhow::Result<()>;
+
+	/// Returns the fences requested by [`CommitRequest::add_out_fence`] in order, `None` where the backend has none.
+	///
+	/// Nonblocking commits fail with [`CommitBusy`] while a previous commit on the same crtcs is pending.
+	fn atomic_commit(&self, flags: AtomicCommitFlags, request: &CommitRequest) -> anyhow::Result<Vec<Option<SyncFile>>>;
+
+	/// Legacy modeset showing `framebuffer` on `crtc` driving `connectors`, blocks until it is applied.
+	fn set_crtc(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle, connectors: &[ConnectorHandle], mode: Mode) -> anyhow::Result<()>;
+
+	/// Legacy page flip of `crtc` to `framebuffer` at the next vblank, sends a page flip event.
+	fn page_flip(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle) -> anyhow::Result<()>;
+
+	/// Blocks until at least one event is available and returns all available events.
+	fn receive_events(&self) -> anyhow::Result<Vec<DrmEvent>>;
+
+	/// Notes the formats the renderer can import before configurations are validated against them.
+	///
+	/// Only captures keep them, so that replays negotiate the same candidates, see [`super::record`].
+	fn note_importer_formats(&self, _formats: &FormatTable) {}
+}
+
+impl DrmDevice {
+	fn raw_resources(&self) -> anyhow::Result<ResourceHandles> {
+		self.resource_handles().context("Failed to query control device resources")
+	}
+
+	/// Bit indices and names of a bitmask property, which the drm crate does not expose.
+	fn bitmask_values(&self, property: PropertyHandle) -> anyhow::Result<Vec<(u64, String)>> {
+		let mut enums = Vec::new();
+		drm_ffi::mode::get_property(self.as_raw_fd(), property.into(), None, Some(&mut enums)).context("Failed to query bitmask property values")?;
+
+		Ok(
+			enums.iter().map(
+				|value| {
+					let name: Vec<u8> = value.name.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+
+					(value.value, String::from_utf8_lossy(&name).into_owned())
+				}
+			).collect()
+		)
+	}
+}
+impl DrmBackend for DrmDevice {
+	fn is_atomic(&self) -> bool {
+		DrmDevice::is_atomic(self)
+	}
+
+	fn resources(&self) -> anyhow::Result<ResourcesDesc> {
+		let resources = self.raw_resources()?;
+
+		Ok(
+			ResourcesDesc {
+				connectors: resources.connectors().to_vec(),
+				encoders: resources.encoders().to_vec(),
+				crtcs: resources.crtcs().to_vec()
+			}
+		)
+	}
+
+	fn connector(&self, handle: ConnectorHandle) -> anyhow::Result<ConnectorDesc> {
+		let info = self.get_connector(handle).context("Failed to query connector")?;
+
+		Ok(
+			ConnectorDesc {
+				handle,
+				interface: info.interface(),
+				interface_id: info.interface_id(),
+				state: info.state(),
+				modes: info.modes().to_vec(),
+				encoders: info.encoders().iter().flatten().copied().collect(),
+				current_encoder: info.current_encoder()
+			}
+		)
+	}
+
+	fn encoder(&self, handle: EncoderHandle) -> anyhow::Result<EncoderDesc> {
+		let info = self.get_encoder(handle).context("Failed to query encoder")?;
+
+		Ok(
+			EncoderDesc {
+				handle,
+				kind: info.kind(),
+				crtc: info.crtc(),
+				possible_crtcs: self.raw_resources()?.filter_crtcs(info.possible_crtcs())
+			}
+		)
+	}
+
+	fn crtc(&self, handle: CrtcHandle) -> anyhow::Result<CrtcDesc> {
+		let info = self.get_crtc(handle).context("Failed to query crtc")?;
+
+		Ok(
+			CrtcDesc {
+				handle,
+				mode: info.mode(),
+				framebuffer: info.framebuffer(),
+				position: info.position()
+			}
+		)
+	}
+
+	fn planes(&self) -> anyhow::Result<Vec<PlaneHandle>> {
+		let planes = self.plane_handles().context("Failed to query plane resources")?;
+
+		Ok(planes.planes().to_vec())
+	}
+
+	fn plane(&self, handle: PlaneHandle) -> anyhow::Result<PlaneDesc> {
+		let info = self.get_plane(handle).context("Failed to query plane")?;
+
+		Ok(
+			PlaneDesc {
+				handle,
+				crtc: info.crtc(),
+				framebuffer: info.framebuffer(),
+				possible_crtcs: self.raw_resources()?.filter_crtcs(info.possible_crtcs()),
+				formats: info.formats().to_vec()
+			}
+		)
+	}
+
+	fn properties(&self, object: ObjectHandle) -> anyhow::Result<Vec<PropertyDesc>> {
+		let properties = match object {
+			ObjectHandle::Connector(handle) => self.get_properties(handle),
+			ObjectHandle::Crtc(handle) => self.get_properties(handle),
+			ObjectHandle::Plane(handle) => self.get_properties(handle)
+		}.context("Failed to query object properties")?;
+
+		let (prop_handles, prop_values) = properties.as_props_and_values();
+
+		let mut result = Vec::with_capacity(prop_handles.len());
+		for (&handle, &value) in prop_handles.iter().zip(prop_values.iter()) {
+			let property = self.get_property(handle).context("Failed to query property")?;
+			log::trace!(
+				"Property: {}{}{:?}: {:?} = {}",
+				if property.atomic() { "atomic " } else { "" },
+				if property.mutable() { "mut " } else { "" },
+				property.name(),
+				property.value_type(),
+				value
+			);
+
+			let (kind, enum_values) = match property.value_type() {
+				PropertyValueType::Unknown => (PropertyKind::Unknown, Vec::new()),
+				PropertyValueType::Boolean => (PropertyKind::Boolean, Vec::new()),
+				PropertyValueType::UnsignedRange(min, max) => (PropertyKind::Range { min, max }, Vec::new()),
+				PropertyValueType::SignedRange(min, max) => (PropertyKind::SignedRange { min, max }, Vec::new()),
+				PropertyValueType::Enum(values) => (
+					PropertyKind::Enum,
+					values.values().1.iter().map(
+						|value| (value.value(), value.name().to_string_lossy().into_owned())
+					).collect()
+				),
+				PropertyValueType::Bitmask => (PropertyKind::Bitmask, self.bitmask_values(handle)?),
+				PropertyValueType::Blob => (PropertyKind::Blob, Vec::new()),
+				PropertyValueType::Object
+				| PropertyValueType::CRTC
+				| PropertyValueType::Connector
+				| PropertyValueType::Encoder
+				| PropertyValueType::Framebuffer
+				| PropertyValueType::Plane
+				| PropertyValueType::Property => (PropertyKind::Object, Vec::new())
+			};
+
+			result.push(
+				PropertyDesc {
+					handle,
+					name: property.name().to_string_lossy().into_owned(),
+					kind,
+					value,
+					enum_values
+				}
+			);
+		}
+
+		Ok(result)
+	}
+
+	fn property_blob(&self, blob: u64) -> anyhow::Result<Vec<u8>> {
+		self.get_property_blob(blob).context("Failed to query property blob")
+	}
+
+	fn create_mode_blob(&self, mode: &Mode) -> anyhow::Result<u64> {
+		match self.create_property_blob(mode).context("Failed to create mode property blob")? {
+			PropertyValue::Blob(blob) => Ok(blob),
+			other => Err(anyhow::anyhow!("Unexpected property blob value {:?}", other))
+		}
+	}
+
+	fn destroy_property_blob(&self, blob: u64) -> anyhow::Result<()> {
+		ControlDevice::destroy_property_blob(self, blob).context("Failed to destroy property blob")
+	}
+
+	fn atomic_commit(&self, flags: AtomicCommitFlags, request: &CommitRequest) -> anyhow::Result<Vec<Option<SyncFile>>> {
+		let mut raw_request = AtomicModeReq::new();
+		for &(object, property, value) in request.properties.iter() {
+			let object = RawResourceHandle::new(object.raw()).context("Invalid object handle")?;
+			raw_request.add_raw_property(object, property, value);
+		}
+		for &(object, property, fence) in request.in_fences.iter() {
+			let object = RawResourceHandle::new(object.raw()).context("Invalid object handle")?;
+			raw_request.add_raw_property(object, property, fence as u64);
+		}
+
+		// the kernel writes a new sync_file fd through each pointer once the commit is accepted
+		let mut out_fences: Vec<RawFd> = vec![-1; request.out_fences.len()];
+		for (index, &(object, property)) in request.out_fences.iter().enumerate() {
+			let object = RawResourceHandle::new(object.raw()).context("Invalid object handle")?;
+			// SAFETY: index is in bounds and the vector is neither moved nor reallocated until the commit returns
+			let pointer = unsafe { out_fences.as_mut_ptr().add(index) };
+			raw_request.add_raw_property(object, property, pointer as u64);
+		}
+
+		if let Err(err) = ControlDevice::atomic_commit(self, flags, raw_request) {
+			return match err {
+				SystemError::Unknown { errno } if errno as i32 == nix::libc::EBUSY => Err(CommitBusy.into()),
+				err => Err(anyhow::Error::new(err).context("Failed to perform atomic commit"))
+			}
+		}
+
+		Ok(
+			out_fences.into_iter().map(
+				// SAFETY: the kernel installed a new fd owned by the caller
+				|fd| if fd < 0 { None } else { Some(SyncFile::from(unsafe { OwnedFd::from_raw_fd(fd) })) }
+			).collect()
+		)
+	}
+
+	fn set_crtc(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle, connectors: &[ConnectorHandle], mode: Mode) -> anyhow::Result<()> {
+		ControlDevice::set_crtc(self, crtc, Some(framebuffer), (0, 0), connectors, Some(mode)).context("Failed to set crtc")
+	}
+
+	fn page_flip(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle) -> anyhow::Result<()> {
+		ControlDevice::page_flip(self, crtc, framebuffer, &[PageFlipFlags::PageFlipEvent], None).context("Failed to flip page")
+	}
+
+	fn receive_events(&self) -> anyhow::Result<Vec<DrmEvent>> {
+		let events = ControlDevice::receive_events(self).context("Failed to receive drm events")?;
+
+		Ok(
+			events.filter_map(
+				|event| match event {
+					Event::PageFlip(event) => Some(
+						DrmEvent::PageFlip {
+							crtc: event.crtc,
+							frame: event.frame,
+							time: event.duration
+						}
+					),
+					Event::Vblank(_) | Event::Unknown(_) => None
+				}
+			).collect()
+		)
+	}
+}
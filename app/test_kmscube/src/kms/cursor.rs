@@ -0,0 +1,134 @@
+//! Pointer feedback on the cursor plane of an output, e.g. on touchscreens which show no pointer otherwise.
+//!
+//! Cursor planes scan out small linear ARGB buffers of the size reported by the driver, written by the cpu.
+//! The cursor is a layer on that plane, so it is committed with every present, and moving it only commits its
+//! position without blocking when no present is in flight. Otherwise the next present moves it.
+
+use anyhow::Context;
+
+use drm::{
+	buffer::DrmFourcc,
+	control::{Device, framebuffer::Handle as FramebufferHandle}
+};
+use gbm::{BufferObject, BufferObjectFlags};
+
+use super::{KmsDevice, framebuffer, layer::{LayerId, LayerState}};
+
+/// Format of cursor images, the one every cursor plane supports.
+pub const CURSOR_FORMAT: DrmFourcc = DrmFourcc::Argb8888;
+
+/// Cursor buffer written by the cpu, with a framebuffer for the cursor plane.
+struct CursorBuffer {
+	device: KmsDevice,
+	buffer: BufferObject<()>,
+	framebuffer: FramebufferHandle,
+	size: (u32, u32)
+}
+impl CursorBuffer {
+	fn new(device: KmsDevice, size: (u32, u32)) -> anyhow::Result<Self> {
+		log::trace!("Creating cursor buffer object with {:?}", size);
+
+		// writing is only guaranteed to work together with the cursor flag
+		let buffer = device.create_buffer_object(
+			size.0, size.1,
+			CURSOR_FORMAT, BufferObjectFlags::CURSOR | BufferObjectFlags::WRITE
+		).context("Failed to create cursor buffer object")?;
+		let framebuffer = framebuffer::add_framebuffer(&device, &buffer)?;
+
+		Ok(
+			CursorBuffer {
+				device,
+				buffer,
+				framebuffer,
+				size
+			}
+		)
+	}
+
+	/// Writes `pixels` with rows of `width` into the top left corner, the rest is transparent.
+	fn write(&mut self, pixels: &[u32], width: u32) -> anyhow::Result<()> {
+		let height = if width == 0 { 0 } else { pixels.len() as u32 / width };
+		if width > self.size.0 || height > self.size.1 || width * height != pixels.len() as u32 {
+			anyhow::bail!("Cursor image of {} pixels in rows of {} does not fit into {:?}", pixels.len(), width, self.size);
+		}
+
+		let stride = self.buffer.stride().context("Failed to get cursor buffer stride")? as usize;
+		let mut data = vec![0u8; stride * self.size.1 as usize];
+		if width > 0 {
+			for (row, pixels) in data.chunks_exact_mut(stride).zip(pixels.chunks_exact(width as usize)) {
+				for (bytes, pixel) in row.chunks_exact_mut(4).zip(pixels) {
+					// ARGB8888 is little endian
+					bytes.copy_from_slice(&pixel.to_le_bytes());
+				}
+			}
+		}
+
+		self.buffer.write(&data)?.context("Failed to write cursor buffer")
+	}
+}
+impl Drop for CursorBuffer {
+	fn drop(&mut self) {
+		if let Err(err) = self.device.destroy_framebuffer(self.framebuffer) {
+			log::error!("Failed to destroy cursor framebuffer: {}", err);
+		}
+	}
+}
+
+/// Cursor shown on an output, see [`super::KmsContext::enable_cursor`].
+pub(super) struct Cursor {
+	/// index of the output in [`super::KmsConfig::outputs`]
+	pub output: usize,
+	pub layer: LayerId,
+	buffer: CursorBuffer,
+	/// point of the image placed at the position
+	hotspot: (u32, u32),
+	/// position of the hotspot within the mode
+	position: (i32, i32)
+}
+impl Cursor {
+	/// Allocates a transparent cursor image, which is placed on a plane by `place` returning its layer.
+	pub fn new(
+		device: KmsDevice,
+		output: usize,
+		size: (u32, u32),
+		place: impl FnOnce(FramebufferHandle, &LayerState) -> anyhow::Result<Option<LayerId>>
+	) -> anyhow::Result<Option<Self>> {
+		let mut buffer = CursorBuffer::new(device, size)?;
+		buffer.write(&[], 0)?;
+
+		let layer = match place(buffer.framebuffer, &LayerState::at(0, 0, size.0, size.1))? {
+			None => return Ok(None),
+			Some(layer) => layer
+		};
+
+		Ok(
+			Some(
+				Cursor {
+					output,
+					layer,
+					buffer,
+					hotspot: (0, 0),
+					position: (0, 0)
+				}
+			)
+		)
+	}
+
+	/// Replaces the image in place, so it changes while being scanned out.
+	pub fn set_image(&mut self, pixels: &[u32], width: u32, hotspot: (u32, u32)) -> anyhow::Result<()> {
+		self.buffer.write(pixels, width)?;
+		self.hotspot = hotspot;
+
+		Ok(())
+	}
+
+	/// Moves the hotspot to `position` within the mode, see [`Self::plane_position`].
+	pub fn move_to(&mut self, position: (i32, i32)) {
+		self.position = position;
+	}
+
+	/// Top left corner of the plane for the current position and hotspot.
+	pub fn plane_position(&self) -> (i32, i32) {
+		(self.position.0 - self.hotspot.0 as i32, self.position.1 - self.hotspot.1 as i32)
+	}
+}
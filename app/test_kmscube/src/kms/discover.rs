@@ -0,0 +1,260 @@
+//! Enumeration of DRM primary nodes and selection of the one driving the displays.
+
+use std::{
+	fs,
+	os::unix::io::AsRawFd,
+	path::{Path, PathBuf},
+	str::FromStr
+};
+
+use anyhow::Context;
+
+use drm::{
+	Device,
+	control::connector::State as ConnectorState
+};
+
+use super::{backend::DrmBackend, device::DrmDevice, session::Session};
+
+const DEVICE_DIRECTORY: &str = "/dev/dri";
+const SYSFS_CLASS_DIRECTORY: &str = "/sys/class/drm";
+
+/// What was found out about a primary node by probing it.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+	/// device node, e.g. `/dev/dri/card1`
+	pub path: PathBuf,
+	/// canonical sysfs path of the underlying device, e.g. `/sys/devices/platform/gpu`
+	pub sysfs_path: Option<PathBuf>,
+	pub driver: String,
+	pub driver_description: String,
+	/// major, minor, patchlevel
+	pub driver_version: (i32, i32, i32),
+	/// false for render-only devices like v3d
+	pub supports_kms: bool,
+	pub supports_atomic: bool,
+	/// names of connected connectors, e.g. `HDMI-A-1`
+	pub connected: Vec<String>
+}
+impl DeviceInfo {
+	/// Whether the device can be driven by [`super::KmsContext`] and has something to display on.
+	pub fn is_usable(&self) -> bool {
+		self.supports_kms && !self.connected.is_empty()
+	}
+
+	/// Probes the device at `path`, opened through `session` and closed again afterwards.
+	fn probe(session: &mut dyn Session, path: PathBuf) -> anyhow::Result<Self> {
+		let device = session.open_device(&path).map(DrmDevice::from).context("Failed to open drm device")?;
+		let result = Self::query(&device, path.clone());
+
+		std::mem::drop(device);
+		if let Err(err) = session.close_device(&path) {
+			log::warn!("Failed to close {} after probing: {:#}", path.display(), err);
+		}
+
+		result
+	}
+
+	fn query(device: &DrmDevice, path: PathBuf) -> anyhow::Result<Self> {
+		let driver = device.get_driver().context("Failed to query driver")?;
+		let version = drm_ffi::get_version(device.as_raw_fd(), None, None, None).context("Failed to query driver version")?;
+
+		// render-only devices reject mode setting ioctls
+		let supports_kms = device.resource_handles().is_ok();
+		let supports_atomic = supports_kms && device.enable_atomic().is_ok();
+
+		let mut connected = Vec::new();
+		if supports_kms {
+			for handle in device.resources()?.connectors {
+				let connector = device.connector(handle)?;
+				if connector.state == ConnectorState::Connected {
+					connected.push(connector.name());
+				}
+			}
+		}
+
+		let sysfs_path = path.file_name().and_then(
+			|name| fs::canonicalize(Path::new(SYSFS_CLASS_DIRECTORY).join(name).join("device")).ok()
+		);
+
+		Ok(
+			DeviceInfo {
+				path,
+				sysfs_path,
+				driver: driver.name().to_string_lossy().into_owned(),
+				driver_description: driver.description().to_string_lossy().into_owned(),
+				driver_version: (version.version_major, version.version_minor, version.version_patchlevel),
+				supports_kms,
+				supports_atomic,
+				connected
+			}
+		)
+	}
+}
+impl std::fmt::Display for DeviceInfo {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{}: {} {}.{}.{} ({})",
+			self.path.display(),
+			self.driver,
+			self.driver_version.0, self.driver_version.1, self.driver_version.2,
+			self.driver_description
+		)?;
+
+		if let Some(ref sysfs_path) = self.sysfs_path {
+			write!(f, " at {}", sysfs_path.display())?;
+		}
+
+		if !self.supports_kms {
+			return write!(f, ", render only")
+		}
+		if !self.supports_atomic {
+			write!(f, ", legacy modesetting only")?;
+		}
+
+		if self.connected.is_empty() {
+			write!(f, ", nothing connected")
+		} else {
+			write!(f, ", connected: {}", self.connected.join(", "))
+		}
+	}
+}
+
+/// Probes all `/dev/dri/card*` nodes in numeric order, opening them through `session`.
+///
+/// Nodes which fail to probe are logged and skipped.
+pub fn enumerate_devices(session: &mut dyn Session) -> anyhow::Result<Vec<DeviceInfo>> {
+	let mut paths: Vec<(u32, PathBuf)> = Vec::new();
+	for entry in fs::read_dir(DEVICE_DIRECTORY).with_context(|| format!("Failed to list {}", DEVICE_DIRECTORY))? {
+		let entry = entry.with_context(|| format!("Failed to list {}", DEVICE_DIRECTORY))?;
+
+		let name = entry.file_name();
+		let index = name.to_str().and_then(|name| name.strip_prefix("card")).and_then(|index| index.parse().ok());
+		if let Some(index) = index {
+			paths.push((index, entry.path()));
+		}
+	}
+	paths.sort();
+
+	let mut devices = Vec::with_capacity(paths.len());
+	for (_, path) in paths {
+		match DeviceInfo::probe(session, path.clone()) {
+			Ok(info) => {
+				log::debug!("Device {}", info);
+				devices.push(info);
+			}
+			Err(err) => log::warn!("Failed to probe {}: {:#}", path.display(), err)
+		}
+	}
+
+	Ok(devices)
+}
+
+/// Which device to open.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DeviceSelector {
+	/// first device supporting atomic modesetting with a connected connector
+	#[default]
+	Any,
+	/// explicit device node, used without probing
+	Path(PathBuf),
+	/// driver name, e.g. `vc4`
+	Driver(String),
+	/// sysfs path of the device, either canonical or a trailing part of it, e.g. `platform/gpu`
+	Sysfs(PathBuf)
+}
+impl DeviceSelector {
+	fn matches(&self, device: &DeviceInfo) -> bool {
+		match self {
+			DeviceSelector::Any => true,
+			DeviceSelector::Path(path) => device.path == *path,
+			DeviceSelector::Driver(driver) => device.driver == *driver,
+			DeviceSelector::Sysfs(path) => match device.sysfs_path {
+				None => false,
+				Some(ref sysfs_path) => sysfs_path.ends_with(path)
+					|| fs::canonicalize(path).map(|path| *sysfs_path == path).unwrap_or(false)
+			}
+		}
+	}
+
+	/// Resolves the selector to a device node, probing devices through `session`.
+	pub fn choose(&self, session: &mut dyn Session) -> anyhow::Result<PathBuf> {
+		if let DeviceSelector::Path(path) = self {
+			return Ok(path.clone())
+		}
+
+		let devices = enumerate_devices(session)?;
+		let mut candidates = devices.iter().filter(|device| self.matches(device));
+
+		// prefer usable devices, but let explicit selections fail later with a proper error
+		let chosen = match self {
+			DeviceSelector::Any => candidates.find(|device| device.is_usable()),
+			_ => {
+				let candidates: Vec<&DeviceInfo> = candidates.collect();
+				candidates.iter().find(|device| device.is_usable()).or_else(
+					|| candidates.iter().find(|device| device.supports_kms)
+				).copied()
+			}
+		};
+
+		match chosen {
+			Some(device) => {
+				log::info!("Choosing device {}", device);
+				Ok(device.path.clone())
+			}
+			None => {
+				let available: Vec<String> = devices.iter().map(|device| device.to_string()).collect();
+
+				Err(
+					anyhow::anyhow!(
+						"Did not find a device matching {} among [{}]",
+						self, available.join("; ")
+					)
+				)
+			}
+		}
+	}
+}
+impl FromStr for DeviceSelector {
+	type Err = anyhow::Error;
+
+	/// Parses `any`, `driver:NAME`, `sysfs:PATH` or a device node path.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.is_empty() || s == "any" {
+			Ok(DeviceSelector::Any)
+		} else if let Some(driver) = s.strip_prefix("driver:") {
+			Ok(DeviceSelector::Driver(driver.to_string()))
+		} else if let Some(path) = s.strip_prefix("sysfs:") {
+			Ok(DeviceSelector::Sysfs(path.into()))
+		} else {
+			Ok(DeviceSelector::Path(s.into()))
+		}
+	}
+}
+impl std::fmt::Display for DeviceSelector {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			DeviceSelector::Any => write!(f, "any"),
+			DeviceSelector::Path(path) => write!(f, "{}", path.display()),
+			DeviceSelector::Driver(driver) => write!(f, "driver:{}", driver),
+			DeviceSelector::Sysfs(path) => write!(f, "sysfs:{}", path.display())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::fake::FakeSession;
+	use super::*;
+
+	#[test]
+	fn probing_closes_devices() {
+		let script = FakeSession::default();
+		let mut session = script.clone();
+
+		// not a drm device, so probing fails after opening it
+		assert!(DeviceInfo::probe(&mut session, PathBuf::from("/dev/null")).is_err());
+		assert!(script.open_devices().is_empty());
+	}
+}
@@ -0,0 +1,678 @@
+//! In-memory DRM topology which can be scripted to exercise configuration selection without hardware.
+
+use std::{
+	cell::{Cell, RefCell},
+	collections::{HashMap, VecDeque},
+	fs,
+	os::unix::io::OwnedFd,
+	path::{Path, PathBuf},
+	rc::Rc,
+	time::Duration
+};
+
+use anyhow::Context;
+
+use drm::buffer::DrmFourcc;
+use drm::control::{
+	Mode, ModeFlags, ModeTypeFlags,
+	RawResourceHandle,
+	atomic::AtomicCommitFlags,
+	connector::{Handle as ConnectorHandle, Interface as ConnectorInterface, State as ConnectorState},
+	encoder::{Handle as EncoderHandle, Kind as EncoderKind},
+	crtc::Handle as CrtcHandle,
+	framebuffer::Handle as FramebufferHandle,
+	plane::Handle as PlaneHandle,
+	property::Handle as PropertyHandle
+};
+
+use super::hotplug::Uevent;
+use super::mode::Timings;
+use super::session::{Session, SessionEvent};
+use super::format::FormatTable;
+use super::sync_file::{SyncFile, SwSyncTimeline};
+use super::backend::{
+	DrmBackend,
+	ObjectHandle,
+	CommitBusy,
+	CommitRequest,
+	DrmEvent,
+	ResourcesDesc,
+	ConnectorDesc,
+	EncoderDesc,
+	CrtcDesc,
+	PlaneDesc,
+	PropertyDesc,
+	PropertyKind
+};
+
+/// Interval between the fake vblanks reported in page flip events.
+const FRAME_TIME: Duration = Duration::from_micros(16_667);
+
+/// Values of the plane `type` enum property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaneType {
+	Overlay = 0,
+	Primary = 1,
+	Cursor = 2
+}
+
+/// Legacy modesetting call made on a [`FakeDevice`].
+#[derive(Debug, Clone)]
+pub enum LegacyCall {
+	SetCrtc { crtc: CrtcHandle, framebuffer: FramebufferHandle, connectors: Vec<ConnectorHandle>, mode: Mode },
+	PageFlip { crtc: CrtcHandle, framebuffer: FramebufferHandle }
+}
+
+/// Builds a mode with plausible blanking for the given visible size and refresh rate.
+pub fn fake_mode(width: u16, height: u16, vrefresh: u32, mode_type: ModeTypeFlags) -> Mode {
+	let htotal = width + 160;
+	let vtotal = height + 30;
+
+	let timings = Timings {
+		// rounded up, the refresh rate is derived from the timings like the kernel does and must not drop below `vrefresh`
+		clock: (htotal as u32 * vtotal as u32 * vrefresh + 999) / 1000,
+		hdisplay: width,
+		hsync_start: width + 48,
+		hsync_end: width + 80,
+		htotal,
+		vdisplay: height,
+		vsync_start: height + 3,
+		vsync_end: height + 8,
+		vtotal,
+		flags: ModeFlags::empty()
+	};
+
+	timings.to_mode(&format!("{}x{}", width, height), mode_type)
+}
+
+/// Builds the uevent message the kernel sends when connectors of the drm device `devnum` may have changed.
+pub fn hotplug_uevent(devnum: (u64, u64)) -> Vec<u8> {
+	let devpath = format!("/devices/platform/gpu/drm/card{}", devnum.1);
+	let properties = [
+		("ACTION", "change".to_string()),
+		("DEVPATH", devpath.clone()),
+		("SUBSYSTEM", "drm".to_string()),
+		("HOTPLUG", "1".to_string()),
+		("DEVNAME", format!("dri/card{}", devnum.1)),
+		("DEVTYPE", "drm_minor".to_string()),
+		("MAJOR", devnum.0.to_string()),
+		("MINOR", devnum.1.to_string())
+	];
+
+	Uevent {
+		action: "change".to_string(),
+		devpath,
+		properties: properties.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+	}.to_message()
+}
+
+/// Scriptable in-memory implementation of [`DrmBackend`].
+///
+/// Objects get the standard atomic properties when they are added, so a [`super::KmsContext`] configuration
+/// can be resolved against it. Use [`FakeDevice::remove_property`] to simulate drivers missing some of them.
+///
+/// Out fences are only handed out after [`FakeDevice::enable_fences`], they signal when the page flip events are received.
+/// Nonblocking commits fail with [`CommitBusy`] until then.
+/// [`FakeDevice::set_legacy`] simulates drivers without atomic modesetting.
+#[derive(Debug, Default)]
+pub struct FakeDevice {
+	last_id: Cell<u32>,
+	connectors: Vec<ConnectorDesc>,
+	encoders: Vec<EncoderDesc>,
+	crtcs: Vec<CrtcDesc>,
+	planes: Vec<PlaneDesc>,
+	properties: HashMap<ObjectHandle, Vec<PropertyDesc>>,
+	/// property handles are shared between objects of the same kind, like in the kernel
+	property_handles: HashMap<(&'static str, String), PropertyHandle>,
+	blobs: HashMap<u64, Vec<u8>>,
+	/// mode blobs created and not destroyed yet
+	mode_blobs: RefCell<Vec<u64>>,
+	commits: RefCell<Vec<(AtomicCommitFlags, CommitRequest)>>,
+	/// rejects atomic commits if set
+	legacy: bool,
+	legacy_calls: RefCell<Vec<LegacyCall>>,
+	/// page flip events of commits requesting them, flipped immediately
+	events: RefCell<VecDeque<DrmEvent>>,
+	/// timeline of out fences, at the number of the commit whose events were received last
+	timeline: RefCell<Option<SwSyncTimeline>>
+}
+impl FakeDevice {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	fn allocate<T: From<RawResourceHandle>>(&self) -> T {
+		self.last_id.set(self.last_id.get() + 1);
+		drm::control::from_u32(self.last_id.get()).unwrap()
+	}
+
+	fn object_kind(object: ObjectHandle) -> &'static str {
+		match object {
+			ObjectHandle::Connector(_) => "connector",
+			ObjectHandle::Crtc(_) => "crtc",
+			ObjectHandle::Plane(_) => "plane"
+		}
+	}
+
+	/// Adds a property of unknown type or replaces the value of an existing one, keeping its type.
+	pub fn set_property(&mut self, object: ObjectHandle, name: &str, value: u64) {
+		let kind = self.properties.get(&object).and_then(
+			|properties| properties.iter().find(|property| property.name == name)
+		).map(|property| property.kind).unwrap_or_default();
+
+		self.set_typed_property(object, name, kind, value, Vec::new())
+	}
+
+	pub fn set_enum_property(&mut self, object: ObjectHandle, name: &str, value: u64, enum_values: Vec<(u64, String)>) {
+		self.set_typed_property(object, name, PropertyKind::Enum, value, enum_values)
+	}
+
+	/// Adds a property or replaces an existing one, `enum_values` are only used by enum and bitmask properties.
+	pub fn set_typed_property(
+		&mut self,
+		object: ObjectHandle,
+		name: &str,
+		kind: PropertyKind,
+		value: u64,
+		enum_values: Vec<(u64, String)>
+	) {
+		let key = (Self::object_kind(object), name.to_string());
+		let handle = match self.property_handles.get(&key) {
+			Some(handle) => *handle,
+			None => {
+				let handle = self.allocate();
+				self.property_handles.insert(key, handle);
+
+				handle
+			}
+		};
+
+		let properties = self.properties.entry(object).or_default();
+		match properties.iter_mut().find(|property| property.handle == handle) {
+			Some(property) => {
+				property.kind = kind;
+				property.value = value;
+				property.enum_values = enum_values;
+			}
+			None => properties.push(
+				PropertyDesc {
+					handle,
+					name: name.to_string(),
+					kind,
+					value,
+					enum_values
+				}
+			)
+		}
+	}
+
+	pub fn remove_property(&mut self, object: ObjectHandle, name: &str) {
+		if let Some(properties) = self.properties.get_mut(&object) {
+			properties.retain(|property| property.name != name);
+		}
+	}
+
+	pub fn add_crtc(&mut self) -> CrtcHandle {
+		let handle = self.allocate();
+		self.crtcs.push(
+			CrtcDesc {
+				handle,
+				mode: None,
+				framebuffer: None,
+				position: (0, 0)
+			}
+		);
+
+		let object = ObjectHandle::Crtc(handle);
+		self.set_typed_property(object, "ACTIVE", PropertyKind::Boolean, 0, Vec::new());
+		self.set_typed_property(object, "MODE_ID", PropertyKind::Blob, 0, Vec::new());
+		self.set_typed_property(object, "OUT_FENCE_PTR", PropertyKind::Range { min: 0, max: u64::MAX }, 0, Vec::new());
+
+		handle
+	}
+
+	pub fn add_encoder(&mut self, kind: EncoderKind, possible_crtcs: &[CrtcHandle]) -> EncoderHandle {
+		let handle = self.allocate();
+		self.encoders.push(
+			EncoderDesc {
+				handle,
+				kind,
+				crtc: None,
+				possible_crtcs: possible_crtcs.to_vec()
+			}
+		);
+
+		handle
+	}
+
+	pub fn add_connector(
+		&mut self,
+		interface: ConnectorInterface,
+		interface_id: u32,
+		state: ConnectorState,
+		modes: Vec<Mode>,
+		encoders: &[EncoderHandle]
+	) -> ConnectorHandle {
+		let handle = self.allocate();
+		self.connectors.push(
+			ConnectorDesc {
+				handle,
+				interface,
+				interface_id,
+				state,
+				modes,
+				encoders: encoders.to_vec(),
+				current_encoder: None
+			}
+		);
+
+		self.set_typed_property(ObjectHandle::Connector(handle), "CRTC_ID", PropertyKind::Object, 0, Vec::new());
+
+		handle
+	}
+
+	pub fn add_plane(&mut self, plane_type: PlaneType, possible_crtcs: &[CrtcHandle], formats: &[u32]) -> PlaneHandle {
+		let handle = self.allocate();
+		self.planes.push(
+			PlaneDesc {
+				handle,
+				crtc: None,
+				framebuffer: None,
+				possible_crtcs: possible_crtcs.to_vec(),
+				formats: formats.to_vec()
+			}
+		);
+
+		let object = ObjectHandle::Plane(handle);
+		self.set_enum_property(
+			object, "type", plane_type as u64,
+			vec![
+				(PlaneType::Overlay as u64, "Overlay".to_string()),
+				(PlaneType::Primary as u64, "Primary".to_string()),
+				(PlaneType::Cursor as u64, "Cursor".to_string())
+			]
+		);
+		// kinds and limits as reported by the kernel
+		let u32_range = PropertyKind::Range { min: 0, max: u32::MAX as u64 };
+		let i32_range = PropertyKind::SignedRange { min: i32::MIN as i64, max: i32::MAX as i64 };
+		for (name, kind) in [
+			("FB_ID", PropertyKind::Object),
+			("CRTC_ID", PropertyKind::Object),
+			("IN_FENCE_FD", PropertyKind::SignedRange { min: -1, max: i32::MAX as i64 }),
+			("SRC_X", u32_range),
+			("SRC_Y", u32_range),
+			("SRC_W", u32_range),
+			("SRC_H", u32_range),
+			("CRTC_X", i32_range),
+			("CRTC_Y", i32_range),
+			("CRTC_W", PropertyKind::Range { min: 0, max: i32::MAX as u64 }),
+			("CRTC_H", PropertyKind::Range { min: 0, max: i32::MAX as u64 })
+		] {
+			self.set_typed_property(object, name, kind, 0, Vec::new());
+		}
+
+		handle
+	}
+
+	pub fn add_blob(&mut self, data: Vec<u8>) -> u64 {
+		let blob: u32 = self.allocate::<RawResourceHandle>().into();
+		self.blobs.insert(blob as u64, data);
+
+		blob as u64
+	}
+
+	/// Sets the connector `EDID` property to a blob containing `edid`.
+	pub fn set_edid(&mut self, connector: ConnectorHandle, edid: Vec<u8>) {
+		let blob = self.add_blob(edid);
+		self.set_typed_property(ObjectHandle::Connector(connector), "EDID", PropertyKind::Blob, blob, Vec::new());
+	}
+
+	/// Sets the plane `IN_FORMATS` property to a blob listing `formats`.
+	pub fn set_in_formats(&mut self, plane: PlaneHandle, formats: &FormatTable) {
+		let blob = self.add_blob(formats.to_in_formats());
+		self.set_typed_property(ObjectHandle::Plane(plane), "IN_FORMATS", PropertyKind::Blob, blob, Vec::new());
+	}
+
+	/// Marks `encoder` as currently driving `crtc` for `connector`, as if left behind by a previous user.
+	pub fn link(&mut self, connector: ConnectorHandle, encoder: EncoderHandle, crtc: Option<CrtcHandle>) {
+		if let Some(desc) = self.connectors.iter_mut().find(|desc| desc.handle == connector) {
+			desc.current_encoder = Some(encoder);
+		}
+		if let Some(desc) = self.encoders.iter_mut().find(|desc| desc.handle == encoder) {
+			desc.crtc = crtc;
+		}
+	}
+
+	/// Sets the mode `crtc` shows and whether it is `ACTIVE`, as if left behind by a previous user.
+	pub fn set_crtc_mode(&mut self, crtc: CrtcHandle, mode: Option<Mode>) {
+		if let Some(desc) = self.crtcs.iter_mut().find(|desc| desc.handle == crtc) {
+			desc.mode = mode;
+		}
+		self.set_property(ObjectHandle::Crtc(crtc), "ACTIVE", mode.is_some() as u64);
+	}
+
+	/// Commits performed so far, in order.
+	pub fn commits(&self) -> Vec<(AtomicCommitFlags, CommitRequest)> {
+		self.commits.borrow().clone()
+	}
+
+	pub fn set_connector_state(&mut self, connector: ConnectorHandle, state: ConnectorState) {
+		if let Some(desc) = self.connectors.iter_mut().find(|desc| desc.handle == connector) {
+			desc.state = state;
+		}
+	}
+
+	pub fn set_legacy(&mut self, legacy: bool) {
+		self.legacy = legacy;
+	}
+
+	/// Legacy calls performed so far, in order.
+	pub fn legacy_calls(&self) -> Vec<LegacyCall> {
+		self.legacy_calls.borrow().clone()
+	}
+
+	/// Mode blobs created and not destroyed yet, in order of creation.
+	pub fn mode_blobs(&self) -> Vec<u64> {
+		self.mode_blobs.borrow().clone()
+	}
+
+	/// Hands out `sw_sync` fences for `OUT_FENCE_PTR`, fails if `sw_sync` is not available.
+	pub fn enable_fences(&mut self) -> anyhow::Result<()> {
+		*self.timeline.get_mut() = Some(SwSyncTimeline::new()?);
+
+		Ok(())
+	}
+
+	pub fn set_connector_modes(&mut self, connector: ConnectorHandle, modes: Vec<Mode>) {
+		if let Some(desc) = self.connectors.iter_mut().find(|desc| desc.handle == connector) {
+			desc.modes = modes;
+		}
+	}
+}
+impl DrmBackend for FakeDevice {
+	fn is_atomic(&self) -> bool {
+		!self.legacy
+	}
+
+	fn resources(&self) -> anyhow::Result<ResourcesDesc> {
+		Ok(
+			ResourcesDesc {
+				connectors: self.connectors.iter().map(|desc| desc.handle).collect(),
+				encoders: self.encoders.iter().map(|desc| desc.handle).collect(),
+				crtcs: self.crtcs.iter().map(|desc| desc.handle).collect()
+			}
+		)
+	}
+
+	fn connector(&self, handle: ConnectorHandle) -> anyhow::Result<ConnectorDesc> {
+		self.connectors.iter().find(|desc| desc.handle == handle).cloned().ok_or_else(
+			|| anyhow::anyhow!("No such connector {:?}", handle)
+		)
+	}
+
+	fn encoder(&self, handle: EncoderHandle) -> anyhow::Result<EncoderDesc> {
+		self.encoders.iter().find(|desc| desc.handle == handle).cloned().ok_or_else(
+			|| anyhow::anyhow!("No such encoder {:?}", handle)
+		)
+	}
+
+	fn crtc(&self, handle: CrtcHandle) -> anyhow::Result<CrtcDesc> {
+		self.crtcs.iter().find(|desc| desc.handle == handle).cloned().ok_or_else(
+			|| anyhow::anyhow!("No such crtc {:?}", handle)
+		)
+	}
+
+	fn planes(&self) -> anyhow::Result<Vec<PlaneHandle>> {
+		Ok(self.planes.iter().map(|desc| desc.handle).collect())
+	}
+
+	fn plane(&self, handle: PlaneHandle) -> anyhow::Result<PlaneDesc> {
+		self.planes.iter().find(|desc| desc.handle == handle).cloned().ok_or_else(
+			|| anyhow::anyhow!("No such plane {:?}", handle)
+		)
+	}
+
+	fn properties(&self, object: ObjectHandle) -> anyhow::Result<Vec<PropertyDesc>> {
+		Ok(self.properties.get(&object).cloned().unwrap_or_default())
+	}
+
+	fn property_blob(&self, blob: u64) -> anyhow::Result<Vec<u8>> {
+		self.blobs.get(&blob).cloned().ok_or_else(
+			|| anyhow::anyhow!("No such blob {}", blob)
+		)
+	}
+
+	fn create_mode_blob(&self, _mode: &Mode) -> anyhow::Result<u64> {
+		let blob: u32 = self.allocate::<RawResourceHandle>().into();
+		self.mode_blobs.borrow_mut().push(blob as u64);
+
+		Ok(blob as u64)
+	}
+
+	fn destroy_property_blob(&self, blob: u64) -> anyhow::Result<()> {
+		let mut mode_blobs = self.mode_blobs.borrow_mut();
+		let index = mode_blobs.iter().position(|&created| created == blob).with_context(|| format!("No such mode blob {}", blob))?;
+		mode_blobs.remove(index);
+
+		Ok(())
+	}
+
+	fn atomic_commit(&self, flags: AtomicCommitFlags, request: &CommitRequest) -> anyhow::Result<Vec<Option<SyncFile>>> {
+		if self.legacy {
+			anyhow::bail!("Atomic commits are not supported");
+		}
+		// a commit is pending until its page flip events are received
+		let busy = flags.contains(AtomicCommitFlags::NONBLOCK) && !flags.contains(AtomicCommitFlags::TEST_ONLY);
+		if busy && !self.events.borrow().is_empty() {
+			return Err(CommitBusy.into())
+		}
+		self.commits.borrow_mut().push((flags, request.clone()));
+		let frame = self.commits.borrow().len() as u32;
+
+		if flags.contains(AtomicCommitFlags::PAGE_FLIP_EVENT) && !flags.contains(AtomicCommitFlags::TEST_ONLY) {
+			let plane_crtc_id = self.property_handles.get(&("plane", "CRTC_ID".to_string())).copied();
+
+			// every crtc touched by the commit sends an event
+			let mut crtcs: Vec<CrtcHandle> = Vec::new();
+			for &(object, property, value) in request.properties.iter() {
+				let crtc = match object {
+					ObjectHandle::Crtc(handle) => Some(handle),
+					ObjectHandle::Plane(_) if Some(property) == plane_crtc_id && value != 0 => drm::control::from_u32(value as u32),
+					_ => None
+				};
+
+				if let Some(crtc) = crtc {
+					if !crtcs.contains(&crtc) {
+						crtcs.push(crtc);
+					}
+				}
+			}
+
+			for crtc in crtcs {
+				self.events.borrow_mut().push_back(
+					DrmEvent::PageFlip {
+						crtc,
+						frame,
+						time: FRAME_TIME * frame
+					}
+				);
+			}
+		}
+
+		let mut out_fences = Vec::with_capacity(request.out_fences.len());
+		for _ in request.out_fences.iter() {
+			let fence = match *self.timeline.borrow() {
+				Some(ref timeline) if !flags.contains(AtomicCommitFlags::TEST_ONLY) => Some(timeline.create_fence(frame)?),
+				_ => None
+			};
+			out_fences.push(fence);
+		}
+
+		Ok(out_fences)
+	}
+
+	fn set_crtc(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle, connectors: &[ConnectorHandle], mode: Mode) -> anyhow::Result<()> {
+		self.legacy_calls.borrow_mut().push(
+			LegacyCall::SetCrtc { crtc, framebuffer, connectors: connectors.to_vec(), mode }
+		);
+
+		Ok(())
+	}
+
+	fn page_flip(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle) -> anyhow::Result<()> {
+		self.legacy_calls.borrow_mut().push(LegacyCall::PageFlip { crtc, framebuffer });
+
+		// vblank counters are per crtc
+		let frame = self.legacy_calls.borrow().iter().filter(
+			|call| matches!(call, LegacyCall::PageFlip { crtc: flipped, .. } if *flipped == crtc)
+		).count() as u32;
+		self.events.borrow_mut().push_back(
+			DrmEvent::PageFlip {
+				crtc,
+				frame,
+				time: FRAME_TIME * frame
+			}
+		);
+
+		Ok(())
+	}
+
+	fn receive_events(&self) -> anyhow::Result<Vec<DrmEvent>> {
+		let events: Vec<DrmEvent> = self.events.borrow_mut().drain(..).collect();
+		if events.is_empty() {
+			anyhow::bail!("No events pending, reading would block forever");
+		}
+
+		// all commits so far have flipped
+		if let Some(ref mut timeline) = *self.timeline.borrow_mut() {
+			timeline.signal(self.commits.borrow().len() as u32)?;
+		}
+
+		Ok(events)
+	}
+}
+
+/// Variations of the [`Topology`] shared by the tests.
+#[derive(Debug, Clone, Copy)]
+pub struct TopologyOptions {
+	pub dp_state: ConnectorState,
+	/// index of the crtc the HDMI encoder is left driving, if any
+	pub hdmi_crtc: Option<usize>,
+	/// adds an overlay plane before the primary planes and a cursor plane after them
+	pub overlay_and_cursor: bool
+}
+impl Default for TopologyOptions {
+	fn default() -> Self {
+		Self {
+			dp_state: ConnectorState::Connected,
+			hdmi_crtc: None,
+			overlay_and_cursor: false
+		}
+	}
+}
+
+/// Two crtcs with a primary plane each, HDMI-A-1 connected and DP-1 at 1920x1080 on TMDS encoders.
+///
+/// The HDMI encoder can drive both crtcs, the DP one only the second.
+#[derive(Debug)]
+pub struct Topology {
+	pub device: FakeDevice,
+	pub crtcs: [CrtcHandle; 2],
+	pub encoders: [EncoderHandle; 2],
+	pub hdmi: ConnectorHandle,
+	pub dp: ConnectorHandle,
+	pub primary: [PlaneHandle; 2],
+	pub overlay: Option<PlaneHandle>,
+	pub cursor: Option<PlaneHandle>
+}
+impl Topology {
+	pub fn new(options: &TopologyOptions) -> Self {
+		let mut device = FakeDevice::new();
+		let crtcs = [device.add_crtc(), device.add_crtc()];
+		let encoders = [device.add_encoder(EncoderKind::TMDS, &crtcs), device.add_encoder(EncoderKind::TMDS, &crtcs[1 ..])];
+
+		let mode = fake_mode(1920, 1080, 60, ModeTypeFlags::PREFERRED | ModeTypeFlags::DRIVER);
+		let hdmi = device.add_connector(ConnectorInterface::HDMIA, 1, ConnectorState::Connected, vec![mode], &encoders[.. 1]);
+		let dp = device.add_connector(ConnectorInterface::DisplayPort, 1, options.dp_state, vec![mode], &encoders[1 ..]);
+		if let Some(index) = options.hdmi_crtc {
+			device.link(hdmi, encoders[0], Some(crtcs[index]));
+		}
+
+		// overlay first, so that primary planes have to be preferred
+		let xrgb = DrmFourcc::Xrgb8888 as u32;
+		let overlay = options.overlay_and_cursor.then(|| device.add_plane(PlaneType::Overlay, &crtcs, &[xrgb]));
+		let primary = [device.add_plane(PlaneType::Primary, &crtcs[.. 1], &[xrgb]), device.add_plane(PlaneType::Primary, &crtcs[1 ..], &[xrgb])];
+		let cursor = options.overlay_and_cursor.then(|| device.add_plane(PlaneType::Cursor, &crtcs, &[DrmFourcc::Argb8888 as u32]));
+
+		Topology { device, crtcs, encoders, hdmi, dp, primary, overlay, cursor }
+	}
+}
+
+#[derive(Debug, Default)]
+struct FakeSessionState {
+	events: VecDeque<SessionEvent>,
+	paused: bool,
+	acknowledged_pauses: usize,
+	/// devices opened and not closed yet
+	open_devices: Vec<PathBuf>
+}
+
+/// Session whose pauses and resumes are scripted, opening devices directly.
+///
+/// Clones share their state, so a test can keep one to script events for a context owning another.
+#[derive(Debug, Clone, Default)]
+pub struct FakeSession {
+	state: Rc<RefCell<FakeSessionState>>
+}
+impl FakeSession {
+	/// Queues `event` to be returned by the next dispatch.
+	pub fn push_event(&self, event: SessionEvent) {
+		self.state.borrow_mut().events.push_back(event);
+	}
+
+	/// Number of pauses confirmed through [`Session::acknowledge_pause`].
+	pub fn acknowledged_pauses(&self) -> usize {
+		self.state.borrow().acknowledged_pauses
+	}
+
+	/// Devices opened through [`Session::open_device`] and not closed again.
+	pub fn open_devices(&self) -> Vec<PathBuf> {
+		self.state.borrow().open_devices.clone()
+	}
+}
+impl Session for FakeSession {
+	fn open_device(&mut self, path: &Path) -> anyhow::Result<OwnedFd> {
+		let file = fs::OpenOptions::new().read(true).write(true).open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+		self.state.borrow_mut().open_devices.push(path.to_path_buf());
+
+		Ok(OwnedFd::from(file))
+	}
+
+	fn close_device(&mut self, path: &Path) -> anyhow::Result<()> {
+		let mut state = self.state.borrow_mut();
+		let index = state.open_devices.iter().position(|opened| opened == path).with_context(|| format!("Device {} is not open", path.display()))?;
+		state.open_devices.remove(index);
+
+		Ok(())
+	}
+
+	fn dispatch(&mut self) -> anyhow::Result<Vec<SessionEvent>> {
+		let mut state = self.state.borrow_mut();
+		let events: Vec<SessionEvent> = state.events.drain(..).collect();
+		if let Some(&last) = events.last() {
+			state.paused = last == SessionEvent::Paused;
+		}
+
+		Ok(events)
+	}
+
+	fn acknowledge_pause(&mut self) -> anyhow::Result<()> {
+		self.state.borrow_mut().acknowledged_pauses += 1;
+
+		Ok(())
+	}
+
+	fn is_active(&self) -> bool {
+		!self.state.borrow().paused
+	}
+}
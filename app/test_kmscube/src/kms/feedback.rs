@@ -0,0 +1,226 @@
+//! Presentation feedback from page flip events.
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc, time::Duration};
+
+use drm::control::crtc::Handle as CrtcHandle;
+
+/// When a presented frame reached one crtc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlipTiming {
+	pub crtc: CrtcHandle,
+	/// vblank sequence number of the crtc
+	pub sequence: u32,
+	/// `CLOCK_MONOTONIC` timestamp of the vblank, as reported by the kernel
+	pub time: Duration,
+	/// time since the previous flip on this crtc, `None` for the first flip after a modeset
+	pub interval: Option<Duration>,
+	/// vblanks the frame arrived late, counted from the first vblank after the commit was made
+	pub missed: u32
+}
+
+#[derive(Debug, Default)]
+struct FeedbackState {
+	/// sequential number of the commit
+	commit: u64,
+	/// crtcs which have to flip
+	crtcs: Vec<CrtcHandle>,
+	/// `CLOCK_MONOTONIC` time the commit was made
+	submitted: Duration,
+	flips: Vec<FlipTiming>
+}
+
+/// Handle resolving to the flip timings of a presented frame once all of its crtcs have flipped.
+///
+/// Resolved while the context waits for page flips, at the latest during the next present.
+#[derive(Debug, Clone, Default)]
+pub struct PresentFeedback(Rc<RefCell<FeedbackState>>);
+impl PresentFeedback {
+	/// Sequential number of the commit, starting at 1.
+	pub fn commit(&self) -> u64 {
+		self.0.borrow().commit
+	}
+
+	pub fn is_presented(&self) -> bool {
+		let state = self.0.borrow();
+
+		state.flips.len() >= state.crtcs.len()
+	}
+
+	/// Timings of each crtc, once presented.
+	pub fn timings(&self) -> Option<Vec<FlipTiming>> {
+		if self.is_presented() {
+			Some(self.0.borrow().flips.clone())
+		} else {
+			None
+		}
+	}
+
+	/// Most vblanks missed on any crtc, once presented.
+	pub fn missed(&self) -> Option<u32> {
+		self.timings().map(
+			|timings| timings.iter().map(|timing| timing.missed).max().unwrap_or(0)
+		)
+	}
+}
+
+/// Matches page flip events to the commit in flight.
+#[derive(Default)]
+pub(super) struct FeedbackTracker {
+	commits: u64,
+	/// commits whose flips have all been received
+	completed: u64,
+	pending: Option<PresentFeedback>,
+	/// sequence and time of the last flip of each crtc
+	last_flips: HashMap<CrtcHandle, (u32, Duration)>
+}
+impl FeedbackTracker {
+	/// Starts tracking a commit touching `crtcs`, made at `submitted` on `CLOCK_MONOTONIC`.
+	pub fn begin(&mut self, crtcs: &[CrtcHandle], modeset: bool, submitted: Duration) -> PresentFeedback {
+		if modeset {
+			// vblank counters may restart when crtcs are enabled
+			for crtc in crtcs {
+				self.last_flips.remove(crtc);
+			}
+		}
+
+		self.commits += 1;
+		let feedback = PresentFeedback(
+			Rc::new(
+				RefCell::new(
+					FeedbackState {
+						commit: self.commits,
+						crtcs: crtcs.to_vec(),
+						submitted,
+						flips: Vec::with_capacity(crtcs.len())
+					}
+				)
+			)
+		);
+		self.pending = Some(feedback.clone());
+
+		feedback
+	}
+
+	pub fn is_pending(&self) -> bool {
+		self.pending.is_some()
+	}
+
+	/// Number of commits started so far.
+	pub fn commits(&self) -> u64 {
+		self.commits
+	}
+
+	/// Number of commits whose flips have all been received.
+	pub fn completed(&self) -> u64 {
+		self.completed
+	}
+
+	/// Records a page flip event, ignoring crtcs not part of the pending commit.
+	pub fn page_flip(&mut self, crtc: CrtcHandle, sequence: u32, time: Duration) {
+		let pending = match self.pending {
+			None => {
+				log::warn!("Unexpected page flip on {:?}", crtc);
+				return
+			}
+			Some(ref pending) => pending
+		};
+		if !pending.0.borrow().crtcs.contains(&crtc) {
+			log::debug!("Ignoring page flip on {:?} which is not part of the pending commit", crtc);
+			return
+		}
+
+		let submitted = pending.0.borrow().submitted;
+		let (interval, missed) = match self.last_flips.insert(crtc, (sequence, time)) {
+			Some((last_sequence, last_time)) if sequence > last_sequence => {
+				let interval = time.saturating_sub(last_time);
+				// frames presented less often than the refresh rate only target the first vblank after their commit
+				let period = interval.as_nanos() / (sequence - last_sequence) as u128;
+				let idle = match period {
+					0 => 0,
+					period => submitted.saturating_sub(last_time).as_nanos() / period
+				};
+				let target = last_sequence as u128 + 1 + idle;
+
+				(Some(interval), (sequence as u128).saturating_sub(target) as u32)
+			}
+			_ => (None, 0)
+		};
+		if missed > 0 {
+			log::debug!("Missed {} vblanks on {:?} before sequence {}", missed, crtc, sequence);
+		}
+
+		let mut state = pending.0.borrow_mut();
+		state.flips.push(
+			FlipTiming {
+				crtc,
+				sequence,
+				time,
+				interval,
+				missed
+			}
+		);
+
+		if state.flips.len() >= state.crtcs.len() {
+			self.completed = state.commit;
+			std::mem::drop(state);
+			self.pending = None;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const PERIOD: Duration = Duration::from_micros(16_667);
+
+	fn crtc() -> CrtcHandle {
+		drm::control::from_u32(31).unwrap()
+	}
+
+	/// Commits at `submitted` vblanks and flips at the vblank `flipped`, returns the missed vblanks of each.
+	fn missed(commits: &[(u32, u32)]) -> Vec<u32> {
+		let mut tracker = FeedbackTracker::default();
+
+		commits.iter().enumerate().map(
+			|(index, &(submitted, flipped))| {
+				// submitted a bit after the vblank
+				let feedback = tracker.begin(&[crtc()], index == 0, PERIOD * submitted + PERIOD / 4);
+				tracker.page_flip(crtc(), flipped, PERIOD * flipped);
+				assert_eq!(tracker.completed(), index as u64 + 1);
+
+				feedback.missed().unwrap()
+			}
+		).collect()
+	}
+
+	#[test]
+	fn every_vblank() {
+		assert_eq!(missed(&[(0, 1), (1, 2), (2, 3)]), vec![0, 0, 0]);
+	}
+
+	#[test]
+	fn half_refresh_rate_is_not_missing() {
+		// 30 fps on 60 Hz, rendering idles for a vblank before each commit
+		assert_eq!(missed(&[(0, 1), (2, 3), (4, 5), (6, 7)]), vec![0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn late_flips_are_missing() {
+		assert_eq!(missed(&[(0, 1), (1, 3), (3, 4), (5, 8)]), vec![0, 1, 0, 2]);
+	}
+
+	#[test]
+	fn ignores_other_crtcs() {
+		let mut tracker = FeedbackTracker::default();
+		let feedback = tracker.begin(&[crtc()], true, Duration::ZERO);
+
+		tracker.page_flip(drm::control::from_u32(32).unwrap(), 1, PERIOD);
+		assert!(tracker.is_pending());
+		assert!(!feedback.is_presented());
+
+		tracker.page_flip(crtc(), 1, PERIOD);
+		assert!(!tracker.is_pending());
+		assert_eq!(feedback.timings().unwrap()[0].interval, None);
+	}
+}
@@ -0,0 +1,247 @@
+//! Formats and modifiers supported by planes and renderers, and negotiating a common one.
+//!
+//! Planes list theirs in the `IN_FORMATS` blob, renderers through `EGL_EXT_image_dma_buf_import_modifiers`.
+//! Tiled or compressed modifiers usually save memory bandwidth, so they are preferred over linear layout,
+//! which every plane and renderer supports and which is tried last.
+
+use anyhow::Context;
+
+use drm::buffer::{DrmFourcc, DrmModifier};
+
+/// `FORMAT_BLOB_CURRENT`, version of the `IN_FORMATS` layout.
+const IN_FORMATS_VERSION: u32 = 1;
+/// Size of `struct drm_format_modifier_blob`.
+const IN_FORMATS_HEADER_SIZE: usize = 24;
+/// Size of `struct drm_format_modifier`.
+const IN_FORMATS_MODIFIER_SIZE: usize = 24;
+
+fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+	let bytes = data.get(offset .. offset + 4).context("IN_FORMATS blob is truncated")?;
+
+	Ok(u32::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> anyhow::Result<u64> {
+	let bytes = data.get(offset .. offset + 8).context("IN_FORMATS blob is truncated")?;
+
+	Ok(u64::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+/// Formats with the modifiers supported for each, in order of preference of whoever reported them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatTable {
+	/// fourcc codes and modifiers
+	formats: Vec<(u32, Vec<u64>)>
+}
+impl FormatTable {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Table of fourcc codes and the modifiers of each, as returned by [`Self::raw`].
+	pub fn from_raw(formats: Vec<(u32, Vec<u64>)>) -> Self {
+		FormatTable { formats }
+	}
+
+	pub fn raw(&self) -> &[(u32, Vec<u64>)] {
+		&self.formats
+	}
+
+	/// Parses the `IN_FORMATS` blob of a plane, a `struct drm_format_modifier_blob`.
+	pub fn from_in_formats(data: &[u8]) -> anyhow::Result<Self> {
+		let version = read_u32(data, 0)?;
+		if version != IN_FORMATS_VERSION {
+			anyhow::bail!("Unsupported IN_FORMATS version {}", version);
+		}
+		let count_formats = read_u32(data, 8)? as usize;
+		let formats_offset = read_u32(data, 12)? as usize;
+		let count_modifiers = read_u32(data, 16)? as usize;
+		let modifiers_offset = read_u32(data, 20)? as usize;
+
+		let mut table = FormatTable::new();
+		for index in 0 .. count_formats {
+			table.formats.push((read_u32(data, formats_offset + index * 4)?, Vec::new()));
+		}
+
+		for index in 0 .. count_modifiers {
+			let offset = modifiers_offset + index * IN_FORMATS_MODIFIER_SIZE;
+			// formats supported with the modifier, as a mask of 64 formats starting at `first`
+			let mask = read_u64(data, offset)?;
+			let first = read_u32(data, offset + 8)? as usize;
+			let modifier = read_u64(data, offset + 16)?;
+
+			for bit in 0 .. 64 {
+				if mask & 1 << bit != 0 {
+					let (_, modifiers) = table.formats.get_mut(first + bit).with_context(
+						|| format!("IN_FORMATS modifier {:#x} refers to format {} of {}", modifier, first + bit, count_formats)
+					)?;
+					modifiers.push(modifier);
+				}
+			}
+		}
+
+		Ok(table)
+	}
+
+	/// Encodes the table as an `IN_FORMATS` blob for a `FakeDevice`.
+	///
+	/// Panics with more than 64 formats.
+	#[cfg(test)]
+	pub fn to_in_formats(&self) -> Vec<u8> {
+		assert!(self.formats.len() <= 64, "IN_FORMATS blobs are only encoded with up to 64 formats");
+
+		let mut modifiers: Vec<(u64, u64)> = Vec::new();
+		for (index, (_, format_modifiers)) in self.formats.iter().enumerate() {
+			for &modifier in format_modifiers.iter() {
+				match modifiers.iter_mut().find(|(existing, _)| *existing == modifier) {
+					Some((_, mask)) => { *mask |= 1 << index; }
+					None => modifiers.push((modifier, 1 << index))
+				}
+			}
+		}
+
+		let formats_offset = IN_FORMATS_HEADER_SIZE;
+		// modifiers are 8 byte aligned
+		let modifiers_offset = (formats_offset + self.formats.len() * 4).next_multiple_of(8);
+
+		let mut data = Vec::with_capacity(modifiers_offset + modifiers.len() * IN_FORMATS_MODIFIER_SIZE);
+		for value in [IN_FORMATS_VERSION, 0, self.formats.len() as u32, formats_offset as u32, modifiers.len() as u32, modifiers_offset as u32] {
+			data.extend_from_slice(&value.to_ne_bytes());
+		}
+		for &(format, _) in self.formats.iter() {
+			data.extend_from_slice(&format.to_ne_bytes());
+		}
+		data.resize(modifiers_offset, 0);
+		for (modifier, mask) in modifiers {
+			data.extend_from_slice(&mask.to_ne_bytes());
+			// offset and padding
+			data.extend_from_slice(&[0; 8]);
+			data.extend_from_slice(&modifier.to_ne_bytes());
+		}
+
+		data
+	}
+
+	/// Adds `modifier` to the modifiers of `format`, after the ones added before.
+	pub fn insert(&mut self, format: DrmFourcc, modifier: DrmModifier) {
+		let (format, modifier) = (format as u32, u64::from(modifier));
+
+		let modifiers = match self.formats.iter().position(|&(existing, _)| existing == format) {
+			Some(index) => &mut self.formats[index].1,
+			None => {
+				self.formats.push((format, Vec::new()));
+				&mut self.formats.last_mut().unwrap().1
+			}
+		};
+		if !modifiers.contains(&modifier) {
+			modifiers.push(modifier);
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.formats.is_empty()
+	}
+
+	/// Modifiers supported for `format`, empty if the format is not supported.
+	pub fn modifiers(&self, format: DrmFourcc) -> Vec<DrmModifier> {
+		self.formats.iter().find(|&&(existing, _)| existing == format as u32).map(
+			|(_, modifiers)| modifiers.iter().map(|&modifier| DrmModifier::from(modifier)).collect()
+		).unwrap_or_default()
+	}
+
+	pub fn contains(&self, format: DrmFourcc, modifier: DrmModifier) -> bool {
+		self.modifiers(format).contains(&modifier)
+	}
+}
+
+/// Modifiers to try for `format`, best first: the ones every table supports in order of the first table.
+///
+/// Linear layout is moved to the end and appended if missing, since it is the fallback expected to work everywhere.
+pub fn negotiate_modifiers(format: DrmFourcc, tables: &[&FormatTable]) -> Vec<DrmModifier> {
+	let mut modifiers: Vec<DrmModifier> = match tables.split_first() {
+		None => Vec::new(),
+		Some((first, rest)) => first.modifiers(format).into_iter().filter(
+			|&modifier| modifier != DrmModifier::Invalid && modifier != DrmModifier::Linear
+				&& rest.iter().all(|table| table.contains(format, modifier))
+		).collect()
+	};
+	modifiers.push(DrmModifier::Linear);
+
+	modifiers
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// `I915_FORMAT_MOD_X_TILED`
+	const X_TILED: u64 = 0x0100000000000001;
+	/// `I915_FORMAT_MOD_Y_TILED`
+	const Y_TILED: u64 = 0x0100000000000002;
+
+	fn table(formats: &[(DrmFourcc, &[u64])]) -> FormatTable {
+		let mut table = FormatTable::new();
+		for &(format, modifiers) in formats {
+			for &modifier in modifiers {
+				table.insert(format, DrmModifier::from(modifier));
+			}
+		}
+
+		table
+	}
+
+	#[test]
+	fn in_formats_round_trip() {
+		let linear = u64::from(DrmModifier::Linear);
+		let table = table(&[
+			(DrmFourcc::Xrgb8888, &[X_TILED, linear, Y_TILED]),
+			(DrmFourcc::Argb8888, &[linear]),
+			(DrmFourcc::Nv12, &[Y_TILED])
+		]);
+
+		let data = table.to_in_formats();
+		assert_eq!(FormatTable::from_in_formats(&data).unwrap(), table);
+	}
+
+	#[test]
+	fn in_formats_rejects_invalid_blobs() {
+		let data = table(&[(DrmFourcc::Xrgb8888, &[X_TILED])]).to_in_formats();
+		assert!(FormatTable::from_in_formats(&data[.. data.len() - 1]).is_err());
+
+		let mut future = data.clone();
+		future[.. 4].copy_from_slice(&2u32.to_ne_bytes());
+		assert!(FormatTable::from_in_formats(&future).is_err());
+
+		// the modifier refers to a second format which is not listed
+		let mut out_of_range = data;
+		let modifiers_offset = read_u32(&out_of_range, 20).unwrap() as usize;
+		out_of_range[modifiers_offset .. modifiers_offset + 8].copy_from_slice(&0b10u64.to_ne_bytes());
+		assert!(FormatTable::from_in_formats(&out_of_range).is_err());
+	}
+
+	#[test]
+	fn negotiation_keeps_order_of_first_table() {
+		let linear = u64::from(DrmModifier::Linear);
+		let plane = table(&[(DrmFourcc::Xrgb8888, &[linear, Y_TILED, X_TILED])]);
+		let renderer = table(&[(DrmFourcc::Xrgb8888, &[X_TILED, Y_TILED, linear])]);
+
+		assert_eq!(
+			negotiate_modifiers(DrmFourcc::Xrgb8888, &[&plane, &renderer]),
+			vec![DrmModifier::from(Y_TILED), DrmModifier::from(X_TILED), DrmModifier::Linear]
+		);
+		assert_eq!(
+			negotiate_modifiers(DrmFourcc::Xrgb8888, &[&renderer, &plane]),
+			vec![DrmModifier::from(X_TILED), DrmModifier::from(Y_TILED), DrmModifier::Linear]
+		);
+	}
+
+	#[test]
+	fn negotiation_appends_linear() {
+		let plane = table(&[(DrmFourcc::Xrgb8888, &[X_TILED, Y_TILED])]);
+		let renderer = table(&[(DrmFourcc::Xrgb8888, &[Y_TILED])]);
+
+		assert_eq!(negotiate_modifiers(DrmFourcc::Xrgb8888, &[&plane, &renderer]), vec![DrmModifier::from(Y_TILED), DrmModifier::Linear]);
+		assert_eq!(negotiate_modifiers(DrmFourcc::Argb8888, &[&plane, &renderer]), vec![DrmModifier::Linear]);
+		assert_eq!(negotiate_modifiers(DrmFourcc::Xrgb8888, &[]), vec![DrmModifier::Linear]);
+	}
+}
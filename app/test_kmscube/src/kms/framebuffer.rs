@@ -1,47 +1,68 @@
+use std::any::Any;
+
 use anyhow::Context;
 
 use drm::{
-	control::{framebuffer::Handle as FramebufferHandle, Mode, Device},
+	control::{framebuffer::Handle as FramebufferHandle, Device},
 	buffer::{DrmFourcc, DrmModifier}
 };
 use gbm::{BufferObject, BufferObjectFlags};
 
 use super::KmsDevice;
 
+/// Adds a framebuffer for all planes of `buffer`, passing its modifier unless the layout is implicit or linear.
+pub fn add_framebuffer<U: 'static>(device: &KmsDevice, buffer: &BufferObject<U>) -> anyhow::Result<FramebufferHandle> {
+	let (modifiers, flags) = match buffer.modifier().context("Failed to get buffer object modifier")? {
+		// both are the layout the kernel assumes without modifiers
+		DrmModifier::Invalid | DrmModifier::Linear => ([None; 4], 0),
+		modifier => {
+			let planes = buffer.plane_count().context("Failed to get buffer object plane count")? as usize;
+			let mut modifiers = [None; 4];
+			for slot in modifiers.iter_mut().take(planes) {
+				*slot = Some(modifier);
+			}
+
+			(modifiers, drm_ffi::DRM_MODE_FB_MODIFIERS)
+		}
+	};
+
+	device.add_planar_framebuffer(buffer, &modifiers, flags).context("Failed to create framebuffer")
+}
+
 pub struct FrameBufferObject {
 	device: KmsDevice,
 	buffer: BufferObject<()>,
-	framebuffer: FramebufferHandle
+	framebuffer: FramebufferHandle,
+	/// renderer-specific state attached to this buffer, dropped together with it
+	render_target: Option<Box<dyn Any>>
 }
 impl FrameBufferObject {
-	pub fn new(
+	/// Framebuffer of `width` x `height`, e.g. the render size of an output or a layer, see [`super::KmsContext::add_layer`].
+	pub fn with_size(
 		device: KmsDevice,
-		mode: &Mode,
+		width: u32,
+		height: u32,
 		format: DrmFourcc,
 		modifier: DrmModifier
 	) -> anyhow::Result<Self> {
-		log::trace!("Creating buffer object with {:?} {:?} {:?}", modifier, format, mode.size());
-		// let buffer = device.create_buffer_object_with_modifiers(
-		// 	mode.size().0 as u32, mode.size().1 as u32,
-		// 	format, std::iter::once(modifier)
-		// ).context("Failed to create buffer object")?;
-
-		let buffer = device.create_buffer_object(
-			mode.size().0 as u32, mode.size().1 as u32,
-			format, BufferObjectFlags::RENDERING | BufferObjectFlags::SCANOUT
-		).context("Failed to create buffer object")?;
-
-		let framebuffer = device.add_planar_framebuffer(
-			&buffer,
-			&[Some(modifier), None, None, None],
-			0
-		).context("Failed to create framebuffer")?;
-		
+		log::trace!("Creating buffer object with {:?} {:?} {:?}", modifier, format, (width, height));
+		let flags = BufferObjectFlags::RENDERING | BufferObjectFlags::SCANOUT;
+
+		let buffer = match modifier {
+			DrmModifier::Invalid => device.create_buffer_object(width, height, format, flags),
+			// also works with drivers which do not support modifiers
+			DrmModifier::Linear => device.create_buffer_object(width, height, format, flags | BufferObjectFlags::LINEAR),
+			modifier => device.create_buffer_object_with_modifiers(width, height, format, std::iter::once(modifier))
+		}.context("Failed to create buffer object")?;
+
+		let framebuffer = add_framebuffer(&device, &buffer)?;
+
 		Ok(
 			FrameBufferObject {
 				device,
 				buffer,
-				framebuffer
+				framebuffer,
+				render_target: None
 			}
 		)
 	}
@@ -53,6 +74,14 @@ impl FrameBufferObject {
 	pub fn framebuffer(&self) -> FramebufferHandle {
 		self.framebuffer
 	}
+
+	pub fn render_target<T: Any>(&self) -> Option<&T> {
+		self.render_target.as_ref().and_then(|target| target.downcast_ref())
+	}
+
+	pub fn set_render_target<T: Any>(&mut self, target: T) {
+		self.render_target = Some(Box::new(target));
+	}
 }
 impl Drop for FrameBufferObject {
     fn drop(&mut self) {
@@ -0,0 +1,358 @@
+//! Connector hotplug detection through kernel uevents.
+//!
+//! The kernel broadcasts a `change` uevent with `HOTPLUG=1` on the drm device whenever connector status may have changed.
+//! It does not say which connector changed, so all connectors are probed again and compared with the previous state.
+
+use std::{
+	collections::HashMap,
+	os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd}
+};
+
+use anyhow::Context;
+
+use drm::control::{
+	Mode,
+	connector::{Handle as ConnectorHandle, State as ConnectorState}
+};
+use nix::{
+	errno::Errno,
+	sys::socket::{self, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType}
+};
+
+use super::backend::{DrmBackend, ConnectorDesc};
+
+/// Multicast group of uevents sent by the kernel, as opposed to those rebroadcast by udevd.
+const KERNEL_UEVENT_GROUP: u32 = 1;
+/// Upper bound of a uevent message, `UEVENT_BUFFER_SIZE` in the kernel.
+const UEVENT_BUFFER_SIZE: usize = 2048;
+
+/// Kernel uevent, `ACTION@DEVPATH` followed by `KEY=VALUE` pairs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uevent {
+	pub action: String,
+	pub devpath: String,
+	pub properties: HashMap<String, String>
+}
+impl Uevent {
+	/// Parses a nul separated kernel uevent message, returns `None` for malformed or udevd messages.
+	pub fn parse(message: &[u8]) -> Option<Self> {
+		let mut fields = message.split(|&b| b == 0).filter(|field| !field.is_empty()).map(String::from_utf8_lossy);
+
+		let header = fields.next()?;
+		let (action, devpath) = header.split_once('@')?;
+
+		let properties = fields.filter_map(
+			|field| field.split_once('=').map(|(key, value)| (key.to_string(), value.to_string()))
+		).collect();
+
+		Some(
+			Uevent {
+				action: action.to_string(),
+				devpath: devpath.to_string(),
+				properties
+			}
+		)
+	}
+
+	/// Serializes the uevent in the format sent by the kernel.
+	#[cfg(test)]
+	pub fn to_message(&self) -> Vec<u8> {
+		let mut message = format!("{}@{}\0", self.action, self.devpath).into_bytes();
+		for (key, value) in self.properties.iter() {
+			message.extend_from_slice(format!("{}={}\0", key, value).as_bytes());
+		}
+
+		message
+	}
+
+	pub fn get(&self, key: &str) -> Option<&str> {
+		self.properties.get(key).map(String::as_str)
+	}
+
+	/// Whether this is a connector hotplug event of the drm device with number `devnum`.
+	pub fn is_hotplug_of(&self, devnum: (u64, u64)) -> bool {
+		let device = self.get("MAJOR").and_then(|major| major.parse().ok()).zip(
+			self.get("MINOR").and_then(|minor| minor.parse().ok())
+		);
+
+		self.action == "change"
+			&& self.get("SUBSYSTEM") == Some("drm")
+			&& self.get("HOTPLUG") == Some("1")
+			&& device == Some(devnum)
+	}
+}
+
+/// Result of receiving from a [`UeventSocket`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Received {
+	Message(Vec<u8>),
+	/// the socket buffer overflowed and messages were dropped, e.g. while the session was paused
+	Overflow
+}
+
+/// Netlink socket receiving kernel uevents.
+pub struct UeventSocket {
+	fd: OwnedFd
+}
+impl UeventSocket {
+	pub fn new() -> anyhow::Result<Self> {
+		let fd = socket::socket(
+			AddressFamily::Netlink,
+			SockType::Datagram,
+			SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+			SockProtocol::NetlinkKObjectUEvent
+		).context("Failed to create uevent socket")?;
+		// SAFETY: the fd was just created and is not owned by anything else
+		let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+		socket::bind(fd.as_raw_fd(), &NetlinkAddr::new(0, KERNEL_UEVENT_GROUP)).context("Failed to bind uevent socket")?;
+
+		Ok(UeventSocket { fd })
+	}
+
+	/// Receives the next pending message without blocking.
+	///
+	/// An overflow is reported once, the messages still queued can be received after it.
+	pub fn receive(&self) -> anyhow::Result<Option<Received>> {
+		let mut buffer = vec![0u8; UEVENT_BUFFER_SIZE];
+
+		match socket::recv(self.fd.as_raw_fd(), &mut buffer, MsgFlags::empty()) {
+			Ok(length) => {
+				buffer.truncate(length);
+				Ok(Some(Received::Message(buffer)))
+			}
+			Err(Errno::EAGAIN) => Ok(None),
+			Err(Errno::ENOBUFS) => Ok(Some(Received::Overflow)),
+			Err(err) => Err(err).context("Failed to receive uevent")
+		}
+	}
+}
+impl AsRawFd for UeventSocket {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.as_raw_fd()
+	}
+}
+
+/// What changed about a connector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectorChange {
+	Connected,
+	Disconnected,
+	/// still connected, but with a different mode list, e.g. after switching monitors
+	ModesChanged
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotplugEvent {
+	pub connector: ConnectorHandle,
+	/// kernel connector name, e.g. `HDMI-A-1`
+	pub name: String,
+	pub change: ConnectorChange
+}
+
+/// Timings identifying a mode, modes do not implement comparison.
+type ModeKey = (u32, (u16, u16), (u16, u16, u16), (u16, u16, u16), u32, u32);
+
+fn mode_key(mode: &Mode) -> ModeKey {
+	(mode.clock(), mode.size(), mode.hsync(), mode.vsync(), mode.vrefresh(), mode.flags().bits())
+}
+
+struct ConnectorSnapshot {
+	name: String,
+	connected: bool,
+	modes: Vec<ModeKey>
+}
+impl ConnectorSnapshot {
+	fn new(connector: &ConnectorDesc) -> Self {
+		ConnectorSnapshot {
+			name: connector.name(),
+			connected: connector.state == ConnectorState::Connected,
+			modes: connector.modes.iter().map(mode_key).collect()
+		}
+	}
+}
+
+/// Tracks connector state of a device and turns hotplug uevents into per-connector events.
+pub struct HotplugMonitor {
+	socket: Option<UeventSocket>,
+	/// major and minor number of the watched device
+	devnum: (u64, u64),
+	connectors: HashMap<ConnectorHandle, ConnectorSnapshot>
+}
+impl HotplugMonitor {
+	/// Creates a monitor without a socket, messages have to be passed to [`Self::handle_message`].
+	pub fn new(backend: &(impl DrmBackend + ?Sized), devnum: (u64, u64)) -> anyhow::Result<Self> {
+		let mut monitor = HotplugMonitor {
+			socket: None,
+			devnum,
+			connectors: HashMap::new()
+		};
+		monitor.probe(backend)?;
+
+		Ok(monitor)
+	}
+
+	/// Creates a monitor listening on the kernel uevent socket.
+	pub fn open(backend: &(impl DrmBackend + ?Sized), devnum: (u64, u64)) -> anyhow::Result<Self> {
+		let mut monitor = Self::new(backend, devnum)?;
+		monitor.socket = Some(UeventSocket::new()?);
+
+		Ok(monitor)
+	}
+
+	/// Socket to wait on for readability, if any.
+	pub fn socket(&self) -> Option<&UeventSocket> {
+		self.socket.as_ref()
+	}
+
+	/// Probes all connectors and returns the differences to the previous probe.
+	pub fn probe(&mut self, backend: &(impl DrmBackend + ?Sized)) -> anyhow::Result<Vec<HotplugEvent>> {
+		let mut events = Vec::new();
+		let mut connectors = HashMap::new();
+
+		for handle in backend.resources()?.connectors {
+			let current = ConnectorSnapshot::new(&backend.connector(handle)?);
+
+			let change = match self.connectors.remove(&handle) {
+				None if current.connected => Some(ConnectorChange::Connected),
+				None => None,
+				Some(previous) => match (previous.connected, current.connected) {
+					(false, true) => Some(ConnectorChange::Connected),
+					(true, false) => Some(ConnectorChange::Disconnected),
+					(true, true) if previous.modes != current.modes => Some(ConnectorChange::ModesChanged),
+					_ => None
+				}
+			};
+			if let Some(change) = change {
+				events.push(HotplugEvent { connector: handle, name: current.name.clone(), change });
+			}
+
+			connectors.insert(handle, current);
+		}
+
+		// connectors which vanished, like DisplayPort MST ports
+		for (handle, previous) in self.connectors.drain() {
+			if previous.connected {
+				events.push(HotplugEvent { connector: handle, name: previous.name, change: ConnectorChange::Disconnected });
+			}
+		}
+		self.connectors = connectors;
+
+		for event in events.iter() {
+			log::info!("Connector {} {:?}", event.name, event.change);
+		}
+
+		Ok(events)
+	}
+
+	/// Handles one uevent message, probing connectors again if it is a hotplug of the watched device.
+	pub fn handle_message(&mut self, backend: &(impl DrmBackend + ?Sized), message: &[u8]) -> anyhow::Result<Vec<HotplugEvent>> {
+		match Uevent::parse(message) {
+			Some(uevent) if uevent.is_hotplug_of(self.devnum) => {
+				log::debug!("Hotplug uevent {}", uevent.devpath);
+
+				self.probe(backend)
+			}
+			_ => Ok(Vec::new())
+		}
+	}
+
+	/// Whether `received` requires probing the connectors again.
+	fn needs_probe(&self, received: &Received) -> bool {
+		match received {
+			Received::Message(message) => Uevent::parse(message).map(|uevent| uevent.is_hotplug_of(self.devnum)).unwrap_or(false),
+			Received::Overflow => {
+				// any of the dropped messages may have been a hotplug
+				log::warn!("Uevents were lost, probing connectors again");
+				true
+			}
+		}
+	}
+
+	/// Handles all pending uevents without blocking.
+	pub fn poll(&mut self, backend: &(impl DrmBackend + ?Sized)) -> anyhow::Result<Vec<HotplugEvent>> {
+		let mut hotplug = false;
+		if let Some(ref socket) = self.socket {
+			while let Some(received) = socket.receive()? {
+				hotplug |= self.needs_probe(&received);
+			}
+		}
+
+		// one probe covers any number of queued hotplugs
+		if hotplug {
+			self.probe(backend)
+		} else {
+			Ok(Vec::new())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use drm::control::{
+		ModeTypeFlags,
+		connector::Interface as ConnectorInterface,
+		encoder::Kind as EncoderKind
+	};
+
+	use super::super::fake::{FakeDevice, fake_mode, hotplug_uevent};
+	use super::*;
+
+	const DEVNUM: (u64, u64) = (226, 0);
+
+	#[test]
+	fn parse_uevent() {
+		let uevent = Uevent::parse(&hotplug_uevent(DEVNUM)).unwrap();
+		assert_eq!(uevent.action, "change");
+		assert_eq!(uevent.devpath, "/devices/platform/gpu/drm/card0");
+		assert_eq!(uevent.get("HOTPLUG"), Some("1"));
+		assert_eq!(uevent.get("SEQNUM"), None);
+		assert_eq!(Uevent::parse(&uevent.to_message()), Some(uevent.clone()));
+
+		assert!(uevent.is_hotplug_of(DEVNUM));
+		assert!(!uevent.is_hotplug_of((226, 1)));
+	}
+
+	#[test]
+	fn parse_other_messages() {
+		// udevd rebroadcasts start with a binary header instead of ACTION@DEVPATH
+		assert_eq!(Uevent::parse(b"libudev\0\xfe\xed\xca\xfe"), None);
+		assert_eq!(Uevent::parse(b""), None);
+
+		let uevent = Uevent::parse(b"add@/devices/platform/gpu/drm/card0\0ACTION=add\0SUBSYSTEM=drm\0MAJOR=226\0MINOR=0\0").unwrap();
+		assert!(!uevent.is_hotplug_of(DEVNUM));
+	}
+
+	#[test]
+	fn connector_changes() {
+		let mut device = FakeDevice::new();
+		let crtc = device.add_crtc();
+		let encoder = device.add_encoder(EncoderKind::TMDS, &[crtc]);
+		let modes = vec![fake_mode(1920, 1080, 60, ModeTypeFlags::PREFERRED | ModeTypeFlags::DRIVER)];
+		let connector = device.add_connector(ConnectorInterface::HDMIA, 1, ConnectorState::Disconnected, modes, &[encoder]);
+
+		let mut monitor = HotplugMonitor::new(&device, DEVNUM).unwrap();
+		let event = |change: ConnectorChange| vec![HotplugEvent { connector, name: "HDMI-A-1".to_string(), change }];
+
+		device.set_connector_state(connector, ConnectorState::Connected);
+		// only hotplugs of the watched device trigger a probe
+		assert!(monitor.handle_message(&device, &hotplug_uevent((226, 1))).unwrap().is_empty());
+		assert_eq!(monitor.handle_message(&device, &hotplug_uevent(DEVNUM)).unwrap(), event(ConnectorChange::Connected));
+		assert!(monitor.handle_message(&device, &hotplug_uevent(DEVNUM)).unwrap().is_empty());
+
+		device.set_connector_modes(connector, vec![fake_mode(3840, 2160, 60, ModeTypeFlags::PREFERRED | ModeTypeFlags::DRIVER)]);
+		assert_eq!(monitor.handle_message(&device, &hotplug_uevent(DEVNUM)).unwrap(), event(ConnectorChange::ModesChanged));
+
+		device.set_connector_state(connector, ConnectorState::Disconnected);
+		assert_eq!(monitor.handle_message(&device, &hotplug_uevent(DEVNUM)).unwrap(), event(ConnectorChange::Disconnected));
+	}
+
+	#[test]
+	fn overflow_needs_probe() {
+		let monitor = HotplugMonitor::new(&FakeDevice::new(), DEVNUM).unwrap();
+
+		assert!(monitor.needs_probe(&Received::Message(hotplug_uevent(DEVNUM))));
+		assert!(!monitor.needs_probe(&Received::Message(hotplug_uevent((226, 1)))));
+		assert!(monitor.needs_probe(&Received::Overflow));
+	}
+}
@@ -0,0 +1,265 @@
+//! Extra buffers scanned out on overlay planes of an output, blended over the rendered buffer by the display hardware.
+//!
+//! Layers are committed together with the outputs, so they change in the same vblank as the rendered buffers.
+//! Planes are limited and drivers restrict formats, scaling and placement, so adding a layer may find no plane
+//! which can show it, in which case the application has to compose it in GL instead.
+
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use drm::control::{
+	crtc::Handle as CrtcHandle,
+	framebuffer::Handle as FramebufferHandle,
+	plane::Handle as PlaneHandle,
+	property::Handle as PropertyHandle
+};
+
+use super::{
+	backend::{DrmBackend, ObjectHandle, CommitRequest},
+	property::{PLANE_PROPERTIES, PropertyRegistry, TypedValue}
+};
+
+/// How a layer is blended with the planes below it, the plane property `pixel blend mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlendMode {
+	/// color channels are already multiplied with alpha, the kernel default
+	#[default]
+	PreMultiplied,
+	/// color channels are multiplied with alpha when blending
+	Coverage,
+	/// alpha channel of the buffer is ignored
+	None
+}
+impl BlendMode {
+	fn property_value(self) -> &'static str {
+		match self {
+			BlendMode::PreMultiplied => "Pre-multiplied",
+			BlendMode::Coverage => "Coverage",
+			BlendMode::None => "None"
+		}
+	}
+}
+impl FromStr for BlendMode {
+	type Err = anyhow::Error;
+
+	/// Parses the kernel names `pre-multiplied`, `coverage` and `none`, ignoring case.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		[BlendMode::PreMultiplied, BlendMode::Coverage, BlendMode::None].into_iter().find(
+			|mode| mode.property_value().eq_ignore_ascii_case(s.trim())
+		).with_context(|| format!("Unknown blend mode \"{}\", expected pre-multiplied, coverage or none", s))
+	}
+}
+
+/// Placement and blending of a layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerState {
+	/// region of the framebuffer to show, as x, y, width and height
+	pub source: (u32, u32, u32, u32),
+	/// where the region is shown within the mode of the output, as x, y, width and height
+	///
+	/// Scaled if the size differs from `source`, which not every plane supports.
+	pub destination: (i32, i32, u32, u32),
+	/// position among the planes of the output, higher is closer to the viewer, `None` keeps the plane default
+	pub zpos: Option<u64>,
+	/// opacity of the whole layer from transparent at 0 to opaque at `u16::MAX`
+	pub alpha: u16,
+	pub blend: BlendMode
+}
+impl LayerState {
+	/// Opaque layer showing all of a buffer of `width` x `height` unscaled at `x`, `y`.
+	pub fn at(x: i32, y: i32, width: u32, height: u32) -> Self {
+		LayerState {
+			source: (0, 0, width, height),
+			destination: (x, y, width, height),
+			zpos: None,
+			alpha: u16::MAX,
+			blend: BlendMode::default()
+		}
+	}
+}
+
+/// Identifies a layer added to a [`super::KmsContext`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LayerId(pub(super) u64);
+
+/// Overlay plane showing a framebuffer on an output.
+pub(super) struct Layer {
+	pub id: LayerId,
+	/// index of the output in [`super::KmsConfig::outputs`]
+	pub output: usize,
+	pub plane: PlaneHandle,
+	properties: PropertyRegistry,
+	/// `None` once removed, the plane is disabled by the next commit
+	framebuffer: Option<FramebufferHandle>,
+	/// property values committed for the plane
+	values: Vec<(PropertyHandle, u64)>,
+	/// whether a commit has shown the layer, only then it can be moved on its own
+	committed: bool
+}
+impl Layer {
+	/// Layer on `plane` showing `framebuffer` on `crtc`, fails if the plane cannot be configured like `state`.
+	pub fn new(
+		backend: &(impl DrmBackend + ?Sized),
+		id: LayerId,
+		output: usize,
+		crtc: CrtcHandle,
+		plane: PlaneHandle,
+		framebuffer: FramebufferHandle,
+		state: &LayerState
+	) -> anyhow::Result<Self> {
+		let properties = PropertyRegistry::query(backend, ObjectHandle::Plane(plane), &PLANE_PROPERTIES)?;
+		let values = Self::encode(&properties, crtc, framebuffer, state)?;
+
+		Ok(
+			Layer {
+				id,
+				output,
+				plane,
+				properties,
+				framebuffer: Some(framebuffer),
+				values,
+				committed: false
+			}
+		)
+	}
+
+	fn encode(
+		properties: &PropertyRegistry,
+		crtc: CrtcHandle,
+		framebuffer: FramebufferHandle,
+		state: &LayerState
+	) -> anyhow::Result<Vec<(PropertyHandle, u64)>> {
+		let (source_x, source_y, source_width, source_height) = state.source;
+		let (x, y, width, height) = state.destination;
+		if source_width == 0 || source_height == 0 || width == 0 || height == 0 {
+			anyhow::bail!("Layer must not be empty, got source {:?} and destination {:?}", state.source, state.destination);
+		}
+
+		// source coordinates are 16.16 fixed point
+		let mut values = vec![
+			properties.encode("FB_ID", &TypedValue::Object(u32::from(framebuffer)))?,
+			properties.encode("CRTC_ID", &TypedValue::Object(u32::from(crtc)))?,
+			properties.encode("SRC_X", &TypedValue::Range((source_x as u64) << 16))?,
+			properties.encode("SRC_Y", &TypedValue::Range((source_y as u64) << 16))?,
+			properties.encode("SRC_W", &TypedValue::Range((source_width as u64) << 16))?,
+			properties.encode("SRC_H", &TypedValue::Range((source_height as u64) << 16))?,
+			properties.encode("CRTC_X", &TypedValue::SignedRange(x as i64))?,
+			properties.encode("CRTC_Y", &TypedValue::SignedRange(y as i64))?,
+			properties.encode("CRTC_W", &TypedValue::Range(width as u64))?,
+			properties.encode("CRTC_H", &TypedValue::Range(height as u64))?
+		];
+
+		// defaults of optional properties are only left out when the plane lacks them
+		if let Some(zpos) = state.zpos {
+			values.push(properties.encode("zpos", &TypedValue::Range(zpos))?);
+		}
+		match properties.get("alpha") {
+			None if state.alpha != u16::MAX => anyhow::bail!("Plane has no alpha property for opacity {}", state.alpha),
+			None => (),
+			Some(_) => values.push(properties.encode("alpha", &TypedValue::Range(state.alpha as u64))?)
+		}
+		match properties.get("pixel blend mode") {
+			None if state.blend != BlendMode::default() => anyhow::bail!("Plane has no pixel blend mode property for {:?}", state.blend),
+			None => (),
+			Some(_) => values.push(properties.encode("pixel blend mode", &TypedValue::Enum(state.blend.property_value().to_string()))?)
+		}
+		if properties.contains("rotation") {
+			values.push(properties.encode("rotation", &TypedValue::Bitmask(vec!["rotate-0".to_string()]))?);
+		}
+
+		Ok(values)
+	}
+
+	/// Shows `framebuffer` like `state` from the next commit on.
+	pub fn set(&mut self, crtc: CrtcHandle, framebuffer: FramebufferHandle, state: &LayerState) -> anyhow::Result<()> {
+		self.values = Self::encode(&self.properties, crtc, framebuffer, state).with_context(|| format!("Failed to configure layer on {:?}", self.plane))?;
+		self.framebuffer = Some(framebuffer);
+
+		Ok(())
+	}
+
+	/// Moves the layer to `x`, `y` with the next commit, returns the values of `CRTC_X` and `CRTC_Y` to commit on their own.
+	///
+	/// Does nothing once the layer is disabled, `None` then.
+	pub fn move_to(&mut self, x: i32, y: i32) -> anyhow::Result<Option<[(PropertyHandle, u64); 2]>> {
+		if !self.is_enabled() {
+			return Ok(None)
+		}
+
+		let position = [
+			self.properties.encode("CRTC_X", &TypedValue::SignedRange(x as i64))?,
+			self.properties.encode("CRTC_Y", &TypedValue::SignedRange(y as i64))?
+		];
+		for &(property, value) in position.iter() {
+			if let Some(slot) = self.values.iter_mut().find(|(existing, _)| *existing == property) {
+				slot.1 = value;
+			}
+		}
+
+		Ok(Some(position))
+	}
+
+	/// Disables the plane with the next commit, after which the layer is dropped.
+	pub fn disable(&mut self) -> anyhow::Result<()> {
+		self.values = vec![
+			self.properties.encode("FB_ID", &TypedValue::Object(0))?,
+			self.properties.encode("CRTC_ID", &TypedValue::Object(0))?
+		];
+		self.framebuffer = None;
+
+		Ok(())
+	}
+
+	/// Whether the layer still shows a framebuffer, otherwise it only disables its plane.
+	pub fn is_enabled(&self) -> bool {
+		self.framebuffer.is_some()
+	}
+
+	pub fn set_committed(&mut self, committed: bool) {
+		self.committed = committed;
+	}
+
+	/// Whether a commit has shown the layer on its crtc since it was added or the session was paused.
+	pub fn is_committed(&self) -> bool {
+		self.committed
+	}
+
+	pub fn add_to_request(&self, request: &mut CommitRequest) {
+		let plane = ObjectHandle::Plane(self.plane);
+		for &(property, value) in self.values.iter() {
+			request.add(plane, property, value);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use drm::buffer::DrmFourcc;
+
+	use super::super::fake::{FakeDevice, PlaneType};
+	use super::*;
+
+	#[test]
+	fn disabled_layers_do_not_move() {
+		let mut device = FakeDevice::new();
+		let crtc = device.add_crtc();
+		let plane = device.add_plane(PlaneType::Overlay, &[crtc], &[DrmFourcc::Argb8888 as u32]);
+		let framebuffer: FramebufferHandle = drm::control::from_u32(100).unwrap();
+		let mut layer = Layer::new(&device, LayerId(0), 0, crtc, plane, framebuffer, &LayerState::at(0, 0, 64, 64)).unwrap();
+		let properties = PropertyRegistry::query(&device, ObjectHandle::Plane(plane), &PLANE_PROPERTIES).unwrap();
+		let (x, y) = (properties.handle("CRTC_X").unwrap(), properties.handle("CRTC_Y").unwrap());
+
+		assert_eq!(layer.move_to(10, -20).unwrap(), Some([(x, 10), (y, -20i64 as u64)]));
+		let mut request = CommitRequest::new();
+		layer.add_to_request(&mut request);
+		assert_eq!(request.get(ObjectHandle::Plane(plane), x), Some(10));
+
+		layer.disable().unwrap();
+		assert_eq!(layer.move_to(30, 40).unwrap(), None);
+		let mut request = CommitRequest::new();
+		layer.add_to_request(&mut request);
+		assert_eq!(request.get(ObjectHandle::Plane(plane), x), None);
+		assert_eq!(request.get(ObjectHandle::Plane(plane), properties.handle("FB_ID").unwrap()), Some(0));
+	}
+}
@@ -1,28 +1,63 @@
-use std::path::Path;
+use std::{cell::{Cell, RefCell}, os::unix::io::{AsRawFd, RawFd}, path::{Path, PathBuf}, time::Duration};
 
 use anyhow::Context;
 
 use drm::{
 	Device,
-	ClientCapability,
 	control::{
-		Device as ControlDevice,
 		Mode,
-		connector::{Info as ConnectorInfo},
-		plane::{Info as PlaneInfo},
-		property::{Handle as PropertyHandle, Value as PropertyValue}
+		atomic::AtomicCommitFlags,
+		crtc::Handle as CrtcHandle,
+		framebuffer::Handle as FramebufferHandle,
+		plane::Handle as PlaneHandle,
+		property::Handle as PropertyHandle
 	},
 	buffer::{DrmFourcc, DrmModifier}
 };
 use gbm::Device as GbmDevice;
+use nix::time::{ClockId, clock_gettime};
 
 type KmsDevice = GbmDevice<DrmDevice>;
 
+mod backend;
+mod cursor;
 mod device;
+mod discover;
+#[cfg(test)]
+mod fake;
+mod feedback;
+mod format;
 mod framebuffer;
+mod hotplug;
+mod layer;
+mod mode;
+mod property;
+pub mod record;
+mod restore;
+mod scale;
+mod select;
+mod session;
+mod surface;
+mod sync_file;
 
+use backend::{DrmBackend, DrmEvent, ObjectHandle, CommitBusy, CommitRequest, ConnectorDesc, PlaneDesc, PropertyKind};
+use property::{CONNECTOR_PROPERTIES, CRTC_PROPERTIES, PLANE_PROPERTIES, PropertyRegistry, PropertySpec, TypedValue};
+use cursor::{CURSOR_FORMAT, Cursor};
 use device::{DrmDevice, IndexedCrtc};
+use layer::Layer;
+pub use discover::{DeviceInfo, DeviceSelector, enumerate_devices};
+pub use feedback::{FlipTiming, PresentFeedback};
+pub use format::FormatTable;
 pub use framebuffer::FrameBufferObject;
+pub use hotplug::{ConnectorChange, HotplugEvent, HotplugMonitor};
+pub use layer::{BlendMode, LayerId, LayerState};
+pub use mode::{ModeRequest, ModeTarget, cvt_mode, parse_cvt_mode, parse_modeline, parse_size};
+pub use restore::exit_requested;
+pub use scale::Insets;
+pub use select::ConnectorSelector;
+pub use session::{Session, SessionEvent, SessionKind, open as open_session};
+pub use surface::KmsSurface;
+pub use sync_file::SyncFile;
 
 struct CommitPropertyCache {
 	/// connector property `CRTC_ID`
@@ -31,10 +66,10 @@ struct CommitPropertyCache {
 	pub crtc_mode_id: PropertyHandle,
 	/// crtc property `ACTIVE`
 	pub crtc_active: PropertyHandle,
-	// /// crtc property `OUT_FENCE_PTR`
-	// pub crtc_out_fence_ptr: PropertyHandle,
-	/// plane property `IN_FENCE_FD`
-	pub plane_in_fence_fd: PropertyHandle,
+	/// crtc property `OUT_FENCE_PTR`, missing on kernels without explicit fencing
+	pub crtc_out_fence_ptr: Option<PropertyHandle>,
+	/// plane property `IN_FENCE_FD`, missing on kernels without explicit fencing
+	pub plane_in_fence_fd: Option<PropertyHandle>,
 	/// plane property `FB_ID`
 	pub plane_fb_id: PropertyHandle,
 	/// plane property `CRTC_ID`
@@ -55,310 +90,2265 @@ struct CommitPropertyCache {
 	pub plane_crtc_w: PropertyHandle,
 	/// plane property `CRTC_H`
 	pub plane_crtc_h: PropertyHandle,
+	/// optional plane properties reset on modesets, as another client may have left the plane rotated or translucent
+	pub plane_defaults: Vec<(PropertyHandle, u64)>,
 	/// blob containing mode
-	pub blob_mode: PropertyValue<'static>
+	pub blob_mode: u64
 }
 
-pub struct KmsContext {
-	device: KmsDevice,
-	connector: ConnectorInfo,
+/// What the application asks for when choosing a configuration.
+#[derive(Debug, Clone, Default)]
+pub struct KmsOptions {
+	pub connector: ConnectorSelector,
+	pub mode: ModeRequest,
+	/// program this mode instead of choosing one of the connector modes, see [`cvt_mode`] and [`parse_modeline`]
+	pub custom_mode: Option<Mode>,
+	/// how to get access to the device
+	pub session: SessionKind,
+	/// drive every connected connector matching `connector` instead of only the first one
+	pub all_outputs: bool,
+	/// show the same buffer on every connected connector matching `connector`, scaled to fit each mode
+	pub mirror: bool,
+	/// render at this size instead of the mode size, scaled by the plane to fit the mode with its aspect ratio preserved
+	///
+	/// Buffers are presented at the mode size if the planes cannot scale them, see [`KmsConfig::validate`].
+	pub render_size: Option<(u16, u16)>,
+	/// borders left empty within the mode, e.g. for TVs cutting off the edges, shrinking the buffers shown
+	pub overscan: Insets,
+	/// scanout formats in order of preference, [`DEFAULT_FORMATS`] if empty
+	///
+	/// Buffers are linear until [`KmsContext::negotiate_modifiers`] finds a better modifier for the chosen format.
+	pub formats: Vec<DrmFourcc>,
+	/// record all drm traffic into this file, see [`record`]
+	pub capture: Option<PathBuf>
+}
+
+/// Formats tried when [`KmsOptions::formats`] is empty, supported for scanout by almost every driver.
+pub const DEFAULT_FORMATS: [DrmFourcc; 3] = [DrmFourcc::Xrgb8888, DrmFourcc::Xbgr8888, DrmFourcc::Rgb565];
+
+/// Primary plane of an output and the properties committed for it, only used with atomic modesetting.
+struct AtomicPlane {
+	plane: PlaneDesc,
+	property_cache: CommitPropertyCache
+}
+
+/// Connector together with the crtc, primary plane and mode driving it.
+pub struct KmsOutput {
+	connector: ConnectorDesc,
 	mode: Mode,
 	crtc: IndexedCrtc,
-	plane: PlaneInfo,
-	property_cache: CommitPropertyCache
+	/// `None` with legacy modesetting, which implicitly uses the primary plane of the crtc
+	atomic: Option<AtomicPlane>,
+	/// size of the presented buffers, differs from the mode when mirroring or scaling
+	source_size: (u16, u16),
+	/// where the buffer is scanned out within the mode, as x, y, width and height
+	crtc_rect: (u32, u32, u32, u32)
 }
-impl KmsContext {
+impl KmsOutput {
 	fn cache_commit_properties(
-		device: &KmsDevice,
-		connector: &ConnectorInfo,
+		device: &(impl DrmBackend + ?Sized),
+		connector: &ConnectorDesc,
 		crtc: &IndexedCrtc,
-		plane: &PlaneInfo,
-		mode: &Mode
+		plane: &PlaneDesc,
+		blob_mode: u64
 	) -> anyhow::Result<CommitPropertyCache> {
-		macro_rules! find_properties {
-			(
-				$handles: expr;
-				$( let $var_name: ident = $prop_name: literal; )+
-			) => {
-				$(
-					let mut $var_name: Option<PropertyHandle> = None;
-				)+
-				for &handle in $handles {
-					let property = device.get_property(handle).context("Failed to query property")?;
-
-					match property.name().to_str() {
-						$(
-							Ok($prop_name) => {
-								assert!($var_name.is_none());
-								$var_name = Some(handle);
+		let connector = PropertyRegistry::query(device, ObjectHandle::Connector(connector.handle), &CONNECTOR_PROPERTIES)?;
+		let crtc = PropertyRegistry::query(device, ObjectHandle::Crtc(crtc.handle()), &CRTC_PROPERTIES)?;
+		let plane = PropertyRegistry::query(device, ObjectHandle::Plane(plane.handle), &PLANE_PROPERTIES)?;
+
+		let mut plane_defaults = Vec::new();
+		if let Some(alpha) = plane.get("alpha") {
+			let opaque = match alpha.kind {
+				PropertyKind::Range { max, .. } => max,
+				_ => u16::MAX as u64
+			};
+			plane_defaults.push((alpha.handle, opaque));
+		}
+		if plane.contains("rotation") {
+			plane_defaults.push(plane.encode("rotation", &TypedValue::Bitmask(vec!["rotate-0".to_string()]))?);
+		}
+
+		Ok(
+			CommitPropertyCache {
+				connector_crtc_id: connector.handle("CRTC_ID")?,
+				crtc_mode_id: crtc.handle("MODE_ID")?,
+				crtc_active: crtc.handle("ACTIVE")?,
+				crtc_out_fence_ptr: crtc.get("OUT_FENCE_PTR").map(|property| property.handle),
+				plane_in_fence_fd: plane.get("IN_FENCE_FD").map(|property| property.handle),
+				plane_fb_id: plane.handle("FB_ID")?,
+				plane_crtc_id: plane.handle("CRTC_ID")?,
+				plane_src_x: plane.handle("SRC_X")?,
+				plane_src_y: plane.handle("SRC_Y")?,
+				plane_src_w: plane.handle("SRC_W")?,
+				plane_src_h: plane.handle("SRC_H")?,
+				plane_crtc_x: plane.handle("CRTC_X")?,
+				plane_crtc_y: plane.handle("CRTC_Y")?,
+				plane_crtc_w: plane.handle("CRTC_W")?,
+				plane_crtc_h: plane.handle("CRTC_H")?,
+				plane_defaults,
+				blob_mode
+			}
+		)
+	}
+	
+	fn choose_mode(options: &KmsOptions, connector: &ConnectorDesc) -> anyhow::Result<Mode> {
+		match options.custom_mode {
+			None => options.mode.choose(&connector.modes),
+			Some(mode) => {
+				log::info!("Using custom mode: {}", mode::describe_mode(&mode));
+
+				Ok(mode)
+			}
+		}
+	}
+
+	/// Chooses mode, crtc and plane for `connector` when it is the only output.
+	fn choose(
+		backend: &(impl DrmBackend + ?Sized),
+		options: &KmsOptions,
+		connector: ConnectorDesc,
+		crtcs: &[CrtcHandle],
+		atomic: bool
+	) -> anyhow::Result<Self> {
+		let mode = Self::choose_mode(options, &connector)?;
+		let crtc = select::choose_crtc(
+			backend,
+			connector.current_encoder,
+			&connector.encoders,
+			crtcs,
+			&[]
+		)?;
+		let plane = if atomic { Some(select::choose_plane(backend, &crtc, &[])?) } else { None };
+
+		Self::new(backend, connector, mode, crtc, plane)
+	}
+
+	/// Output without a plane if `plane` is `None`, to be driven through legacy modesetting.
+	fn new(
+		backend: &(impl DrmBackend + ?Sized),
+		connector: ConnectorDesc,
+		mode: Mode,
+		crtc: IndexedCrtc,
+		plane: Option<PlaneDesc>
+	) -> anyhow::Result<Self> {
+		let atomic = match plane {
+			None => None,
+			Some(plane) => {
+				let blob_mode = backend.create_mode_blob(&mode)?;
+				let property_cache = match Self::cache_commit_properties(backend, &connector, &crtc, &plane, blob_mode) {
+					Ok(property_cache) => property_cache,
+					Err(err) => {
+						destroy_mode_blob(backend, blob_mode);
+						return Err(err).context("Failed to cache commit properties")
+					}
+				};
+
+				Some(AtomicPlane { plane, property_cache })
+			}
+		};
+
+		Ok(
+			KmsOutput {
+				connector,
+				mode,
+				crtc,
+				atomic,
+				source_size: mode.size(),
+				crtc_rect: (0, 0, mode.size().0 as u32, mode.size().1 as u32)
+			}
+		)
+	}
+
+	/// Destroys the mode blob of the output, which must no longer be committed afterwards.
+	fn destroy(self, backend: &(impl DrmBackend + ?Sized)) {
+		if let Some(atomic) = self.atomic {
+			destroy_mode_blob(backend, atomic.property_cache.blob_mode);
+		}
+	}
+
+	fn atomic(&self) -> anyhow::Result<&AtomicPlane> {
+		self.atomic.as_ref().with_context(|| format!("Output {} is driven through legacy modesetting", self.name()))
+	}
+
+	/// Primary plane chosen for this output, `None` with legacy modesetting.
+	fn plane_handle(&self) -> Option<PlaneHandle> {
+		self.atomic.as_ref().map(|atomic| atomic.plane.handle)
+	}
+
+	/// Formats and modifiers of the plane from its `IN_FORMATS` property, `None` if the driver does not report them.
+	fn in_formats(&self, backend: &(impl DrmBackend + ?Sized)) -> anyhow::Result<Option<FormatTable>> {
+		let plane = ObjectHandle::Plane(self.atomic()?.plane.handle);
+		let properties = PropertyRegistry::query(backend, plane, &[PropertySpec::optional("IN_FORMATS")])?;
+
+		match properties.get("IN_FORMATS") {
+			Some(property) if property.value != 0 => {
+				let blob = backend.property_blob(property.value)?;
+				let table = FormatTable::from_in_formats(&blob).with_context(|| format!("Failed to parse IN_FORMATS of {:?}", plane))?;
+
+				Ok(Some(table))
+			}
+			_ => Ok(None)
+		}
+	}
+
+	/// Adds the properties presenting `framebuffer` on this output to `request`, requesting an out fence for the crtc.
+	fn add_to_request(
+		&self,
+		request: &mut CommitRequest,
+		allow_modeset: bool,
+		framebuffer: FramebufferHandle
+	) -> anyhow::Result<()> {
+		let AtomicPlane { ref plane, ref property_cache } = *self.atomic()?;
+		let connector = ObjectHandle::Connector(self.connector.handle);
+		let crtc = ObjectHandle::Crtc(self.crtc.handle());
+		let plane = ObjectHandle::Plane(plane.handle);
+		let crtc_id = u32::from(self.crtc.handle()) as u64;
+		let (source_width, source_height) = (self.source_size.0 as u64, self.source_size.1 as u64);
+		let (crtc_x, crtc_y, crtc_w, crtc_h) = self.crtc_rect;
+
+		if allow_modeset {
+			request.add(connector, property_cache.connector_crtc_id, crtc_id);
+			request.add(crtc, property_cache.crtc_mode_id, property_cache.blob_mode);
+			request.add(crtc, property_cache.crtc_active, 1);
+			for &(property, value) in property_cache.plane_defaults.iter() {
+				request.add(plane, property, value);
+			}
+		}
+
+		request.add(plane, property_cache.plane_fb_id, u32::from(framebuffer) as u64);
+		request.add(plane, property_cache.plane_crtc_id, crtc_id);
+		request.add(plane, property_cache.plane_src_x, 0);
+		request.add(plane, property_cache.plane_src_y, 0);
+		request.add(plane, property_cache.plane_src_w, source_width << 16);
+		request.add(plane, property_cache.plane_src_h, source_height << 16);
+		request.add(plane, property_cache.plane_crtc_x, crtc_x as u64);
+		request.add(plane, property_cache.plane_crtc_y, crtc_y as u64);
+		request.add(plane, property_cache.plane_crtc_w, crtc_w as u64);
+		request.add(plane, property_cache.plane_crtc_h, crtc_h as u64);
+
+		if let Some(out_fence_ptr) = property_cache.crtc_out_fence_ptr {
+			request.add_out_fence(crtc, out_fence_ptr);
+		}
+
+		Ok(())
+	}
+
+	/// Whether [`Self::add_to_request`] requests an out fence for this output.
+	fn has_out_fence(&self) -> bool {
+		match self.atomic() {
+			Err(_) => false,
+			Ok(atomic) => atomic.property_cache.crtc_out_fence_ptr.is_some()
+		}
+	}
+
+	/// Makes the plane wait for the sync_file `fence`, signaled when rendering into the committed framebuffer completes.
+	///
+	/// Returns false if the plane lacks `IN_FENCE_FD`, then the commit relies on implicit synchronization of the buffer.
+	fn add_in_fence(&self, request: &mut CommitRequest, fence: RawFd) -> anyhow::Result<bool> {
+		let atomic = self.atomic()?;
+		match atomic.property_cache.plane_in_fence_fd {
+			None => Ok(false),
+			Some(in_fence_fd) => {
+				request.add_in_fence(ObjectHandle::Plane(atomic.plane.handle), in_fence_fd, fence);
+
+				Ok(true)
+			}
+		}
+	}
+
+	/// Outputs driving the same connector and crtc with other modes and planes, in order of preference.
+	///
+	/// Starts with the current mode on other planes, then the next ranked modes on all planes.
+	fn fallbacks(&self, backend: &(impl DrmBackend + ?Sized), options: &KmsOptions, taken_planes: &[PlaneHandle]) -> anyhow::Result<Vec<(Mode, PlaneDesc)>> {
+		let modes: Vec<Mode> = match options.custom_mode {
+			None => options.mode.rank(&self.connector.modes).into_iter().map(|candidate| candidate.mode).collect(),
+			Some(mode) => vec![mode]
+		};
+		let plane = &self.atomic()?.plane;
+		let planes = select::fallback_planes(backend, &self.crtc, plane.handle, taken_planes)?;
+
+		let mut fallbacks: Vec<(Mode, PlaneDesc)> = planes.iter().map(|plane| (self.mode, plane.clone())).collect();
+		// the current mode ranks first
+		for &mode in modes.iter().skip(1) {
+			fallbacks.extend(
+				std::iter::once(plane).chain(planes.iter()).map(|plane| (mode, plane.clone()))
+			);
+		}
+
+		Ok(fallbacks)
+	}
+
+	/// Kernel connector name, e.g. `HDMI-A-1`.
+	pub fn name(&self) -> String {
+		self.connector.name()
+	}
+
+	/// Connector, mode and plane for log messages.
+	fn describe(&self) -> String {
+		match self.plane_handle() {
+			None => format!("{} {}", self.name(), mode::describe_mode(&self.mode)),
+			Some(plane) => format!("{} {} on plane {:?}", self.name(), mode::describe_mode(&self.mode), plane)
+		}
+	}
+
+	pub fn resolution(&self) -> [usize; 2] {
+		[self.mode.size().0 as usize, self.mode.size().1 as usize]
+	}
+
+	/// Size of the buffers presented on this output, differs from [`Self::resolution`] when scaling.
+	pub fn render_size(&self) -> [usize; 2] {
+		[self.source_size.0 as usize, self.source_size.1 as usize]
+	}
+
+	/// Maps a rectangle within the mode, like [`LayerState::destination`], to the buffers presented on this output.
+	pub fn render_rect(&self, (x, y, width, height): (i32, i32, u32, u32)) -> (i32, i32, u32, u32) {
+		let (crtc_x, crtc_y, crtc_w, crtc_h) = self.crtc_rect;
+		let scale_x = |value: i64| value * self.source_size.0 as i64 / crtc_w as i64;
+		let scale_y = |value: i64| value * self.source_size.1 as i64 / crtc_h as i64;
+
+		(
+			scale_x(x as i64 - crtc_x as i64) as i32,
+			scale_y(y as i64 - crtc_y as i64) as i32,
+			scale_x(width as i64) as u32,
+			scale_y(height as i64) as u32
+		)
+	}
+
+	/// Scans out buffers of `source_size`, scaled to fit the mode without `insets`.
+	fn scale_from(&mut self, source_size: (u16, u16), insets: &Insets) -> anyhow::Result<()> {
+		self.crtc_rect = scale::place_rect(source_size, self.mode.size(), insets).with_context(
+			|| format!("Overscan insets {:?} leave nothing of mode {}", insets, mode::describe_mode(&self.mode))
+		)?;
+		self.source_size = source_size;
+
+		Ok(())
+	}
+
+	/// Plane property `FB_ID`, used to recognize presented framebuffers in recorded commits.
+	///
+	/// `None` with legacy modesetting.
+	pub fn plane_fb_id(&self) -> Option<(ObjectHandle, PropertyHandle)> {
+		self.atomic.as_ref().map(
+			|atomic| (ObjectHandle::Plane(atomic.plane.handle), atomic.property_cache.plane_fb_id)
+		)
+	}
+
+	/// Shows `framebuffer` through legacy modesetting, performing a modeset first if `allow_modeset` is set.
+	fn legacy_present(
+		&self,
+		backend: &(impl DrmBackend + ?Sized),
+		allow_modeset: bool,
+		framebuffer: FramebufferHandle
+	) -> anyhow::Result<()> {
+		if allow_modeset {
+			backend.set_crtc(self.crtc.handle(), framebuffer, &[self.connector.handle], self.mode).with_context(
+				|| format!("Failed to set mode {} on {}", mode::describe_mode(&self.mode), self.name())
+			)?;
+		}
+
+		// also flip after a modeset, so that every present completes with a page flip event
+		backend.page_flip(self.crtc.handle(), framebuffer).with_context(|| format!("Failed to flip {}", self.name()))
+	}
+}
+
+/// Display configuration chosen for a device, independent of buffer allocation.
+///
+/// Only talks to the device through [`DrmBackend`], so it can also be resolved against fake or recorded devices.
+pub struct KmsConfig {
+	outputs: Vec<KmsOutput>,
+	/// whether all outputs scan out the same buffers
+	mirrored: bool,
+	/// whether outputs are presented with atomic commits, otherwise with legacy `set_crtc` and `page_flip`
+	atomic: bool,
+	/// size of the presented buffers if not the mode size, see [`KmsOptions::render_size`]
+	render_size: Option<(u16, u16)>,
+	overscan: Insets
+}
+impl KmsConfig {
+	/// Chooses connectors and a mode, crtc and plane for each of them.
+	///
+	/// Planes are only chosen if the backend supports atomic commits, see [`DrmBackend::is_atomic`].
+	/// Otherwise the outputs are driven through legacy modesetting, which can neither mirror nor scale.
+	pub fn choose(backend: &(impl DrmBackend + ?Sized), options: &KmsOptions) -> anyhow::Result<Self> {
+		let resources = backend.resources()?;
+		let atomic = backend.is_atomic();
+
+		let scaled = options.render_size.is_some() || !options.overscan.is_empty();
+		let (render_size, overscan) = if scaled && !atomic {
+			log::warn!("Scaling needs atomic modesetting, rendering at the mode size");
+			(None, Insets::default())
+		} else {
+			(options.render_size, options.overscan)
+		};
+
+		if !options.all_outputs && !options.mirror {
+			let connector = select::choose_connector(backend, &resources.connectors, &options.connector)?;
+			let output = KmsOutput::choose(backend, options, connector, &resources.crtcs, atomic)?;
+
+			let mut config = KmsConfig { outputs: vec![output], mirrored: false, atomic, render_size, overscan };
+			if let Err(err) = config.apply_scaling(false) {
+				config.destroy(backend);
+				return Err(err)
+			}
+
+			return Ok(config)
+		}
+
+		// mirroring also drives every matching connector
+		let mut candidates: Vec<(ConnectorDesc, Mode, Vec<(IndexedCrtc, Option<PlaneDesc>)>)> = Vec::new();
+		for connector in select::choose_connectors(backend, &resources.connectors, &options.connector)? {
+			let mode = match KmsOutput::choose_mode(options, &connector) {
+				Ok(mode) => mode,
+				Err(err) => {
+					log::warn!("Skipping connector {}: {:#}", connector.name(), err);
+					continue;
+				}
+			};
+
+			let mut routes = Vec::new();
+			for crtc in select::crtc_candidates(backend, connector.current_encoder, &connector.encoders, &resources.crtcs)? {
+				if atomic {
+					for plane in select::plane_candidates(backend, &crtc, &[])? {
+						routes.push((crtc.clone(), Some(plane)));
+					}
+				} else {
+					routes.push((crtc, None));
+				}
+			}
+
+			candidates.push((connector, mode, routes));
+		}
+
+		// usually there are fewer crtcs than connectors, so drive as many as possible
+		let route_handles: Vec<Vec<select::Route>> = candidates.iter().map(
+			|(_, _, routes)| routes.iter().map(|(crtc, plane)| (crtc.handle(), plane.as_ref().map(|plane| plane.handle))).collect()
+		).collect();
+		let mut outputs: Vec<KmsOutput> = Vec::new();
+		for ((connector, mode, mut routes), chosen) in candidates.into_iter().zip(select::assign_routes(&route_handles)) {
+			let name = connector.name();
+			let result = match chosen {
+				None => Err(anyhow::anyhow!("Did not find a free crtc and plane")),
+				Some(chosen) => {
+					let (crtc, plane) = routes.swap_remove(chosen);
+					log::info!("Choosing crtc {:?} and plane {:?} for connector {}", crtc, plane.as_ref().map(|plane| plane.handle), name);
+
+					KmsOutput::new(backend, connector, mode, crtc, plane)
+				}
+			};
+
+			match result {
+				Ok(output) => outputs.push(output),
+				Err(err) => log::warn!("Skipping connector {}: {:#}", name, err)
+			}
+		}
+
+		if outputs.is_empty() {
+			anyhow::bail!("Could not configure any of the connected connectors");
+		}
+
+		let mut config = KmsConfig { outputs, mirrored: false, atomic, render_size, overscan };
+		if options.mirror && !atomic {
+			log::warn!("Mirroring needs atomic modesetting, driving {} outputs separately", config.outputs.len());
+		} else if options.mirror && config.outputs.len() > 1 {
+			config.mirrored = true;
+		}
+		if let Err(err) = config.apply_scaling(config.mirrored) {
+			config.destroy(backend);
+			return Err(err)
+		}
+		if config.mirrored {
+			let source_size = config.outputs[0].source_size;
+			log::info!("Mirroring {}x{} onto {} outputs", source_size.0, source_size.1, config.outputs.len());
+		}
+
+		Ok(config)
+	}
+
+	/// Destroys the mode blobs of all outputs, the configuration must no longer be committed afterwards.
+	pub fn destroy(self, backend: &(impl DrmBackend + ?Sized)) {
+		destroy_outputs(backend, self.outputs);
+	}
+
+	/// Places the buffers of every output within its mode, all of the size of the first output if `mirrored` is set.
+	///
+	/// Buffers have the render size if one is set, otherwise the mode size.
+	fn apply_scaling(&mut self, mirrored: bool) -> anyhow::Result<()> {
+		let mirror_size = self.render_size.unwrap_or(self.outputs[0].mode.size());
+		for output in self.outputs.iter_mut() {
+			let source_size = if mirrored { mirror_size } else { self.render_size.unwrap_or(output.mode.size()) };
+			output.scale_from(source_size, &self.overscan)?;
+
+			if output.crtc_rect != (0, 0, output.mode.size().0 as u32, output.mode.size().1 as u32) {
+				log::debug!("Showing {}x{} on output {} at {:?}", source_size.0, source_size.1, output.name(), output.crtc_rect);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Whether buffers are scaled or inset on their own, independent of mirroring.
+	fn is_scaled(&self) -> bool {
+		self.render_size.is_some() || !self.overscan.is_empty()
+	}
+
+	pub fn outputs(&self) -> &[KmsOutput] {
+		&self.outputs
+	}
+
+	pub fn is_mirrored(&self) -> bool {
+		self.mirrored
+	}
+
+	pub fn is_atomic(&self) -> bool {
+		self.atomic
+	}
+
+	/// Number of distinct buffers presented per frame.
+	pub fn render_targets(&self) -> usize {
+		if self.mirrored { 1 } else { self.outputs.len() }
+	}
+
+	/// Checks with a test-only commit that all outputs can scan out `framebuffer` at once.
+	///
+	/// Otherwise falls back to separate buffers per output, of the mode size unless a render size is set.
+	/// Returns whether the outputs are still mirrored.
+	pub fn test_mirror(&mut self, backend: &(impl DrmBackend + ?Sized), framebuffer: FramebufferHandle) -> anyhow::Result<bool> {
+		if !self.mirrored {
+			return Ok(false)
+		}
+
+		let outputs: Vec<&KmsOutput> = self.outputs.iter().collect();
+		match test_commit(backend, &outputs, &vec![framebuffer; outputs.len()]) {
+			Ok(()) => Ok(true),
+			Err(err) => {
+				log::warn!("Cannot scan out one buffer on all outputs, falling back to separate buffers: {:#}", err);
+
+				self.mirrored = false;
+				self.apply_scaling(false)?;
+
+				Ok(false)
+			}
+		}
+	}
+
+	/// Formats of [`KmsOptions::formats`] in linear layout, which every renderer can use.
+	pub fn default_candidates(options: &KmsOptions) -> Vec<(DrmFourcc, DrmModifier)> {
+		let formats = if options.formats.is_empty() { &DEFAULT_FORMATS[..] } else { &options.formats[..] };
+
+		formats.iter().map(|&format| (format, DrmModifier::Linear)).collect()
+	}
+
+	/// Modifiers of `format` supported by the planes of all outputs and `importer`, best first and linear last.
+	///
+	/// Only linear layout is returned if a plane does not report its modifiers or with legacy modesetting.
+	pub fn negotiated_candidates(
+		&self,
+		backend: &(impl DrmBackend + ?Sized),
+		format: DrmFourcc,
+		importer: &FormatTable
+	) -> anyhow::Result<Vec<(DrmFourcc, DrmModifier)>> {
+		let mut tables = Vec::with_capacity(self.outputs.len());
+		if self.atomic {
+			for output in self.outputs.iter() {
+				match output.in_formats(backend)? {
+					Some(table) => tables.push(table),
+					None => {
+						log::info!("Plane of output {} does not report modifiers, keeping linear layout", output.name());
+						return Ok(vec![(format, DrmModifier::Linear)])
+					}
+				}
+			}
+		}
+
+		let tables: Vec<&FormatTable> = tables.iter().chain(std::iter::once(importer)).collect();
+		let modifiers = format::negotiate_modifiers(format, &tables);
+		log::debug!("Modifiers of {:?} supported by planes and renderer: {:?}", format, modifiers);
+
+		Ok(modifiers.into_iter().map(|modifier| (format, modifier)).collect())
+	}
+
+	/// Checks the chosen configuration with test-only commits and falls back to other candidates until the device accepts it.
+	///
+	/// Format and modifier pairs of `candidates` are tried in order, see [`Self::default_candidates`] and
+	/// [`Self::negotiated_candidates`]. For each of them every output is tested together with the outputs before it,
+	/// falling back to other planes and then to lower ranked modes. If every candidate is rejected while the buffers are
+	/// scaled or inset, e.g. beyond the scaling limits of the planes, all of them are tried again at the mode size.
+	/// `allocate` creates a framebuffer of the source size of an output in the given format, together with whatever has
+	/// to be kept alive for it.
+	/// Returns the accepted format, or an error listing every rejected candidate.
+	pub fn validate<B>(
+		&mut self,
+		backend: &(impl DrmBackend + ?Sized),
+		options: &KmsOptions,
+		candidates: &[(DrmFourcc, DrmModifier)],
+		mut allocate: impl FnMut(&KmsOutput, DrmFourcc, DrmModifier) -> anyhow::Result<(FramebufferHandle, B)>
+	) -> anyhow::Result<(DrmFourcc, DrmModifier)> {
+		if !self.atomic {
+			let (format, modifier) = *candidates.first().context("No format candidates")?;
+			log::info!("Cannot test configurations without atomic modesetting, choosing format {:?} with {:?}", format, modifier);
+
+			return Ok((format, modifier))
+		}
+
+		// outputs are validated at their own size, mirroring is checked afterwards by `test_mirror`
+		self.apply_scaling(false)?;
+
+		let mut failures: Vec<String> = Vec::new();
+		if let Some(accepted) = self.validate_candidates(backend, options, candidates, &mut allocate, &mut failures)? {
+			return Ok(accepted)
+		}
+		if self.is_scaled() {
+			log::warn!("Device rejected scaling to {:?} with overscan {:?}, presenting at the mode size", self.render_size, self.overscan);
+
+			self.render_size = None;
+			self.overscan = Insets::default();
+			self.apply_scaling(false)?;
+			if let Some(accepted) = self.validate_candidates(backend, options, candidates, &mut allocate, &mut failures)? {
+				return Ok(accepted)
+			}
+		}
+
+		Err(
+			anyhow::anyhow!(
+				"Device rejected all candidate configurations:\n{}",
+				failures.join("\n")
+			)
+		)
+	}
+
+	/// Tries `candidates` in order, applies the fallbacks of the first accepted one and returns it.
+	fn validate_candidates<B>(
+		&mut self,
+		backend: &(impl DrmBackend + ?Sized),
+		options: &KmsOptions,
+		candidates: &[(DrmFourcc, DrmModifier)],
+		allocate: &mut impl FnMut(&KmsOutput, DrmFourcc, DrmModifier) -> anyhow::Result<(FramebufferHandle, B)>,
+		failures: &mut Vec<String>
+	) -> anyhow::Result<Option<(DrmFourcc, DrmModifier)>> {
+		for &(format, modifier) in candidates {
+			match self.validate_format(backend, options, format, modifier, allocate, failures) {
+				Ok(replacements) => {
+					for (output, replacement) in self.outputs.iter_mut().zip(replacements) {
+						if let Some(replacement) = replacement {
+							log::warn!("Falling back to {}", replacement.describe());
+							std::mem::replace(output, replacement).destroy(backend);
+						}
+					}
+					if self.mirrored {
+						self.apply_scaling(true)?;
+					}
+
+					log::info!("Choosing format {:?} with {:?}", format, modifier);
+					return Ok(Some((format, modifier)))
+				}
+				Err(err) => log::warn!("Cannot scan out {:?} with {:?}: {:#}", format, modifier, err)
+			}
+		}
+
+		Ok(None)
+	}
+
+	/// Validates all outputs with one format, returns the fallbacks replacing rejected outputs.
+	///
+	/// Outputs and fallbacks of the same source size are tested with the same probe framebuffer.
+	fn validate_format<B>(
+		&self,
+		backend: &(impl DrmBackend + ?Sized),
+		options: &KmsOptions,
+		format: DrmFourcc,
+		modifier: DrmModifier,
+		allocate: &mut impl FnMut(&KmsOutput, DrmFourcc, DrmModifier) -> anyhow::Result<(FramebufferHandle, B)>,
+		failures: &mut Vec<String>
+	) -> anyhow::Result<Vec<Option<KmsOutput>>> {
+		let mut replacements: Vec<Option<KmsOutput>> = Vec::with_capacity(self.outputs.len());
+		// probe framebuffers by source size, kept alive until all outputs have been tested together
+		let mut probes: Vec<((u16, u16), FramebufferHandle, B)> = Vec::new();
+		// framebuffers of the outputs validated so far, included in every following test
+		let mut validated: Vec<FramebufferHandle> = Vec::with_capacity(self.outputs.len());
+
+		for (index, current) in self.outputs.iter().enumerate() {
+			let taken_planes: Vec<PlaneHandle> = self.outputs.iter().enumerate().filter(|&(other, _)| other != index).filter_map(
+				|(other, output)| replacements.get(other).and_then(Option::as_ref).unwrap_or(output).plane_handle()
+			).collect();
+			let fallbacks = match current.fallbacks(backend, options, &taken_planes) {
+				Ok(fallbacks) => fallbacks,
+				Err(err) => {
+					destroy_outputs(backend, replacements.into_iter().flatten());
+					return Err(err)
+				}
+			};
+
+			let mut candidates = std::iter::once(None).chain(fallbacks.into_iter().map(Some));
+			let accepted = loop {
+				let candidate = match candidates.next() {
+					None => break None,
+					Some(None) => None,
+					Some(Some((mode, plane))) => {
+						let plane_handle = plane.handle;
+						let candidate = KmsOutput::new(backend, current.connector.clone(), mode, current.crtc.clone(), Some(plane)).and_then(
+							|mut candidate| match candidate.scale_from(self.render_size.unwrap_or(mode.size()), &self.overscan) {
+								Ok(()) => Ok(candidate),
+								Err(err) => {
+									candidate.destroy(backend);
+									Err(err)
+								}
 							}
-						)+
-						_ => ()
+						);
+						match candidate {
+							Ok(candidate) => Some(candidate),
+							Err(err) => {
+								failures.push(
+									format!("{} {} on plane {:?}: {:#}", current.name(), mode::describe_mode(&mode), plane_handle, err)
+								);
+								continue;
+							}
+						}
+					}
+				};
+				let output = candidate.as_ref().unwrap_or(current);
+				let description = format!("{} as {:?} with {:?}", output.describe(), format, modifier);
+
+				let probe = probes.iter().find(|&&(size, _, _)| size == output.source_size).map(|&(_, framebuffer, _)| framebuffer);
+				let probe = match probe {
+					Some(framebuffer) => Ok(framebuffer),
+					None => allocate(output, format, modifier).map(
+						|(framebuffer, buffer)| {
+							probes.push((output.source_size, framebuffer, buffer));
+							framebuffer
+						}
+					)
+				};
+				let result = probe.and_then(
+					|framebuffer| {
+						let outputs: Vec<&KmsOutput> = self.outputs[.. index].iter().zip(replacements.iter()).map(
+							|(output, replacement)| replacement.as_ref().unwrap_or(output)
+						).chain(std::iter::once(output)).collect();
+						let framebuffers: Vec<FramebufferHandle> = validated.iter().copied().chain(std::iter::once(framebuffer)).collect();
+
+						test_commit(backend, &outputs, &framebuffers).map(|()| framebuffer)
+					}
+				);
+				match result {
+					Ok(framebuffer) => {
+						log::debug!("Accepted {}", description);
+						break Some((candidate, framebuffer))
+					}
+					Err(err) => {
+						log::debug!("Rejected {}: {:#}", description, err);
+						failures.push(format!("{}: {:#}", description, err));
+						if let Some(candidate) = candidate {
+							candidate.destroy(backend);
+						}
 					}
 				}
-				$(
-					let $var_name: PropertyHandle = $var_name.context(concat!("Could not find property ", $prop_name))?;
-				)+
+			};
+
+			match accepted {
+				None => {
+					destroy_outputs(backend, replacements.into_iter().flatten());
+					anyhow::bail!("No candidate for output {} passed", current.name())
+				}
+				Some((candidate, framebuffer)) => {
+					replacements.push(candidate);
+					validated.push(framebuffer);
+				}
+			}
+		}
+
+		Ok(replacements)
+	}
+
+	/// Builds the atomic request presenting `framebuffers` on the chosen planes, one per output in output order.
+	///
+	/// All outputs are committed together so that they flip at the same time.
+	pub fn commit_request(
+		&self,
+		allow_modeset: bool,
+		framebuffers: &[FramebufferHandle]
+	) -> anyhow::Result<(AtomicCommitFlags, CommitRequest)> {
+		if framebuffers.len() != self.outputs.len() {
+			anyhow::bail!("Got {} framebuffers for {} outputs", framebuffers.len(), self.outputs.len());
+		}
+
+		// the page flip event tells when the commit applied, so there is no need to block
+		let mut flags = AtomicCommitFlags::PAGE_FLIP_EVENT | AtomicCommitFlags::NONBLOCK;
+		let mut request = CommitRequest::new();
+
+		if allow_modeset {
+			flags |= AtomicCommitFlags::ALLOW_MODESET;
+		}
+
+		for (output, &framebuffer) in self.outputs.iter().zip(framebuffers) {
+			output.add_to_request(&mut request, allow_modeset, framebuffer)?;
+		}
+
+		Ok((flags, request))
+	}
+
+	/// Whether every output is already active with the chosen mode on the chosen crtc, e.g. as left by the firmware.
+	///
+	/// The first commit can then present without a modeset, avoiding a blank screen while the monitor resyncs.
+	/// Always false with legacy modesetting, whose page flips reject framebuffers of another format than the current one.
+	pub fn matches_current_state(&self, backend: &(impl DrmBackend + ?Sized)) -> anyhow::Result<bool> {
+		if !self.atomic {
+			return Ok(false)
+		}
+
+		for output in self.outputs.iter() {
+			let crtc = backend.crtc(output.crtc.handle())?;
+			let properties = PropertyRegistry::query(backend, ObjectHandle::Crtc(crtc.handle), &[PropertySpec::required("ACTIVE")])?;
+			let active = properties.property("ACTIVE")?.value != 0;
+			let driven = match output.connector.current_encoder {
+				None => false,
+				Some(encoder) => backend.encoder(encoder)?.crtc == Some(crtc.handle)
+			};
+			let same_mode = match crtc.mode {
+				None => false,
+				Some(ref mode) => mode::same_timings(mode, &output.mode)
+			};
+
+			if !(active && driven && same_mode) {
+				log::debug!(
+					"Output {} needs a modeset: active {}, driven by {:?} {}, current mode {:?}",
+					output.name(), active, crtc.handle, driven, crtc.mode.as_ref().map(mode::describe_mode)
+				);
+				return Ok(false)
+			}
+		}
+
+		Ok(true)
+	}
+
+	/// Planes of type `plane_type` of output `output` which can scan out `format`, without the ones of the outputs and `layers`.
+	fn layer_planes(
+		&self,
+		backend: &(impl DrmBackend + ?Sized),
+		layers: &[Layer],
+		output: usize,
+		plane_type: &str,
+		format: DrmFourcc
+	) -> anyhow::Result<Vec<PlaneDesc>> {
+		let crtc = match self.outputs.get(output) {
+			None => anyhow::bail!("No output {}, configuration has {}", output, self.outputs.len()),
+			Some(output) => output.crtc.handle()
+		};
+
+		let mut taken: Vec<PlaneHandle> = self.outputs.iter().filter_map(KmsOutput::plane_handle).collect();
+		taken.extend(layers.iter().map(|layer| layer.plane));
+
+		select::free_planes(backend, crtc, plane_type, format as u32, &taken)
+	}
+
+	/// Checks with a test-only modeset that the outputs can scan out `framebuffers` together with `layers` and `candidate`.
+	fn test_layer(
+		&self,
+		backend: &(impl DrmBackend + ?Sized),
+		layers: &[Layer],
+		framebuffers: &[FramebufferHandle],
+		candidate: &Layer
+	) -> anyhow::Result<()> {
+		let (_, mut request) = self.commit_request(true, framebuffers)?;
+		for layer in layers.iter().chain(std::iter::once(candidate)) {
+			layer.add_to_request(&mut request);
+		}
+		// the kernel rejects page flip events for test-only commits, and nothing would signal out fences
+		request.out_fences.clear();
+
+		backend.atomic_commit(AtomicCommitFlags::ALLOW_MODESET | AtomicCommitFlags::TEST_ONLY, &request).map(|_| ())
+	}
+
+	/// Presents `framebuffers` through legacy modesetting, one per output in output order.
+	///
+	/// Outputs are flipped one after another, so they may show their buffers in different vblanks.
+	pub fn legacy_commit(
+		&self,
+		backend: &(impl DrmBackend + ?Sized),
+		allow_modeset: bool,
+		framebuffers: &[FramebufferHandle]
+	) -> anyhow::Result<()> {
+		if framebuffers.len() != self.outputs.len() {
+			anyhow::bail!("Got {} framebuffers for {} outputs", framebuffers.len(), self.outputs.len());
+		}
+
+		for (output, &framebuffer) in self.outputs.iter().zip(framebuffers) {
+			output.legacy_present(backend, allow_modeset, framebuffer)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Checks with a test-only modeset that `outputs` can scan out `framebuffers` at once, one per output.
+fn test_commit(
+	backend: &(impl DrmBackend + ?Sized),
+	outputs: &[&KmsOutput],
+	framebuffers: &[FramebufferHandle]
+) -> anyhow::Result<()> {
+	// the kernel rejects page flip events for test-only commits, and nothing would signal out fences
+	let flags = AtomicCommitFlags::ALLOW_MODESET | AtomicCommitFlags::TEST_ONLY;
+	let mut request = CommitRequest::new();
+	for (output, &framebuffer) in outputs.iter().zip(framebuffers) {
+		output.add_to_request(&mut request, true, framebuffer)?;
+	}
+	request.out_fences.clear();
+
+	backend.atomic_commit(flags, &request).map(|_| ())
+}
+
+/// Commits `position` of `plane` on its own without blocking, returns false if the device is busy with a previous commit.
+fn commit_plane_position(
+	backend: &(impl DrmBackend + ?Sized),
+	plane: PlaneHandle,
+	position: [(PropertyHandle, u64); 2]
+) -> anyhow::Result<bool> {
+	let mut request = CommitRequest::new();
+	for (property, value) in position {
+		request.add(ObjectHandle::Plane(plane), property, value);
+	}
+
+	match backend.atomic_commit(AtomicCommitFlags::NONBLOCK, &request) {
+		Ok(_) => Ok(true),
+		Err(err) if err.is::<CommitBusy>() => Ok(false),
+		Err(err) => Err(err).context("Failed to move plane")
+	}
+}
+
+/// Destroys a mode blob of an output, failing only leaks it until the device is closed.
+fn destroy_mode_blob(backend: &(impl DrmBackend + ?Sized), blob: u64) {
+	if let Err(err) = backend.destroy_property_blob(blob) {
+		log::warn!("Failed to destroy mode blob {}: {:#}", blob, err);
+	}
+}
+
+fn destroy_outputs(backend: &(impl DrmBackend + ?Sized), outputs: impl IntoIterator<Item = KmsOutput>) {
+	for output in outputs {
+		output.destroy(backend);
+	}
+}
+
+pub struct KmsContext {
+	device: KmsDevice,
+	/// major and minor number of the device, keying its saved display state
+	devnum: (u64, u64),
+	backend: Box<dyn DrmBackend>,
+	config: KmsConfig,
+	/// `None` if uevents are not available, e.g. in containers without netlink access
+	hotplug: Option<HotplugMonitor>,
+	/// page flips of the commit in flight
+	feedback: RefCell<feedback::FeedbackTracker>,
+	/// scanout format accepted by the device, see [`KmsConfig::validate`]
+	format: (DrmFourcc, DrmModifier),
+	/// formats the renderer can import, set by [`Self::negotiate_modifiers`]
+	importer: Option<FormatTable>,
+	/// overlay planes committed together with the outputs, see [`Self::add_layer`]
+	layers: RefCell<Vec<Layer>>,
+	/// id of the next added layer
+	next_layer: u64,
+	/// cursors shown on the cursor planes of outputs, see [`Self::enable_cursor`]
+	cursors: Vec<Cursor>,
+	/// scanned out framebuffers of replaced swapchains with the last commit made before, kept until a later commit has flipped
+	retired: RefCell<Vec<(u64, FrameBufferObject)>>,
+	/// replaced surfaces with the last commit made before, kept like `retired` since they own their scanned out buffer
+	retired_surfaces: RefCell<Vec<(u64, KmsSurface)>>,
+	/// framebuffers of the last commit by output, layers are tested with them, empty until the first present of a configuration
+	scanout: RefCell<Vec<FramebufferHandle>>,
+	/// whether the next commit may skip its modeset, see [`KmsConfig::matches_current_state`]
+	takeover: Cell<bool>,
+	/// set while another session owns the display, nothing is committed meanwhile
+	paused: bool,
+	/// whether the next commit must modeset, e.g. after another session used the display
+	force_modeset: Cell<bool>,
+	/// declared last so that the device is closed before the session
+	session: Box<dyn Session>
+}
+impl KmsContext {
+	/// Opens the device at `path` through `session` and chooses a configuration for it.
+	///
+	/// Open the session with [`open_session`] of [`KmsOptions::session`], and choose the device through it too.
+	pub fn with_session<P: AsRef<Path>>(mut session: Box<dyn Session>, path: P, options: &KmsOptions) -> anyhow::Result<Self> {
+		let device = session.open_device(path.as_ref()).map(DrmDevice::from).context("Failed to open drm device")?;
+
+		if let Err(err) = device.enable_atomic() {
+			log::warn!("Falling back to legacy modesetting: {:#}", err);
+		}
+
+		let backend: Box<dyn DrmBackend> = match options.capture {
+			None => Box::new(device.clone()),
+			Some(ref capture) => Box::new(
+				record::RecordingBackend::new(device.clone(), capture).context("Failed to create capture")?
+			)
+		};
+		let mut config = KmsConfig::choose(backend.as_ref(), options)?;
+
+		let devnum = device.devnum().context("Failed to query device number")?;
+		let hotplug = match HotplugMonitor::open(backend.as_ref(), devnum) {
+			Ok(monitor) => Some(monitor),
+			Err(err) => {
+				log::warn!("Hotplug detection is not available: {:#}", err);
+				None
+			}
+		};
+
+		let device = GbmDevice::new(device).context("Failed to create gbm device")?;
+
+		let candidates = KmsConfig::default_candidates(options);
+		let format = match Self::prepare(&device, backend.as_ref(), &mut config, options, &candidates) {
+			Ok(format) => format,
+			Err(err) => {
+				config.destroy(backend.as_ref());
+				return Err(err)
+			}
+		};
+
+		let takeover = Self::probe_takeover(backend.as_ref(), &config);
+
+		// bypassing the capture, restoring is not part of the traffic which is replayed
+		let mut saved = restore::SavedState::new(config.is_atomic());
+		let registered = saved.save(&*device, &config).context("Failed to save display state").and_then(
+			|_| restore::register(devnum, &device, saved)
+		);
+		if let Err(err) = registered {
+			config.destroy(backend.as_ref());
+			return Err(err)
+		}
+		restore::install_handlers();
+
+		Ok(
+			KmsContext {
+				device,
+				devnum,
+				backend,
+				config,
+				hotplug,
+				feedback: RefCell::default(),
+				format,
+				importer: None,
+				layers: RefCell::default(),
+				next_layer: 0,
+				cursors: Vec::new(),
+				retired: RefCell::default(),
+				retired_surfaces: RefCell::default(),
+				scanout: RefCell::default(),
+				takeover: Cell::new(takeover),
+				paused: false,
+				force_modeset: Cell::new(false),
+				session
+			}
+		)
+	}
+
+	/// Validates `config` and whether its outputs can be mirrored, returns the accepted format.
+	fn prepare(
+		device: &KmsDevice,
+		backend: &dyn DrmBackend,
+		config: &mut KmsConfig,
+		options: &KmsOptions,
+		candidates: &[(DrmFourcc, DrmModifier)]
+	) -> anyhow::Result<(DrmFourcc, DrmModifier)> {
+		let format = Self::validate(device, backend, config, options, candidates)?;
+		Self::probe_mirror(device, backend, config, format)?;
+
+		Ok(format)
+	}
+
+	/// Validates `config` with test-only commits of real buffers, see [`KmsConfig::validate`].
+	fn validate(
+		device: &KmsDevice,
+		backend: &dyn DrmBackend,
+		config: &mut KmsConfig,
+		options: &KmsOptions,
+		candidates: &[(DrmFourcc, DrmModifier)]
+	) -> anyhow::Result<(DrmFourcc, DrmModifier)> {
+		config.validate(
+			backend,
+			options,
+			candidates,
+			|output, format, modifier| {
+				let (width, height) = output.source_size;
+				let fbo = FrameBufferObject::with_size(device.clone(), width as u32, height as u32, format, modifier)?;
+
+				Ok((fbo.framebuffer(), fbo))
+			}
+		)
+	}
+
+	fn probe_mirror(
+		device: &KmsDevice,
+		backend: &dyn DrmBackend,
+		config: &mut KmsConfig,
+		(format, modifier): (DrmFourcc, DrmModifier)
+	) -> anyhow::Result<()> {
+		if config.is_mirrored() {
+			let (width, height) = config.outputs[0].source_size;
+			let probe = FrameBufferObject::with_size(device.clone(), width as u32, height as u32, format, modifier)?;
+			config.test_mirror(backend, probe.framebuffer())?;
+		}
+
+		Ok(())
+	}
+
+	fn probe_takeover(backend: &dyn DrmBackend, config: &KmsConfig) -> bool {
+		match config.matches_current_state(backend) {
+			Ok(true) => {
+				log::info!("Outputs already show the chosen modes, taking over without a modeset");
+				true
+			}
+			Ok(false) => false,
+			Err(err) => {
+				log::warn!("Failed to read current display state, taking over with a modeset: {:#}", err);
+				false
+			}
+		}
+	}
+
+	/// Handles pending session events and returns them.
+	///
+	/// When paused, waits for the commit in flight, drops drm master and stops committing until resumed.
+	/// The first commit after resuming performs a modeset since another session may have changed the display state.
+	pub fn poll_session(&mut self) -> anyhow::Result<Vec<SessionEvent>> {
+		let events = self.session.dispatch()?;
+
+		for &event in events.iter() {
+			match event {
+				SessionEvent::Paused => {
+					log::info!("Session paused, stopping to present");
+
+					self.wait_for_flip()?;
+					// seatd and logind usually have already revoked it
+					if let Err(err) = self.device.release_master_lock() {
+						log::debug!("Failed to drop drm master: {}", err);
+					}
+					self.session.acknowledge_pause()?;
+					self.paused = true;
+					// the other session may use the planes, so cursors only move with the next present
+					for layer in self.layers.get_mut().iter_mut() {
+						layer.set_committed(false);
+					}
+				}
+				SessionEvent::Resumed => {
+					log::info!("Session resumed, presenting with a modeset");
+
+					if let Err(err) = self.device.acquire_master_lock() {
+						log::debug!("Failed to become drm master: {}", err);
+					}
+					self.paused = false;
+					self.force_modeset.set(true);
+				}
+			}
+		}
+
+		Ok(events)
+	}
+
+	/// Whether a frame has been presented since the outputs were configured, layers and cursors can only be added afterwards.
+	pub fn has_presented(&self) -> bool {
+		!self.scanout.borrow().is_empty()
+	}
+
+	/// Whether another session owns the display, presenting fails until [`Self::poll_session`] reports a resume.
+	pub fn is_paused(&self) -> bool {
+		self.paused
+	}
+
+	/// Handles pending hotplug uevents and returns the connectors which changed.
+	///
+	/// When the outputs are affected, call [`Self::reconfigure`] and recreate all swapchains and surfaces.
+	pub fn poll_hotplug(&mut self) -> anyhow::Result<Vec<HotplugEvent>> {
+		match self.hotplug {
+			None => Ok(Vec::new()),
+			Some(ref mut monitor) => monitor.poll(self.backend.as_ref())
+		}
+	}
+
+	/// Whether `events` concern connectors driven by this context or could provide new outputs.
+	pub fn is_affected_by(&self, events: &[HotplugEvent]) -> bool {
+		events.iter().any(
+			|event| event.change == ConnectorChange::Connected
+				|| self.config.outputs.iter().any(|output| output.connector.handle == event.connector)
+		)
+	}
+
+	/// Chooses a new configuration, e.g. after a hotplug.
+	///
+	/// Keeps the current format, which the renderer is set up for, only its modifier may change after
+	/// [`Self::negotiate_modifiers`]. Fails if no output can scan it out.
+	///
+	/// Existing swapchains and surfaces must be recreated, passing them as old ones so that the framebuffers on screen
+	/// stay until their replacements are presented. The next commit performs a modeset either way.
+	/// Layers and cursors are removed and have to be added again.
+	pub fn reconfigure(&mut self, options: &KmsOptions) -> anyhow::Result<()> {
+		self.wait_for_flip()?;
+
+		let mut config = KmsConfig::choose(self.backend.as_ref(), options)?;
+		let candidates = match self.importer {
+			None => Ok(vec![self.format]),
+			Some(ref importer) => config.negotiated_candidates(self.backend.as_ref(), self.format.0, importer)
+		};
+		let prepared = candidates.and_then(
+			|candidates| Self::prepare(&self.device, self.backend.as_ref(), &mut config, options, &candidates)
+		).and_then(
+			|format| restore::update(self.devnum, |saved| saved.save(&*self.device, &config)).context("Failed to save display state").map(|()| format)
+		);
+		let format = match prepared {
+			Ok(format) => format,
+			Err(err) => {
+				config.destroy(self.backend.as_ref());
+				return Err(err)
+			}
+		};
+
+		// the planes of removed layers are disabled by the next commit, unless an output uses them now
+		let planes: Vec<PlaneHandle> = config.outputs.iter().filter_map(KmsOutput::plane_handle).collect();
+		let layers = self.layers.get_mut();
+		if layers.iter().any(Layer::is_enabled) {
+			log::info!("Removing layers of the previous configuration");
+		}
+		layers.retain(|layer| !planes.contains(&layer.plane));
+		for layer in layers.iter_mut() {
+			layer.disable()?;
+		}
+		// destroying the cursor framebuffers turns their planes off right away
+		self.cursors.clear();
+		self.scanout.get_mut().clear();
+
+		self.takeover.set(Self::probe_takeover(self.backend.as_ref(), &config));
+		// swapchains passed as old ones are no longer on their first frame
+		self.force_modeset.set(true);
+		// the crtcs keep their own references to committed mode blobs
+		std::mem::replace(&mut self.config, config).destroy(self.backend.as_ref());
+		self.format = format;
+
+		Ok(())
+	}
+
+	/// Chooses the best modifier for the current format supported by the planes and `importer`, e.g. the formats
+	/// EGL can render into, instead of linear layout.
+	///
+	/// Chooses and validates the configuration again like [`Self::reconfigure`], so it must be called before creating
+	/// swapchains and surfaces. On failure the current configuration is kept.
+	pub fn negotiate_modifiers(&mut self, importer: FormatTable, options: &KmsOptions) -> anyhow::Result<()> {
+		if !self.config.is_atomic() {
+			log::info!("Keeping linear layout, modifiers cannot be tested without atomic modesetting");
+			return Ok(())
+		}
+
+		self.backend.note_importer_formats(&importer);
+		self.importer = Some(importer);
+
+		let result = self.reconfigure(options);
+		if result.is_err() {
+			// the current configuration stays, later reconfigurations keep its linear layout as well
+			self.importer = None;
+		}
+
+		result
+	}
+
+	pub fn outputs(&self) -> &[KmsOutput] {
+		self.config.outputs()
+	}
+
+	/// Format which buffers of swapchains and surfaces must have.
+	pub fn format(&self) -> DrmFourcc {
+		self.format.0
+	}
+
+	/// Modifier which buffers of swapchains and surfaces must have.
+	pub fn modifier(&self) -> DrmModifier {
+		self.format.1
+	}
+
+	/// Number of swapchains or surfaces to present per frame, one unless each output needs its own.
+	pub fn render_targets(&self) -> usize {
+		self.config.render_targets()
+	}
+
+	/// Whether the device supports atomic modesetting, otherwise render fences are waited for on the cpu.
+	pub fn is_atomic(&self) -> bool {
+		self.config.is_atomic()
+	}
+
+	/// Size the buffers of `target` must have, the mode size unless scaling.
+	fn target_size(&self, target: usize) -> anyhow::Result<(u32, u32)> {
+		if target >= self.render_targets() {
+			anyhow::bail!("No render target {}, context has {}", target, self.render_targets());
+		}
+
+		// when mirroring, the first output is the source
+		let (width, height) = self.config.outputs[target].source_size;
+		Ok((width as u32, height as u32))
+	}
+
+	/// Creates a swapchain of `framebuffer_count` buffers for render target `target`.
+	///
+	/// The framebuffer `old_swapchain` scans out stays alive until the new swapchain has been presented.
+	pub fn create_swapchain(
+		&self,
+		target: usize,
+		framebuffer_count: usize,
+		format: DrmFourcc,
+		modifier: DrmModifier,
+		old_swapchain: Option<KmsSwapchain>
+	) -> anyhow::Result<KmsSwapchain> {
+		if framebuffer_count < 2 {
+			anyhow::bail!("Swapchain needs at least two framebuffers to flip between");
+		}
+
+		let (width, height) = self.target_size(target)?;
+		let is_first_frame = match old_swapchain {
+			None => true,
+			Some(ref old_swapchain) => old_swapchain.is_first_frame
+		};
+		if let Some(old_swapchain) = old_swapchain {
+			self.retire_swapchain(old_swapchain)?;
+		}
+
+		let mut framebuffers = Vec::with_capacity(framebuffer_count);
+		for _ in 0 .. framebuffer_count {
+			let fbo = FrameBufferObject::with_size(self.device.clone(), width, height, format, modifier)?;
+			framebuffers.push(fbo);
+		}
+
+		let mut buffer_states = vec![BufferState::Free; framebuffers.len()];
+		buffer_states[0] = BufferState::Rendering;
+		let release_fences = framebuffers.iter().map(|_| None).collect();
+
+		Ok(
+			KmsSwapchain {
+				framebuffers,
+				buffer_states,
+				release_fences,
+				render_fence: None,
+				current_index: 0,
+				target,
+				queued_commit: None,
+				is_first_frame
+			}
+		)
+	}
+
+	/// Keeps the framebuffer `swapchain` scans out until the next commit has flipped and drops the others.
+	///
+	/// For swapchains without a replacement, e.g. when a reconfiguration leaves fewer render targets.
+	pub fn retire_swapchain(&self, swapchain: KmsSwapchain) -> anyhow::Result<()> {
+		// do not destroy a framebuffer the hardware is about to flip to
+		self.wait_for_flip()?;
+		// turning off the framebuffer on screen would disable its plane until the replacement is presented
+		if let Some(scanout) = swapchain.into_scanout(self.completed_commits()) {
+			self.retired.borrow_mut().push((self.feedback.borrow().commits(), scanout));
+		}
+
+		Ok(())
+	}
+
+	/// Creates a gbm surface for render target `target`.
+	///
+	/// The buffer `old_surface` scans out stays alive until the new surface has been presented,
+	/// its EGL surface must be destroyed before.
+	pub fn create_surface(
+		&self,
+		target: usize,
+		format: DrmFourcc,
+		modifier: DrmModifier,
+		old_surface: Option<KmsSurface>
+	) -> anyhow::Result<KmsSurface> {
+		let size = self.target_size(target)?;
+		let is_first_frame = match old_surface {
+			None => true,
+			Some(ref old_surface) => old_surface.is_first_frame()
+		};
+		if let Some(old_surface) = old_surface {
+			self.retire_surface(old_surface)?;
+		}
+
+		KmsSurface::new(self.device.clone(), target, size, format, modifier, is_first_frame)
+	}
+
+	/// Keeps `surface` until the next commit has flipped, since it owns the buffer it scans out.
+	///
+	/// Like [`Self::retire_swapchain`], its EGL surface must be destroyed before.
+	pub fn retire_surface(&self, mut surface: KmsSurface) -> anyhow::Result<()> {
+		self.wait_for_flip()?;
+		surface.sync(self.completed_commits());
+		self.retired_surfaces.borrow_mut().push((self.feedback.borrow().commits(), surface));
+
+		Ok(())
+	}
+
+	/// Orders `(target, item)` pairs by target, requiring exactly one item for every target,
+	/// and maps them to one item per output.
+	fn by_output<T: Copy>(
+		&self,
+		items: impl Iterator<Item = (usize, T)>
+	) -> anyhow::Result<Vec<T>> {
+		let mut ordered: Vec<Option<T>> = vec![None; self.render_targets()];
+		for (target, item) in items {
+			let slot = ordered.get_mut(target).with_context(|| format!("No render target {}", target))?;
+			if slot.replace(item).is_some() {
+				anyhow::bail!("Multiple framebuffers for render target {}", target);
 			}
 		}
 
-		let connector_properties = device.get_properties(connector.handle()).context("Failed to query connector properties")?;
-		find_properties!(
-			connector_properties.as_props_and_values().0;
-			let connector_crtc_id = "CRTC_ID";
-		);
+		let ordered = ordered.into_iter().enumerate().map(
+			|(target, item)| item.with_context(|| format!("Missing framebuffer for render target {}", target))
+		).collect::<anyhow::Result<Vec<_>>>()?;
 
-		let crtc_properties = device.get_properties(crtc.info.handle()).context("Failed to query crtc properties")?;
-		find_properties!(
-			crtc_properties.as_props_and_values().0;
-			let crtc_mode_id = "MODE_ID";
-			let crtc_active = "ACTIVE";
-			// let crtc_out_fence_ptr = "OUT_FENCE_PTR";
-		);
+		if self.config.is_mirrored() {
+			Ok(vec![ordered[0]; self.config.outputs.len()])
+		} else {
+			Ok(ordered)
+		}
+	}
 
-		let plane_properties = device.get_properties(plane.handle()).context("Failed to query plane properties")?;
-		find_properties!(
-			plane_properties.as_props_and_values().0;
-			let plane_in_fence_fd = "IN_FENCE_FD";
-			let plane_fb_id = "FB_ID";
-			let plane_crtc_id = "CRTC_ID";
-			let plane_src_x = "SRC_X";
-			let plane_src_y = "SRC_Y";
-			let plane_src_w = "SRC_W";
-			let plane_src_h = "SRC_H";
-			let plane_crtc_x = "CRTC_X";
-			let plane_crtc_y = "CRTC_Y";
-			let plane_crtc_w = "CRTC_W";
-			let plane_crtc_h = "CRTC_H";
-		);
+	/// Maps the out fences of each output to one release fence per render target.
+	///
+	/// A mirrored buffer is released once all outputs have moved on, so their fences are merged.
+	fn fences_by_target(&self, fences: Vec<Option<SyncFile>>) -> Vec<Option<SyncFile>> {
+		if !self.config.is_mirrored() {
+			return fences
+		}
 
-		Ok(
-			CommitPropertyCache {
-				connector_crtc_id,
-				crtc_mode_id,
-				crtc_active,
-				// crtc_out_fence_ptr,
-				plane_in_fence_fd,
-				plane_fb_id,
-				plane_crtc_id,
-				plane_src_x,
-				plane_src_y,
-				plane_src_w,
-				plane_src_h,
-				plane_crtc_x,
-				plane_crtc_y,
-				plane_crtc_w,
-				plane_crtc_h,
-				blob_mode: device.create_property_blob(mode).context("Failed to crate property blob")?
-			}
-		)
-	}
-	
-	pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-		let device = DrmDevice::new(path).context("Failed to open drm device")?;
-
-		let resource_handles = device.resource_handles().context("Failed to query control device resources")?;
-		let connector = device.choose_connector(resource_handles.connectors())?;
-		let mode = device.choose_mode(connector.modes())?;
-		let crtc = device.choose_crtc(
-			connector.current_encoder(),
-			connector.encoders(),
-			resource_handles.crtcs()
-		)?;
+		let mut merged: Option<SyncFile> = None;
+		for fence in fences {
+			let fence = match fence {
+				None => return vec![None],
+				Some(fence) => fence
+			};
 
-		device.set_client_capability(ClientCapability::Atomic, true).context("Failed to set Atomic client capability")?;
-		// device.set_client_capability(ClientCapability::UniversalPlanes, true).context("Failed to set UniversalPlanes capability")?;
-		let plane = device.choose_plane(&crtc)?;
+			merged = match merged {
+				None => Some(fence),
+				Some(ref previous) => match previous.merge(&fence) {
+					Ok(merged) => Some(merged),
+					Err(err) => {
+						log::warn!("Falling back to page flip events for buffer release: {:#}", err);
+						return vec![None]
+					}
+				}
+			};
+		}
 
-		let device = GbmDevice::new(device).context("Failed to create gbm device")?;
+		vec![merged]
+	}
 
-		let property_cache = Self::cache_commit_properties(&device, &connector, &crtc, &plane, &mode).context("Failed to cache commit properties")?;
+	/// Commits `framebuffers` and `in_fences`, one per output, and returns the release fences of each render target.
+	///
+	/// The first commit after taking over outputs which already show the chosen modes skips the modeset,
+	/// unless the device rejects that.
+	fn atomic_commit(
+		&self,
+		allow_modeset: bool,
+		framebuffers: &[FramebufferHandle],
+		in_fences: &[Option<RawFd>]
+	) -> anyhow::Result<(PresentFeedback, Vec<Option<SyncFile>>)> {
+		if self.paused {
+			anyhow::bail!("Cannot present while the session is paused");
+		}
 
-		/*
-		let framebuffers: [FrameBufferObject; FRAMEBUFFERS] = {
-			let mut framebuffers = Vec::with_capacity(FRAMEBUFFERS);
-			for _ in 0 .. FRAMEBUFFERS {
-				let fbo = FrameBufferObject::new(&device, &mode, format, modifier)?;
-				framebuffers.push(fbo);
+		let allow_modeset = allow_modeset || self.force_modeset.replace(false);
+		let takeover = allow_modeset && self.takeover.replace(false);
+		let submitted = clock_gettime(ClockId::CLOCK_MONOTONIC).context("Failed to read monotonic clock")?;
+		let (allow_modeset, out_fences) = match self.commit(allow_modeset && !takeover, framebuffers, in_fences) {
+			Ok(out_fences) => (allow_modeset && !takeover, out_fences),
+			Err(err) if takeover => {
+				log::warn!("Failed to take over without a modeset, falling back to a modeset: {:#}", err);
+				(true, self.commit(true, framebuffers, in_fences)?)
 			}
-			
-			let mut it = framebuffers.into_iter();
-			
-			[(); FRAMEBUFFERS].map(|_| it.next().unwrap())
+			Err(err) => return Err(err)
 		};
-		*/
+		*self.scanout.borrow_mut() = framebuffers.to_vec();
 
-		/*
-		let framebuffers: [FrameBufferObject; FRAMEBUFFERS] = {
-			use std::mem::MaybeUninit;
+		// each crtc sends its own event
+		let crtcs: Vec<CrtcHandle> = self.config.outputs.iter().map(|output| output.crtc.handle()).collect();
+		let submitted = Duration::new(submitted.tv_sec() as u64, submitted.tv_nsec() as u32);
+		let feedback = self.feedback.borrow_mut().begin(&crtcs, allow_modeset, submitted);
 
-			let mut mem = MaybeUninit::<[FrameBufferObject; FRAMEBUFFERS]>::uninit();
+		Ok((feedback, self.fences_by_target(out_fences)))
+	}
 
-			for i in 0 .. FRAMEBUFFERS {
-				match FrameBufferObject::new(&device, &mode, format, modifier) {
-					Ok(fbo) => {
-						unsafe {
-							(mem.as_mut_ptr() as *mut FrameBufferObject).add(i).write(fbo)
-						}
-					},
-					Err(err) => {
-						for i in 0 .. i {
-							unsafe {
-								std::mem::drop(
-									(mem.as_mut_ptr() as *mut FrameBufferObject).add(i).read()
-								);
-							}
-						}
-						return Err(err)
+	/// Commits `framebuffers` and `in_fences` and returns the out fences of each output.
+	///
+	/// Without atomic modesetting the in fences are waited for before flipping and there are no out fences.
+	fn commit(
+		&self,
+		allow_modeset: bool,
+		framebuffers: &[FramebufferHandle],
+		in_fences: &[Option<RawFd>]
+	) -> anyhow::Result<Vec<Option<SyncFile>>> {
+		if self.config.is_atomic() {
+			let (flags, mut request) = self.config.commit_request(allow_modeset, framebuffers)?;
+			for layer in self.layers.borrow().iter() {
+				layer.add_to_request(&mut request);
+			}
+			for (output, fence) in self.config.outputs.iter().zip(in_fences) {
+				if let Some(fence) = *fence {
+					if !output.add_in_fence(&mut request, fence)? {
+						log::trace!("Relying on implicit synchronization for {}", output.describe());
 					}
 				}
 			}
 
-			unsafe { mem.assume_init() }
-		};
-		*/
+			let mut out_fences = match self.backend.atomic_commit(flags, &request) {
+				Err(err) if err.is::<CommitBusy>() => {
+					// a cursor move is still being applied, waiting for it keeps the page flip event
+					log::trace!("Device is busy, committing blocking");
+					self.backend.atomic_commit(flags - AtomicCommitFlags::NONBLOCK, &request)?
+				}
+				result => result?
+			}.into_iter();
+			// outputs without `OUT_FENCE_PTR` release their buffers on page flip events
+			let out_fences = self.config.outputs.iter().map(
+				|output| if output.has_out_fence() { out_fences.next().flatten() } else { None }
+			).collect();
+			// removed layers are off the screen once this commit applies
+			let mut layers = self.layers.borrow_mut();
+			layers.retain(Layer::is_enabled);
+			for layer in layers.iter_mut() {
+				layer.set_committed(true);
+			}
+
+			Ok(out_fences)
+		} else {
+			for &fence in in_fences.iter().flatten() {
+				sync_file::wait_fd(fence, None).context("Failed to wait for render fence")?;
+			}
+			self.config.legacy_commit(self.backend.as_ref(), allow_modeset, framebuffers)?;
+
+			Ok(self.config.outputs.iter().map(|_| None).collect())
+		}
+	}
 
-		/*
-		let framebuffers: [FrameBufferObject; FRAMEBUFFERS] = {
-			use std::mem::MaybeUninit;
+	/// Shows `framebuffer` on a free overlay plane of output `output` like `state`, from the next present on.
+	///
+	/// Every overlay plane which can scan out the format is tried with a test-only commit together with the buffers
+	/// presented last and the other layers, so a frame must have been presented, see [`Self::has_presented`].
+	/// Returns `None` if none accepts the layer or there is no free one, then the layer has to be composed in GL
+	/// instead. Always `None` with legacy modesetting, which only drives primary planes.
+	///
+	/// Rendering into `framebuffer` must have completed, and it must be kept alive until the layer has been removed
+	/// and the next present has flipped.
+	pub fn add_layer(&mut self, output: usize, framebuffer: &FrameBufferObject, state: LayerState) -> anyhow::Result<Option<LayerId>> {
+		let format = framebuffer.buffer().format().context("Failed to get buffer object format")?;
 
-			let mut mem = unsafe { MaybeUninit::<[MaybeUninit<FrameBufferObject>; FRAMEBUFFERS]>::uninit().assume_init() };
+		self.place_layer(output, "Overlay", format, framebuffer.framebuffer(), &state)
+	}
 
-			for i in 0 .. FRAMEBUFFERS {
-				match FrameBufferObject::new(&device, &mode, format, modifier) {
-					Ok(fbo) => { mem[i].write(fbo); },
-					Err(err) => {
-						for i in 0 .. i {
-							unsafe { mem[i].assume_init_drop() }
-						}
-						return Err(err)
-					}
+	/// Places `framebuffer` on the first free plane of type `plane_type` of output `output` which accepts it,
+	/// see [`Self::add_layer`].
+	fn place_layer(
+		&mut self,
+		output: usize,
+		plane_type: &str,
+		format: DrmFourcc,
+		framebuffer: FramebufferHandle,
+		state: &LayerState
+	) -> anyhow::Result<Option<LayerId>> {
+		if !self.config.is_atomic() {
+			log::info!("Layers need atomic modesetting");
+			return Ok(None)
+		}
+		if self.paused {
+			anyhow::bail!("Cannot add layers while the session is paused");
+		}
+		if !self.has_presented() {
+			anyhow::bail!("Cannot add layers before presenting, they are tested together with the presented buffers");
+		}
+
+		let planes = self.config.layer_planes(self.backend.as_ref(), self.layers.get_mut(), output, plane_type, format)?;
+		let (name, crtc) = (self.config.outputs[output].name(), self.config.outputs[output].crtc.handle());
+		if planes.is_empty() {
+			log::info!("No free {} plane of output {} can scan out {:?}", plane_type.to_lowercase(), name, format);
+			return Ok(None)
+		}
+
+		// the presented framebuffers are still scanned out, so they exist and have the current size and format
+		let framebuffers = self.scanout.get_mut().clone();
+		let id = LayerId(self.next_layer);
+		for plane in planes {
+			let layer = match Layer::new(self.backend.as_ref(), id, output, crtc, plane.handle, framebuffer, state) {
+				Ok(layer) => layer,
+				Err(err) => {
+					log::debug!("Cannot show layer on plane {:?}: {:#}", plane.handle, err);
+					continue;
 				}
-			}
+			};
+
+			match self.config.test_layer(self.backend.as_ref(), self.layers.get_mut(), &framebuffers, &layer) {
+				Ok(()) => {
+					restore::update(self.devnum, |saved| saved.save_plane(&*self.device, plane.handle)).context("Failed to save display state")?;
+					log::info!("Showing layer {:?} on plane {:?} of output {}", id, plane.handle, name);
 
-			unsafe {
-				(&mem as *const _ as *const [FrameBufferObject; FRAMEBUFFERS]).read()
+					self.layers.get_mut().push(layer);
+					self.next_layer += 1;
+					return Ok(Some(id))
+				}
+				Err(err) => log::debug!("Plane {:?} rejected layer: {:#}", plane.handle, err)
 			}
-		};
-		*/
+		}
 
-		Ok(
-			KmsContext {
-				device,
-				connector,
-				mode,
-				crtc,
-				plane,
-				property_cache
+		log::info!("No {} plane of output {} accepted the layer", plane_type.to_lowercase(), name);
+		Ok(None)
+	}
+
+	/// Shows `framebuffer` like `state` on the layer `id` from the next present on.
+	///
+	/// Unlike [`Self::add_layer`] this is not tested, so a configuration the plane cannot show fails the next present.
+	pub fn set_layer(&mut self, id: LayerId, framebuffer: &FrameBufferObject, state: LayerState) -> anyhow::Result<()> {
+		let layer = self.layers.get_mut().iter_mut().find(|layer| layer.id == id).with_context(|| format!("No layer {:?}", id))?;
+		if !layer.is_enabled() {
+			anyhow::bail!("Layer {:?} has been removed", id);
+		}
+		let crtc = self.config.outputs[layer.output].crtc.handle();
+
+		layer.set(crtc, framebuffer.framebuffer(), &state)
+	}
+
+	/// Removes the layer `id`, its plane is disabled by the next present.
+	///
+	/// Its framebuffer must be kept alive until that present has flipped.
+	pub fn remove_layer(&mut self, id: LayerId) -> anyhow::Result<()> {
+		let layer = self.layers.get_mut().iter_mut().find(|layer| layer.id == id).with_context(|| format!("No layer {:?}", id))?;
+		log::debug!("Removing layer {:?} from plane {:?}", id, layer.plane);
+
+		layer.disable()
+	}
+
+	/// Size of cursor images reported by the device.
+	pub fn cursor_size(&self) -> (u32, u32) {
+		self.device.cursor_size()
+	}
+
+	/// Shows a transparent cursor on the cursor plane of output `output`, see [`Self::set_cursor_image`].
+	///
+	/// Returns `false` if there is no free cursor plane or it cannot be used, always with legacy modesetting.
+	/// The plane is tested like a layer, see [`Self::add_layer`], and the cursor is shown from the next present on.
+	pub fn enable_cursor(&mut self, output: usize) -> anyhow::Result<bool> {
+		if self.cursors.iter().any(|cursor| cursor.output == output) {
+			return Ok(true)
+		}
+
+		let size = self.cursor_size();
+		let cursor = Cursor::new(
+			self.device.clone(), output, size,
+			|framebuffer, state| self.place_layer(output, "Cursor", CURSOR_FORMAT, framebuffer, state)
+		).with_context(|| format!("Failed to create cursor of output {}", output))?;
+
+		match cursor {
+			None => Ok(false),
+			Some(cursor) => {
+				self.cursors.push(cursor);
+				Ok(true)
 			}
-		)
+		}
 	}
 
-	pub fn create_swapchain(
-		&self,
-		framebuffer_count: usize,
-		format: DrmFourcc,
-		modifier: DrmModifier,
-		old_swapchain: Option<KmsSwapchain>
-	) -> anyhow::Result<KmsSwapchain> {
-		let is_first_frame = match old_swapchain {
-			None => true,
-			Some(ref old_swapchain) => old_swapchain.is_first_frame
+	/// Replaces the cursor image of output `output` with `pixels` in ARGB8888, in rows of `width`.
+	///
+	/// The image must fit into [`Self::cursor_size`], `hotspot` is the pixel placed at the cursor position.
+	/// The image changes right away, possibly while it is scanned out.
+	pub fn set_cursor_image(&mut self, output: usize, pixels: &[u32], width: u32, hotspot: (u32, u32)) -> anyhow::Result<()> {
+		self.cursor_mut(output)?.set_image(pixels, width, hotspot)?;
+
+		// the plane moves if the hotspot did
+		self.place_cursor(output)
+	}
+
+	/// Moves the cursor hotspot of output `output` to `x`, `y` within its mode.
+	///
+	/// Commits only the position of the cursor plane if it is shown and the device is not busy with a present or
+	/// another move, otherwise the cursor moves to the last position with the next present.
+	pub fn move_cursor(&mut self, output: usize, x: i32, y: i32) -> anyhow::Result<()> {
+		self.cursor_mut(output)?.move_to((x, y));
+
+		self.place_cursor(output)
+	}
+
+	fn cursor_mut(&mut self, output: usize) -> anyhow::Result<&mut Cursor> {
+		self.cursors.iter_mut().find(|cursor| cursor.output == output).with_context(|| format!("Output {} has no cursor", output))
+	}
+
+	/// Moves the cursor plane of output `output` to the position of its cursor.
+	///
+	/// The layer keeps the position, so while a present or another move is pending the next present moves the plane,
+	/// and moves until then only commit the last position.
+	fn place_cursor(&mut self, output: usize) -> anyhow::Result<()> {
+		let cursor = self.cursor_mut(output)?;
+		let (id, (x, y)) = (cursor.layer, cursor.plane_position());
+
+		let layer = self.layers.get_mut().iter_mut().find(|layer| layer.id == id).with_context(|| format!("No cursor layer {:?}", id))?;
+		let position = match layer.move_to(x, y)? {
+			None => return Ok(()),
+			Some(position) => position
 		};
-		std::mem::drop(old_swapchain);
+		if !layer.is_committed() || self.paused || self.feedback.borrow().is_pending() {
+			return Ok(())
+		}
 
-		let mut framebuffers = Vec::with_capacity(framebuffer_count);
-		for _ in 0 .. framebuffer_count {
-			let fbo = FrameBufferObject::new(self.device.clone(), &self.mode, format, modifier)?;
-			framebuffers.push(fbo);
+		if !commit_plane_position(self.backend.as_ref(), layer.plane, position)? {
+			log::trace!("Deferring cursor move of output {} to the next present", output);
 		}
+		Ok(())
+	}
 
-		Ok(
-			KmsSwapchain {
-				framebuffers,
-				current_index: 0,
-				is_first_frame
+	/// Number of commits which have been completely flipped.
+	fn completed_commits(&self) -> u64 {
+		self.feedback.borrow().completed()
+	}
+
+	/// Drops the framebuffers of replaced swapchains and surfaces once they are no longer scanned out.
+	fn release_retired(&self) {
+		let completed = self.completed_commits();
+		self.retired.borrow_mut().retain(|&(commit, _)| commit >= completed);
+		self.retired_surfaces.borrow_mut().retain(|&(commit, _)| commit >= completed);
+	}
+
+	/// Receives drm events until every crtc of the commit in flight has flipped, if there is one.
+	///
+	/// Resolves the [`PresentFeedback`] of that commit.
+	pub fn wait_for_flip(&self) -> anyhow::Result<()> {
+		while self.feedback.borrow().is_pending() {
+			for event in self.backend.receive_events()? {
+				match event {
+					DrmEvent::PageFlip { crtc, frame, time } => {
+						log::trace!("Page flip on {:?}: frame {} at {:?}", crtc, frame, time);
+
+						self.feedback.borrow_mut().page_flip(crtc, frame, time);
+					}
+				}
 			}
-		)
+		}
+
+		Ok(())
 	}
 
-	fn atomic_commit(
-		&self,
-		allow_modeset: bool,
-		fbo: &FrameBufferObject
-	) -> anyhow::Result<()> {
-		use drm::control::atomic::{AtomicCommitFlags, AtomicModeReq};
-
-		// let mut flags = AtomicCommitFlags::NONBLOCK;
-		/*
-		* DRM_MODE_PAGE_FLIP_EVENT signalizes that we want to receive a
-		* page-flip event in the DRM-fd when the page-flip happens. This flag
-		* is also used in the non-atomic examples, so you're probably familiar
-		* with it.
-		*
-		* DRM_MODE_ATOMIC_NONBLOCK makes the page-flip non-blocking. We don't
-		* want to be blocked waiting for the commit to happen, since we can use
-		* this time to prepare a new framebuffer, for instance. We can only do
-		* this because there are mechanisms to know when the commit is complete
-		* (like page flip event, explained above).
-		*/
-		let mut flags = AtomicCommitFlags::empty();
-		let mut request = AtomicModeReq::new();
+	/// Presents the current framebuffer of each swapchain in a single commit, one swapchain per render target.
+	///
+	/// The commit does not block, afterwards each swapchain hands out a buffer which is neither queued nor scanned out.
+	/// If there is none, a scanned out buffer is handed out together with a release fence when the driver provides one,
+	/// otherwise this waits for the flip.
+	pub fn present_swapchains(&self, swapchains: &mut [KmsSwapchain]) -> anyhow::Result<PresentFeedback> {
+		let render_fences: Vec<Option<SyncFile>> = swapchains.iter_mut().map(|swapchain| swapchain.render_fence.take()).collect();
+		let planes = self.by_output(
+			swapchains.iter().zip(render_fences.iter()).map(
+				|(swapchain, fence)| (
+					swapchain.target,
+					(swapchain.current_framebuffer().1.framebuffer(), fence.as_ref().map(SyncFile::as_raw_fd))
+				)
+			)
+		)?;
+		let (framebuffers, in_fences): (Vec<_>, Vec<_>) = planes.into_iter().unzip();
+		let allow_modeset = swapchains.iter().any(|swapchain| swapchain.is_first_frame);
 
-		if allow_modeset {
-			request.add_property(self.connector.handle(), self.property_cache.connector_crtc_id, self.crtc.info.handle().into());
-			request.add_property(self.crtc.handle(), self.property_cache.crtc_mode_id, self.property_cache.blob_mode);
-			request.add_property(self.crtc.handle(), self.property_cache.crtc_active, PropertyValue::Boolean(true));
+		// only one commit can be in flight
+		self.wait_for_flip()?;
+		self.release_retired();
+		for swapchain in swapchains.iter_mut() {
+			swapchain.sync(self.completed_commits());
+		}
 
-			flags |= AtomicCommitFlags::ALLOW_MODESET;
+		let (feedback, mut release_fences) = self.atomic_commit(allow_modeset, &framebuffers, &in_fences)?;
+		// the kernel holds its own references
+		std::mem::drop(render_fences);
+
+		for swapchain in swapchains.iter_mut() {
+			swapchain.is_first_frame = false;
+			swapchain.buffer_states[swapchain.current_index] = BufferState::Queued;
+			swapchain.queued_commit = Some(feedback.commit());
+
+			let release_fence = release_fences.get_mut(swapchain.target).and_then(Option::take);
+			swapchain.set_release_fence(release_fence);
+			if !swapchain.has_free_buffer() {
+				swapchain.release_fenced_scanout();
+			}
+		}
+
+		if !swapchains.iter().all(KmsSwapchain::has_free_buffer) {
+			self.wait_for_flip()?;
+			self.release_retired();
+			for swapchain in swapchains.iter_mut() {
+				swapchain.sync(self.completed_commits());
+			}
+		}
+		for swapchain in swapchains.iter_mut() {
+			swapchain.acquire_next()?;
 		}
 
-		request.add_property(self.plane.handle(), self.property_cache.plane_fb_id, fbo.framebuffer().into());
-		request.add_property(self.plane.handle(), self.property_cache.plane_crtc_id, self.crtc.info.handle().into());
-		request.add_property(self.plane.handle(), self.property_cache.plane_src_x, 0.into());
-		request.add_property(self.plane.handle(), self.property_cache.plane_src_y, 0.into());
-		request.add_property(self.plane.handle(), self.property_cache.plane_src_w, ((self.mode.size().0 as u64) << 16).into());
-		request.add_property(self.plane.handle(), self.property_cache.plane_src_h, ((self.mode.size().1 as u64) << 16).into());
-		request.add_property(self.plane.handle(), self.property_cache.plane_crtc_x, PropertyValue::SignedRange(0));
-		request.add_property(self.plane.handle(), self.property_cache.plane_crtc_y, PropertyValue::SignedRange(0));
-		request.add_property(self.plane.handle(), self.property_cache.plane_crtc_w, (self.mode.size().0 as u64).into());
-		request.add_property(self.plane.handle(), self.property_cache.plane_crtc_h, (self.mode.size().1 as u64).into());
+		Ok(feedback)
+	}
 
-		self.device.atomic_commit(flags, request).context("Failed to perform atomic commit")?;
+	/// Presents the buffers rendered by the last `eglSwapBuffers` of each surface in a single commit, one surface per render target.
+	///
+	/// The commit does not block, but waits for the previous one to complete. Buffers are released to gbm only after
+	/// their page flip, since gbm cannot wait on release fences.
+	pub fn present_surfaces(&self, surfaces: &mut [KmsSurface]) -> anyhow::Result<PresentFeedback> {
+		// only one commit can be in flight, and this releases buffers back to the surfaces
+		self.wait_for_flip()?;
+		self.release_retired();
+		for surface in surfaces.iter_mut() {
+			surface.sync(self.completed_commits());
+		}
 
-		Ok(())
+		let mut locked = Vec::with_capacity(surfaces.len());
+		for surface in surfaces.iter_mut() {
+			locked.push((surface.target(), (surface.lock_next_buffer()?, surface.render_fence())));
+		}
+		let (framebuffers, in_fences): (Vec<_>, Vec<_>) = self.by_output(locked.into_iter())?.into_iter().unzip();
+		let allow_modeset = surfaces.iter().any(|surface| surface.is_first_frame());
+
+		let (feedback, _) = self.atomic_commit(allow_modeset, &framebuffers, &in_fences)?;
+		for surface in surfaces.iter_mut() {
+			surface.mark_queued(feedback.commit());
+		}
+
+		Ok(feedback)
 	}
 
 	pub fn device(&self) -> &KmsDevice {
 		&self.device
 	}
 
-	pub fn resolution(&self) -> [usize; 2] {
-		[self.mode.size().0 as usize, self.mode.size().1 as usize]
+	/// Restores the display state found before taking over the outputs, blocking until it is applied.
+	///
+	/// Also done on drop, call it before dropping swapchains and surfaces so that their framebuffers are never turned
+	/// off while scanned out. Does nothing if the state has already been restored.
+	pub fn restore(&self) -> anyhow::Result<()> {
+		let saved = match restore::take(self.devnum) {
+			None => return Ok(()),
+			Some(saved) => saved
+		};
+		if self.paused {
+			log::info!("Not restoring display state, another session owns the display");
+			return Ok(())
+		}
+		self.wait_for_flip()?;
+
+		saved.restore(&*self.device)
+	}
+}
+impl Drop for KmsContext {
+	fn drop(&mut self) {
+		if let Err(err) = self.restore() {
+			log::error!("Failed to restore display state: {:#}", err);
+		}
+
+		destroy_outputs(self.backend.as_ref(), self.config.outputs.drain(..));
 	}
 }
 
+/// Where a swapchain buffer is in its presentation cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BufferState {
+	Free,
+	/// handed out as the current framebuffer
+	Rendering,
+	/// committed, waiting for the page flip
+	Queued,
+	/// being scanned out
+	Scanout
+}
+
 pub struct KmsSwapchain {
 	framebuffers: Vec<FrameBufferObject>,
+	buffer_states: Vec<BufferState>,
+	/// out fence of the commit replacing each buffer on screen, until it has flipped
+	release_fences: Vec<Option<SyncFile>>,
+	/// signaled when rendering into the current buffer completes
+	render_fence: Option<SyncFile>,
 	current_index: usize,
+	/// render target index, see [`KmsContext::render_targets`]
+	target: usize,
+	/// commit which presented the queued buffer
+	queued_commit: Option<u64>,
 	is_first_frame: bool
 }
 impl KmsSwapchain {
-	pub fn swap(&mut self) {
-		self.current_index = (self.current_index + 1) % self.framebuffers.len();
+	fn has_free_buffer(&self) -> bool {
+		self.buffer_states.contains(&BufferState::Free)
+	}
+
+	/// Makes the next free buffer the current one.
+	fn acquire_next(&mut self) -> anyhow::Result<()> {
+		let count = self.framebuffers.len();
+		let next = (1 ..= count).map(|offset| (self.current_index + offset) % count).find(
+			|&index| self.buffer_states[index] == BufferState::Free
+		).context("Swapchain has no free buffer")?;
+
+		self.buffer_states[next] = BufferState::Rendering;
+		self.current_index = next;
+
+		Ok(())
+	}
+
+	/// Once the commit of the queued buffer has flipped, it is scanned out and the previously scanned out one can be reused.
+	fn sync(&mut self, completed_commits: u64) {
+		match self.queued_commit {
+			Some(commit) if commit <= completed_commits => { self.queued_commit = None; }
+			_ => return
+		}
+
+		for (state, release_fence) in self.buffer_states.iter_mut().zip(self.release_fences.iter_mut()) {
+			*state = match *state {
+				BufferState::Scanout => {
+					// signaled together with the flip
+					*release_fence = None;
+					BufferState::Free
+				}
+				BufferState::Queued => BufferState::Scanout,
+				state => state
+			};
+		}
+	}
+
+	/// Takes the buffer being scanned out, once the commits made so far have flipped.
+	fn into_scanout(mut self, completed_commits: u64) -> Option<FrameBufferObject> {
+		self.sync(completed_commits);
+
+		let index = self.buffer_states.iter().position(|&state| state == BufferState::Scanout)?;
+		Some(self.framebuffers.swap_remove(index))
+	}
+
+	/// Attaches the out fence of the commit just made to the buffer it takes off the screen.
+	fn set_release_fence(&mut self, fence: Option<SyncFile>) {
+		if let Some(index) = self.buffer_states.iter().position(|&state| state == BufferState::Scanout) {
+			self.release_fences[index] = fence;
+		}
+	}
+
+	/// Makes the scanned out buffer free if it has a release fence, rendering has to wait for the fence instead of the flip.
+	fn release_fenced_scanout(&mut self) {
+		for (state, release_fence) in self.buffer_states.iter_mut().zip(self.release_fences.iter()) {
+			if *state == BufferState::Scanout && release_fence.is_some() {
+				*state = BufferState::Free;
+			}
+		}
+	}
+
+	/// Takes the fence which has to signal before rendering into the current framebuffer, if it is still being scanned out.
+	///
+	/// Wait on it on the gpu or cpu, a framebuffer with a pending release fence must not be rendered into.
+	pub fn take_release_fence(&mut self) -> Option<SyncFile> {
+		self.release_fences[self.current_index].take()
+	}
+
+	/// Attaches a fence signaled when rendering into the current framebuffer completes.
+	///
+	/// The commit waits for it instead of relying on implicit synchronization, so rendering does not need to be finished first.
+	pub fn set_render_fence(&mut self, fence: SyncFile) {
+		self.render_fence = Some(fence);
 	}
 
 	pub fn current_framebuffer(&self) -> (usize, &FrameBufferObject) {
 		(self.current_index, &self.framebuffers[self.current_index])
 	}
 
+	pub fn current_framebuffer_mut(&mut self) -> (usize, &mut FrameBufferObject) {
+		(self.current_index, &mut self.framebuffers[self.current_index])
+	}
+
+	/// Presents this swapchain alone, only valid for contexts with a single render target.
+	///
+	/// Moves on to the next buffer, see [`KmsContext::present_swapchains`].
 	pub fn present(
 		&mut self,
 		context: &KmsContext
-	) -> anyhow::Result<()> {
-		context.atomic_commit(self.is_first_frame, self.current_framebuffer().1)?;
-		self.is_first_frame = false;
+	) -> anyhow::Result<PresentFeedback> {
+		context.present_swapchains(std::slice::from_mut(self))
+	}
+}
 
-		Ok(())
+#[cfg(test)]
+mod tests {
+	use drm::control::{ModeTypeFlags, connector::Handle as ConnectorHandle};
+
+	use super::fake::{LegacyCall, PlaneType, Topology, TopologyOptions, fake_mode};
+	use super::sync_file::SwSyncTimeline;
+	use super::*;
+
+	/// Both connectors connected, the HDMI encoder currently drives the second crtc, the only one DP can use.
+	fn topology() -> Topology {
+		Topology::new(&TopologyOptions { hdmi_crtc: Some(1), ..TopologyOptions::default() })
+	}
+
+	fn routes(config: &KmsConfig) -> Vec<(ConnectorHandle, CrtcHandle, Option<PlaneHandle>)> {
+		config.outputs().iter().map(|output| (output.connector.handle, output.crtc.handle(), output.plane_handle())).collect()
+	}
+
+	#[test]
+	fn single_output_keeps_current_crtc() {
+		let topology = topology();
+
+		let config = KmsConfig::choose(&topology.device, &KmsOptions::default()).unwrap();
+		assert_eq!(routes(&config), vec![(topology.hdmi, topology.crtcs[1], Some(topology.primary[1]))]);
+	}
+
+	#[test]
+	fn all_outputs_reassign_crtcs() {
+		let topology = topology();
+		let options = KmsOptions { all_outputs: true, ..KmsOptions::default() };
+
+		let config = KmsConfig::choose(&topology.device, &options).unwrap();
+		assert_eq!(
+			routes(&config),
+			vec![
+				(topology.hdmi, topology.crtcs[0], Some(topology.primary[0])),
+				(topology.dp, topology.crtcs[1], Some(topology.primary[1]))
+			]
+		);
+	}
+
+	#[test]
+	fn commit_request_needs_framebuffer_per_output() {
+		let topology = topology();
+		let options = KmsOptions { all_outputs: true, ..KmsOptions::default() };
+		let config = KmsConfig::choose(&topology.device, &options).unwrap();
+		let framebuffer: FramebufferHandle = drm::control::from_u32(100).unwrap();
+
+		assert!(config.commit_request(false, &[framebuffer]).is_err());
+
+		let (flags, request) = config.commit_request(true, &[framebuffer, framebuffer]).unwrap();
+		assert!(flags.contains(AtomicCommitFlags::ALLOW_MODESET | AtomicCommitFlags::PAGE_FLIP_EVENT));
+		assert_eq!(request.out_fences.len(), 2);
+		for output in config.outputs() {
+			let (plane, fb_id) = output.plane_fb_id().unwrap();
+			assert_eq!(request.get(plane, fb_id), Some(100));
+		}
+	}
+
+	#[test]
+	fn legacy_commit_sets_crtc_and_flips() {
+		let mut topology = topology();
+		topology.device.set_legacy(true);
+		let config = KmsConfig::choose(&topology.device, &KmsOptions::default()).unwrap();
+		let framebuffer: FramebufferHandle = drm::control::from_u32(100).unwrap();
+		assert!(!config.is_atomic());
+		assert_eq!(routes(&config), vec![(topology.hdmi, topology.crtcs[1], None)]);
+
+		assert!(config.commit_request(true, &[framebuffer]).is_err());
+		assert!(config.legacy_commit(&topology.device, true, &[]).is_err());
+		assert!(topology.device.legacy_calls().is_empty());
+
+		config.legacy_commit(&topology.device, true, &[framebuffer]).unwrap();
+		config.legacy_commit(&topology.device, false, &[framebuffer]).unwrap();
+		let calls = topology.device.legacy_calls();
+		assert_eq!(calls.len(), 3);
+		match calls[0] {
+			LegacyCall::SetCrtc { crtc, framebuffer: shown, ref connectors, mode } => {
+				assert_eq!((crtc, shown), (topology.crtcs[1], framebuffer));
+				assert_eq!(connectors, &[topology.hdmi]);
+				assert_eq!(mode.size(), (1920, 1080));
+			}
+			ref call => panic!("Expected a modeset, got {:?}", call)
+		}
+		for call in &calls[1 ..] {
+			assert!(matches!(*call, LegacyCall::PageFlip { crtc, framebuffer: shown } if crtc == topology.crtcs[1] && shown == framebuffer));
+		}
+	}
+
+	#[test]
+	fn validation_allocates_one_probe_per_size() {
+		let topology = topology();
+		let options = KmsOptions { all_outputs: true, ..KmsOptions::default() };
+		let mut config = KmsConfig::choose(&topology.device, &options).unwrap();
+
+		let mut allocated = Vec::new();
+		let format = config.validate(
+			&topology.device,
+			&options,
+			&KmsConfig::default_candidates(&options),
+			|output, _, _| {
+				allocated.push(output.source_size);
+				Ok((drm::control::from_u32(100).unwrap(), ()))
+			}
+		).unwrap();
+
+		assert_eq!(format, (DrmFourcc::Xrgb8888, DrmModifier::Linear));
+		assert_eq!(allocated, vec![(1920, 1080)]);
+	}
+
+	#[test]
+	fn rejected_outputs_destroy_mode_blobs() {
+		let mut topology = topology();
+		let modes = vec![
+			fake_mode(1920, 1080, 60, ModeTypeFlags::PREFERRED | ModeTypeFlags::DRIVER),
+			fake_mode(1280, 720, 60, ModeTypeFlags::DRIVER)
+		];
+		topology.device.set_connector_modes(topology.hdmi, modes);
+		let options = KmsOptions::default();
+		let mut config = KmsConfig::choose(&topology.device, &options).unwrap();
+		let chosen = topology.device.mode_blobs();
+		assert_eq!(chosen.len(), 1);
+
+		config.validate(
+			&topology.device,
+			&options,
+			&KmsConfig::default_candidates(&options),
+			|output, _, _| match output.source_size {
+				(1920, 1080) => Err(anyhow::anyhow!("Too large")),
+				_ => Ok((drm::control::from_u32(100).unwrap(), ()))
+			}
+		).unwrap();
+
+		assert_eq!(config.outputs()[0].resolution(), [1280, 720]);
+		let replaced = topology.device.mode_blobs();
+		assert_eq!(replaced.len(), 1);
+		assert_ne!(replaced, chosen);
+
+		config.destroy(&topology.device);
+		assert!(topology.device.mode_blobs().is_empty());
+	}
+
+	#[test]
+	#[ignore = "needs sw_sync from debugfs, run as root with CONFIG_SW_SYNC"]
+	fn fenced_commit() {
+		let mut topology = topology();
+		topology.device.enable_fences().unwrap();
+		let config = KmsConfig::choose(&topology.device, &KmsOptions::default()).unwrap();
+		let framebuffer: FramebufferHandle = drm::control::from_u32(100).unwrap();
+
+		let mut renderer = SwSyncTimeline::new().unwrap();
+		let render_fence = renderer.create_fence(1).unwrap();
+		let (flags, mut request) = config.commit_request(true, &[framebuffer]).unwrap();
+		assert!(config.outputs()[0].add_in_fence(&mut request, render_fence.as_raw_fd()).unwrap());
+		assert_eq!(request.in_fences.len(), 1);
+		assert_eq!(request.in_fences[0].0, ObjectHandle::Plane(topology.primary[1]));
+		assert_eq!(request.in_fences[0].2, render_fence.as_raw_fd());
+
+		// test-only commits do not hand out fences
+		let out_fences = topology.device.atomic_commit(flags | AtomicCommitFlags::TEST_ONLY, &request).unwrap();
+		assert!(out_fences.iter().all(Option::is_none));
+
+		let mut out_fences = topology.device.atomic_commit(flags, &request).unwrap();
+		let release_fence = out_fences.pop().flatten().unwrap();
+		assert!(out_fences.is_empty());
+		assert!(!release_fence.is_signaled().unwrap());
+
+		renderer.signal(1).unwrap();
+		assert!(render_fence.is_signaled().unwrap());
+		assert!(!release_fence.is_signaled().unwrap());
+
+		assert_eq!(topology.device.receive_events().unwrap().len(), 1);
+		assert!(release_fence.is_signaled().unwrap());
+	}
+
+	#[test]
+	fn negotiated_candidates_follow_in_formats() {
+		let mut topology = topology();
+		let config = KmsConfig::choose(&topology.device, &KmsOptions::default()).unwrap();
+		// I915_FORMAT_MOD_X_TILED and I915_FORMAT_MOD_Y_TILED
+		let (x_tiled, y_tiled, linear) = (0x0100000000000001, 0x0100000000000002, u64::from(DrmModifier::Linear));
+		let xrgb = DrmFourcc::Xrgb8888;
+		let importer = FormatTable::from_raw(vec![(xrgb as u32, vec![y_tiled, x_tiled, linear])]);
+
+		// planes which do not report their modifiers keep linear layout
+		assert_eq!(config.negotiated_candidates(&topology.device, xrgb, &importer).unwrap(), vec![(xrgb, DrmModifier::Linear)]);
+
+		topology.device.set_in_formats(topology.primary[1], &FormatTable::from_raw(vec![(xrgb as u32, vec![linear, x_tiled, y_tiled])]));
+		assert_eq!(
+			config.negotiated_candidates(&topology.device, xrgb, &importer).unwrap(),
+			vec![(xrgb, DrmModifier::from(x_tiled)), (xrgb, DrmModifier::from(y_tiled)), (xrgb, DrmModifier::Linear)]
+		);
+	}
+
+	#[test]
+	fn layers_need_free_planes() {
+		let mut topology = topology();
+		let config = KmsConfig::choose(&topology.device, &KmsOptions::default()).unwrap();
+		let argb = DrmFourcc::Argb8888;
+
+		// primary planes are used by the outputs
+		assert!(config.layer_planes(&topology.device, &[], 0, "Overlay", argb).unwrap().is_empty());
+		assert!(config.layer_planes(&topology.device, &[], 1, "Overlay", argb).is_err());
+
+		let overlay = topology.device.add_plane(PlaneType::Overlay, &topology.crtcs, &[argb as u32]);
+		let planes = config.layer_planes(&topology.device, &[], 0, "Overlay", argb).unwrap();
+		assert_eq!(planes.iter().map(|plane| plane.handle).collect::<Vec<_>>(), vec![overlay]);
+		assert!(config.layer_planes(&topology.device, &[], 0, "Overlay", DrmFourcc::Nv12).unwrap().is_empty());
+
+		let framebuffer: FramebufferHandle = drm::control::from_u32(101).unwrap();
+		let layer = Layer::new(&topology.device, LayerId(0), 0, topology.crtcs[1], overlay, framebuffer, &LayerState::at(0, 0, 64, 64)).unwrap();
+		assert!(config.layer_planes(&topology.device, &[layer], 0, "Overlay", argb).unwrap().is_empty());
+	}
+
+	#[test]
+	fn layers_are_tested_with_presented_buffers() {
+		let mut topology = topology();
+		let overlay = topology.device.add_plane(PlaneType::Overlay, &topology.crtcs, &[DrmFourcc::Argb8888 as u32]);
+		let config = KmsConfig::choose(&topology.device, &KmsOptions::default()).unwrap();
+		let presented: FramebufferHandle = drm::control::from_u32(100).unwrap();
+		let framebuffer: FramebufferHandle = drm::control::from_u32(101).unwrap();
+
+		let layer = Layer::new(&topology.device, LayerId(0), 0, topology.crtcs[1], overlay, framebuffer, &LayerState::at(0, 0, 64, 64)).unwrap();
+		config.test_layer(&topology.device, &[], &[presented], &layer).unwrap();
+
+		let commits = topology.device.commits();
+		let (flags, request) = commits.last().unwrap();
+		assert!(flags.contains(AtomicCommitFlags::TEST_ONLY));
+		assert!(request.out_fences.is_empty());
+		let fb_ids: Vec<(ObjectHandle, u64)> = request.properties.iter().filter(
+			|&&(object, property, _)| topology.device.properties(object).unwrap().iter().any(|desc| desc.handle == property && desc.name == "FB_ID")
+		).map(|&(object, _, value)| (object, value)).collect();
+		assert_eq!(
+			fb_ids,
+			vec![(ObjectHandle::Plane(topology.primary[1]), u32::from(presented) as u64), (ObjectHandle::Plane(overlay), u32::from(framebuffer) as u64)]
+		);
+	}
+
+	#[test]
+	fn render_rect_follows_scaling() {
+		let topology = topology();
+		let mut config = KmsConfig::choose(&topology.device, &KmsOptions::default()).unwrap();
+		let output = &mut config.outputs[0];
+		assert_eq!(output.render_rect((32, 32, 128, 128)), (32, 32, 128, 128));
+
+		output.scale_from((960, 540), &Insets::default()).unwrap();
+		assert_eq!(output.render_rect((32, 32, 128, 128)), (16, 16, 64, 64));
+
+		// pillarboxed within 1920x960
+		let insets = Insets { left: 0, top: 60, right: 0, bottom: 60 };
+		output.scale_from((960, 540), &insets).unwrap();
+		assert_eq!(output.crtc_rect, (107, 60, 1706, 960));
+		assert_eq!(output.render_rect((107, 60, 1706, 960)), (0, 0, 960, 540));
+	}
+
+	#[test]
+	fn plane_moves_are_deferred_while_busy() {
+		let mut topology = topology();
+		let cursor = topology.device.add_plane(PlaneType::Cursor, &topology.crtcs, &[CURSOR_FORMAT as u32]);
+		let config = KmsConfig::choose(&topology.device, &KmsOptions::default()).unwrap();
+		let properties = PropertyRegistry::query(&topology.device, ObjectHandle::Plane(cursor), &PLANE_PROPERTIES).unwrap();
+		let position = [(properties.handle("CRTC_X").unwrap(), 10), (properties.handle("CRTC_Y").unwrap(), 20)];
+
+		let framebuffer: FramebufferHandle = drm::control::from_u32(100).unwrap();
+		let (flags, request) = config.commit_request(true, &[framebuffer]).unwrap();
+		topology.device.atomic_commit(flags, &request).unwrap();
+		assert!(!commit_plane_position(&topology.device, cursor, position).unwrap());
+
+		topology.device.receive_events().unwrap();
+		assert!(commit_plane_position(&topology.device, cursor, position).unwrap());
+
+		let commits = topology.device.commits();
+		assert_eq!(commits.len(), 2);
+		let (flags, request) = commits.last().unwrap();
+		assert_eq!(*flags, AtomicCommitFlags::NONBLOCK);
+		assert_eq!(
+			request.properties,
+			vec![(ObjectHandle::Plane(cursor), position[0].0, 10), (ObjectHandle::Plane(cursor), position[1].0, 20)]
+		);
+	}
+
+	#[test]
+	fn commit_without_explicit_fencing() {
+		let mut topology = topology();
+		topology.device.remove_property(ObjectHandle::Crtc(topology.crtcs[1]), "OUT_FENCE_PTR");
+		topology.device.remove_property(ObjectHandle::Plane(topology.primary[1]), "IN_FENCE_FD");
+		let config = KmsConfig::choose(&topology.device, &KmsOptions::default()).unwrap();
+		let framebuffer: FramebufferHandle = drm::control::from_u32(100).unwrap();
+
+		let (_, mut request) = config.commit_request(true, &[framebuffer]).unwrap();
+		assert!(request.out_fences.is_empty());
+		assert!(!config.outputs()[0].has_out_fence());
+
+		// falls back to implicit synchronization
+		assert!(!config.outputs()[0].add_in_fence(&mut request, -1).unwrap());
+		assert!(request.in_fences.is_empty());
 	}
 }
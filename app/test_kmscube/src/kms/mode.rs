@@ -0,0 +1,555 @@
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use drm::control::{Mode, ModeFlags, ModeTypeFlags};
+
+/// What kind of mode the application wants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModeTarget {
+	/// driver or user defined mode, then preferred, then largest and fastest
+	#[default]
+	Default,
+	/// only modes with exactly this size, and refresh rate if given
+	Exact { width: u16, height: u16, refresh: Option<u32> },
+	/// any mode, ranked by distance from this size and refresh rate if given
+	Closest { width: u16, height: u16, refresh: Option<u32> }
+}
+
+/// Mode selection policy.
+///
+/// Candidates are filtered by the limits and then scored according to the target, see [`ModeRequest::rank`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ModeRequest {
+	pub target: ModeTarget,
+	/// largest allowed resolution, e.g. to limit bandwidth
+	pub max_size: Option<(u16, u16)>,
+	pub min_refresh: Option<u32>,
+	pub allow_interlaced: bool,
+	pub allow_doublescan: bool
+}
+
+/// Mode together with its score, higher is better.
+#[derive(Debug, Clone, Copy)]
+pub struct ScoredMode {
+	pub mode: Mode,
+	pub score: i64
+}
+
+/// Refresh rates reported by the kernel are rounded, so 59.94 Hz modes report 60.
+const REFRESH_TOLERANCE: u32 = 1;
+
+fn refresh_matches(mode: &Mode, refresh: u32) -> bool {
+	(mode.vrefresh() as i64 - refresh as i64).unsigned_abs() as u32 <= REFRESH_TOLERANCE
+}
+
+pub fn describe_mode(mode: &Mode) -> String {
+	format!(
+		"\"{}\" {}x{}@{} [{:?}][{:?}]",
+		mode.name().to_str().unwrap_or("<Unknown>"),
+		mode.size().0, mode.size().1,
+		mode.vrefresh(),
+		mode.mode_type(),
+		mode.flags()
+	)
+}
+
+/// Whether both modes have the same timings, ignoring name and type which differ between firmware and connector modes.
+pub fn same_timings(a: &Mode, b: &Mode) -> bool {
+	let (a, b) = (drm_ffi::drm_mode_modeinfo::from(*a), drm_ffi::drm_mode_modeinfo::from(*b));
+
+	a.clock == b.clock
+		&& (a.hdisplay, a.hsync_start, a.hsync_end, a.htotal, a.hskew) == (b.hdisplay, b.hsync_start, b.hsync_end, b.htotal, b.hskew)
+		&& (a.vdisplay, a.vsync_start, a.vsync_end, a.vtotal, a.vscan) == (b.vdisplay, b.vsync_start, b.vsync_end, b.vtotal, b.vscan)
+		&& a.flags == b.flags
+}
+
+impl ModeRequest {
+	fn accepts(&self, mode: &Mode) -> bool {
+		if !self.allow_interlaced && mode.flags().contains(ModeFlags::INTERLACE) {
+			return false
+		}
+		if !self.allow_doublescan && mode.flags().contains(ModeFlags::DBLSCAN) {
+			return false
+		}
+
+		if let Some((max_width, max_height)) = self.max_size {
+			if mode.size().0 > max_width || mode.size().1 > max_height {
+				return false
+			}
+		}
+		if let Some(min_refresh) = self.min_refresh {
+			if mode.vrefresh() + REFRESH_TOLERANCE < min_refresh {
+				return false
+			}
+		}
+
+		match self.target {
+			ModeTarget::Exact { width, height, refresh } => {
+				mode.size() == (width, height) && refresh.map(|refresh| refresh_matches(mode, refresh)).unwrap_or(true)
+			}
+			_ => true
+		}
+	}
+
+	/// Encodes the default preference chain into a single number:
+	/// USERDEF|DRIVER, then PREFERRED, then area, then vrefresh.
+	fn base_score(mode: &Mode) -> i64 {
+		let user_driver = mode.mode_type().contains(ModeTypeFlags::USERDEF | ModeTypeFlags::DRIVER) as i64;
+		let preferred = mode.mode_type().contains(ModeTypeFlags::PREFERRED) as i64;
+		let area = (mode.size().0 as i64 * mode.size().1 as i64).min((1 << 26) - 1);
+		let vrefresh = (mode.vrefresh() as i64).min((1 << 9) - 1);
+
+		(user_driver << 36) | (preferred << 35) | (area << 9) | vrefresh
+	}
+
+	fn score(&self, mode: &Mode) -> i64 {
+		let base = Self::base_score(mode);
+
+		match self.target {
+			ModeTarget::Default | ModeTarget::Exact { .. } => base,
+			ModeTarget::Closest { width, height, refresh } => {
+				let distance = (mode.size().0 as i64 - width as i64).abs()
+					+ (mode.size().1 as i64 - height as i64).abs()
+					+ refresh.map(|refresh| (mode.vrefresh() as i64 - refresh as i64).abs() * 16).unwrap_or(0);
+
+				// distance dominates, the base score only breaks ties
+				base - (distance << 37)
+			}
+		}
+	}
+
+	/// Filters `modes` and returns them ordered from best to worst.
+	pub fn rank(&self, modes: &[Mode]) -> Vec<ScoredMode> {
+		let mut ranked: Vec<ScoredMode> = modes.iter().filter(|mode| self.accepts(mode)).map(
+			|&mode| ScoredMode {
+				mode,
+				score: self.score(&mode)
+			}
+		).collect();
+
+		// stable, so equal modes keep the connector order
+		ranked.sort_by(|a, b| b.score.cmp(&a.score));
+
+		ranked
+	}
+
+	pub fn choose(&self, modes: &[Mode]) -> anyhow::Result<Mode> {
+		for mode in modes {
+			log::trace!("Mode: {}", describe_mode(mode));
+		}
+
+		let ranked = self.rank(modes);
+		for candidate in ranked.iter() {
+			log::debug!("Mode candidate {}: {}", candidate.score, describe_mode(&candidate.mode));
+		}
+
+		match ranked.first() {
+			None => Err(
+				anyhow::anyhow!(
+					"Did not find any modes for chosen connector satisfying {:?} ({} modes available)",
+					self, modes.len()
+				)
+			),
+			Some(chosen) => {
+				log::info!("Choosing mode: {}", describe_mode(&chosen.mode));
+				Ok(chosen.mode)
+			}
+		}
+	}
+}
+
+/// Parses `WxH`, e.g. `1920x1080`.
+pub fn parse_size(s: &str) -> anyhow::Result<(u16, u16)> {
+	let (width, height) = s.split_once('x').with_context(|| format!("Invalid size \"{}\", expected WxH", s))?;
+	let size: (u16, u16) = (
+		width.parse().with_context(|| format!("Invalid width \"{}\"", width))?,
+		height.parse().with_context(|| format!("Invalid height \"{}\"", height))?
+	);
+	if size.0 == 0 || size.1 == 0 {
+		anyhow::bail!("Invalid size \"{}\", width and height must not be zero", s);
+	}
+
+	Ok(size)
+}
+
+fn parse_size_refresh(s: &str) -> anyhow::Result<(u16, u16, Option<u32>)> {
+	let (size, refresh) = match s.split_once('@') {
+		None => (s, None),
+		Some((size, refresh)) => (size, Some(refresh.parse().with_context(|| format!("Invalid refresh rate \"{}\"", refresh))?))
+	};
+	let (width, height) = parse_size(size)?;
+
+	Ok((width, height, refresh))
+}
+
+impl FromStr for ModeRequest {
+	type Err = anyhow::Error;
+
+	/// Parses a comma separated list of `WxH[@Hz]` (exact), `~WxH[@Hz]` (closest), `max:WxH`, `min-refresh:Hz`,
+	/// `interlaced` and `doublescan`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut request = ModeRequest::default();
+
+		for term in s.split(',').map(str::trim).filter(|term| !term.is_empty()) {
+			if let Some(size) = term.strip_prefix("max:") {
+				request.max_size = Some(parse_size(size)?);
+			} else if let Some(refresh) = term.strip_prefix("min-refresh:") {
+				request.min_refresh = Some(refresh.parse().with_context(|| format!("Invalid refresh rate \"{}\"", refresh))?);
+			} else if term == "interlaced" {
+				request.allow_interlaced = true;
+			} else if term == "doublescan" {
+				request.allow_doublescan = true;
+			} else if let Some(target) = term.strip_prefix('~') {
+				let (width, height, refresh) = parse_size_refresh(target)?;
+				request.target = ModeTarget::Closest { width, height, refresh };
+			} else {
+				let (width, height, refresh) = parse_size_refresh(term)?;
+				request.target = ModeTarget::Exact { width, height, refresh };
+			}
+		}
+
+		Ok(request)
+	}
+}
+
+/// Raw timings of a mode, clock in kHz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+	pub clock: u32,
+	pub hdisplay: u16,
+	pub hsync_start: u16,
+	pub hsync_end: u16,
+	pub htotal: u16,
+	pub vdisplay: u16,
+	pub vsync_start: u16,
+	pub vsync_end: u16,
+	pub vtotal: u16,
+	pub flags: ModeFlags
+}
+impl Timings {
+	/// Refresh rate rounded the same way the kernel does in `drm_mode_vrefresh`.
+	pub fn vrefresh(&self) -> u32 {
+		let mut numerator = self.clock as u64 * 1000;
+		let mut denominator = self.htotal as u64 * self.vtotal as u64;
+		if denominator == 0 {
+			return 0
+		}
+
+		if self.flags.contains(ModeFlags::INTERLACE) {
+			numerator *= 2;
+		}
+		if self.flags.contains(ModeFlags::DBLSCAN) {
+			denominator *= 2;
+		}
+
+		((numerator + denominator / 2) / denominator) as u32
+	}
+
+	pub fn to_mode(&self, name: &str, mode_type: ModeTypeFlags) -> Mode {
+		// DRM_DISPLAY_MODE_LEN, keeping the terminating nul
+		let mut raw_name = [0; 32];
+		for (dst, src) in raw_name.iter_mut().take(31).zip(name.bytes()) {
+			*dst = src as _;
+		}
+
+		Mode::from(
+			drm_ffi::drm_mode_modeinfo {
+				clock: self.clock,
+				hdisplay: self.hdisplay,
+				hsync_start: self.hsync_start,
+				hsync_end: self.hsync_end,
+				htotal: self.htotal,
+				hskew: 0,
+				vdisplay: self.vdisplay,
+				vsync_start: self.vsync_start,
+				vsync_end: self.vsync_end,
+				vtotal: self.vtotal,
+				vscan: 0,
+				vrefresh: self.vrefresh(),
+				flags: self.flags.bits(),
+				type_: mode_type.bits(),
+				name: raw_name
+			}
+		)
+	}
+
+	fn validate(&self) -> anyhow::Result<()> {
+		let horizontal = self.hdisplay <= self.hsync_start && self.hsync_start <= self.hsync_end && self.hsync_end <= self.htotal;
+		let vertical = self.vdisplay <= self.vsync_start && self.vsync_start <= self.vsync_end && self.vsync_end <= self.vtotal;
+
+		if self.clock == 0 || self.hdisplay == 0 || self.vdisplay == 0 || !horizontal || !vertical {
+			anyhow::bail!("Invalid mode timings {:?}", self);
+		}
+
+		Ok(())
+	}
+}
+
+/// Generates VESA Coordinated Video Timings for `width`x`height`@`refresh`, without margins or interlacing.
+///
+/// Follows the reference implementation used by the `cvt` utility and the X server.
+pub fn cvt_timings(width: u16, height: u16, refresh: f64, reduced_blanking: bool) -> anyhow::Result<Timings> {
+	// character cell horizontal granularity
+	const H_GRANULARITY: i64 = 8;
+	// minimum vertical front porch
+	const MIN_V_PORCH: i64 = 3;
+	// minimum vertical back porch
+	const MIN_V_BPORCH: i64 = 6;
+	// pixel clock step in kHz
+	const CLOCK_STEP: i64 = 250;
+
+	if width == 0 || height == 0 || refresh <= 0.0 {
+		anyhow::bail!("Invalid CVT request {}x{}@{}", width, height, refresh);
+	}
+
+	let hdisplay = width as i64 - width as i64 % H_GRANULARITY;
+	let vdisplay = height as i64;
+
+	// vsync width encodes the aspect ratio
+	let vsync = if vdisplay % 3 == 0 && vdisplay * 4 / 3 == hdisplay {
+		4
+	} else if vdisplay % 9 == 0 && vdisplay * 16 / 9 == hdisplay {
+		5
+	} else if vdisplay % 10 == 0 && vdisplay * 16 / 10 == hdisplay {
+		6
+	} else if vdisplay % 4 == 0 && vdisplay * 5 / 4 == hdisplay {
+		7
+	} else if vdisplay % 9 == 0 && vdisplay * 15 / 9 == hdisplay {
+		7
+	} else {
+		10
+	};
+
+	let (hperiod, htotal, hsync_start, hsync_end, vtotal) = if !reduced_blanking {
+		// minimum time of vertical sync + back porch in us
+		const MIN_VSYNC_BP: f64 = 550.0;
+		// nominal hsync width in % of line period
+		const HSYNC_PERCENTAGE: i64 = 8;
+		// blanking formula gradient and offset, already scaled by the K and J factors
+		const M_PRIME: f64 = 600.0 * 128.0 / 256.0;
+		const C_PRIME: f64 = (40.0 - 20.0) * 128.0 / 256.0 + 20.0;
+
+		let hperiod = (1000000.0 / refresh - MIN_VSYNC_BP) / (vdisplay + MIN_V_PORCH) as f64;
+
+		let vsync_and_back_porch = ((MIN_VSYNC_BP / hperiod) as i64 + 1).max(vsync + MIN_V_PORCH);
+		let vtotal = vdisplay + vsync_and_back_porch + MIN_V_PORCH;
+
+		let hblank_percentage = (C_PRIME - M_PRIME * hperiod / 1000.0).max(20.0);
+		let mut hblank = (hdisplay as f64 * hblank_percentage / (100.0 - hblank_percentage)) as i64;
+		hblank -= hblank % (2 * H_GRANULARITY);
+
+		let htotal = hdisplay + hblank;
+		let hsync_end = hdisplay + hblank / 2;
+		let mut hsync_start = hsync_end - (htotal * HSYNC_PERCENTAGE) / 100;
+		hsync_start += H_GRANULARITY - hsync_start % H_GRANULARITY;
+
+		(hperiod, htotal, hsync_start, hsync_end, vtotal)
+	} else {
+		// minimum vertical blanking interval in us
+		const RB_MIN_VBLANK: f64 = 460.0;
+		const RB_H_SYNC: i64 = 32;
+		const RB_H_BLANK: i64 = 160;
+		const RB_V_FPORCH: i64 = 3;
+
+		let hperiod = (1000000.0 / refresh - RB_MIN_VBLANK) / vdisplay as f64;
+
+		let vbi_lines = ((RB_MIN_VBLANK / hperiod + 1.0) as i64).max(RB_V_FPORCH + vsync + MIN_V_BPORCH);
+		let vtotal = vdisplay + vbi_lines;
+
+		let htotal = hdisplay + RB_H_BLANK;
+		let hsync_end = hdisplay + RB_H_BLANK / 2;
+		let hsync_start = hsync_end - RB_H_SYNC;
+
+		(hperiod, htotal, hsync_start, hsync_end, vtotal)
+	};
+
+	let mut clock = (htotal as f64 * 1000.0 / hperiod) as i64;
+	clock -= clock % CLOCK_STEP;
+
+	let vsync_start = vdisplay + MIN_V_PORCH;
+	let timings = Timings {
+		clock: u32::try_from(clock).context("CVT clock out of range")?,
+		hdisplay: u16::try_from(hdisplay)?,
+		hsync_start: u16::try_from(hsync_start).context("CVT timings out of range")?,
+		hsync_end: u16::try_from(hsync_end).context("CVT timings out of range")?,
+		htotal: u16::try_from(htotal).context("CVT timings out of range")?,
+		vdisplay: u16::try_from(vdisplay)?,
+		vsync_start: u16::try_from(vsync_start).context("CVT timings out of range")?,
+		vsync_end: u16::try_from(vsync_start + vsync).context("CVT timings out of range")?,
+		vtotal: u16::try_from(vtotal).context("CVT timings out of range")?,
+		flags: if reduced_blanking {
+			ModeFlags::PHSYNC | ModeFlags::NVSYNC
+		} else {
+			ModeFlags::NHSYNC | ModeFlags::PVSYNC
+		}
+	};
+	timings.validate()?;
+
+	Ok(timings)
+}
+
+/// Generates a CVT or CVT reduced blanking mode, see [`cvt_timings`].
+pub fn cvt_mode(width: u16, height: u16, refresh: f64, reduced_blanking: bool) -> anyhow::Result<Mode> {
+	let timings = cvt_timings(width, height, refresh, reduced_blanking)?;
+	let name = format!("{}x{}{}", width, height, if reduced_blanking { "R" } else { "" });
+
+	Ok(timings.to_mode(&name, ModeTypeFlags::USERDEF))
+}
+
+/// Parses `WxH[@Hz]` and generates a CVT mode for it, refresh defaults to 60 Hz.
+pub fn parse_cvt_mode(s: &str, reduced_blanking: bool) -> anyhow::Result<Mode> {
+	let (size, refresh) = match s.split_once('@') {
+		None => (s, 60.0),
+		Some((size, refresh)) => (size, refresh.parse().with_context(|| format!("Invalid refresh rate \"{}\"", refresh))?)
+	};
+	let (width, height) = parse_size(size)?;
+
+	cvt_mode(width, height, refresh, reduced_blanking)
+}
+
+/// Parses an X11 modeline like `Modeline "1920x1080_60.00" 173.00 1920 2048 2248 2576 1080 1083 1088 1120 -hsync +vsync`.
+///
+/// The `Modeline` keyword and the name are optional.
+pub fn parse_modeline(s: &str) -> anyhow::Result<Mode> {
+	let mut rest = s.trim();
+	if rest.get(.. 8).map(|keyword| keyword.eq_ignore_ascii_case("modeline")).unwrap_or(false) {
+		rest = rest[8 ..].trim_start();
+	}
+
+	let name = if let Some(quoted) = rest.strip_prefix('"') {
+		let (name, after) = quoted.split_once('"').context("Unterminated modeline name")?;
+		rest = after;
+
+		Some(name.to_string())
+	} else {
+		None
+	};
+
+	let mut tokens = rest.split_whitespace();
+
+	let clock_token = tokens.next().context("Modeline is missing clock")?;
+	let clock_mhz: f64 = clock_token.parse().with_context(|| format!("Invalid modeline clock \"{}\"", clock_token))?;
+
+	let mut next_number = |what: &str| -> anyhow::Result<u16> {
+		let token = tokens.next().with_context(|| format!("Modeline is missing {}", what))?;
+		token.parse().with_context(|| format!("Invalid modeline {} \"{}\"", what, token))
+	};
+
+	let hdisplay = next_number("hdisplay")?;
+	let hsync_start = next_number("hsync start")?;
+	let hsync_end = next_number("hsync end")?;
+	let htotal = next_number("htotal")?;
+	let vdisplay = next_number("vdisplay")?;
+	let vsync_start = next_number("vsync start")?;
+	let vsync_end = next_number("vsync end")?;
+	let vtotal = next_number("vtotal")?;
+
+	let mut flags = ModeFlags::empty();
+	for flag in tokens {
+		flags |= match flag.to_ascii_lowercase().as_str() {
+			"+hsync" => ModeFlags::PHSYNC,
+			"-hsync" => ModeFlags::NHSYNC,
+			"+vsync" => ModeFlags::PVSYNC,
+			"-vsync" => ModeFlags::NVSYNC,
+			"+csync" => ModeFlags::PCSYNC,
+			"-csync" => ModeFlags::NCSYNC,
+			"composite" => ModeFlags::CSYNC,
+			"interlace" => ModeFlags::INTERLACE,
+			"doublescan" => ModeFlags::DBLSCAN,
+			other => anyhow::bail!("Unknown modeline flag \"{}\"", other)
+		};
+	}
+
+	let timings = Timings {
+		clock: (clock_mhz * 1000.0).round() as u32,
+		hdisplay,
+		hsync_start,
+		hsync_end,
+		htotal,
+		vdisplay,
+		vsync_start,
+		vsync_end,
+		vtotal,
+		flags
+	};
+	timings.validate()?;
+
+	let name = name.unwrap_or_else(|| format!("{}x{}", hdisplay, vdisplay));
+	Ok(timings.to_mode(&name, ModeTypeFlags::USERDEF))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_sizes() {
+		assert_eq!(parse_size("1920x1080").unwrap(), (1920, 1080));
+
+		assert!(parse_size("1920").is_err());
+		assert!(parse_size("0x0").is_err());
+		assert!(parse_size("1920x0").is_err());
+		assert!(parse_size("0x1080").is_err());
+	}
+
+	fn timings(clock: u32, horizontal: [u16; 4], vertical: [u16; 4], flags: ModeFlags) -> Timings {
+		Timings {
+			clock,
+			hdisplay: horizontal[0],
+			hsync_start: horizontal[1],
+			hsync_end: horizontal[2],
+			htotal: horizontal[3],
+			vdisplay: vertical[0],
+			vsync_start: vertical[1],
+			vsync_end: vertical[2],
+			vtotal: vertical[3],
+			flags
+		}
+	}
+
+	/// Modelines printed by the `cvt` utility.
+	#[test]
+	fn cvt_reference_modelines() {
+		let cvt = ModeFlags::NHSYNC | ModeFlags::PVSYNC;
+		let reduced = ModeFlags::PHSYNC | ModeFlags::NVSYNC;
+
+		assert_eq!(cvt_timings(1920, 1080, 60.0, false).unwrap(), timings(173000, [1920, 2048, 2248, 2576], [1080, 1083, 1088, 1120], cvt));
+		assert_eq!(cvt_timings(1024, 768, 60.0, false).unwrap(), timings(63500, [1024, 1072, 1176, 1328], [768, 771, 775, 798], cvt));
+		assert_eq!(cvt_timings(1280, 800, 60.0, false).unwrap(), timings(83500, [1280, 1352, 1480, 1680], [800, 803, 809, 831], cvt));
+		assert_eq!(cvt_timings(800, 600, 60.0, false).unwrap(), timings(38250, [800, 832, 912, 1024], [600, 603, 607, 624], cvt));
+		assert_eq!(cvt_timings(1920, 1080, 60.0, true).unwrap(), timings(138500, [1920, 1968, 2000, 2080], [1080, 1083, 1088, 1111], reduced));
+		assert_eq!(cvt_timings(2560, 1440, 60.0, true).unwrap(), timings(241500, [2560, 2608, 2640, 2720], [1440, 1443, 1448, 1481], reduced));
+
+		assert!(cvt_timings(0, 1080, 60.0, false).is_err());
+		assert!(cvt_timings(1920, 1080, 0.0, false).is_err());
+	}
+
+	#[test]
+	fn modeline() {
+		let mode = parse_modeline("Modeline \"1920x1080_60.00\"  173.00  1920 2048 2248 2576  1080 1083 1088 1120 -hsync +vsync").unwrap();
+		assert_eq!(mode.name().to_str().unwrap(), "1920x1080_60.00");
+		assert_eq!(mode.vrefresh(), 60);
+		assert!(mode.mode_type().contains(ModeTypeFlags::USERDEF));
+		assert!(same_timings(&mode, &cvt_mode(1920, 1080, 60.0, false).unwrap()));
+
+		// keyword and name are optional
+		let mode = parse_modeline("138.50 1920 1968 2000 2080 1080 1083 1088 1111 +HSync -VSync").unwrap();
+		assert_eq!(mode.name().to_str().unwrap(), "1920x1080");
+		assert!(same_timings(&mode, &cvt_mode(1920, 1080, 60.0, true).unwrap()));
+
+		let mode = parse_modeline("modeline 74.25 1920 2008 2052 2200 1080 1084 1094 1125 interlace +hsync +vsync").unwrap();
+		assert_eq!(mode.flags(), ModeFlags::INTERLACE | ModeFlags::PHSYNC | ModeFlags::PVSYNC);
+		assert_eq!(mode.vrefresh(), 60);
+	}
+
+	#[test]
+	fn invalid_modelines() {
+		assert!(parse_modeline("\"1920x1080 173.00 1920 2048 2248 2576 1080 1083 1088 1120").is_err());
+		assert!(parse_modeline("173.00 1920 2048 2248 2576 1080 1083 1088").is_err());
+		assert!(parse_modeline("173.00 1920 2048 2248 2576 1080 1083 1088 1120 +sync").is_err());
+		// sync after the total
+		assert!(parse_modeline("173.00 1920 2048 2600 2576 1080 1083 1088 1120").is_err());
+		assert!(parse_modeline("0 1920 2048 2248 2576 1080 1083 1088 1120").is_err());
+	}
+}
@@ -0,0 +1,353 @@
+//! Looking up properties of drm objects by name and converting their values.
+//!
+//! Property handles differ between drivers and kernel versions, so an object is queried once and the handles are
+//! reused for every commit. Properties an object cannot be driven without are required, optional ones such as
+//! `alpha` or `rotation` are only set when the driver has them.
+
+use std::collections::{HashMap, hash_map::Entry};
+
+use anyhow::Context;
+
+use drm::control::property::Handle as PropertyHandle;
+
+use super::backend::{DrmBackend, ObjectHandle, PropertyDesc, PropertyKind};
+
+/// Value of a property converted according to its [`PropertyKind`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypedValue {
+	Boolean(bool),
+	Range(u64),
+	SignedRange(i64),
+	/// name of the enum value
+	Enum(String),
+	/// names of the set bits
+	Bitmask(Vec<String>),
+	/// blob id, 0 for none
+	Blob(u64),
+	/// object id, 0 for none
+	Object(u32),
+	/// value of a property whose type is unknown
+	Raw(u64)
+}
+impl TypedValue {
+	/// Raw value of scalar values, `None` for enum and bitmask values which need the property to be converted.
+	pub fn raw(&self) -> Option<u64> {
+		match *self {
+			TypedValue::Boolean(value) => Some(value as u64),
+			TypedValue::Range(value) => Some(value),
+			TypedValue::SignedRange(value) => Some(value as u64),
+			TypedValue::Enum(_) | TypedValue::Bitmask(_) => None,
+			TypedValue::Blob(id) => Some(id),
+			TypedValue::Object(id) => Some(id as u64),
+			TypedValue::Raw(value) => Some(value)
+		}
+	}
+}
+
+impl PropertyDesc {
+	/// Current value converted according to the property type.
+	pub fn typed_value(&self) -> anyhow::Result<TypedValue> {
+		self.decode(self.value)
+	}
+
+	/// Converts a raw `value` of this property.
+	pub fn decode(&self, value: u64) -> anyhow::Result<TypedValue> {
+		let typed = match self.kind {
+			PropertyKind::Unknown => TypedValue::Raw(value),
+			PropertyKind::Boolean => TypedValue::Boolean(value != 0),
+			PropertyKind::Range { .. } => TypedValue::Range(value),
+			PropertyKind::SignedRange { .. } => TypedValue::SignedRange(value as i64),
+			PropertyKind::Enum => {
+				let name = self.enum_values.iter().find(|&&(enum_value, _)| enum_value == value).map(|(_, name)| name.clone());
+
+				TypedValue::Enum(name.with_context(|| format!("Value {} of property {} is not one of its enum values", value, self.name))?)
+			}
+			PropertyKind::Bitmask => {
+				let mut names = Vec::new();
+				let mut unnamed = value;
+				for &(bit, ref name) in self.enum_values.iter().filter(|&&(bit, _)| bit < 64) {
+					if value & 1 << bit != 0 {
+						names.push(name.clone());
+						unnamed &= !(1 << bit);
+					}
+				}
+				anyhow::ensure!(unnamed == 0, "Value {:#x} of property {} has bits without a name set", value, self.name);
+
+				TypedValue::Bitmask(names)
+			}
+			PropertyKind::Blob => TypedValue::Blob(value),
+			PropertyKind::Object => TypedValue::Object(value as u32)
+		};
+
+		Ok(typed)
+	}
+
+	/// Converts `value` to a raw value of this property, failing if it does not match the type or the limits.
+	pub fn encode(&self, value: &TypedValue) -> anyhow::Result<u64> {
+		match (self.kind, value) {
+			(PropertyKind::Boolean, &TypedValue::Boolean(value)) => Ok(value as u64),
+			(PropertyKind::Range { min, max }, &TypedValue::Range(value)) => {
+				anyhow::ensure!((min ..= max).contains(&value), "Value {} of property {} is outside of {}..={}", value, self.name, min, max);
+
+				Ok(value)
+			}
+			(PropertyKind::SignedRange { min, max }, &TypedValue::SignedRange(value)) => {
+				anyhow::ensure!((min ..= max).contains(&value), "Value {} of property {} is outside of {}..={}", value, self.name, min, max);
+
+				Ok(value as u64)
+			}
+			(PropertyKind::Enum, TypedValue::Enum(name)) => self.enum_value(name),
+			(PropertyKind::Bitmask, TypedValue::Bitmask(names)) => names.iter().try_fold(
+				0,
+				|mask, name| {
+					let bit = self.enum_value(name)?;
+					anyhow::ensure!(bit < 64, "Bit {} of property {} does not fit into a value", name, self.name);
+
+					Ok(mask | 1 << bit)
+				}
+			),
+			(PropertyKind::Blob, &TypedValue::Blob(id)) => Ok(id),
+			(PropertyKind::Object, &TypedValue::Object(id)) => Ok(id as u64),
+			(PropertyKind::Unknown, value) => value.raw().with_context(|| format!("Cannot convert {:?} for property {} of unknown type", value, self.name)),
+			(kind, value) => anyhow::bail!("Cannot assign {:?} to property {} of type {:?}", value, self.name, kind)
+		}
+	}
+
+	fn enum_value(&self, name: &str) -> anyhow::Result<u64> {
+		self.enum_values.iter().find(|(_, enum_name)| enum_name == name).map(|&(value, _)| value).with_context(
+			|| format!("Property {} has no value {}", self.name, name)
+		)
+	}
+}
+
+/// Property looked up by a [`PropertyRegistry`] and whether the object is unusable without it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PropertySpec {
+	pub name: &'static str,
+	pub required: bool
+}
+impl PropertySpec {
+	pub const fn required(name: &'static str) -> Self {
+		PropertySpec { name, required: true }
+	}
+
+	pub const fn optional(name: &'static str) -> Self {
+		PropertySpec { name, required: false }
+	}
+}
+
+/// Connector properties set when presenting.
+pub const CONNECTOR_PROPERTIES: [PropertySpec; 1] = [PropertySpec::required("CRTC_ID")];
+/// Crtc properties set when presenting, without `OUT_FENCE_PTR` buffers are released on page flip events.
+pub const CRTC_PROPERTIES: [PropertySpec; 3] = [
+	PropertySpec::required("MODE_ID"),
+	PropertySpec::required("ACTIVE"),
+	PropertySpec::optional("OUT_FENCE_PTR")
+];
+/// Plane properties set when presenting or showing layers, without `IN_FENCE_FD` commits rely on implicit synchronization.
+pub const PLANE_PROPERTIES: [PropertySpec; 15] = [
+	PropertySpec::optional("IN_FENCE_FD"),
+	PropertySpec::required("FB_ID"),
+	PropertySpec::required("CRTC_ID"),
+	PropertySpec::required("SRC_X"),
+	PropertySpec::required("SRC_Y"),
+	PropertySpec::required("SRC_W"),
+	PropertySpec::required("SRC_H"),
+	PropertySpec::required("CRTC_X"),
+	PropertySpec::required("CRTC_Y"),
+	PropertySpec::required("CRTC_W"),
+	PropertySpec::required("CRTC_H"),
+	PropertySpec::optional("zpos"),
+	PropertySpec::optional("alpha"),
+	PropertySpec::optional("pixel blend mode"),
+	PropertySpec::optional("rotation")
+];
+
+/// Properties of one object by name, queried once so that the handles can be reused across commits.
+#[derive(Debug, Clone)]
+pub struct PropertyRegistry {
+	object: ObjectHandle,
+	properties: HashMap<String, PropertyDesc>,
+	/// properties expected when querying
+	specs: Vec<PropertySpec>
+}
+impl PropertyRegistry {
+	/// Queries the properties of `object`, failing if it lacks any of `specs` that is required.
+	///
+	/// Missing optional properties are logged. Properties not in `specs` can be looked up as well.
+	pub fn query(backend: &(impl DrmBackend + ?Sized), object: ObjectHandle, specs: &[PropertySpec]) -> anyhow::Result<Self> {
+		let mut properties = HashMap::new();
+		for property in backend.properties(object).with_context(|| format!("Failed to query properties of {:?}", object))? {
+			match properties.entry(property.name.clone()) {
+				Entry::Occupied(_) => log::warn!("Ignoring duplicate property {} of {:?}", property.name, object),
+				Entry::Vacant(entry) => { entry.insert(property); }
+			}
+		}
+
+		let registry = PropertyRegistry {
+			object,
+			properties,
+			specs: specs.to_vec()
+		};
+
+		let missing = registry.missing_required();
+		if !missing.is_empty() {
+			anyhow::bail!("{:?} lacks required properties {}", object, missing.join(", "));
+		}
+		let missing = registry.missing_optional();
+		if !missing.is_empty() {
+			log::debug!("{:?} lacks optional properties {}", object, missing.join(", "));
+		}
+
+		Ok(registry)
+	}
+
+	fn missing(&self, required: bool) -> Vec<&'static str> {
+		self.specs.iter().filter(
+			|spec| spec.required == required && !self.properties.contains_key(spec.name)
+		).map(|spec| spec.name).collect()
+	}
+
+	/// Required properties of the specs which the object lacks, empty after a successful query.
+	pub fn missing_required(&self) -> Vec<&'static str> {
+		self.missing(true)
+	}
+
+	/// Optional properties of the specs which the object lacks.
+	pub fn missing_optional(&self) -> Vec<&'static str> {
+		self.missing(false)
+	}
+
+	pub fn get(&self, name: &str) -> Option<&PropertyDesc> {
+		self.properties.get(name)
+	}
+
+	pub fn contains(&self, name: &str) -> bool {
+		self.properties.contains_key(name)
+	}
+
+	/// Property `name`, fails if the object lacks it.
+	pub fn property(&self, name: &str) -> anyhow::Result<&PropertyDesc> {
+		self.get(name).with_context(|| format!("Could not find property {} of {:?}", name, self.object))
+	}
+
+	/// Handle of property `name`, fails if the object lacks it.
+	pub fn handle(&self, name: &str) -> anyhow::Result<PropertyHandle> {
+		self.property(name).map(|property| property.handle)
+	}
+
+	/// Value of property `name` as of the query.
+	pub fn value(&self, name: &str) -> anyhow::Result<TypedValue> {
+		self.property(name)?.typed_value()
+	}
+
+	/// Raw value and handle assigning `value` to property `name`, checked against the property type.
+	pub fn encode(&self, name: &str, value: &TypedValue) -> anyhow::Result<(PropertyHandle, u64)> {
+		let property = self.property(name)?;
+
+		Ok((property.handle, property.encode(value)?))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use drm::control::plane::Handle as PlaneHandle;
+
+	use super::*;
+	use super::super::fake::{FakeDevice, PlaneType};
+
+	fn property(kind: PropertyKind, enum_values: &[(u64, &str)]) -> PropertyDesc {
+		PropertyDesc {
+			handle: drm::control::from_u32(1).unwrap(),
+			name: "test".to_string(),
+			kind,
+			value: 0,
+			enum_values: enum_values.iter().map(|&(value, name)| (value, name.to_string())).collect()
+		}
+	}
+
+	#[test]
+	fn enum_values() {
+		let property = property(PropertyKind::Enum, &[(0, "None"), (1, "Pre-multiplied"), (2, "Coverage")]);
+
+		assert_eq!(property.decode(2).unwrap(), TypedValue::Enum("Coverage".to_string()));
+		assert_eq!(property.encode(&TypedValue::Enum("Pre-multiplied".to_string())).unwrap(), 1);
+		assert!(property.decode(3).is_err());
+		assert!(property.encode(&TypedValue::Enum("Opaque".to_string())).is_err());
+		assert!(property.encode(&TypedValue::Range(1)).is_err());
+	}
+
+	#[test]
+	fn bitmask_values() {
+		let property = property(PropertyKind::Bitmask, &[(0, "rotate-0"), (2, "rotate-180"), (4, "reflect-x")]);
+		let value = TypedValue::Bitmask(vec!["rotate-180".to_string(), "reflect-x".to_string()]);
+
+		assert_eq!(property.encode(&value).unwrap(), 0b10100);
+		assert_eq!(property.decode(0b10100).unwrap(), value);
+		assert_eq!(property.encode(&TypedValue::Bitmask(Vec::new())).unwrap(), 0);
+		// bit 1 has no name
+		assert!(property.decode(0b10).is_err());
+		assert!(property.encode(&TypedValue::Bitmask(vec!["rotate-90".to_string()])).is_err());
+	}
+
+	#[test]
+	fn range_values() {
+		let property = property(PropertyKind::Range { min: 0, max: 0xffff }, &[]);
+
+		assert_eq!(property.decode(0x8000).unwrap(), TypedValue::Range(0x8000));
+		assert_eq!(property.encode(&TypedValue::Range(0xffff)).unwrap(), 0xffff);
+		assert!(property.encode(&TypedValue::Range(0x10000)).is_err());
+		assert!(property.encode(&TypedValue::SignedRange(1)).is_err());
+	}
+
+	#[test]
+	fn signed_range_values() {
+		let property = property(PropertyKind::SignedRange { min: -1, max: i32::MAX as i64 }, &[]);
+
+		assert_eq!(property.decode(u64::MAX).unwrap(), TypedValue::SignedRange(-1));
+		assert_eq!(property.encode(&TypedValue::SignedRange(-1)).unwrap(), u64::MAX);
+		assert_eq!(property.encode(&TypedValue::SignedRange(5)).unwrap(), 5);
+		assert!(property.encode(&TypedValue::SignedRange(-2)).is_err());
+		assert!(property.encode(&TypedValue::SignedRange(i32::MAX as i64 + 1)).is_err());
+	}
+
+	fn plane() -> (FakeDevice, PlaneHandle) {
+		let mut device = FakeDevice::new();
+		let crtc = device.add_crtc();
+		let plane = device.add_plane(PlaneType::Overlay, &[crtc], &[]);
+
+		(device, plane)
+	}
+
+	#[test]
+	fn registry_encodes_by_name() {
+		let (mut device, plane) = plane();
+		let object = ObjectHandle::Plane(plane);
+		device.set_property(object, "CRTC_X", 10);
+		// unknown types pass raw values through
+		device.set_property(object, "vendor", 3);
+
+		let registry = PropertyRegistry::query(&device, object, &PLANE_PROPERTIES).unwrap();
+		let crtc_x = registry.property("CRTC_X").unwrap();
+		assert_eq!(registry.value("CRTC_X").unwrap(), TypedValue::SignedRange(10));
+		assert_eq!(registry.encode("CRTC_X", &TypedValue::SignedRange(-5)).unwrap(), (crtc_x.handle, -5i64 as u64));
+		assert!(registry.encode("CRTC_X", &TypedValue::Range(5)).is_err());
+		assert_eq!(registry.encode("vendor", &TypedValue::Range(7)).unwrap().1, 7);
+		assert!(registry.encode("alpha", &TypedValue::Range(0)).is_err());
+		assert!(registry.missing_optional().contains(&"alpha"));
+	}
+
+	#[test]
+	fn registry_requires_properties() {
+		let (mut device, plane) = plane();
+		let object = ObjectHandle::Plane(plane);
+
+		device.remove_property(object, "IN_FENCE_FD");
+		let registry = PropertyRegistry::query(&device, object, &PLANE_PROPERTIES).unwrap();
+		assert!(registry.missing_optional().contains(&"IN_FENCE_FD"));
+		assert!(registry.missing_required().is_empty());
+
+		device.remove_property(object, "FB_ID");
+		assert!(PropertyRegistry::query(&device, object, &PLANE_PROPERTIES).is_err());
+	}
+}
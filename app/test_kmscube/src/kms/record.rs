@@ -0,0 +1,1133 @@
+//! Recording of DRM traffic into capture files and replaying it without hardware.
+//!
+//! A capture is a JSON lines file, each line is one query with its answer or one atomic commit.
+//! Lines are flushed as they are recorded so that a capture of a crashing run is still usable.
+
+use std::{
+	cell::RefCell,
+	collections::{HashMap, VecDeque},
+	fs,
+	io::{BufRead, BufReader, BufWriter, Write},
+	path::Path,
+	time::Duration
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use drm::buffer::{DrmFourcc, DrmModifier};
+use drm::control::{
+	Mode,
+	atomic::AtomicCommitFlags,
+	connector::{Handle as ConnectorHandle, Interface as ConnectorInterface, State as ConnectorState},
+	encoder::{Handle as EncoderHandle, Kind as EncoderKind},
+	crtc::Handle as CrtcHandle,
+	framebuffer::Handle as FramebufferHandle,
+	plane::Handle as PlaneHandle
+};
+
+use super::{
+	KmsConfig,
+	KmsOptions,
+	KmsOutput,
+	backend::{
+		DrmBackend,
+		ObjectHandle,
+		CommitRequest,
+		DrmEvent,
+		ResourcesDesc,
+		ConnectorDesc,
+		EncoderDesc,
+		CrtcDesc,
+		PlaneDesc,
+		PropertyDesc,
+		PropertyKind
+	},
+	format::FormatTable,
+	sync_file::SyncFile
+};
+
+fn handle<T: From<drm::control::RawResourceHandle>>(raw: u32) -> anyhow::Result<T> {
+	drm::control::from_u32(raw).with_context(|| format!("Invalid handle {} in capture", raw))
+}
+
+fn handles<T: From<drm::control::RawResourceHandle>>(raw: &[u32]) -> anyhow::Result<Vec<T>> {
+	raw.iter().map(|&raw| handle(raw)).collect()
+}
+
+fn raw_handles<T: Copy + Into<u32>>(handles: &[T]) -> Vec<u32> {
+	handles.iter().map(|&handle| handle.into()).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RecordedMode {
+	clock: u32,
+	hdisplay: u16,
+	hsync_start: u16,
+	hsync_end: u16,
+	htotal: u16,
+	hskew: u16,
+	vdisplay: u16,
+	vsync_start: u16,
+	vsync_end: u16,
+	vtotal: u16,
+	vscan: u16,
+	vrefresh: u32,
+	flags: u32,
+	mode_type: u32,
+	name: String
+}
+impl From<&Mode> for RecordedMode {
+	fn from(mode: &Mode) -> Self {
+		let raw = drm_ffi::drm_mode_modeinfo::from(*mode);
+		let name = raw.name.iter().take_while(|&&c| c != 0).map(|&c| c as u8 as char).collect();
+
+		RecordedMode {
+			clock: raw.clock,
+			hdisplay: raw.hdisplay,
+			hsync_start: raw.hsync_start,
+			hsync_end: raw.hsync_end,
+			htotal: raw.htotal,
+			hskew: raw.hskew,
+			vdisplay: raw.vdisplay,
+			vsync_start: raw.vsync_start,
+			vsync_end: raw.vsync_end,
+			vtotal: raw.vtotal,
+			vscan: raw.vscan,
+			vrefresh: raw.vrefresh,
+			flags: raw.flags,
+			mode_type: raw.type_,
+			name
+		}
+	}
+}
+impl From<&RecordedMode> for Mode {
+	fn from(mode: &RecordedMode) -> Self {
+		// DRM_DISPLAY_MODE_LEN, keeping the terminating nul
+		let mut name = [0; 32];
+		for (dst, src) in name.iter_mut().take(31).zip(mode.name.bytes()) {
+			*dst = src as _;
+		}
+
+		Mode::from(
+			drm_ffi::drm_mode_modeinfo {
+				clock: mode.clock,
+				hdisplay: mode.hdisplay,
+				hsync_start: mode.hsync_start,
+				hsync_end: mode.hsync_end,
+				htotal: mode.htotal,
+				hskew: mode.hskew,
+				vdisplay: mode.vdisplay,
+				vsync_start: mode.vsync_start,
+				vsync_end: mode.vsync_end,
+				vtotal: mode.vtotal,
+				vscan: mode.vscan,
+				vrefresh: mode.vrefresh,
+				flags: mode.flags,
+				type_: mode.mode_type,
+				name
+			}
+		)
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResources {
+	connectors: Vec<u32>,
+	encoders: Vec<u32>,
+	crtcs: Vec<u32>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedConnector {
+	/// `DRM_MODE_CONNECTOR_*`
+	interface: u32,
+	interface_id: u32,
+	/// `DRM_MODE_CONNECTED`, `DRM_MODE_DISCONNECTED` or `DRM_MODE_UNKNOWNCONNECTION`
+	state: u32,
+	modes: Vec<RecordedMode>,
+	encoders: Vec<u32>,
+	current_encoder: Option<u32>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedEncoder {
+	/// `DRM_MODE_ENCODER_*`
+	kind: u32,
+	crtc: Option<u32>,
+	possible_crtcs: Vec<u32>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedCrtc {
+	mode: Option<RecordedMode>,
+	framebuffer: Option<u32>,
+	position: (u32, u32)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedPlane {
+	crtc: Option<u32>,
+	framebuffer: Option<u32>,
+	possible_crtcs: Vec<u32>,
+	formats: Vec<u32>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedProperty {
+	handle: u32,
+	name: String,
+	/// unknown in captures made before types were recorded
+	#[serde(default)]
+	kind: RecordedPropertyKind,
+	value: u64,
+	enum_values: Vec<(u64, String)>
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RecordedPropertyKind {
+	#[default]
+	Unknown,
+	Boolean,
+	Range { min: u64, max: u64 },
+	SignedRange { min: i64, max: i64 },
+	Enum,
+	Bitmask,
+	Blob,
+	Object
+}
+impl From<PropertyKind> for RecordedPropertyKind {
+	fn from(kind: PropertyKind) -> Self {
+		match kind {
+			PropertyKind::Unknown => RecordedPropertyKind::Unknown,
+			PropertyKind::Boolean => RecordedPropertyKind::Boolean,
+			PropertyKind::Range { min, max } => RecordedPropertyKind::Range { min, max },
+			PropertyKind::SignedRange { min, max } => RecordedPropertyKind::SignedRange { min, max },
+			PropertyKind::Enum => RecordedPropertyKind::Enum,
+			PropertyKind::Bitmask => RecordedPropertyKind::Bitmask,
+			PropertyKind::Blob => RecordedPropertyKind::Blob,
+			PropertyKind::Object => RecordedPropertyKind::Object
+		}
+	}
+}
+impl From<RecordedPropertyKind> for PropertyKind {
+	fn from(kind: RecordedPropertyKind) -> Self {
+		match kind {
+			RecordedPropertyKind::Unknown => PropertyKind::Unknown,
+			RecordedPropertyKind::Boolean => PropertyKind::Boolean,
+			RecordedPropertyKind::Range { min, max } => PropertyKind::Range { min, max },
+			RecordedPropertyKind::SignedRange { min, max } => PropertyKind::SignedRange { min, max },
+			RecordedPropertyKind::Enum => PropertyKind::Enum,
+			RecordedPropertyKind::Bitmask => PropertyKind::Bitmask,
+			RecordedPropertyKind::Blob => PropertyKind::Blob,
+			RecordedPropertyKind::Object => PropertyKind::Object
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordedObject {
+	Connector(u32),
+	Crtc(u32),
+	Plane(u32)
+}
+impl From<ObjectHandle> for RecordedObject {
+	fn from(object: ObjectHandle) -> Self {
+		match object {
+			ObjectHandle::Connector(handle) => RecordedObject::Connector(handle.into()),
+			ObjectHandle::Crtc(handle) => RecordedObject::Crtc(handle.into()),
+			ObjectHandle::Plane(handle) => RecordedObject::Plane(handle.into())
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RecordedCommit {
+	flags: u32,
+	properties: Vec<(RecordedObject, u32, u64)>,
+	/// objects waiting for a fence, without the fd which is only valid in the recorded session
+	#[serde(default)]
+	in_fences: Vec<(RecordedObject, u32)>,
+	/// objects returning a fence
+	#[serde(default)]
+	out_fences: Vec<(RecordedObject, u32)>,
+	/// rejected by the device, e.g. a test-only commit probing for support
+	#[serde(default)]
+	failed: bool
+}
+impl RecordedCommit {
+	fn new(flags: AtomicCommitFlags, request: &CommitRequest, failed: bool) -> Self {
+		RecordedCommit {
+			flags: flags.bits(),
+			properties: request.properties.iter().map(
+				|&(object, property, value)| (object.into(), property.into(), value)
+			).collect(),
+			in_fences: request.in_fences.iter().map(
+				|&(object, property, _)| (object.into(), property.into())
+			).collect(),
+			out_fences: request.out_fences.iter().map(
+				|&(object, property)| (object.into(), property.into())
+			).collect(),
+			failed
+		}
+	}
+
+	/// Whether this is a present retried without `NONBLOCK` after the device was busy, see [`super::CommitBusy`].
+	fn is_blocking_present(&self) -> bool {
+		let flags = AtomicCommitFlags::from_bits_truncate(self.flags);
+
+		flags.contains(AtomicCommitFlags::PAGE_FLIP_EVENT) && !flags.intersects(AtomicCommitFlags::NONBLOCK | AtomicCommitFlags::TEST_ONLY)
+	}
+
+	/// Whether both commits request the same state.
+	///
+	/// In fences are not compared since they depend on the renderer, not on the chosen configuration.
+	fn same_request(&self, other: &RecordedCommit) -> bool {
+		self.flags == other.flags && self.properties == other.properties && self.out_fences == other.out_fences
+	}
+
+	/// Framebuffer shown by `output` in this commit.
+	fn framebuffer_of(&self, output: &KmsOutput) -> Option<FramebufferHandle> {
+		let (plane, fb_id) = output.plane_fb_id()?;
+		let (plane, fb_id) = (RecordedObject::from(plane), u32::from(fb_id));
+
+		self.properties.iter().find_map(
+			|&(object, property, value)| if object == plane && property == fb_id {
+				drm::control::from_u32(value as u32)
+			} else {
+				None
+			}
+		)
+	}
+}
+
+/// Legacy modesetting call, framebuffer handles are only valid in the recorded session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RecordedLegacyCall {
+	SetCrtc { crtc: u32, framebuffer: u32, connectors: Vec<u32>, mode: RecordedMode },
+	PageFlip { crtc: u32, framebuffer: u32 }
+}
+impl RecordedLegacyCall {
+	fn set_crtc(crtc: CrtcHandle, framebuffer: FramebufferHandle, connectors: &[ConnectorHandle], mode: &Mode) -> Self {
+		RecordedLegacyCall::SetCrtc {
+			crtc: crtc.into(),
+			framebuffer: framebuffer.into(),
+			connectors: raw_handles(connectors),
+			mode: mode.into()
+		}
+	}
+
+	fn page_flip(crtc: CrtcHandle, framebuffer: FramebufferHandle) -> Self {
+		RecordedLegacyCall::PageFlip { crtc: crtc.into(), framebuffer: framebuffer.into() }
+	}
+
+	fn crtc(&self) -> u32 {
+		match *self {
+			RecordedLegacyCall::SetCrtc { crtc, .. } => crtc,
+			RecordedLegacyCall::PageFlip { crtc, .. } => crtc
+		}
+	}
+
+	fn framebuffer(&self) -> u32 {
+		match *self {
+			RecordedLegacyCall::SetCrtc { framebuffer, .. } => framebuffer,
+			RecordedLegacyCall::PageFlip { framebuffer, .. } => framebuffer
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RecordedEvent {
+	PageFlip { crtc: u32, frame: u32, time: Duration }
+}
+impl From<&DrmEvent> for RecordedEvent {
+	fn from(event: &DrmEvent) -> Self {
+		match *event {
+			DrmEvent::PageFlip { crtc, frame, time } => RecordedEvent::PageFlip { crtc: crtc.into(), frame, time }
+		}
+	}
+}
+impl RecordedEvent {
+	fn to_event(&self) -> anyhow::Result<DrmEvent> {
+		match *self {
+			RecordedEvent::PageFlip { crtc, frame, time } => Ok(DrmEvent::PageFlip { crtc: handle(crtc)?, frame, time })
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Entry {
+	Resources { answer: RecordedResources },
+	Connector { handle: u32, answer: RecordedConnector },
+	Encoder { handle: u32, answer: RecordedEncoder },
+	Crtc { handle: u32, answer: RecordedCrtc },
+	Planes { answer: Vec<u32> },
+	Plane { handle: u32, answer: RecordedPlane },
+	Properties { object: RecordedObject, answer: Vec<RecordedProperty> },
+	Blob { blob: u64, answer: Vec<u8> },
+	ModeBlob { mode: RecordedMode, answer: u64 },
+	Commit { commit: RecordedCommit },
+	Atomic { answer: bool },
+	Legacy {
+		call: RecordedLegacyCall,
+		/// rejected by the device
+		#[serde(default)]
+		failed: bool
+	},
+	Events { answer: Vec<RecordedEvent> },
+	/// formats and modifiers the renderer can import, as fourcc codes with their modifiers
+	ImporterFormats { formats: Vec<(u32, Vec<u64>)> }
+}
+
+fn encoder_kind(kind: EncoderKind) -> u32 {
+	#[allow(unreachable_patterns)]
+	match kind {
+		EncoderKind::None => 0,
+		EncoderKind::DAC => 1,
+		EncoderKind::TMDS => 2,
+		EncoderKind::LVDS => 3,
+		EncoderKind::TVDAC => 4,
+		EncoderKind::Virtual => 5,
+		EncoderKind::DSI => 6,
+		EncoderKind::DPMST => 7,
+		EncoderKind::DPI => 8,
+		_ => 0
+	}
+}
+
+fn connector_state(state: ConnectorState) -> u32 {
+	match state {
+		ConnectorState::Connected => 1,
+		ConnectorState::Disconnected => 2,
+		ConnectorState::Unknown => 3
+	}
+}
+
+/// Backend forwarding to `inner` and appending every answer and commit to a capture file.
+pub struct RecordingBackend<B> {
+	inner: B,
+	output: RefCell<BufWriter<fs::File>>
+}
+impl<B: DrmBackend> RecordingBackend<B> {
+	pub fn new(inner: B, path: &Path) -> anyhow::Result<Self> {
+		let file = fs::File::create(path).context("Failed to create capture file")?;
+		log::info!("Recording drm traffic into {}", path.display());
+
+		Ok(
+			RecordingBackend {
+				inner,
+				output: RefCell::new(BufWriter::new(file))
+			}
+		)
+	}
+
+	fn record(&self, entry: Entry) {
+		let mut output = self.output.borrow_mut();
+
+		let result = serde_json::to_writer(&mut *output, &entry).map_err(anyhow::Error::from).and_then(
+			|_| {
+				output.write_all(b"\n")?;
+				output.flush()?;
+
+				Ok(())
+			}
+		);
+		if let Err(err) = result {
+			log::error!("Failed to record drm traffic: {}", err);
+		}
+	}
+}
+impl<B: DrmBackend> DrmBackend for RecordingBackend<B> {
+	fn is_atomic(&self) -> bool {
+		let answer = self.inner.is_atomic();
+		self.record(
+			Entry::Atomic { answer }
+		);
+
+		answer
+	}
+
+	fn resources(&self) -> anyhow::Result<ResourcesDesc> {
+		let answer = self.inner.resources()?;
+		self.record(
+			Entry::Resources {
+				answer: RecordedResources {
+					connectors: raw_handles(&answer.connectors),
+					encoders: raw_handles(&answer.encoders),
+					crtcs: raw_handles(&answer.crtcs)
+				}
+			}
+		);
+
+		Ok(answer)
+	}
+
+	fn connector(&self, handle: ConnectorHandle) -> anyhow::Result<ConnectorDesc> {
+		let answer = self.inner.connector(handle)?;
+		self.record(
+			Entry::Connector {
+				handle: handle.into(),
+				answer: RecordedConnector {
+					interface: ConnectorDesc::interface_type(answer.interface),
+					interface_id: answer.interface_id,
+					state: connector_state(answer.state),
+					modes: answer.modes.iter().map(RecordedMode::from).collect(),
+					encoders: raw_handles(&answer.encoders),
+					current_encoder: answer.current_encoder.map(Into::into)
+				}
+			}
+		);
+
+		Ok(answer)
+	}
+
+	fn encoder(&self, handle: EncoderHandle) -> anyhow::Result<EncoderDesc> {
+		let answer = self.inner.encoder(handle)?;
+		self.record(
+			Entry::Encoder {
+				handle: handle.into(),
+				answer: RecordedEncoder {
+					kind: encoder_kind(answer.kind),
+					crtc: answer.crtc.map(Into::into),
+					possible_crtcs: raw_handles(&answer.possible_crtcs)
+				}
+			}
+		);
+
+		Ok(answer)
+	}
+
+	fn crtc(&self, handle: CrtcHandle) -> anyhow::Result<CrtcDesc> {
+		let answer = self.inner.crtc(handle)?;
+		self.record(
+			Entry::Crtc {
+				handle: handle.into(),
+				answer: RecordedCrtc {
+					mode: answer.mode.as_ref().map(RecordedMode::from),
+					framebuffer: answer.framebuffer.map(Into::into),
+					position: answer.position
+				}
+			}
+		);
+
+		Ok(answer)
+	}
+
+	fn planes(&self) -> anyhow::Result<Vec<PlaneHandle>> {
+		let answer = self.inner.planes()?;
+		self.record(
+			Entry::Planes { answer: raw_handles(&answer) }
+		);
+
+		Ok(answer)
+	}
+
+	fn plane(&self, handle: PlaneHandle) -> anyhow::Result<PlaneDesc> {
+		let answer = self.inner.plane(handle)?;
+		self.record(
+			Entry::Plane {
+				handle: handle.into(),
+				answer: RecordedPlane {
+					crtc: answer.crtc.map(Into::into),
+					framebuffer: answer.framebuffer.map(Into::into),
+					possible_crtcs: raw_handles(&answer.possible_crtcs),
+					formats: answer.formats.clone()
+				}
+			}
+		);
+
+		Ok(answer)
+	}
+
+	fn properties(&self, object: ObjectHandle) -> anyhow::Result<Vec<PropertyDesc>> {
+		let answer = self.inner.properties(object)?;
+		self.record(
+			Entry::Properties {
+				object: object.into(),
+				answer: answer.iter().map(
+					|property| RecordedProperty {
+						handle: property.handle.into(),
+						name: property.name.clone(),
+						kind: property.kind.into(),
+						value: property.value,
+						enum_values: property.enum_values.clone()
+					}
+				).collect()
+			}
+		);
+
+		Ok(answer)
+	}
+
+	fn property_blob(&self, blob: u64) -> anyhow::Result<Vec<u8>> {
+		let answer = self.inner.property_blob(blob)?;
+		self.record(
+			Entry::Blob { blob, answer: answer.clone() }
+		);
+
+		Ok(answer)
+	}
+
+	fn create_mode_blob(&self, mode: &Mode) -> anyhow::Result<u64> {
+		let answer = self.inner.create_mode_blob(mode)?;
+		self.record(
+			Entry::ModeBlob { mode: mode.into(), answer }
+		);
+
+		Ok(answer)
+	}
+
+	fn destroy_property_blob(&self, blob: u64) -> anyhow::Result<()> {
+		// has no answer to replay
+		self.inner.destroy_property_blob(blob)
+	}
+
+	fn atomic_commit(&self, flags: AtomicCommitFlags, request: &CommitRequest) -> anyhow::Result<Vec<Option<SyncFile>>> {
+		let result = self.inner.atomic_commit(flags, request);
+		self.record(
+			Entry::Commit { commit: RecordedCommit::new(flags, request, result.is_err()) }
+		);
+
+		result
+	}
+
+	fn set_crtc(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle, connectors: &[ConnectorHandle], mode: Mode) -> anyhow::Result<()> {
+		let result = self.inner.set_crtc(crtc, framebuffer, connectors, mode);
+		self.record(
+			Entry::Legacy { call: RecordedLegacyCall::set_crtc(crtc, framebuffer, connectors, &mode), failed: result.is_err() }
+		);
+
+		result
+	}
+
+	fn page_flip(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle) -> anyhow::Result<()> {
+		let result = self.inner.page_flip(crtc, framebuffer);
+		self.record(
+			Entry::Legacy { call: RecordedLegacyCall::page_flip(crtc, framebuffer), failed: result.is_err() }
+		);
+
+		result
+	}
+
+	fn receive_events(&self) -> anyhow::Result<Vec<DrmEvent>> {
+		let answer = self.inner.receive_events()?;
+		self.record(
+			Entry::Events { answer: answer.iter().map(RecordedEvent::from).collect() }
+		);
+
+		Ok(answer)
+	}
+
+	fn note_importer_formats(&self, formats: &FormatTable) {
+		self.inner.note_importer_formats(formats);
+		self.record(
+			Entry::ImporterFormats { formats: formats.raw().to_vec() }
+		);
+	}
+}
+
+/// Backend answering queries from a capture and checking commits against the recorded ones.
+///
+/// Queries are answered with the last recorded answer for the same object, so the code under test does not need to
+/// ask in exactly the same order. Commits and legacy calls must match the recorded ones in order.
+#[derive(Default)]
+pub struct ReplayBackend {
+	resources: Option<RecordedResources>,
+	connectors: HashMap<u32, RecordedConnector>,
+	encoders: HashMap<u32, RecordedEncoder>,
+	crtcs: HashMap<u32, RecordedCrtc>,
+	planes: Option<Vec<u32>>,
+	plane_infos: HashMap<u32, RecordedPlane>,
+	properties: HashMap<RecordedObject, Vec<RecordedProperty>>,
+	blobs: HashMap<u64, Vec<u8>>,
+	mode_blobs: Vec<(RecordedMode, u64)>,
+	commits: Vec<RecordedCommit>,
+	next_commit: RefCell<usize>,
+	/// recorded answer of [`DrmBackend::is_atomic`], captures predating the legacy path are atomic
+	atomic: Option<bool>,
+	/// legacy calls and whether they failed
+	legacy_calls: Vec<(RecordedLegacyCall, bool)>,
+	next_legacy_call: RefCell<usize>,
+	/// answered in recorded order
+	events: RefCell<VecDeque<Vec<RecordedEvent>>>,
+	/// `None` if modifiers were not negotiated when recording
+	importer_formats: Option<FormatTable>
+}
+impl ReplayBackend {
+	pub fn load(path: &Path) -> anyhow::Result<Self> {
+		let file = fs::File::open(path).context("Failed to open capture file")?;
+
+		let mut result = ReplayBackend::default();
+		for (number, line) in BufReader::new(file).lines().enumerate() {
+			let line = line.context("Failed to read capture file")?;
+			if line.trim().is_empty() {
+				continue;
+			}
+
+			let entry: Entry = serde_json::from_str(&line).with_context(|| format!("Invalid capture entry on line {}", number + 1))?;
+			match entry {
+				Entry::Resources { answer } => { result.resources = Some(answer); }
+				Entry::Connector { handle, answer } => { result.connectors.insert(handle, answer); }
+				Entry::Encoder { handle, answer } => { result.encoders.insert(handle, answer); }
+				Entry::Crtc { handle, answer } => { result.crtcs.insert(handle, answer); }
+				Entry::Planes { answer } => { result.planes = Some(answer); }
+				Entry::Plane { handle, answer } => { result.plane_infos.insert(handle, answer); }
+				Entry::Properties { object, answer } => { result.properties.insert(object, answer); }
+				Entry::Blob { blob, answer } => { result.blobs.insert(blob, answer); }
+				Entry::ModeBlob { mode, answer } => { result.mode_blobs.push((mode, answer)); }
+				Entry::Commit { commit } => { result.commits.push(commit); }
+				Entry::Atomic { answer } => { result.atomic = Some(answer); }
+				Entry::Legacy { call, failed } => { result.legacy_calls.push((call, failed)); }
+				Entry::Events { answer } => { result.events.borrow_mut().push_back(answer); }
+				Entry::ImporterFormats { formats } => { result.importer_formats = Some(FormatTable::from_raw(formats)); }
+			}
+		}
+
+		Ok(result)
+	}
+
+	/// Framebuffers and failure of each recorded commit not replayed yet, in order, one framebuffer per output.
+	///
+	/// Framebuffer handles are only valid in the recorded session, so commits are rebuilt with these to compare them.
+	/// Fails if a commit sets planes other than those of the outputs, like layers and cursors do.
+	fn recorded_presents(&self, config: &KmsConfig) -> anyhow::Result<Vec<(Vec<FramebufferHandle>, bool)>> {
+		let planes: Vec<RecordedObject> = config.outputs().iter().filter_map(KmsOutput::plane_handle).map(
+			|plane| ObjectHandle::Plane(plane).into()
+		).collect();
+
+		self.commits.iter().enumerate().skip(*self.next_commit.borrow()).map(
+			|(index, commit)| {
+				let other_plane = commit.properties.iter().find(
+					|&&(object, _, _)| matches!(object, RecordedObject::Plane(_)) && !planes.contains(&object)
+				);
+				if let Some((plane, _, _)) = other_plane {
+					anyhow::bail!("Recorded commit {} sets {:?} which no output uses, layer and cursor commits cannot be replayed", index, plane);
+				}
+
+				let framebuffers = config.outputs().iter().map(
+					|output| commit.framebuffer_of(output).with_context(
+						|| format!("Recorded commit {} does not set FB_ID of the plane chosen for {}", index, output.name())
+					)
+				).collect::<anyhow::Result<Vec<_>>>()?;
+
+				Ok((framebuffers, commit.failed))
+			}
+		).collect()
+	}
+
+	/// Modeset flag and framebuffers of each recorded legacy present not replayed yet, in order, one framebuffer per output.
+	///
+	/// A present is one page flip per output, preceded by `set_crtc` of every output if it is a modeset.
+	fn recorded_legacy_presents(&self, config: &KmsConfig) -> anyhow::Result<Vec<(bool, Vec<FramebufferHandle>)>> {
+		let outputs = config.outputs();
+		let mut calls = self.legacy_calls.iter().skip(*self.next_legacy_call.borrow()).map(|(call, _)| call).peekable();
+
+		let mut result = Vec::new();
+		while calls.peek().is_some() {
+			let allow_modeset = matches!(calls.peek(), Some(RecordedLegacyCall::SetCrtc { .. }));
+			if allow_modeset {
+				for _ in outputs {
+					calls.next().context("Capture ends within a legacy modeset")?;
+				}
+			}
+
+			let framebuffers = outputs.iter().map(
+				|output| {
+					let call = calls.next().context("Capture ends within a legacy present")?;
+					if call.crtc() != u32::from(output.crtc.handle()) {
+						anyhow::bail!("Recorded legacy call {:?} does not target the crtc chosen for {}", call, output.name());
+					}
+
+					handle(call.framebuffer())
+				}
+			).collect::<anyhow::Result<Vec<_>>>()?;
+
+			result.push((allow_modeset, framebuffers));
+		}
+
+		Ok(result)
+	}
+
+	/// Framebuffer shown by `output` in the next recorded commit, to replay the test-only commits validating a configuration.
+	fn next_framebuffer(&self, output: &KmsOutput) -> anyhow::Result<FramebufferHandle> {
+		let index = *self.next_commit.borrow();
+		let commit = self.commits.get(index).context("Capture does not contain more commits")?;
+
+		commit.framebuffer_of(output).with_context(
+			|| format!("Recorded commit {} does not set FB_ID of the plane tested for {}", index, output.name())
+		)
+	}
+
+	fn replay_legacy_call(&self, replayed: RecordedLegacyCall) -> anyhow::Result<()> {
+		let mut next_call = self.next_legacy_call.borrow_mut();
+
+		let (recorded, failed) = self.legacy_calls.get(*next_call).with_context(|| format!("Unexpected legacy call {}, capture does not contain more legacy calls", *next_call))?;
+		if *recorded != replayed {
+			anyhow::bail!(
+				"Legacy call {} differs from capture\nrecorded: {:?}\nreplayed: {:?}",
+				*next_call, recorded, replayed
+			);
+		}
+
+		*next_call += 1;
+		if *failed {
+			anyhow::bail!("Legacy call {} failed when recorded", *next_call - 1);
+		}
+
+		Ok(())
+	}
+
+	/// Checks that every recorded commit and legacy call was replayed.
+	fn finish(&self) -> anyhow::Result<()> {
+		let replayed = *self.next_commit.borrow();
+		if replayed != self.commits.len() {
+			anyhow::bail!("Only {} of {} recorded commits were replayed", replayed, self.commits.len());
+		}
+		let replayed = *self.next_legacy_call.borrow();
+		if replayed != self.legacy_calls.len() {
+			anyhow::bail!("Only {} of {} recorded legacy calls were replayed", replayed, self.legacy_calls.len());
+		}
+
+		Ok(())
+	}
+}
+impl DrmBackend for ReplayBackend {
+	fn is_atomic(&self) -> bool {
+		self.atomic.unwrap_or(true)
+	}
+
+	fn resources(&self) -> anyhow::Result<ResourcesDesc> {
+		let recorded = self.resources.as_ref().context("Capture does not contain resources")?;
+
+		Ok(
+			ResourcesDesc {
+				connectors: handles(&recorded.connectors)?,
+				encoders: handles(&recorded.encoders)?,
+				crtcs: handles(&recorded.crtcs)?
+			}
+		)
+	}
+
+	fn connector(&self, handle: ConnectorHandle) -> anyhow::Result<ConnectorDesc> {
+		let raw: u32 = handle.into();
+		let recorded = self.connectors.get(&raw).with_context(|| format!("Capture does not contain connector {}", raw))?;
+
+		let state = match recorded.state {
+			1 => ConnectorState::Connected,
+			2 => ConnectorState::Disconnected,
+			_ => ConnectorState::Unknown
+		};
+
+		Ok(
+			ConnectorDesc {
+				handle,
+				interface: ConnectorInterface::from(recorded.interface),
+				interface_id: recorded.interface_id,
+				state,
+				modes: recorded.modes.iter().map(Mode::from).collect(),
+				encoders: handles(&recorded.encoders)?,
+				current_encoder: recorded.current_encoder.map(self::handle).transpose()?
+			}
+		)
+	}
+
+	fn encoder(&self, handle: EncoderHandle) -> anyhow::Result<EncoderDesc> {
+		let raw: u32 = handle.into();
+		let recorded = self.encoders.get(&raw).with_context(|| format!("Capture does not contain encoder {}", raw))?;
+
+		Ok(
+			EncoderDesc {
+				handle,
+				kind: EncoderKind::from(recorded.kind),
+				crtc: recorded.crtc.map(self::handle).transpose()?,
+				possible_crtcs: handles(&recorded.possible_crtcs)?
+			}
+		)
+	}
+
+	fn crtc(&self, handle: CrtcHandle) -> anyhow::Result<CrtcDesc> {
+		let raw: u32 = handle.into();
+		let recorded = self.crtcs.get(&raw).with_context(|| format!("Capture does not contain crtc {}", raw))?;
+
+		Ok(
+			CrtcDesc {
+				handle,
+				mode: recorded.mode.as_ref().map(Mode::from),
+				framebuffer: recorded.framebuffer.map(self::handle).transpose()?,
+				position: recorded.position
+			}
+		)
+	}
+
+	fn planes(&self) -> anyhow::Result<Vec<PlaneHandle>> {
+		handles(self.planes.as_ref().context("Capture does not contain planes")?)
+	}
+
+	fn plane(&self, handle: PlaneHandle) -> anyhow::Result<PlaneDesc> {
+		let raw: u32 = handle.into();
+		let recorded = self.plane_infos.get(&raw).with_context(|| format!("Capture does not contain plane {}", raw))?;
+
+		Ok(
+			PlaneDesc {
+				handle,
+				crtc: recorded.crtc.map(self::handle).transpose()?,
+				framebuffer: recorded.framebuffer.map(self::handle).transpose()?,
+				possible_crtcs: handles(&recorded.possible_crtcs)?,
+				formats: recorded.formats.clone()
+			}
+		)
+	}
+
+	fn properties(&self, object: ObjectHandle) -> anyhow::Result<Vec<PropertyDesc>> {
+		let recorded = self.properties.get(&object.into()).with_context(|| format!("Capture does not contain properties of {:?}", object))?;
+
+		recorded.iter().map(
+			|property| Ok(
+				PropertyDesc {
+					handle: handle(property.handle)?,
+					name: property.name.clone(),
+					kind: match property.kind {
+						// older captures only kept the values of enums
+						RecordedPropertyKind::Unknown if !property.enum_values.is_empty() => PropertyKind::Enum,
+						kind => kind.into()
+					},
+					value: property.value,
+					enum_values: property.enum_values.clone()
+				}
+			)
+		).collect()
+	}
+
+	fn property_blob(&self, blob: u64) -> anyhow::Result<Vec<u8>> {
+		self.blobs.get(&blob).cloned().with_context(|| format!("Capture does not contain blob {}", blob))
+	}
+
+	fn create_mode_blob(&self, mode: &Mode) -> anyhow::Result<u64> {
+		let mode = RecordedMode::from(mode);
+
+		self.mode_blobs.iter().find_map(
+			|(recorded, blob)| if *recorded == mode { Some(*blob) } else { None }
+		).with_context(|| format!("Capture does not contain a blob for mode {:?}", mode))
+	}
+
+	fn destroy_property_blob(&self, _blob: u64) -> anyhow::Result<()> {
+		Ok(())
+	}
+
+	fn atomic_commit(&self, flags: AtomicCommitFlags, request: &CommitRequest) -> anyhow::Result<Vec<Option<SyncFile>>> {
+		let mut next_commit = self.next_commit.borrow_mut();
+
+		let recorded = self.commits.get(*next_commit).with_context(|| format!("Unexpected commit {}, capture does not contain more commits", *next_commit))?;
+		let replayed = RecordedCommit::new(flags, request, false);
+		if !recorded.same_request(&replayed) {
+			anyhow::bail!(
+				"Commit {} differs from capture\nrecorded: {:?}\nreplayed: {:?}",
+				*next_commit, recorded, replayed
+			);
+		}
+
+		*next_commit += 1;
+		if recorded.failed {
+			anyhow::bail!("Commit {} failed when recorded", *next_commit - 1);
+		}
+
+		// recorded fences are long gone, presenting code falls back to page flip events
+		Ok(request.out_fences.iter().map(|_| None).collect())
+	}
+
+	fn set_crtc(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle, connectors: &[ConnectorHandle], mode: Mode) -> anyhow::Result<()> {
+		self.replay_legacy_call(RecordedLegacyCall::set_crtc(crtc, framebuffer, connectors, &mode))
+	}
+
+	fn page_flip(&self, crtc: CrtcHandle, framebuffer: FramebufferHandle) -> anyhow::Result<()> {
+		self.replay_legacy_call(RecordedLegacyCall::page_flip(crtc, framebuffer))
+	}
+
+	fn receive_events(&self) -> anyhow::Result<Vec<DrmEvent>> {
+		let recorded = self.events.borrow_mut().pop_front().context("Capture does not contain more events")?;
+
+		recorded.iter().map(RecordedEvent::to_event).collect()
+	}
+}
+
+/// Re-runs configuration selection, takeover and presenting against a capture and checks that every recorded commit is reproduced.
+///
+/// `options` should match the ones used when recording. Captures of runs with layers and cursors, see
+/// [`super::KmsContext::add_layer`] and [`super::KmsContext::enable_cursor`], and of presents retried blocking
+/// because the device was busy are rejected, since they depend on the application and on timing.
+/// Hotplugs and session switches are not reproduced either, so their commits differ.
+pub fn verify_capture(path: &Path, options: &KmsOptions) -> anyhow::Result<()> {
+	let replay = ReplayBackend::load(path)?;
+	if let Some(index) = replay.commits.iter().position(RecordedCommit::is_blocking_present) {
+		anyhow::bail!("Recorded commit {} is a present retried after the device was busy, which cannot be replayed", index);
+	}
+
+	let mut config = KmsConfig::choose(&replay, options).context("Failed to choose configuration from capture")?;
+	if !config.is_atomic() {
+		for (allow_modeset, framebuffers) in replay.recorded_legacy_presents(&config)? {
+			config.legacy_commit(&replay, allow_modeset, &framebuffers)?;
+		}
+		replay.finish()?;
+
+		log::info!("Legacy capture {} replayed successfully", path.display());
+		return Ok(());
+	}
+	let validate = |config: &mut KmsConfig, candidates: &[(DrmFourcc, DrmModifier)]| -> anyhow::Result<(DrmFourcc, DrmModifier)> {
+		let format = config.validate(
+			&replay,
+			options,
+			candidates,
+			|output, _, _| replay.next_framebuffer(output).map(|framebuffer| (framebuffer, ()))
+		).context("Failed to validate configuration from capture")?;
+		if config.is_mirrored() {
+			// the commit after validation probes whether mirroring works, see `KmsContext::with_session`
+			let (framebuffers, _) = replay.recorded_presents(config)?.into_iter().next().context("Capture does not contain the mirror test commit")?;
+			config.test_mirror(&replay, framebuffers[0])?;
+		}
+
+		Ok(format)
+	};
+
+	let (format, _) = validate(&mut config, &KmsConfig::default_candidates(options))?;
+	if let Some(ref importer) = replay.importer_formats {
+		// see `KmsContext::negotiate_modifiers`
+		config = KmsConfig::choose(&replay, options).context("Failed to choose configuration from capture")?;
+		let candidates = config.negotiated_candidates(&replay, format, importer)?;
+		validate(&mut config, &candidates)?;
+	}
+
+	// see `KmsContext::atomic_commit`, only the first present performs a modeset, unless it takes over the outputs
+	let mut takeover = super::KmsContext::probe_takeover(&replay, &config);
+	let mut first = true;
+	for (framebuffers, failed) in replay.recorded_presents(&config)? {
+		let (flags, request) = config.commit_request(first && !takeover, &framebuffers)?;
+		match replay.atomic_commit(flags, &request) {
+			Ok(_) => { first = false; }
+			// e.g. taking over without a modeset, retried with one
+			Err(_) if failed => { takeover = false; }
+			Err(err) => return Err(err)
+		}
+	}
+	replay.finish()?;
+
+	log::info!("Capture {} replayed successfully", path.display());
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use drm::control::ModeTypeFlags;
+
+	use super::super::fake::{FakeDevice, Topology, TopologyOptions, fake_mode};
+	use super::super::property::{PLANE_PROPERTIES, PropertyRegistry};
+	use super::*;
+
+	fn framebuffer(raw: u32) -> FramebufferHandle {
+		drm::control::from_u32(raw).unwrap()
+	}
+
+	/// Capture file of the test `name`, unique per test process.
+	fn capture_path(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("test_kmscube_{}_{}.jsonl", std::process::id(), name))
+	}
+
+	/// Records choosing and validating the default configuration of `device` followed by `presents` presents into `path`,
+	/// like `KmsContext` does.
+	fn record(device: FakeDevice, path: &Path, presents: u32) -> (RecordingBackend<FakeDevice>, KmsConfig) {
+		let backend = RecordingBackend::new(device, path).unwrap();
+		let options = KmsOptions::default();
+
+		let mut config = KmsConfig::choose(&backend, &options).unwrap();
+		config.validate(&backend, &options, &KmsConfig::default_candidates(&options), |_, _, _| Ok((framebuffer(100), ()))).unwrap();
+
+		let takeover = super::super::KmsContext::probe_takeover(&backend, &config);
+		for present in 0 .. presents {
+			let framebuffers = vec![framebuffer(101 + present % 2); config.outputs().len()];
+			let (flags, request) = config.commit_request(present == 0 && !takeover, &framebuffers).unwrap();
+			backend.atomic_commit(flags, &request).unwrap();
+			backend.receive_events().unwrap();
+		}
+
+		(backend, config)
+	}
+
+	#[test]
+	fn replay_capture() {
+		let path = capture_path("replay_capture");
+		record(Topology::new(&TopologyOptions::default()).device, &path, 3);
+
+		verify_capture(&path, &KmsOptions::default()).unwrap();
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn replay_takeover() {
+		let path = capture_path("replay_takeover");
+		let mut topology = Topology::new(&TopologyOptions { hdmi_crtc: Some(1), ..TopologyOptions::default() });
+		topology.device.set_crtc_mode(topology.crtcs[1], Some(fake_mode(1920, 1080, 60, ModeTypeFlags::PREFERRED | ModeTypeFlags::DRIVER)));
+		let (backend, _) = record(topology.device, &path, 2);
+
+		// neither present performs a modeset
+		let presents: Vec<AtomicCommitFlags> = backend.inner.commits().iter().map(|&(flags, _)| flags).filter(
+			|flags| !flags.contains(AtomicCommitFlags::TEST_ONLY)
+		).collect();
+		assert_eq!(presents.len(), 2);
+		assert!(presents.iter().all(|flags| !flags.contains(AtomicCommitFlags::ALLOW_MODESET)));
+
+		verify_capture(&path, &KmsOptions::default()).unwrap();
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn replay_detects_other_configuration() {
+		let path = capture_path("replay_detects_other_configuration");
+		let mut topology = Topology::new(&TopologyOptions::default());
+		let modes = vec![
+			fake_mode(1920, 1080, 60, ModeTypeFlags::PREFERRED | ModeTypeFlags::DRIVER),
+			fake_mode(1280, 720, 60, ModeTypeFlags::DRIVER)
+		];
+		topology.device.set_connector_modes(topology.hdmi, modes);
+		record(topology.device, &path, 3);
+
+		// asking for another mode changes every commit
+		let options = KmsOptions { mode: "1280x720".parse().unwrap(), ..KmsOptions::default() };
+		assert!(verify_capture(&path, &options).is_err());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn replay_rejects_cursor_commits() {
+		let path = capture_path("replay_rejects_cursor_commits");
+		let topology = Topology::new(&TopologyOptions { overlay_and_cursor: true, ..TopologyOptions::default() });
+		let cursor = topology.cursor.unwrap();
+		let (backend, _) = record(topology.device, &path, 1);
+
+		let properties = PropertyRegistry::query(&backend, ObjectHandle::Plane(cursor), &PLANE_PROPERTIES).unwrap();
+		let position = [(properties.handle("CRTC_X").unwrap(), 10), (properties.handle("CRTC_Y").unwrap(), 20)];
+		assert!(super::super::commit_plane_position(&backend, cursor, position).unwrap());
+
+		let error = verify_capture(&path, &KmsOptions::default()).unwrap_err().to_string();
+		assert!(error.contains("cannot be replayed"), "{}", error);
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn replay_rejects_blocking_presents() {
+		let path = capture_path("replay_rejects_blocking_presents");
+		let (backend, config) = record(Topology::new(&TopologyOptions::default()).device, &path, 1);
+
+		let (flags, request) = config.commit_request(false, &[framebuffer(102)]).unwrap();
+		backend.atomic_commit(flags - AtomicCommitFlags::NONBLOCK, &request).unwrap();
+
+		let error = verify_capture(&path, &KmsOptions::default()).unwrap_err().to_string();
+		assert!(error.contains("cannot be replayed"), "{}", error);
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,335 @@
+//! Saving the display state found when taking over outputs and restoring it on exit.
+//!
+//! Without restoring, the crtcs keep scanning out framebuffers which are destroyed on exit, so the console stays dark.
+//! The saved state is registered globally per device so that it is also restored from the panic hook, and termination
+//! signals only request an exit so that the presenting loop can stop and restore from a normal context.
+
+use std::{
+	os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+	sync::{Mutex, Once, PoisonError, atomic::{AtomicBool, Ordering}}
+};
+
+use anyhow::Context;
+
+use drm::control::{
+	atomic::AtomicCommitFlags,
+	connector::Handle as ConnectorHandle,
+	plane::Handle as PlaneHandle,
+	property::Handle as PropertyHandle
+};
+
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use super::{
+	KmsConfig,
+	backend::{DrmBackend, ObjectHandle, CommitRequest, CrtcDesc},
+	device::DrmDevice,
+	property::{CONNECTOR_PROPERTIES, CRTC_PROPERTIES, PLANE_PROPERTIES, PropertyRegistry, PropertySpec}
+};
+
+/// Properties of the presenting specs which are not saved: `MODE_ID` is restored from the saved mode, fences are no
+/// state and `zpos` is immutable on some drivers.
+const UNSAVED_PROPERTIES: [&str; 4] = ["MODE_ID", "OUT_FENCE_PTR", "IN_FENCE_FD", "zpos"];
+
+/// Property values of one object as found before taking over.
+#[derive(Debug, Clone)]
+struct SavedObject {
+	object: ObjectHandle,
+	properties: Vec<(PropertyHandle, u64)>
+}
+
+#[derive(Debug, Clone)]
+struct SavedCrtc {
+	crtc: CrtcDesc,
+	/// connectors driven by the crtc
+	connectors: Vec<ConnectorHandle>,
+	/// property `MODE_ID`, `None` with legacy modesetting
+	mode_id: Option<PropertyHandle>
+}
+
+/// Connector, crtc and plane state taken over by a [`super::KmsContext`].
+#[derive(Debug, Clone)]
+pub struct SavedState {
+	/// whether the state is restored with an atomic commit, otherwise with legacy `set_crtc`
+	atomic: bool,
+	crtcs: Vec<SavedCrtc>,
+	/// property values of connectors, crtcs and planes, empty with legacy modesetting
+	objects: Vec<SavedObject>
+}
+impl SavedState {
+	pub fn new(atomic: bool) -> Self {
+		SavedState {
+			atomic,
+			crtcs: Vec::new(),
+			objects: Vec::new()
+		}
+	}
+
+	/// Saves the state of the objects used by `config` which have not been saved yet.
+	///
+	/// Must be called before presenting on them, objects saved earlier keep their first saved state.
+	pub fn save(&mut self, backend: &(impl DrmBackend + ?Sized), config: &KmsConfig) -> anyhow::Result<()> {
+		let resources = backend.resources()?;
+
+		for output in config.outputs() {
+			let crtc = output.crtc.handle();
+			if !self.crtcs.iter().any(|saved| saved.crtc.handle == crtc) {
+				let mut connectors = Vec::new();
+				for &connector in resources.connectors.iter() {
+					let encoder = match backend.connector(connector)?.current_encoder {
+						None => continue,
+						Some(encoder) => encoder
+					};
+					if backend.encoder(encoder)?.crtc == Some(crtc) {
+						connectors.push(connector);
+					}
+				}
+
+				let mode_id = if self.atomic {
+					for &connector in connectors.iter() {
+						self.save_object(backend, ObjectHandle::Connector(connector), &CONNECTOR_PROPERTIES)?;
+					}
+					let properties = self.save_object(backend, ObjectHandle::Crtc(crtc), &CRTC_PROPERTIES)?;
+
+					Some(properties.handle("MODE_ID")?)
+				} else {
+					None
+				};
+
+				log::debug!("Saving state of {:?} driving {:?}", crtc, connectors);
+				self.crtcs.push(
+					SavedCrtc {
+						crtc: backend.crtc(crtc)?,
+						connectors,
+						mode_id
+					}
+				);
+			}
+
+			if self.atomic {
+				// the connector may have been off or driven by another crtc
+				self.save_object(backend, ObjectHandle::Connector(output.connector.handle), &CONNECTOR_PROPERTIES)?;
+			}
+			if let Some(plane) = output.plane_handle() {
+				self.save_object(backend, ObjectHandle::Plane(plane), &PLANE_PROPERTIES)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Saves the state of `plane` unless it has been saved already, e.g. an overlay plane before showing a layer on it.
+	pub fn save_plane(&mut self, backend: &(impl DrmBackend + ?Sized), plane: PlaneHandle) -> anyhow::Result<()> {
+		self.save_object(backend, ObjectHandle::Plane(plane), &PLANE_PROPERTIES).map(|_| ())
+	}
+
+	/// Saves the values of `specs` of `object` which it has, unless it has already been saved.
+	fn save_object(
+		&mut self,
+		backend: &(impl DrmBackend + ?Sized),
+		object: ObjectHandle,
+		specs: &[PropertySpec]
+	) -> anyhow::Result<PropertyRegistry> {
+		let registry = PropertyRegistry::query(backend, object, specs)?;
+		if self.objects.iter().any(|saved| saved.object == object) {
+			return Ok(registry)
+		}
+
+		let properties = specs.iter().filter(|spec| !UNSAVED_PROPERTIES.contains(&spec.name)).filter_map(
+			|spec| registry.get(spec.name).map(|property| (property.handle, property.value))
+		).collect();
+		self.objects.push(SavedObject { object, properties });
+
+		Ok(registry)
+	}
+
+	/// Restores the saved state, blocking until it is applied.
+	///
+	/// Framebuffers presented since saving must still exist or no longer be scanned out.
+	pub fn restore(&self, backend: &(impl DrmBackend + ?Sized)) -> anyhow::Result<()> {
+		if self.atomic {
+			let mut request = CommitRequest::new();
+			for saved in self.objects.iter() {
+				for &(property, value) in saved.properties.iter() {
+					request.add(saved.object, property, value);
+				}
+			}
+			let mut blobs = Vec::with_capacity(self.crtcs.len());
+			let mut result = Ok(());
+			for saved in self.crtcs.iter() {
+				let blob = match saved.crtc.mode {
+					None => 0,
+					Some(ref mode) => match backend.create_mode_blob(mode) {
+						Ok(blob) => {
+							blobs.push(blob);
+							blob
+						}
+						Err(err) => {
+							result = Err(err);
+							break;
+						}
+					}
+				};
+				request.add(ObjectHandle::Crtc(saved.crtc.handle), saved.mode_id.expect("Atomic state saves MODE_ID"), blob);
+			}
+
+			if result.is_ok() {
+				result = backend.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, &request).map(|_| ());
+			}
+			// the committed state keeps its own references
+			for blob in blobs {
+				if let Err(err) = backend.destroy_property_blob(blob) {
+					log::warn!("Failed to destroy mode blob {}: {:#}", blob, err);
+				}
+			}
+			result.context("Failed to restore display state")?;
+		} else {
+			for saved in self.crtcs.iter() {
+				match (saved.crtc.framebuffer, saved.crtc.mode) {
+					(Some(framebuffer), Some(mode)) => {
+						backend.set_crtc(saved.crtc.handle, framebuffer, &saved.connectors, mode).context("Failed to restore display state")?;
+					}
+					// destroying the presented framebuffers turns the crtc off
+					_ => log::debug!("Leaving {:?} to be disabled, it was off before", saved.crtc.handle)
+				}
+			}
+		}
+
+		log::info!("Restored display state of {} crtcs", self.crtcs.len());
+		Ok(())
+	}
+}
+
+/// Device and saved state restored by the panic hook if no context has restored them first.
+struct Registered {
+	/// major and minor number of the device, see [`DrmDevice::devnum`]
+	devnum: (u64, u64),
+	device: OwnedFd,
+	state: SavedState
+}
+
+static REGISTERED: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static INSTALL_HANDLERS: Once = Once::new();
+
+/// Registers `state` of `device` with number `devnum` to be restored by [`take`] or the panic hook.
+///
+/// Fails if a state of the device is already registered, e.g. by another context driving it.
+/// The panic hook only restores registered states once [`install_handlers`] has been called.
+pub fn register(devnum: (u64, u64), device: &DrmDevice, state: SavedState) -> anyhow::Result<()> {
+	let mut registered = REGISTERED.lock().unwrap_or_else(PoisonError::into_inner);
+	if registered.iter().any(|registered| registered.devnum == devnum) {
+		anyhow::bail!("Display state of device {}:{} is already saved by another context", devnum.0, devnum.1);
+	}
+
+	let device = nix::unistd::dup(device.as_raw_fd()).context("Failed to duplicate drm fd")?;
+	// SAFETY: dup returned a new fd owned by us
+	let device = unsafe { OwnedFd::from_raw_fd(device) };
+
+	registered.push(Registered { devnum, device, state });
+
+	Ok(())
+}
+
+/// Updates the registered state of the device `devnum`, e.g. to save outputs taken over after a hotplug.
+pub fn update(devnum: (u64, u64), f: impl FnOnce(&mut SavedState) -> anyhow::Result<()>) -> anyhow::Result<()> {
+	let mut registered = REGISTERED.lock().unwrap_or_else(PoisonError::into_inner);
+
+	match registered.iter_mut().find(|registered| registered.devnum == devnum) {
+		None => Ok(()),
+		Some(registered) => f(&mut registered.state)
+	}
+}
+
+/// Unregisters and returns the registered state of the device `devnum`, `None` if it has already been restored.
+pub fn take(devnum: (u64, u64)) -> Option<SavedState> {
+	let mut registered = REGISTERED.lock().unwrap_or_else(PoisonError::into_inner);
+
+	let index = registered.iter().position(|registered| registered.devnum == devnum)?;
+	Some(registered.swap_remove(index).state)
+}
+
+/// Whether SIGINT or SIGTERM has been received since [`install_handlers`].
+///
+/// A second signal terminates immediately.
+pub fn exit_requested() -> bool {
+	EXIT_REQUESTED.load(Ordering::Relaxed)
+}
+
+extern "C" fn request_exit(_: nix::libc::c_int) {
+	EXIT_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Installs the SIGINT and SIGTERM handlers requesting an exit and the panic hook restoring registered states, once per process.
+pub fn install_handlers() {
+	INSTALL_HANDLERS.call_once(
+		|| {
+			// restart interrupted syscalls so that blocking reads of drm events do not fail
+			let action = SigAction::new(
+				SigHandler::Handler(request_exit),
+				SaFlags::SA_RESTART | SaFlags::SA_RESETHAND,
+				SigSet::empty()
+			);
+			for signal in [Signal::SIGINT, Signal::SIGTERM] {
+				// SAFETY: the handler only stores into an atomic
+				if let Err(err) = unsafe { signal::sigaction(signal, &action) } {
+					log::warn!("Failed to install {} handler, display state is not restored on it: {}", signal, err);
+				}
+			}
+
+			let previous = std::panic::take_hook();
+			std::panic::set_hook(
+				Box::new(
+					move |info| {
+						// a panic while updating the state must not deadlock
+						let registered = match REGISTERED.try_lock() {
+							Ok(mut registered) => std::mem::take(&mut *registered),
+							Err(_) => Vec::new()
+						};
+						for registered in registered {
+							let device = DrmDevice::from(registered.device);
+							if let Err(err) = registered.state.restore(&device) {
+								log::error!("Failed to restore display state after panic: {:#}", err);
+							}
+						}
+
+						previous(info)
+					}
+				)
+			);
+		}
+	);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs::File;
+
+	use super::*;
+
+	#[test]
+	fn registration_per_device() {
+		let device = DrmDevice::from(OwnedFd::from(File::open("/dev/null").unwrap()));
+		// not a real drm device number, other tests do not register states
+		let (first, second) = ((1000, 0), (1000, 1));
+
+		register(first, &device, SavedState::new(true)).unwrap();
+		register(second, &device, SavedState::new(false)).unwrap();
+		assert!(register(first, &device, SavedState::new(true)).is_err());
+
+		update(
+			second,
+			|saved| {
+				saved.atomic = true;
+				Ok(())
+			}
+		).unwrap();
+		assert!(take(second).unwrap().atomic);
+		assert!(take(second).is_none());
+		assert!(take(first).unwrap().atomic);
+
+		// restored states can be registered again
+		register(first, &device, SavedState::new(true)).unwrap();
+		assert!(take(first).is_some());
+	}
+}
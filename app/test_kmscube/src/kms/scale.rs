@@ -0,0 +1,123 @@
+//! Placement of presented buffers within the mode of an output, scaled by the plane.
+//!
+//! Buffers of another size than the mode, e.g. a fixed design resolution on a 4K TV or the size of the first output
+//! when mirroring, are letterboxed or pillarboxed so that their aspect ratio is preserved. Overscan insets shrink the
+//! area they are fitted into, for TVs cutting off the edges of the picture.
+
+use std::str::FromStr;
+
+use anyhow::Context;
+
+/// Borders left empty on each side of the mode, in pixels of the mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Insets {
+	pub left: u16,
+	pub top: u16,
+	pub right: u16,
+	pub bottom: u16
+}
+impl Insets {
+	pub fn is_empty(&self) -> bool {
+		*self == Insets::default()
+	}
+}
+impl FromStr for Insets {
+	type Err = anyhow::Error;
+
+	/// Parses `N` for every side, `H,V` for left and right and for top and bottom, or `L,T,R,B`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let values = s.split(',').map(
+			|value| value.trim().parse::<u16>().with_context(|| format!("Invalid inset \"{}\"", value))
+		).collect::<anyhow::Result<Vec<_>>>()?;
+
+		match values[..] {
+			[inset] => Ok(Insets { left: inset, top: inset, right: inset, bottom: inset }),
+			[horizontal, vertical] => Ok(Insets { left: horizontal, top: vertical, right: horizontal, bottom: vertical }),
+			[left, top, right, bottom] => Ok(Insets { left, top, right, bottom }),
+			_ => anyhow::bail!("Invalid insets \"{}\", expected N, H,V or L,T,R,B", s)
+		}
+	}
+}
+
+/// Largest rectangle with the aspect ratio of `source` centered in `destination`, as x, y, width and height.
+fn fit_rect(source: (u16, u16), destination: (u32, u32)) -> (u32, u32, u32, u32) {
+	let (source_width, source_height) = (source.0 as u64, source.1 as u64);
+	let (width, height) = (destination.0 as u64, destination.1 as u64);
+	if source_width == 0 || source_height == 0 {
+		// nothing to keep the aspect ratio of, also avoids dividing by zero
+		return (0, 0, width as u32, height as u32)
+	}
+
+	let (fit_width, fit_height) = if source_width * height > width * source_height {
+		(width, source_height * width / source_width)
+	} else {
+		(source_width * height / source_height, height)
+	};
+
+	(((width - fit_width) / 2) as u32, ((height - fit_height) / 2) as u32, fit_width as u32, fit_height as u32)
+}
+
+/// Where a buffer of `source` size is shown within a mode of `mode_size` without the `insets`, as x, y, width and height.
+///
+/// `None` if the insets leave nothing of the mode.
+pub fn place_rect(source: (u16, u16), mode_size: (u16, u16), insets: &Insets) -> Option<(u32, u32, u32, u32)> {
+	let width = (mode_size.0 as u32).checked_sub(insets.left as u32 + insets.right as u32).filter(|&width| width > 0)?;
+	let height = (mode_size.1 as u32).checked_sub(insets.top as u32 + insets.bottom as u32).filter(|&height| height > 0)?;
+	let (x, y, fit_width, fit_height) = fit_rect(source, (width, height));
+
+	// extreme aspect ratios would round to nothing
+	Some((insets.left as u32 + x, insets.top as u32 + y, fit_width.max(1), fit_height.max(1)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn letterbox() {
+		assert_eq!(place_rect((1920, 1080), (1920, 1440), &Insets::default()), Some((0, 180, 1920, 1080)));
+	}
+
+	#[test]
+	fn pillarbox() {
+		assert_eq!(place_rect((1280, 1024), (1920, 1080), &Insets::default()), Some((285, 0, 1350, 1080)));
+	}
+
+	#[test]
+	fn same_aspect_ratio_fills_mode() {
+		assert_eq!(place_rect((1280, 720), (1920, 1080), &Insets::default()), Some((0, 0, 1920, 1080)));
+	}
+
+	#[test]
+	fn insets_shrink_area() {
+		let insets = Insets { left: 32, top: 18, right: 32, bottom: 18 };
+		assert_eq!(place_rect((1920, 1080), (1920, 1080), &insets), Some((32, 18, 1856, 1044)));
+	}
+
+	#[test]
+	fn insets_consuming_mode() {
+		let horizontal = Insets { left: 960, top: 0, right: 960, bottom: 0 };
+		assert_eq!(place_rect((1920, 1080), (1920, 1080), &horizontal), None);
+
+		let vertical = Insets { left: 0, top: 1000, right: 0, bottom: 1000 };
+		assert_eq!(place_rect((1920, 1080), (1920, 1080), &vertical), None);
+	}
+
+	#[test]
+	fn empty_source() {
+		assert_eq!(fit_rect((0, 1080), (1920, 1080)), (0, 0, 1920, 1080));
+		assert_eq!(fit_rect((1920, 0), (1920, 1080)), (0, 0, 1920, 1080));
+	}
+
+	#[test]
+	fn parse_insets() {
+		assert_eq!("16".parse::<Insets>().unwrap(), Insets { left: 16, top: 16, right: 16, bottom: 16 });
+		assert_eq!("32, 18".parse::<Insets>().unwrap(), Insets { left: 32, top: 18, right: 32, bottom: 18 });
+		assert_eq!("1,2,3,4".parse::<Insets>().unwrap(), Insets { left: 1, top: 2, right: 3, bottom: 4 });
+		assert!("0".parse::<Insets>().unwrap().is_empty());
+
+		assert!("1,2,3".parse::<Insets>().is_err());
+		assert!("-1".parse::<Insets>().is_err());
+		assert!("".parse::<Insets>().is_err());
+	}
+}
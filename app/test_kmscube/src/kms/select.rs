@@ -0,0 +1,752 @@
+use std::{iter, str::FromStr};
+
+use drm::control::{
+	connector::{Handle as ConnectorHandle, State as ConnectorState},
+	encoder::Handle as EncoderHandle,
+	crtc::Handle as CrtcHandle,
+	plane::Handle as PlaneHandle
+};
+
+use super::{
+	backend::{DrmBackend, ObjectHandle, ConnectorDesc, PlaneDesc, PropertyDesc},
+	device::IndexedCrtc,
+	property::{PropertyRegistry, PropertySpec, TypedValue}
+};
+
+/// Which connector to drive.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ConnectorSelector {
+	/// first connected connector
+	#[default]
+	Any,
+	/// kernel connector name, interface type and id, e.g. `HDMI-A-2`
+	Name(String),
+	/// monitor name from the connector EDID
+	Monitor(String),
+	/// first connected connector matching one of the selectors, in order of preference
+	Preference(Vec<ConnectorSelector>)
+}
+impl ConnectorSelector {
+	fn needs_edid(&self) -> bool {
+		match self {
+			ConnectorSelector::Any | ConnectorSelector::Name(_) => false,
+			ConnectorSelector::Monitor(_) => true,
+			ConnectorSelector::Preference(selectors) => selectors.iter().any(Self::needs_edid)
+		}
+	}
+
+	fn alternatives(&self) -> Vec<&ConnectorSelector> {
+		match self {
+			ConnectorSelector::Preference(selectors) => selectors.iter().flat_map(Self::alternatives).collect(),
+			selector => vec![selector]
+		}
+	}
+
+	fn matches(&self, connector: &ConnectorDesc, monitor: Option<&str>) -> bool {
+		match self {
+			ConnectorSelector::Any => true,
+			ConnectorSelector::Name(name) => connector.name().eq_ignore_ascii_case(name),
+			ConnectorSelector::Monitor(name) => monitor == Some(name.as_str()),
+			ConnectorSelector::Preference(selectors) => selectors.iter().any(|selector| selector.matches(connector, monitor))
+		}
+	}
+}
+
+/// Splits `s` at commas outside of double quotes.
+fn split_unquoted(s: &str) -> anyhow::Result<Vec<&str>> {
+	let mut parts = Vec::new();
+	let mut start = 0;
+	let mut quoted = false;
+	for (index, c) in s.char_indices() {
+		match c {
+			'"' => quoted = !quoted,
+			',' if !quoted => {
+				parts.push(&s[start .. index]);
+				start = index + 1;
+			}
+			_ => ()
+		}
+	}
+	if quoted {
+		anyhow::bail!("Unterminated quote in connector selector \"{}\"", s);
+	}
+	parts.push(&s[start ..]);
+
+	Ok(parts)
+}
+
+/// Removes the double quotes around `s`, if any.
+fn unquote(s: &str) -> &str {
+	s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(s)
+}
+
+impl FromStr for ConnectorSelector {
+	type Err = anyhow::Error;
+
+	/// Parses `any`, a connector name like `HDMI-A-2`, `monitor:<EDID name>` or a comma separated preference list of those.
+	///
+	/// Names containing commas are put in double quotes, e.g. `monitor:"Acme, Inc"`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let alternatives = split_unquoted(s)?;
+		if alternatives.len() > 1 {
+			let selectors = alternatives.into_iter().map(str::parse).collect::<Result<Vec<_>, _>>()?;
+			return Ok(ConnectorSelector::Preference(selectors))
+		}
+
+		let s = s.trim();
+		if s.is_empty() {
+			anyhow::bail!("Empty connector selector");
+		}
+
+		let selector = if s.eq_ignore_ascii_case("any") {
+			ConnectorSelector::Any
+		} else if let Some(monitor) = s.strip_prefix("monitor:") {
+			ConnectorSelector::Monitor(unquote(monitor).to_string())
+		} else {
+			ConnectorSelector::Name(unquote(s).to_string())
+		};
+
+		Ok(selector)
+	}
+}
+/// Wraps names containing commas in double quotes, so that they parse back to one selector.
+struct Quoted<'a>(&'a str);
+impl std::fmt::Display for Quoted<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if self.0.contains(',') {
+			write!(f, "\"{}\"", self.0)
+		} else {
+			write!(f, "{}", self.0)
+		}
+	}
+}
+
+impl std::fmt::Display for ConnectorSelector {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ConnectorSelector::Any => write!(f, "any"),
+			ConnectorSelector::Name(name) => write!(f, "{}", Quoted(name)),
+			ConnectorSelector::Monitor(name) => write!(f, "monitor:{}", Quoted(name)),
+			ConnectorSelector::Preference(selectors) => {
+				for (i, selector) in selectors.iter().enumerate() {
+					if i > 0 {
+						write!(f, ",")?;
+					}
+					write!(f, "{}", selector)?;
+				}
+
+				Ok(())
+			}
+		}
+	}
+}
+
+/// Extracts the monitor name from the display descriptor blocks of an EDID base block.
+pub fn edid_monitor_name(edid: &[u8]) -> Option<String> {
+	const DESCRIPTOR_OFFSETS: [usize; 4] = [54, 72, 90, 108];
+	const MONITOR_NAME_TAG: u8 = 0xFC;
+
+	for offset in DESCRIPTOR_OFFSETS {
+		let descriptor = edid.get(offset .. offset + 18)?;
+
+		// display descriptors have a zero pixel clock
+		if descriptor[0 .. 3] == [0, 0, 0] && descriptor[3] == MONITOR_NAME_TAG {
+			let name: String = descriptor[5 ..].iter()
+				.take_while(|&&b| b != b'\n')
+				.map(|&b| b as char)
+				.collect();
+
+			return Some(name.trim_end().to_string())
+		}
+	}
+
+	None
+}
+
+fn connector_monitor_name(
+	backend: &(impl DrmBackend + ?Sized),
+	connector: &ConnectorDesc
+) -> anyhow::Result<Option<String>> {
+	let properties = PropertyRegistry::query(backend, ObjectHandle::Connector(connector.handle), &[PropertySpec::optional("EDID")])?;
+
+	match properties.get("EDID") {
+		Some(property) if property.value != 0 => {
+			let edid = backend.property_blob(property.value)?;
+			Ok(edid_monitor_name(&edid))
+		}
+		_ => Ok(None)
+	}
+}
+
+fn connector_candidates(
+	backend: &(impl DrmBackend + ?Sized),
+	connectors: &[ConnectorHandle],
+	selector: &ConnectorSelector
+) -> anyhow::Result<Vec<(ConnectorDesc, Option<String>)>> {
+	let mut candidates = Vec::with_capacity(connectors.len());
+	for &handle in connectors {
+		let connector = backend.connector(handle)?;
+		let monitor = if selector.needs_edid() && connector.state == ConnectorState::Connected {
+			connector_monitor_name(backend, &connector)?
+		} else {
+			None
+		};
+		log::trace!(
+			"Connector: {} {:?}#{} [{:?}] {:?}",
+			connector.name(), connector.interface, connector.interface_id, connector.state, monitor
+		);
+
+		candidates.push((connector, monitor));
+	}
+
+	Ok(candidates)
+}
+
+fn no_connector_error(
+	selector: &ConnectorSelector,
+	failures: &[String],
+	candidates: &[(ConnectorDesc, Option<String>)]
+) -> anyhow::Error {
+	anyhow::anyhow!(
+		"Did not find a connected connector for \"{}\" ({}), available: {}",
+		selector,
+		failures.join(", "),
+		candidates.iter().map(
+			|(connector, _)| format!("{} [{:?}]", connector.name(), connector.state)
+		).collect::<Vec<_>>().join(", ")
+	)
+}
+
+pub fn choose_connector(
+	backend: &(impl DrmBackend + ?Sized),
+	connectors: &[ConnectorHandle],
+	selector: &ConnectorSelector
+) -> anyhow::Result<ConnectorDesc> {
+	let candidates = connector_candidates(backend, connectors, selector)?;
+
+	let mut failures = Vec::new();
+	for alternative in selector.alternatives() {
+		let mut matching = candidates.iter().filter(
+			|(connector, monitor)| alternative.matches(connector, monitor.as_deref())
+		).peekable();
+
+		if matching.peek().is_none() {
+			failures.push(format!("{}: no such connector", alternative));
+			continue;
+		}
+
+		let mut connected = matching.filter(|(connector, _)| connector.state == ConnectorState::Connected);
+		match connected.next() {
+			None => {
+				failures.push(format!("{}: not connected", alternative));
+			}
+			Some((connector, _)) => {
+				if connected.next().is_some() {
+					log::warn!("Found multiple connected connectors matching \"{}\". Choosing first one.", alternative);
+				}
+
+				log::info!("Choosing connector: {} ({:?}#{})", connector.name(), connector.interface, connector.interface_id);
+				return Ok(connector.clone())
+			}
+		}
+	}
+
+	Err(no_connector_error(selector, &failures, &candidates))
+}
+
+/// Chooses every connected connector matching `selector`, ordered by the selector preference.
+pub fn choose_connectors(
+	backend: &(impl DrmBackend + ?Sized),
+	connectors: &[ConnectorHandle],
+	selector: &ConnectorSelector
+) -> anyhow::Result<Vec<ConnectorDesc>> {
+	let candidates = connector_candidates(backend, connectors, selector)?;
+
+	let mut chosen: Vec<ConnectorDesc> = Vec::new();
+	let mut failures = Vec::new();
+	for alternative in selector.alternatives() {
+		let connected = candidates.iter().filter(
+			|(connector, monitor)| connector.state == ConnectorState::Connected && alternative.matches(connector, monitor.as_deref())
+		);
+
+		let mut found = false;
+		for (connector, _) in connected {
+			found = true;
+			if chosen.iter().any(|other| other.handle == connector.handle) {
+				continue;
+			}
+
+			log::info!("Choosing connector: {} ({:?}#{})", connector.name(), connector.interface, connector.interface_id);
+			chosen.push(connector.clone());
+		}
+
+		if !found {
+			failures.push(format!("{}: nothing connected", alternative));
+		}
+	}
+
+	if chosen.is_empty() {
+		return Err(no_connector_error(selector, &failures, &candidates))
+	}
+
+	Ok(chosen)
+}
+
+/// Crtcs the encoders can drive, in order of preference: for each encoder the crtc it currently drives, then the other
+/// possible ones.
+pub fn crtc_candidates(
+	backend: &(impl DrmBackend + ?Sized),
+	current_encoder: Option<EncoderHandle>,
+	encoders: &[EncoderHandle],
+	crtcs: &[CrtcHandle]
+) -> anyhow::Result<Vec<IndexedCrtc>> {
+	let mut candidates: Vec<IndexedCrtc> = Vec::new();
+
+	for handle in iter::once(current_encoder).chain(encoders.iter().copied().map(Some)) {
+		let handle = match handle {
+			None => continue,
+			Some(handle) => handle
+		};
+
+		let encoder = backend.encoder(handle)?;
+		log::trace!("Encoder: {:?} ({:?})", encoder.kind, encoder.crtc);
+
+		for handle in encoder.crtc.into_iter().chain(encoder.possible_crtcs.iter().copied()) {
+			if candidates.iter().any(|candidate| candidate.handle() == handle) {
+				continue;
+			}
+
+			// we need to find the index of this crtc
+			let index = match crtcs.iter().position(|&crtc| crtc == handle) {
+				None => {
+					log::warn!("Encoder crtc {:?} is not in the device crtc list", handle);
+					continue;
+				}
+				Some(index) => index
+			};
+
+			let crtc = backend.crtc(handle)?;
+			log::trace!("Crtc: {:?}", crtc);
+
+			candidates.push(
+				IndexedCrtc {
+					info: crtc,
+					index
+				}
+			);
+		}
+	}
+
+	Ok(candidates)
+}
+
+pub fn choose_crtc(
+	backend: &(impl DrmBackend + ?Sized),
+	current_encoder: Option<EncoderHandle>,
+	encoders: &[EncoderHandle],
+	crtcs: &[CrtcHandle],
+	taken: &[CrtcHandle]
+) -> anyhow::Result<IndexedCrtc> {
+	let chosen = crtc_candidates(backend, current_encoder, encoders, crtcs)?.into_iter().find(
+		|crtc| !taken.contains(&crtc.handle())
+	);
+
+	match chosen {
+		None => Err(anyhow::anyhow!("Did not find any free crtcs for chosen mode and available encoders")),
+		Some(crtc) => {
+			log::info!("Choosing crtc: {:?}", crtc);
+
+			Ok(crtc)
+		}
+	}
+}
+
+/// Returns whether the plane `type` property is `type_name`, e.g. `Primary` or `Overlay`.
+fn has_plane_type(backend: &(impl DrmBackend + ?Sized), plane: &PlaneDesc, type_name: &str) -> anyhow::Result<bool> {
+	let properties = PropertyRegistry::query(backend, ObjectHandle::Plane(plane.handle), &[PropertySpec::optional("type")])?;
+
+	match properties.get("type").map(PropertyDesc::typed_value) {
+		None => Ok(false),
+		Some(Ok(TypedValue::Enum(name))) => Ok(name == type_name),
+		Some(value) => {
+			log::warn!("Unexpected value for property \"type\": {:?}", value);
+			Ok(false)
+		}
+	}
+}
+
+/// Returns whether the plane `type` property is `Primary`.
+pub fn is_primary_plane(backend: &(impl DrmBackend + ?Sized), plane: &PlaneDesc) -> anyhow::Result<bool> {
+	has_plane_type(backend, plane, "Primary")
+}
+
+/// Planes compatible with `crtc` which are not taken, primary planes first.
+pub fn plane_candidates(
+	backend: &(impl DrmBackend + ?Sized),
+	crtc: &IndexedCrtc,
+	taken: &[PlaneHandle]
+) -> anyhow::Result<Vec<PlaneDesc>> {
+	let mut primary = Vec::new();
+	let mut other = Vec::new();
+
+	for handle in backend.planes()?.into_iter().filter(|handle| !taken.contains(handle)) {
+		let plane = backend.plane(handle)?;
+		log::trace!("Plane: {:?}", plane);
+
+		// check that plane is compatible with the crtc
+		if !plane.possible_crtcs.contains(&crtc.handle()) {
+			continue;
+		}
+
+		if is_primary_plane(backend, &plane)? {
+			primary.push(plane);
+		} else {
+			other.push(plane);
+		}
+	}
+	primary.append(&mut other);
+
+	Ok(primary)
+}
+
+pub fn choose_plane(
+	backend: &(impl DrmBackend + ?Sized),
+	crtc: &IndexedCrtc,
+	taken: &[PlaneHandle]
+) -> anyhow::Result<PlaneDesc> {
+	match plane_candidates(backend, crtc, taken)?.into_iter().next() {
+		None => Err(anyhow::anyhow!("Did not find any free planes for chosen crtc")),
+		Some(plane) => {
+			let is_primary = is_primary_plane(backend, &plane)?;
+			if !is_primary {
+				log::warn!("Did not find a primary plane for chosen crtc");
+			}
+
+			log::info!(
+				"Choosing plane: {:?}{}",
+				plane.handle,
+				if is_primary { " [Primary]" } else { "" }
+			);
+			Ok(plane)
+		}
+	}
+}
+
+/// Planes compatible with `crtc` other than `chosen`, primary planes first, to fall back to when `chosen` is rejected.
+pub fn fallback_planes(
+	backend: &(impl DrmBackend + ?Sized),
+	crtc: &IndexedCrtc,
+	chosen: PlaneHandle,
+	taken: &[PlaneHandle]
+) -> anyhow::Result<Vec<PlaneDesc>> {
+	let mut planes = plane_candidates(backend, crtc, taken)?;
+	planes.retain(|plane| plane.handle != chosen);
+
+	Ok(planes)
+}
+
+/// Planes of type `type_name`, e.g. `Overlay` or `Cursor`, compatible with `crtc` which can scan out `format`,
+/// skipping the ones already used.
+pub fn free_planes(
+	backend: &(impl DrmBackend + ?Sized),
+	crtc: CrtcHandle,
+	type_name: &str,
+	format: u32,
+	taken: &[PlaneHandle]
+) -> anyhow::Result<Vec<PlaneDesc>> {
+	let mut free = Vec::new();
+
+	for handle in backend.planes()?.into_iter().filter(|handle| !taken.contains(handle)) {
+		let plane = backend.plane(handle)?;
+		if !plane.possible_crtcs.contains(&crtc) || !plane.formats.contains(&format) {
+			continue;
+		}
+
+		if has_plane_type(backend, &plane, type_name)? {
+			free.push(plane);
+		}
+	}
+
+	Ok(free)
+}
+
+/// Crtc and plane pair an output can be driven with, the plane is `None` with legacy modesetting.
+pub type Route = (CrtcHandle, Option<PlaneHandle>);
+
+struct RouteSearch<'a> {
+	routes: &'a [Vec<Route>],
+	/// crtcs used by any route, no more outputs than these can be driven
+	crtc_count: usize,
+	current: Vec<Option<usize>>,
+	best: Vec<Option<usize>>,
+	best_count: usize
+}
+impl RouteSearch<'_> {
+	fn is_free(&self, route: Route) -> bool {
+		self.current.iter().zip(self.routes).filter_map(|(&chosen, routes)| chosen.map(|chosen| routes[chosen])).all(
+			|(crtc, plane)| crtc != route.0 && (plane.is_none() || plane != route.1)
+		)
+	}
+
+	fn search(&mut self, count: usize) {
+		let index = self.current.len();
+		if index == self.routes.len() {
+			if count > self.best_count {
+				self.best = self.current.clone();
+				self.best_count = count;
+			}
+			return
+		}
+
+		// nothing left to gain on this branch
+		let remaining = (self.routes.len() - index).min(self.crtc_count - count);
+		if count + remaining <= self.best_count {
+			return
+		}
+
+		for (chosen, &route) in self.routes[index].iter().enumerate() {
+			if !self.is_free(route) {
+				continue;
+			}
+
+			self.current.push(Some(chosen));
+			self.search(count + 1);
+			self.current.pop();
+
+			if self.best_count == self.routes.len() {
+				return
+			}
+		}
+
+		self.current.push(None);
+		self.search(count);
+		self.current.pop();
+	}
+}
+
+/// Chooses one of the `routes` of each output so that no crtc or plane is used twice, returns the index of the
+/// chosen route of each output or `None` for outputs left out.
+///
+/// Routes are in order of preference. As many outputs as possible are driven, earlier outputs and their preferred
+/// routes win when there are not enough crtcs or planes for all of them.
+pub fn assign_routes(routes: &[Vec<Route>]) -> Vec<Option<usize>> {
+	let mut crtcs: Vec<CrtcHandle> = routes.iter().flatten().map(|&(crtc, _)| crtc).collect();
+	crtcs.sort_unstable_by_key(|&crtc| u32::from(crtc));
+	crtcs.dedup();
+
+	let mut search = RouteSearch {
+		routes,
+		crtc_count: crtcs.len(),
+		current: Vec::with_capacity(routes.len()),
+		best: vec![None; routes.len()],
+		best_count: 0
+	};
+	search.search(0);
+
+	search.best
+}
+
+#[cfg(test)]
+mod tests {
+	use drm::buffer::DrmFourcc;
+
+	use super::super::fake::{Topology, TopologyOptions};
+	use super::*;
+
+	/// HDMI-A-1 connected and DP-1 disconnected, with an overlay and a cursor plane besides the primary ones.
+	fn topology() -> Topology {
+		Topology::new(&TopologyOptions { dp_state: ConnectorState::Disconnected, overlay_and_cursor: true, ..TopologyOptions::default() })
+	}
+
+	fn connectors(topology: &Topology) -> Vec<ConnectorHandle> {
+		topology.device.resources().unwrap().connectors
+	}
+
+	fn chosen_connector(topology: &Topology, selector: &str) -> anyhow::Result<ConnectorHandle> {
+		choose_connector(&topology.device, &connectors(topology), &selector.parse().unwrap()).map(|connector| connector.handle)
+	}
+
+	/// EDID base block with only a monitor name descriptor.
+	fn edid(monitor: &str) -> Vec<u8> {
+		let mut edid = vec![0; 128];
+		edid[54 .. 59].copy_from_slice(&[0, 0, 0, 0xFC, 0]);
+		let mut name = format!("{}\n", monitor).into_bytes();
+		name.resize(13, b' ');
+		edid[59 .. 72].copy_from_slice(&name);
+
+		edid
+	}
+
+	#[test]
+	fn any_connector() {
+		let topology = topology();
+
+		assert_eq!(chosen_connector(&topology, "any").unwrap(), topology.hdmi);
+	}
+
+	#[test]
+	fn connector_by_name() {
+		let mut topology = topology();
+
+		assert_eq!(chosen_connector(&topology, "hdmi-a-1").unwrap(), topology.hdmi);
+
+		let error = chosen_connector(&topology, "DP-1").unwrap_err().to_string();
+		assert!(error.contains("DP-1: not connected"), "{}", error);
+		let error = chosen_connector(&topology, "VGA-1").unwrap_err().to_string();
+		assert!(error.contains("VGA-1: no such connector"), "{}", error);
+
+		topology.device.set_connector_state(topology.dp, ConnectorState::Connected);
+		assert_eq!(chosen_connector(&topology, "DP-1").unwrap(), topology.dp);
+	}
+
+	#[test]
+	fn connector_by_monitor() {
+		let mut topology = topology();
+		topology.device.set_edid(topology.dp, edid("DELL U2720Q"));
+		topology.device.set_connector_state(topology.dp, ConnectorState::Connected);
+
+		assert_eq!(chosen_connector(&topology, "monitor:DELL U2720Q").unwrap(), topology.dp);
+		assert!(chosen_connector(&topology, "monitor:DELL").is_err());
+	}
+
+	#[test]
+	fn connector_preference() {
+		let mut topology = topology();
+
+		assert_eq!(chosen_connector(&topology, "DP-1,HDMI-A-1").unwrap(), topology.hdmi);
+
+		topology.device.set_connector_state(topology.dp, ConnectorState::Connected);
+		assert_eq!(chosen_connector(&topology, "DP-1,HDMI-A-1").unwrap(), topology.dp);
+
+		let chosen: Vec<ConnectorHandle> = choose_connectors(&topology.device, &connectors(&topology), &"DP-1,any".parse().unwrap())
+			.unwrap().iter().map(|connector| connector.handle).collect();
+		assert_eq!(chosen, vec![topology.dp, topology.hdmi]);
+	}
+
+	#[test]
+	fn parse_selectors() {
+		assert_eq!("ANY".parse::<ConnectorSelector>().unwrap(), ConnectorSelector::Any);
+		assert_eq!(" HDMI-A-1 ".parse::<ConnectorSelector>().unwrap(), ConnectorSelector::Name("HDMI-A-1".to_string()));
+		assert_eq!(
+			"DP-1, monitor:LG TV".parse::<ConnectorSelector>().unwrap(),
+			ConnectorSelector::Preference(vec![ConnectorSelector::Name("DP-1".to_string()), ConnectorSelector::Monitor("LG TV".to_string())])
+		);
+
+		assert!("".parse::<ConnectorSelector>().is_err());
+		assert!("DP-1,".parse::<ConnectorSelector>().is_err());
+		assert!("monitor:\"Acme".parse::<ConnectorSelector>().is_err());
+	}
+
+	#[test]
+	fn quoted_names_keep_commas() {
+		let selector: ConnectorSelector = "monitor:\"Acme, Inc\",HDMI-A-1".parse().unwrap();
+		assert_eq!(
+			selector,
+			ConnectorSelector::Preference(vec![ConnectorSelector::Monitor("Acme, Inc".to_string()), ConnectorSelector::Name("HDMI-A-1".to_string())])
+		);
+		assert_eq!(selector.to_string(), "monitor:\"Acme, Inc\",HDMI-A-1");
+		assert_eq!(selector.to_string().parse::<ConnectorSelector>().unwrap(), selector);
+
+		let mut topology = topology();
+		topology.device.set_edid(topology.dp, edid("Acme, Inc"));
+		topology.device.set_connector_state(topology.dp, ConnectorState::Connected);
+		assert_eq!(chosen_connector(&topology, "monitor:\"Acme, Inc\"").unwrap(), topology.dp);
+	}
+
+	#[test]
+	fn crtc_of_current_encoder() {
+		let mut topology = topology();
+		let crtcs = topology.crtcs;
+		let encoders = topology.encoders;
+
+		let crtc = choose_crtc(&topology.device, None, &encoders[.. 1], &crtcs, &[]).unwrap();
+		assert_eq!((crtc.handle(), crtc.index), (crtcs[0], 0));
+
+		let crtc = choose_crtc(&topology.device, None, &encoders[.. 1], &crtcs, &crtcs[.. 1]).unwrap();
+		assert_eq!((crtc.handle(), crtc.index), (crtcs[1], 1));
+
+		// keeps the crtc left behind by a previous user
+		topology.device.link(topology.hdmi, encoders[0], Some(crtcs[1]));
+		let crtc = choose_crtc(&topology.device, Some(encoders[0]), &encoders[.. 1], &crtcs, &[]).unwrap();
+		assert_eq!(crtc.handle(), crtcs[1]);
+
+		assert!(choose_crtc(&topology.device, None, &encoders[1 ..], &crtcs, &crtcs[1 ..]).is_err());
+	}
+
+	#[test]
+	fn crtc_candidates_in_preference_order() {
+		let mut topology = topology();
+		let crtcs = topology.crtcs;
+
+		let candidates: Vec<CrtcHandle> = crtc_candidates(&topology.device, None, &topology.encoders, &crtcs)
+			.unwrap().iter().map(IndexedCrtc::handle).collect();
+		assert_eq!(candidates, vec![crtcs[0], crtcs[1]]);
+
+		topology.device.link(topology.hdmi, topology.encoders[0], Some(crtcs[1]));
+		let candidates: Vec<(CrtcHandle, usize)> = crtc_candidates(&topology.device, Some(topology.encoders[0]), &topology.encoders[.. 1], &crtcs)
+			.unwrap().iter().map(|crtc| (crtc.handle(), crtc.index)).collect();
+		assert_eq!(candidates, vec![(crtcs[1], 1), (crtcs[0], 0)]);
+	}
+
+	#[test]
+	fn routes_backtrack() {
+		let topology = topology();
+		let [a, b] = topology.crtcs;
+		let (primary, overlay) = (Some(topology.primary[0]), topology.overlay);
+
+		// the first output prefers the only crtc the second one can use
+		assert_eq!(assign_routes(&[vec![(b, None), (a, None)], vec![(b, None)]]), vec![Some(1), Some(0)]);
+
+		// the second output can only use the plane the first one prefers
+		assert_eq!(assign_routes(&[vec![(a, overlay), (a, primary)], vec![(b, overlay)]]), vec![Some(1), Some(0)]);
+
+		// planes are only compared when there are any
+		assert_eq!(assign_routes(&[vec![(a, None)], vec![(b, None)]]), vec![Some(0), Some(0)]);
+	}
+
+	#[test]
+	fn routes_prefer_earlier_outputs() {
+		let topology = topology();
+		let [a, b] = topology.crtcs;
+
+		assert_eq!(assign_routes(&[vec![(a, None)], vec![(a, None)], vec![(b, None)]]), vec![Some(0), None, Some(0)]);
+		assert_eq!(assign_routes(&[vec![(a, None), (b, None)], vec![(a, None), (b, None)], vec![(a, None), (b, None)]]), vec![Some(0), Some(1), None]);
+		assert_eq!(assign_routes(&[Vec::new(), vec![(a, None)]]), vec![None, Some(0)]);
+		assert!(assign_routes(&[]).is_empty());
+	}
+
+	#[test]
+	fn primary_plane_preferred() {
+		let topology = topology();
+		let crtc = choose_crtc(&topology.device, None, &topology.encoders[.. 1], &topology.crtcs, &[]).unwrap();
+
+		assert_eq!(choose_plane(&topology.device, &crtc, &[]).unwrap().handle, topology.primary[0]);
+		assert_eq!(choose_plane(&topology.device, &crtc, &topology.primary[.. 1]).unwrap().handle, topology.overlay);
+
+		let fallback: Vec<PlaneHandle> = fallback_planes(&topology.device, &crtc, topology.primary[0], &[])
+			.unwrap().iter().map(|plane| plane.handle).collect();
+		assert_eq!(fallback, vec![topology.overlay, topology.cursor.unwrap()]);
+	}
+
+	#[test]
+	fn free_planes_by_type_and_format() {
+		let topology = topology();
+		let free = |type_name: &str, format: DrmFourcc, taken: &[PlaneHandle]| -> Vec<PlaneHandle> {
+			free_planes(&topology.device, topology.crtcs[0], type_name, format as u32, taken)
+				.unwrap().iter().map(|plane| plane.handle).collect()
+		};
+
+		assert_eq!(free("Overlay", DrmFourcc::Xrgb8888, &[]), vec![topology.overlay.unwrap()]);
+		assert!(free("Overlay", DrmFourcc::Xrgb8888, &[topology.overlay.unwrap()]).is_empty());
+		assert!(free("Overlay", DrmFourcc::Nv12, &[]).is_empty());
+		assert_eq!(free("Cursor", DrmFourcc::Argb8888, &[]), vec![topology.cursor.unwrap()]);
+		assert_eq!(free("Primary", DrmFourcc::Xrgb8888, &[]), vec![topology.primary[0]]);
+	}
+
+	#[test]
+	fn monitor_name() {
+		assert_eq!(edid_monitor_name(&edid("LG TV")), Some("LG TV".to_string()));
+		assert_eq!(edid_monitor_name(&[0; 128]), None);
+		assert_eq!(edid_monitor_name(&[0; 64]), None);
+	}
+}
@@ -0,0 +1,317 @@
+//! Opening drm devices through a seat session and following VT switches.
+//!
+//! With libseat, seatd or logind open the device for unprivileged users, hand out and revoke drm master and switch
+//! the VT into graphics mode. The direct session opens devices itself, which needs root, and never pauses.
+
+use std::{
+	fs,
+	os::unix::io::{AsRawFd, OwnedFd},
+	path::Path,
+	str::FromStr
+};
+#[cfg(feature = "libseat")]
+use std::{
+	cell::RefCell,
+	collections::VecDeque,
+	os::unix::io::AsFd,
+	path::PathBuf,
+	rc::Rc,
+	time::{Duration, Instant}
+};
+
+use anyhow::Context;
+
+/// Change of the session state, see [`Session::dispatch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+	/// the session has been disabled, e.g. by switching to another VT, devices must not be used until resumed
+	Paused,
+	/// the session is active again, another session may have changed the display state meanwhile
+	Resumed
+}
+
+/// Which session to open devices through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionKind {
+	/// libseat if a seat can be opened, direct access otherwise
+	#[default]
+	Auto,
+	Libseat,
+	Direct
+}
+impl FromStr for SessionKind {
+	type Err = anyhow::Error;
+
+	/// Parses `auto`, `libseat` or `direct`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"auto" => Ok(SessionKind::Auto),
+			"libseat" => Ok(SessionKind::Libseat),
+			"direct" => Ok(SessionKind::Direct),
+			_ => anyhow::bail!("Unknown session \"{}\", expected auto, libseat or direct", s)
+		}
+	}
+}
+
+/// Grants access to devices of the seat and reports when it is taken away.
+pub trait Session {
+	/// Opens the device at `path` for reading and writing, as drm master if it is a primary node.
+	fn open_device(&mut self, path: &Path) -> anyhow::Result<OwnedFd>;
+
+	/// Gives up the device at `path` opened by [`Self::open_device`], e.g. after probing it.
+	///
+	/// The fds handed out for it must have been closed, otherwise the device may stay open.
+	fn close_device(&mut self, path: &Path) -> anyhow::Result<()>;
+
+	/// Handles pending session events without blocking and returns them in order.
+	///
+	/// After [`SessionEvent::Paused`], stop using the devices and call [`Self::acknowledge_pause`].
+	fn dispatch(&mut self) -> anyhow::Result<Vec<SessionEvent>>;
+
+	/// Confirms that the devices are no longer used after a pause, so that the switch can complete.
+	fn acknowledge_pause(&mut self) -> anyhow::Result<()>;
+
+	fn is_active(&self) -> bool;
+}
+
+/// Opens a session of the given kind.
+pub fn open(kind: SessionKind) -> anyhow::Result<Box<dyn Session>> {
+	match kind {
+		SessionKind::Direct => Ok(Box::new(DirectSession::new())),
+		SessionKind::Libseat => open_libseat(),
+		SessionKind::Auto => match open_libseat() {
+			Ok(session) => Ok(session),
+			Err(err) => {
+				log::warn!("Falling back to direct device access: {:#}", err);
+				Ok(Box::new(DirectSession::new()))
+			}
+		}
+	}
+}
+
+#[cfg(feature = "libseat")]
+fn open_libseat() -> anyhow::Result<Box<dyn Session>> {
+	Ok(Box::new(LibseatSession::new()?))
+}
+
+#[cfg(not(feature = "libseat"))]
+fn open_libseat() -> anyhow::Result<Box<dyn Session>> {
+	anyhow::bail!("Built without libseat support")
+}
+
+nix::ioctl_write_int_bad!(kd_set_mode, 0x4B3A);
+nix::ioctl_read_bad!(kd_get_mode, 0x4B3B, nix::libc::c_int);
+
+const KD_GRAPHICS: nix::libc::c_int = 1;
+
+/// Controlling VT switched into graphics mode, so that the console does not draw over the outputs.
+///
+/// Switches back to the previous mode on drop.
+#[derive(Debug)]
+struct VtGraphicsMode {
+	tty: fs::File,
+	previous: nix::libc::c_int
+}
+impl VtGraphicsMode {
+	const PATH: &'static str = "/dev/tty";
+
+	fn enter() -> anyhow::Result<Self> {
+		let tty = fs::OpenOptions::new().read(true).write(true).open(Self::PATH).with_context(|| format!("Failed to open {}", Self::PATH))?;
+
+		let mut previous = 0;
+		// SAFETY: KDGETMODE writes a single int, fails on terminals which are not VTs
+		unsafe { kd_get_mode(tty.as_raw_fd(), &mut previous) }.context("Controlling terminal is not a VT")?;
+		// SAFETY: KDSETMODE takes the mode by value
+		unsafe { kd_set_mode(tty.as_raw_fd(), KD_GRAPHICS as _) }.context("Failed to switch VT into graphics mode")?;
+
+		Ok(VtGraphicsMode { tty, previous })
+	}
+}
+impl Drop for VtGraphicsMode {
+	fn drop(&mut self) {
+		// SAFETY: KDSETMODE takes the mode by value
+		if let Err(err) = unsafe { kd_set_mode(self.tty.as_raw_fd(), self.previous as _) } {
+			log::error!("Failed to restore VT mode: {}", err);
+		}
+	}
+}
+
+/// Opens devices directly, which usually needs root.
+///
+/// VT switches are not followed, the VT is put into graphics mode if the process runs on one.
+#[derive(Debug)]
+pub struct DirectSession {
+	/// switched back on drop, `None` if not running on a VT, e.g. over ssh
+	_vt: Option<VtGraphicsMode>
+}
+impl DirectSession {
+	pub fn new() -> Self {
+		let vt = match VtGraphicsMode::enter() {
+			Ok(vt) => Some(vt),
+			Err(err) => {
+				log::debug!("Leaving VT mode alone: {:#}", err);
+				None
+			}
+		};
+
+		DirectSession { _vt: vt }
+	}
+}
+impl Session for DirectSession {
+	fn open_device(&mut self, path: &Path) -> anyhow::Result<OwnedFd> {
+		let file = fs::OpenOptions::new().read(true).write(true).open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+		Ok(OwnedFd::from(file))
+	}
+
+	fn close_device(&mut self, _path: &Path) -> anyhow::Result<()> {
+		Ok(())
+	}
+
+	fn dispatch(&mut self) -> anyhow::Result<Vec<SessionEvent>> {
+		Ok(Vec::new())
+	}
+
+	fn acknowledge_pause(&mut self) -> anyhow::Result<()> {
+		Ok(())
+	}
+
+	fn is_active(&self) -> bool {
+		true
+	}
+}
+
+/// Session of seatd or logind opened through libseat.
+#[cfg(feature = "libseat")]
+pub struct LibseatSession {
+	seat: libseat::Seat,
+	/// opened devices by path, closed on drop
+	devices: Vec<(PathBuf, libseat::Device)>,
+	/// events queued by the libseat callback
+	events: Rc<RefCell<VecDeque<SessionEvent>>>,
+	active: bool
+}
+#[cfg(feature = "libseat")]
+impl LibseatSession {
+	/// How long to wait for the seat to be enabled after opening it.
+	const ENABLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+	pub fn new() -> anyhow::Result<Self> {
+		let events: Rc<RefCell<VecDeque<SessionEvent>>> = Rc::default();
+
+		let queue = events.clone();
+		let seat = libseat::Seat::open(
+			move |_, event| {
+				let event = match event {
+					libseat::SeatEvent::Enable => SessionEvent::Resumed,
+					libseat::SeatEvent::Disable => SessionEvent::Paused
+				};
+				queue.borrow_mut().push_back(event);
+			}
+		).context("Failed to open libseat seat")?;
+
+		let mut session = LibseatSession {
+			seat,
+			devices: Vec::new(),
+			events,
+			active: false
+		};
+
+		// the seat is enabled asynchronously, swallow that first event
+		let start = Instant::now();
+		while !session.active {
+			let remaining = Self::ENABLE_TIMEOUT.checked_sub(start.elapsed()).context("Seat was not enabled in time")?;
+			session.seat.dispatch(remaining.as_millis() as i32).context("Failed to dispatch libseat events")?;
+			session.take_events();
+		}
+		log::info!("Opened seat {}", session.seat.name());
+
+		Ok(session)
+	}
+
+	/// Takes the queued events, updating whether the seat is active.
+	fn take_events(&mut self) -> Vec<SessionEvent> {
+		let events: Vec<SessionEvent> = self.events.borrow_mut().drain(..).collect();
+		if let Some(&last) = events.last() {
+			self.active = last == SessionEvent::Resumed;
+		}
+
+		events
+	}
+}
+#[cfg(feature = "libseat")]
+impl Session for LibseatSession {
+	fn open_device(&mut self, path: &Path) -> anyhow::Result<OwnedFd> {
+		let device = self.seat.open_device(&path).with_context(|| format!("Failed to open {} through libseat", path.display()))?;
+		let fd = device.as_fd().try_clone_to_owned().context("Failed to duplicate device fd")?;
+		self.devices.push((path.to_path_buf(), device));
+
+		Ok(fd)
+	}
+
+	fn close_device(&mut self, path: &Path) -> anyhow::Result<()> {
+		let index = self.devices.iter().position(|(opened, _)| opened == path).with_context(|| format!("Device {} is not open", path.display()))?;
+		let (_, device) = self.devices.swap_remove(index);
+
+		self.seat.close_device(device).with_context(|| format!("Failed to close {} through libseat", path.display()))
+	}
+
+	fn dispatch(&mut self) -> anyhow::Result<Vec<SessionEvent>> {
+		self.seat.dispatch(0).context("Failed to dispatch libseat events")?;
+
+		Ok(self.take_events())
+	}
+
+	fn acknowledge_pause(&mut self) -> anyhow::Result<()> {
+		self.seat.disable().context("Failed to disable seat")
+	}
+
+	fn is_active(&self) -> bool {
+		self.active
+	}
+}
+#[cfg(feature = "libseat")]
+impl Drop for LibseatSession {
+	fn drop(&mut self) {
+		for (_, device) in self.devices.drain(..) {
+			if let Err(err) = self.seat.close_device(device) {
+				log::warn!("Failed to close device through libseat: {}", err);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::fake::FakeSession;
+	use super::*;
+
+	#[test]
+	fn pause_and_resume() {
+		let script = FakeSession::default();
+		let mut session: Box<dyn Session> = Box::new(script.clone());
+		assert!(session.is_active());
+		assert!(session.dispatch().unwrap().is_empty());
+
+		script.push_event(SessionEvent::Paused);
+		assert_eq!(session.dispatch().unwrap(), vec![SessionEvent::Paused]);
+		assert!(!session.is_active());
+		session.acknowledge_pause().unwrap();
+		assert_eq!(script.acknowledged_pauses(), 1);
+
+		// switching away and back between two dispatches reports both, active afterwards
+		script.push_event(SessionEvent::Resumed);
+		script.push_event(SessionEvent::Paused);
+		script.push_event(SessionEvent::Resumed);
+		assert_eq!(session.dispatch().unwrap(), vec![SessionEvent::Resumed, SessionEvent::Paused, SessionEvent::Resumed]);
+		assert!(session.is_active());
+	}
+
+	#[test]
+	fn parse_session_kinds() {
+		assert_eq!("auto".parse::<SessionKind>().unwrap(), SessionKind::Auto);
+		assert_eq!("libseat".parse::<SessionKind>().unwrap(), SessionKind::Libseat);
+		assert_eq!("direct".parse::<SessionKind>().unwrap(), SessionKind::Direct);
+		assert!("logind".parse::<SessionKind>().is_err());
+	}
+}
@@ -0,0 +1,167 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use anyhow::Context;
+
+use drm::{
+	control::{framebuffer::Handle as FramebufferHandle, Device},
+	buffer::{DrmFourcc, DrmModifier}
+};
+use gbm::{AsRaw, BufferObject, BufferObjectFlags, Surface};
+
+use super::{framebuffer, KmsContext, KmsDevice, PresentFeedback, SyncFile};
+
+/// DRM framebuffer wrapping a surface buffer object, cached in the buffer object user data.
+///
+/// The surface reuses its buffer objects, so the framebuffer is created the first time a buffer is locked and destroyed together with the buffer object.
+pub struct SurfaceFramebuffer {
+	device: KmsDevice,
+	framebuffer: FramebufferHandle
+}
+impl Drop for SurfaceFramebuffer {
+	fn drop(&mut self) {
+		if let Err(err) = self.device.destroy_framebuffer(self.framebuffer) {
+			log::error!("Failed to destroy surface framebuffer: {}", err);
+		}
+	}
+}
+
+/// Presentation through a `gbm_surface` which is rendered into by an EGL window surface, like kmscube does.
+///
+/// Some drivers handle this path better than importing bare buffer objects into EGL.
+pub struct KmsSurface {
+	/// buffer currently being scanned out, released back to the surface once the next one has flipped
+	///
+	/// declared before `surface` so that it is released before the surface is destroyed
+	front_buffer: Option<BufferObject<SurfaceFramebuffer>>,
+	/// buffer locked for the commit in flight, becomes the front buffer once it has flipped
+	queued_buffer: Option<BufferObject<SurfaceFramebuffer>>,
+	/// commit which presented the queued buffer
+	queued_commit: Option<u64>,
+	/// signaled when rendering into the next buffer to lock completes
+	render_fence: Option<SyncFile>,
+	surface: Surface<SurfaceFramebuffer>,
+	device: KmsDevice,
+	/// render target index, see [`KmsContext::render_targets`]
+	target: usize,
+	size: [u32; 2],
+	is_first_frame: bool
+}
+impl KmsSurface {
+	pub(super) fn new(
+		device: KmsDevice,
+		target: usize,
+		(width, height): (u32, u32),
+		format: DrmFourcc,
+		modifier: DrmModifier,
+		is_first_frame: bool
+	) -> anyhow::Result<Self> {
+		log::trace!("Creating gbm surface with {:?} {:?} {:?}", modifier, format, (width, height));
+		let flags = BufferObjectFlags::RENDERING | BufferObjectFlags::SCANOUT;
+
+		let surface = match modifier {
+			DrmModifier::Invalid => device.create_surface(width, height, format, flags),
+			DrmModifier::Linear => device.create_surface(width, height, format, flags | BufferObjectFlags::LINEAR),
+			modifier => device.create_surface_with_modifiers(width, height, format, std::iter::once(modifier))
+		}.context("Failed to create gbm surface")?;
+
+		Ok(
+			KmsSurface {
+				front_buffer: None,
+				queued_buffer: None,
+				queued_commit: None,
+				render_fence: None,
+				surface,
+				device,
+				target,
+				size: [width, height],
+				is_first_frame
+			}
+		)
+	}
+
+	/// Raw `gbm_surface` pointer to be used as the EGL native window.
+	pub fn native_window(&self) -> *mut std::ffi::c_void {
+		self.surface.as_raw() as *mut _
+	}
+
+	pub fn size(&self) -> [u32; 2] {
+		self.size
+	}
+
+	pub(super) fn target(&self) -> usize {
+		self.target
+	}
+
+	pub(super) fn is_first_frame(&self) -> bool {
+		self.is_first_frame
+	}
+
+	fn framebuffer_for(&self, buffer: &mut BufferObject<SurfaceFramebuffer>) -> anyhow::Result<FramebufferHandle> {
+		if let Some(cached) = buffer.userdata().context("Failed to get buffer object user data")? {
+			return Ok(cached.framebuffer)
+		}
+
+		log::trace!("Creating framebuffer for surface buffer with {:?}", buffer.modifier());
+		let framebuffer = framebuffer::add_framebuffer(&self.device, buffer)?;
+
+		buffer.set_userdata(
+			SurfaceFramebuffer {
+				device: self.device.clone(),
+				framebuffer
+			}
+		).context("Failed to set buffer object user data")?;
+
+		Ok(framebuffer)
+	}
+
+	/// Locks the buffer rendered by the last `eglSwapBuffers` so that it can be committed.
+	pub(super) fn lock_next_buffer(&mut self) -> anyhow::Result<FramebufferHandle> {
+		// SAFETY: eglSwapBuffers must have been called on the EGL surface created over this surface
+		let mut buffer = unsafe { self.surface.lock_front_buffer() }.context("Failed to lock front buffer")?;
+		let framebuffer = self.framebuffer_for(&mut buffer)?;
+
+		// releases the buffer of a previously failed commit
+		self.queued_buffer = Some(buffer);
+
+		Ok(framebuffer)
+	}
+
+	/// Attaches a fence signaled when rendering into the buffer of the last `eglSwapBuffers` completes.
+	///
+	/// Create it after `eglSwapBuffers`, so that it covers all rendering into the buffer. The commit waits for it instead of
+	/// relying on implicit synchronization.
+	pub fn set_render_fence(&mut self, fence: SyncFile) {
+		self.render_fence = Some(fence);
+	}
+
+	pub(super) fn render_fence(&self) -> Option<RawFd> {
+		self.render_fence.as_ref().map(SyncFile::as_raw_fd)
+	}
+
+	/// Called once the buffer locked by [`Self::lock_next_buffer`] has been committed in `commit`.
+	pub(super) fn mark_queued(&mut self, commit: u64) {
+		self.queued_commit = Some(commit);
+		// the kernel holds its own reference
+		self.render_fence = None;
+		self.is_first_frame = false;
+	}
+
+	/// Once the commit of the queued buffer has flipped, it is scanned out and the previous front buffer can be rendered into again.
+	pub(super) fn sync(&mut self, completed_commits: u64) {
+		match self.queued_commit {
+			Some(commit) if commit <= completed_commits => {
+				self.queued_commit = None;
+				self.front_buffer = self.queued_buffer.take();
+			}
+			_ => ()
+		}
+	}
+
+	/// Presents this surface alone, only valid for contexts with a single render target.
+	pub fn present(
+		&mut self,
+		context: &KmsContext
+	) -> anyhow::Result<PresentFeedback> {
+		context.present_surfaces(std::slice::from_mut(self))
+	}
+}
@@ -0,0 +1,170 @@
+//! Explicit synchronization through sync_file fds.
+//!
+//! A sync_file wraps a dma fence and becomes readable once the fence has signaled. Drivers hand them out as
+//! `OUT_FENCE_PTR` of commits and accept them as `IN_FENCE_FD` of planes, EGL exports them for rendering.
+
+use std::{
+	os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+	time::Duration
+};
+
+use anyhow::Context;
+
+use nix::poll::{poll, PollFd, PollFlags};
+
+/// `struct sync_merge_data` from `linux/sync_file.h`.
+#[repr(C)]
+struct SyncMergeData {
+	name: [u8; 32],
+	fd2: i32,
+	fence: i32,
+	flags: u32,
+	pad: u32
+}
+
+/// `struct sw_sync_create_fence_data` from `drivers/dma-buf/sw_sync.c`.
+#[cfg(test)]
+#[repr(C)]
+struct SwSyncCreateFenceData {
+	value: u32,
+	name: [u8; 32],
+	fence: i32
+}
+
+nix::ioctl_readwrite!(sync_ioc_merge, b'>', 3, SyncMergeData);
+#[cfg(test)]
+nix::ioctl_readwrite!(sw_sync_ioc_create_fence, b'W', 0, SwSyncCreateFenceData);
+#[cfg(test)]
+nix::ioctl_write_ptr!(sw_sync_ioc_inc, b'W', 1, u32);
+
+/// Copies `name` into a nul terminated fence name.
+fn fence_name(name: &str) -> [u8; 32] {
+	let mut result = [0; 32];
+	for (dst, src) in result.iter_mut().take(31).zip(name.bytes()) {
+		*dst = src;
+	}
+
+	result
+}
+
+/// Waits until the fence of the sync_file `fd` has signaled, returns false if `timeout` elapsed first.
+pub fn wait_fd(fd: RawFd, timeout: Option<Duration>) -> anyhow::Result<bool> {
+	let timeout = timeout.map(|timeout| timeout.as_millis().min(i32::MAX as u128) as i32).unwrap_or(-1);
+	let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+
+	loop {
+		match poll(&mut fds, timeout) {
+			Ok(ready) => return Ok(ready > 0),
+			Err(nix::errno::Errno::EINTR) => continue,
+			Err(err) => return Err(err).context("Failed to poll sync file")
+		}
+	}
+}
+
+/// Owned sync_file fd.
+#[derive(Debug)]
+pub struct SyncFile(OwnedFd);
+impl SyncFile {
+	/// Waits until the fence has signaled, returns false if `timeout` elapsed first.
+	pub fn wait(&self, timeout: Option<Duration>) -> anyhow::Result<bool> {
+		wait_fd(self.0.as_raw_fd(), timeout)
+	}
+
+	pub fn is_signaled(&self) -> anyhow::Result<bool> {
+		self.wait(Some(Duration::ZERO))
+	}
+
+	/// Creates a sync_file which signals once both this one and `other` have signaled.
+	pub fn merge(&self, other: &SyncFile) -> anyhow::Result<SyncFile> {
+		let mut data = SyncMergeData {
+			name: fence_name("test_kmscube merge"),
+			fd2: other.as_raw_fd(),
+			fence: -1,
+			flags: 0,
+			pad: 0
+		};
+		// SAFETY: data matches the layout expected by the ioctl and outlives the call
+		unsafe { sync_ioc_merge(self.0.as_raw_fd(), &mut data) }.context("Failed to merge sync files")?;
+
+		// SAFETY: the kernel returned a new fd owned by the caller
+		Ok(SyncFile(unsafe { OwnedFd::from_raw_fd(data.fence) }))
+	}
+
+	pub fn try_clone(&self) -> anyhow::Result<SyncFile> {
+		self.0.try_clone().map(SyncFile).context("Failed to duplicate sync file")
+	}
+}
+impl From<OwnedFd> for SyncFile {
+	fn from(fd: OwnedFd) -> Self {
+		SyncFile(fd)
+	}
+}
+impl AsRawFd for SyncFile {
+	fn as_raw_fd(&self) -> RawFd {
+		self.0.as_raw_fd()
+	}
+}
+impl IntoRawFd for SyncFile {
+	fn into_raw_fd(self) -> RawFd {
+		self.0.into_raw_fd()
+	}
+}
+
+/// Software timeline of the `sw_sync` debugfs interface, creating fences which are signaled on demand.
+///
+/// Needs `CONFIG_SW_SYNC` and a mounted debugfs, usually only accessible to root. Only used to fake fences in tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct SwSyncTimeline {
+	fd: OwnedFd,
+	/// point up to which fences have been signaled
+	value: u32
+}
+#[cfg(test)]
+impl SwSyncTimeline {
+	const PATH: &'static str = "/sys/kernel/debug/sync/sw_sync";
+
+	pub fn new() -> anyhow::Result<Self> {
+		// every open creates a new timeline
+		let file = std::fs::File::open(Self::PATH).with_context(|| format!("Failed to open {}", Self::PATH))?;
+
+		Ok(
+			SwSyncTimeline {
+				fd: OwnedFd::from(file),
+				value: 0
+			}
+		)
+	}
+
+	/// Creates a fence which signals once the timeline reaches `point`.
+	pub fn create_fence(&self, point: u32) -> anyhow::Result<SyncFile> {
+		let mut data = SwSyncCreateFenceData {
+			value: point,
+			name: fence_name("test_kmscube"),
+			fence: -1
+		};
+		// SAFETY: data matches the layout expected by the ioctl and outlives the call
+		unsafe { sw_sync_ioc_create_fence(self.fd.as_raw_fd(), &mut data) }.context("Failed to create sw_sync fence")?;
+
+		// SAFETY: the kernel returned a new fd owned by the caller
+		Ok(SyncFile(unsafe { OwnedFd::from_raw_fd(data.fence) }))
+	}
+
+	/// Advances the timeline by `count` points, signaling all fences up to the new value.
+	pub fn advance(&mut self, count: u32) -> anyhow::Result<()> {
+		// SAFETY: the ioctl only reads the count
+		unsafe { sw_sync_ioc_inc(self.fd.as_raw_fd(), &count) }.context("Failed to advance sw_sync timeline")?;
+		self.value += count;
+
+		Ok(())
+	}
+
+	/// Signals all fences up to `point`.
+	pub fn signal(&mut self, point: u32) -> anyhow::Result<()> {
+		if point > self.value {
+			self.advance(point - self.value)?;
+		}
+
+		Ok(())
+	}
+}
@@ -1,70 +1,705 @@
+use std::{collections::VecDeque, fs, io::{BufWriter, Write}, path::{Path, PathBuf}};
+
+use anyhow::Context;
+
 use drm::buffer::{DrmFourcc, DrmModifier};
 
 mod kms;
 mod egl;
 
-fn main() {
-	edwardium_logger::Logger::new(
-		edwardium_logger::targets::stderr::StderrTarget::new(log::Level::Debug, Default::default()),
-		std::time::Instant::now()
-	).init_boxed().expect("Failed to initialize logger");
+/// How rendered frames reach the display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PresentBackend {
+	/// bare buffer objects imported into EGL by hand, see [`kms::KmsSwapchain`]
+	Swapchain,
+	/// `gbm_surface` with an EGL window surface, see [`kms::KmsSurface`]
+	Surface
+}
 
-	let kms = kms::KmsContext::new(
-		"/dev/dri/card0"
-	).expect("Failed to initialize drm context");
+/// Resolution used when rendering without a display.
+const HEADLESS_RESOLUTION: [u32; 2] = [1280, 720];
+const FRAME_COUNT: usize = 600;
 
-	let format = DrmFourcc::Xrgb8888;
+struct Options {
+	backend: PresentBackend,
+	/// render offscreen without touching any drm device
+	headless: bool,
+	/// where to write the last headless frame
+	dump: Option<PathBuf>,
+	/// capture to verify instead of running
+	replay: Option<PathBuf>,
+	/// where to write presentation feedback of every frame as CSV
+	feedback_log: Option<PathBuf>,
+	/// print probed devices and exit
+	list_devices: bool,
+	/// show a translucent square on an overlay plane blended like this, see [`Overlay`]
+	overlay: Option<kms::BlendMode>,
+	/// show a crosshair on the cursor plane of the first output, see [`Pointer`]
+	cursor: bool,
+	device: kms::DeviceSelector,
+	kms: kms::KmsOptions
+}
+impl Options {
+	fn from_args() -> anyhow::Result<Self> {
+		let mut options = Options {
+			backend: PresentBackend::Swapchain,
+			headless: false,
+			dump: None,
+			replay: None,
+			feedback_log: None,
+			list_devices: false,
+			overlay: None,
+			cursor: false,
+			device: kms::DeviceSelector::default(),
+			kms: kms::KmsOptions::default()
+		};
 
-	let egl = egl::EglContext::new(&kms, format).expect("Failed to initialize egl");
+		let mut args = std::env::args().skip(1);
+		while let Some(arg) = args.next() {
+			match arg.as_str() {
+				"--swapchain" => { options.backend = PresentBackend::Swapchain; }
+				"--surface" => { options.backend = PresentBackend::Surface; }
+				"--headless" => { options.headless = true; }
+				"--dump" => { options.dump = Some(args.next().context("Missing path for --dump")?.into()); }
+				"--capture" => { options.kms.capture = Some(args.next().context("Missing path for --capture")?.into()); }
+				"--replay" => { options.replay = Some(args.next().context("Missing path for --replay")?.into()); }
+				"--feedback-log" => { options.feedback_log = Some(args.next().context("Missing path for --feedback-log")?.into()); }
+				"--list-devices" => { options.list_devices = true; }
+				"--overlay" => { options.overlay = Some(args.next().context("Missing blend mode for --overlay")?.parse()?); }
+				"--cursor" => { options.cursor = true; }
+				"--device" => { options.device = args.next().context("Missing selector for --device")?.parse()?; }
+				"--session" => { options.kms.session = args.next().context("Missing kind for --session")?.parse()?; }
+				"--all-outputs" => { options.kms.all_outputs = true; }
+				"--mirror" => { options.kms.mirror = true; }
+				"--render-size" => { options.kms.render_size = Some(kms::parse_size(&args.next().context("Missing size for --render-size")?)?); }
+				"--overscan" => { options.kms.overscan = args.next().context("Missing insets for --overscan")?.parse()?; }
+				"--formats" => { options.kms.formats = parse_formats(&args.next().context("Missing list for --formats")?)?; }
+				"--connector" => { options.kms.connector = args.next().context("Missing selector for --connector")?.parse()?; }
+				"--mode" => { options.kms.mode = args.next().context("Missing request for --mode")?.parse()?; }
+				"--cvt" => { options.kms.custom_mode = Some(kms::parse_cvt_mode(&args.next().context("Missing size for --cvt")?, false)?); }
+				"--cvt-rb" => { options.kms.custom_mode = Some(kms::parse_cvt_mode(&args.next().context("Missing size for --cvt-rb")?, true)?); }
+				"--modeline" => { options.kms.custom_mode = Some(kms::parse_modeline(&args.next().context("Missing modeline for --modeline")?)?); }
+				_ => anyhow::bail!("Unknown argument \"{}\"", arg)
+			}
+		}
 
-	let mut swapchain = kms.create_swapchain(
-		2,
-		format,
-		DrmModifier::Linear,
-		None
-	).expect("Failed to create kms swapchain");
+		Ok(options)
+	}
+}
 
-	// let [width, height] = kms.resolution();
-	// let max_radius = width.max(height) as f32;
+/// Parses a comma separated list of fourcc codes like `XR24,AB24`.
+fn parse_formats(s: &str) -> anyhow::Result<Vec<DrmFourcc>> {
+	s.split(',').map(
+		|code| {
+			let bytes: [u8; 4] = code.as_bytes().try_into().ok().with_context(|| format!("Fourcc code \"{}\" must have four characters", code))?;
+			let format = DrmFourcc::try_from(u32::from_le_bytes(bytes)).map_err(
+				|_| anyhow::anyhow!("Unknown fourcc code \"{}\"", code)
+			)?;
 
-	let mut current_frame: usize = 0;
-	let mut stats_start = (0, std::time::Instant::now());
-	// let mut frame_image = vec![0u8; width * height * 4];
+			Ok(format)
+		}
+	).collect()
+}
 
-	loop {
-		// let frame_radius = (current_frame as f32) / 600.0 * max_radius;
+/// Render targets of all outputs, presented together.
+enum Presenter {
+	Swapchain(Vec<kms::KmsSwapchain>),
+	// egl surfaces are declared first so that they are destroyed before the gbm surfaces
+	Surface(Vec<egl::EglSurface>, Vec<kms::KmsSurface>)
+}
+impl Presenter {
+	/// Creates the render targets of all outputs, replacing those of `old` so that its buffers on screen stay until the first present.
+	fn new(kms: &kms::KmsContext, egl: &egl::EglContext, backend: PresentBackend, old: Option<Presenter>) -> anyhow::Result<Self> {
+		let targets = 0 .. kms.render_targets();
+		let (old_swapchains, old_surfaces) = match old {
+			None => (Vec::new(), Vec::new()),
+			Some(Presenter::Swapchain(swapchains)) => (swapchains, Vec::new()),
+			Some(Presenter::Surface(egl_surfaces, surfaces)) => {
+				// before the gbm surfaces they render into
+				std::mem::drop(egl_surfaces);
+				(Vec::new(), surfaces)
+			}
+		};
+		let (mut old_swapchains, mut old_surfaces) = (old_swapchains.into_iter(), old_surfaces.into_iter());
+
+		let presenter = match backend {
+			PresentBackend::Swapchain => Presenter::Swapchain(
+				targets.map(
+					|target| kms.create_swapchain(
+						target,
+						// one scanned out, one queued and one to render into without waiting
+						3,
+						kms.format(),
+						kms.modifier(),
+						old_swapchains.next()
+					).context("Failed to create kms swapchain")
+				).collect::<anyhow::Result<_>>()?
+			),
+			PresentBackend::Surface => {
+				let surfaces = targets.map(
+					|target| kms.create_surface(target, kms.format(), kms.modifier(), old_surfaces.next()).context("Failed to create kms surface")
+				).collect::<anyhow::Result<Vec<_>>>()?;
+				let egl_surfaces = surfaces.iter().map(
+					// SAFETY: the presenter destroys the egl surfaces before the gbm surfaces
+					|surface| unsafe { egl.create_surface(surface) }.context("Failed to create egl surface")
+				).collect::<anyhow::Result<_>>()?;
+
+				Presenter::Surface(egl_surfaces, surfaces)
+			}
+		};
+		// outputs which are gone keep their last frame until the next present
+		for swapchain in old_swapchains {
+			kms.retire_swapchain(swapchain)?;
+		}
+		for surface in old_surfaces {
+			kms.retire_surface(surface)?;
+		}
+
+		Ok(presenter)
+	}
+
+	fn targets(&self) -> usize {
+		match self {
+			Presenter::Swapchain(swapchains) => swapchains.len(),
+			Presenter::Surface(_, surfaces) => surfaces.len()
+		}
+	}
+
+	fn bind(&mut self, egl: &egl::EglContext, target: usize) -> anyhow::Result<()> {
+		match self {
+			Presenter::Swapchain(swapchains) => {
+				// the buffer may still be scanned out
+				if let Some(fence) = swapchains[target].take_release_fence() {
+					egl.wait_fence(fence)?;
+				}
+
+				egl.bind_framebuffer(swapchains[target].current_framebuffer_mut().1)
+			}
+			Presenter::Surface(egl_surfaces, _) => egl.bind_surface(&egl_surfaces[target])
+		}
+	}
+
+	/// Called after rendering into `target`.
+	///
+	/// Hands a render fence to the commit when possible, otherwise rendering has to finish first.
+	fn finish(&mut self, egl: &egl::EglContext, target: usize) -> anyhow::Result<()> {
+		match self {
+			Presenter::Swapchain(swapchains) => match egl.create_render_fence()? {
+				None => egl.finish(),
+				Some(fence) => swapchains[target].set_render_fence(fence)
+			},
+			Presenter::Surface(egl_surfaces, surfaces) => {
+				egl.swap_buffers(&egl_surfaces[target])?;
+				// swapping may still render into the buffer, e.g. to resolve it
+				if let Some(fence) = egl.create_render_fence()? {
+					surfaces[target].set_render_fence(fence);
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn present(&mut self, kms: &kms::KmsContext) -> anyhow::Result<kms::PresentFeedback> {
+		match self {
+			Presenter::Swapchain(swapchains) => kms.present_swapchains(swapchains),
+			Presenter::Surface(_, surfaces) => kms.present_surfaces(surfaces)
+		}
+	}
+}
+
+/// Translucent square sliding along the top of the first render target, hidden for every other [`Overlay::BLINK_FRAMES`].
+///
+/// Shown on an overlay plane when one is free, otherwise drawn into every frame like a compositor would.
+struct Overlay {
+	framebuffer: kms::FrameBufferObject,
+	blend: kms::BlendMode,
+	/// `None` while hidden or composed in GL
+	layer: Option<kms::LayerId>,
+	/// whether no plane accepted the layer since the square was last hidden
+	composed: bool
+}
+impl Overlay {
+	const SIZE: u32 = 128;
+	const POSITION: i32 = 32;
+	/// frames the square is shown, then hidden for as long
+	const BLINK_FRAMES: usize = 120;
+	/// opacity of the whole square
+	const ALPHA: f32 = 0.75;
+	const COLOR: [f32; 3] = [1.0, 0.8, 0.2];
+
+	fn new(kms: &kms::KmsContext, egl: &egl::EglContext, blend: kms::BlendMode) -> anyhow::Result<Self> {
+		let mut framebuffer = kms::FrameBufferObject::with_size(
+			kms.device().clone(), Self::SIZE, Self::SIZE, DrmFourcc::Argb8888, DrmModifier::Linear
+		).context("Failed to create overlay framebuffer")?;
+
+		// opaque, the plane applies the opacity
+		egl.bind_framebuffer(&mut framebuffer)?;
+		unsafe {
+			gl::ClearColor(Self::COLOR[0], Self::COLOR[1], Self::COLOR[2], 1.0);
+			gl::Clear(gl::COLOR_BUFFER_BIT);
+		}
+		// layers are not synchronized with rendering
+		egl.finish();
+
+		Ok(
+			Overlay {
+				framebuffer,
+				blend,
+				layer: None,
+				composed: false
+			}
+		)
+	}
+
+	fn is_visible(current_frame: usize) -> bool {
+		current_frame / Self::BLINK_FRAMES % 2 == 0
+	}
+
+	/// Position of the top left corner in frame `current_frame`.
+	fn position(current_frame: usize) -> (i32, i32) {
+		(Self::POSITION + (current_frame % Self::BLINK_FRAMES) as i32 * 2, Self::POSITION)
+	}
+
+	fn state(&self, current_frame: usize) -> kms::LayerState {
+		let (x, y) = Self::position(current_frame);
+		let mut state = kms::LayerState::at(x, y, Self::SIZE, Self::SIZE);
+		state.alpha = (Self::ALPHA * u16::MAX as f32) as u16;
+		state.blend = self.blend;
+
+		state
+	}
+
+	/// Updates the layer for frame `current_frame`, adding it again after it was hidden or the outputs were reconfigured.
+	fn update(&mut self, kms: &mut kms::KmsContext, current_frame: usize, reconfigured: bool) -> anyhow::Result<()> {
+		if reconfigured {
+			// reconfiguring removes all layers
+			self.layer = None;
+			self.composed = false;
+		}
+
+		let visible = Self::is_visible(current_frame);
+		match self.layer {
+			Some(layer) if !visible => {
+				kms.remove_layer(layer)?;
+				self.layer = None;
+			}
+			Some(layer) => kms.set_layer(layer, &self.framebuffer, self.state(current_frame))?,
+			None if !visible => { self.composed = false; }
+			None if self.composed => (),
+			// layers are tested with the presented buffers, so the square is missing from the first frame
+			None if !kms.has_presented() => (),
+			None => {
+				self.layer = kms.add_layer(0, &self.framebuffer, self.state(current_frame))?;
+				if self.layer.is_none() {
+					log::info!("Composing overlay in GL");
+					self.composed = true;
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Draws the square over frame `current_frame` in the bound render target `target`, unless a layer shows it.
+	///
+	/// The layer is placed within the mode of `output`, which shows the render target scaled when rendering at another size.
+	fn compose(&self, target: usize, output: &kms::KmsOutput, current_frame: usize) {
+		if !self.composed || target != 0 {
+			return
+		}
+
+		// frames are a single color, so blending is done here instead of drawing with a shader
+		let background = frame_color(current_frame);
+		let blended: Vec<f32> = Self::COLOR.iter().zip(background).map(
+			|(&color, background)| color * Self::ALPHA + background * (1.0 - Self::ALPHA)
+		).collect();
+
+		// scissor boxes start at the bottom
+		let (x, y, width, height) = output.render_rect(self.state(current_frame).destination);
+		let target_height = output.render_size()[1] as i32;
+		unsafe {
+			gl::Enable(gl::SCISSOR_TEST);
+			gl::Scissor(x, target_height - y - height as i32, width as _, height as _);
+			gl::ClearColor(blended[0], blended[1], blended[2], 1.0);
+			gl::Clear(gl::COLOR_BUFFER_BIT);
+			gl::Disable(gl::SCISSOR_TEST);
+		}
+	}
+}
+
+/// Crosshair on the cursor plane of the first output, circling around its center like a finger would.
+struct Pointer {
+	/// whether the cursor is enabled in the current configuration
+	enabled: bool,
+	/// whether there was no cursor plane since the outputs were last configured
+	unavailable: bool
+}
+impl Pointer {
+	/// edge length of the image, or the cursor size if smaller
+	const SIZE: u32 = 24;
+	/// frames per circle
+	const ROUND_FRAMES: usize = 240;
+
+	fn new() -> Self {
+		Pointer {
+			enabled: false,
+			unavailable: false
+		}
+	}
+
+	/// White lines with black outlines crossing at the center of a `size` x `size` image.
+	fn image(size: u32) -> Vec<u32> {
+		let center = size / 2;
+		(0 .. size * size).map(
+			|index| match (index % size).abs_diff(center).min((index / size).abs_diff(center)) {
+				0 => 0xffff_ffff,
+				1 => 0xff00_0000,
+				_ => 0
+			}
+		).collect()
+	}
+
+	/// Moves the cursor for frame `current_frame`, enabling it again after the outputs were reconfigured.
+	fn update(&mut self, kms: &mut kms::KmsContext, current_frame: usize, reconfigured: bool) -> anyhow::Result<()> {
+		if reconfigured {
+			// reconfiguring removes all cursors
+			self.enabled = false;
+			self.unavailable = false;
+		}
+		// cursor planes are tested with the presented buffers like layers
+		if self.unavailable || !kms.has_presented() {
+			return Ok(())
+		}
+
+		if !self.enabled {
+			if !kms.enable_cursor(0)? {
+				log::info!("No cursor plane available, showing no pointer");
+				self.unavailable = true;
+				return Ok(())
+			}
+
+			let (width, height) = kms.cursor_size();
+			let size = Self::SIZE.min(width).min(height);
+			kms.set_cursor_image(0, &Self::image(size), size, (size / 2, size / 2))?;
+			self.enabled = true;
+		}
+
+		let [width, height] = kms.outputs()[0].resolution();
+		let radius = width.min(height) as f32 / 4.0;
+		let angle = (current_frame % Self::ROUND_FRAMES) as f32 / Self::ROUND_FRAMES as f32 * std::f32::consts::TAU;
+		kms.move_cursor(
+			0,
+			(width / 2) as i32 + (radius * angle.cos()) as i32,
+			(height / 2) as i32 + (radius * angle.sin()) as i32
+		)
+	}
+}
+
+/// Collects presentation feedback of presented frames once it resolves.
+struct FeedbackLog {
+	pending: VecDeque<kms::PresentFeedback>,
+	file: Option<BufWriter<fs::File>>,
+	presented: usize,
+	missed: u32
+}
+impl FeedbackLog {
+	fn new(path: Option<&Path>) -> anyhow::Result<Self> {
+		let file = match path {
+			None => None,
+			Some(path) => {
+				let mut file = BufWriter::new(fs::File::create(path).context("Failed to create feedback log")?);
+				writeln!(file, "commit,crtc,sequence,time_us,interval_us,missed").context("Failed to write feedback log")?;
+
+				Some(file)
+			}
+		};
 
-		// for (i, pixel) in frame_image.as_mut_slice().chunks_mut(4).enumerate() {
-		// 	let x = i % width;
-		// 	let y = i / width;
-		// 	let radius = ((x as f32).powi(2) + (y as f32).powi(2)).sqrt();
+		Ok(
+			FeedbackLog {
+				pending: VecDeque::new(),
+				file,
+				presented: 0,
+				missed: 0
+			}
+		)
+	}
+
+	fn push(&mut self, feedback: kms::PresentFeedback) {
+		self.pending.push_back(feedback);
+	}
+
+	/// Logs all resolved feedback, in commit order.
+	fn collect(&mut self) -> anyhow::Result<()> {
+		while let Some(timings) = self.pending.front().and_then(kms::PresentFeedback::timings) {
+			let feedback = self.pending.pop_front().unwrap();
 
-		// 	pixel[0] = if radius <= frame_radius { 0 } else { 255 };
-		// 	pixel[1] = (y & 0xFF) as u8;
-		// 	pixel[2] = (x & 0xFF) as u8;
-		// }
+			for timing in timings.iter() {
+				if timing.missed > 0 {
+					log::warn!("Commit {} missed {} vblanks on {:?}", feedback.commit(), timing.missed, timing.crtc);
+				}
 
-		{
+				if let Some(ref mut file) = self.file {
+					writeln!(
+						file,
+						"{},{},{},{},{},{}",
+						feedback.commit(),
+						u32::from(timing.crtc),
+						timing.sequence,
+						timing.time.as_micros(),
+						timing.interval.map(|interval| interval.as_micros().to_string()).unwrap_or_default(),
+						timing.missed
+					).context("Failed to write feedback log")?;
+				}
+			}
 
+			self.presented += 1;
+			self.missed += feedback.missed().unwrap_or(0);
 		}
 
-		// swapchain.current_framebuffer().buffer_mut().write(&frame_image).unwrap().expect("Failed to map buffer object");
-		// TODO: render
+		Ok(())
+	}
+
+	fn finish(mut self) -> anyhow::Result<()> {
+		self.collect()?;
+		if let Some(ref mut file) = self.file {
+			file.flush().context("Failed to write feedback log")?;
+		}
+
+		log::info!("Presented {} frames, missed {} vblanks", self.presented, self.missed);
+		Ok(())
+	}
+}
+
+/// Color filling frame `current_frame`.
+fn frame_color(current_frame: usize) -> [f32; 3] {
+	let t = (current_frame % 120) as f32 / 120.0;
+
+	[t, 0.2, 1.0 - t]
+}
+
+fn render_frame(current_frame: usize) {
+	let [red, green, blue] = frame_color(current_frame);
+	unsafe {
+		gl::ClearColor(red, green, blue, 1.0);
+		gl::Clear(gl::COLOR_BUFFER_BIT);
+	}
+}
+
+/// Calls `frame` for each frame and logs the average fps every second.
+fn run_frames(mut frame: impl FnMut(usize) -> anyhow::Result<()>) -> anyhow::Result<()> {
+	let mut current_frame: usize = 0;
+	let mut stats_start = (0, std::time::Instant::now());
 
-		swapchain.present(&kms).expect("Failed to present");
-		swapchain.swap();
+	loop {
+		if kms::exit_requested() {
+			log::info!("Exiting on signal after {} frames", current_frame);
+			break;
+		}
+		frame(current_frame)?;
 
 		current_frame += 1;
-		if stats_start.1.elapsed() >= std::time::Duration::from_secs(1) || current_frame >= 600 {
+		if stats_start.1.elapsed() >= std::time::Duration::from_secs(1) || current_frame >= FRAME_COUNT {
 			let elapsed_time = stats_start.1.elapsed();
 			let elapsed_frames = current_frame - stats_start.0;
 			log::debug!("Average fps: {}", elapsed_frames as f32 / elapsed_time.as_secs_f32());
 
-			if current_frame >= 600 {
+			if current_frame >= FRAME_COUNT {
 				break;
 			}
 
 			stats_start = (current_frame, std::time::Instant::now());
 		}
 	}
+
+	Ok(())
+}
+
+/// Writes bottom-up RGBA `pixels` as a binary PPM.
+fn write_ppm(output: &mut impl Write, [width, height]: [u32; 2], pixels: &[u8]) -> anyhow::Result<()> {
+	let required = width as usize * height as usize * 4;
+	if pixels.len() != required {
+		anyhow::bail!("Expected {} bytes of pixels, got {}", required, pixels.len());
+	}
+
+	write!(output, "P6\n{} {}\n255\n", width, height)?;
+	for row in pixels.chunks_exact(width as usize * 4).rev() {
+		for pixel in row.chunks_exact(4) {
+			output.write_all(&pixel[.. 3])?;
+		}
+	}
+	output.flush()?;
+
+	Ok(())
+}
+
+fn run_headless(options: &Options) -> anyhow::Result<()> {
+	let egl = egl::EglContext::new_surfaceless(DrmFourcc::Abgr8888).context("Failed to initialize surfaceless egl")?;
+	// there is no mode to scale to, so the render size is the size of the frames
+	let (width, height) = options.kms.render_size.map_or((HEADLESS_RESOLUTION[0], HEADLESS_RESOLUTION[1]), |(width, height)| (width as u32, height as u32));
+	let target = egl.create_offscreen(width, height).context("Failed to create offscreen target")?;
+
+	let [width, height] = target.size();
+	let mut pixels = vec![0u8; width as usize * height as usize * 4];
+
+	run_frames(
+		|current_frame| {
+			egl.bind_offscreen(&target);
+			render_frame(current_frame);
+			egl.read_pixels(&target, &mut pixels)
+		}
+	)?;
+
+	if let Some(ref path) = options.dump {
+		let mut file = BufWriter::new(fs::File::create(path).context("Failed to create dump file")?);
+		write_ppm(&mut file, target.size(), &pixels)?;
+		log::info!("Wrote last frame to {}", path.display());
+	}
+
+	Ok(())
+}
+
+fn main() {
+	edwardium_logger::Logger::new(
+		edwardium_logger::targets::stderr::StderrTarget::new(log::Level::Debug, Default::default()),
+		std::time::Instant::now()
+	).init_boxed().expect("Failed to initialize logger");
+
+	let options = Options::from_args().expect("Failed to parse arguments");
+
+	if options.headless {
+		run_headless(&options).expect("Failed to render headless");
+		return;
+	}
+
+	if options.list_devices {
+		let mut session = kms::open_session(options.kms.session).expect("Failed to open session");
+		for device in kms::enumerate_devices(session.as_mut()).expect("Failed to enumerate devices") {
+			println!("{}", device);
+		}
+		return;
+	}
+
+	if let Some(ref path) = options.replay {
+		kms::record::verify_capture(path, &options.kms).expect("Failed to verify capture");
+		return;
+	}
+
+	let mut session = kms::open_session(options.kms.session).expect("Failed to open session");
+	let device = options.device.choose(session.as_mut()).expect("Failed to choose drm device");
+	let mut kms = kms::KmsContext::with_session(
+		session,
+		&device,
+		&options.kms
+	).expect("Failed to initialize drm context");
+
+	let egl = egl::EglContext::new(&kms, kms.format()).expect("Failed to initialize egl");
+	match egl.dma_buf_formats() {
+		Ok(Some(formats)) => {
+			if let Err(err) = kms.negotiate_modifiers(formats, &options.kms) {
+				log::warn!("Failed to negotiate modifiers, using linear buffers: {:#}", err);
+			}
+		}
+		Ok(None) => log::info!("Egl cannot import explicit modifiers, using linear buffers"),
+		Err(err) => log::warn!("Failed to query formats egl can import, using linear buffers: {:#}", err)
+	}
+	log::info!("Rendering into {:?} buffers with {:?}", kms.format(), kms.modifier());
+
+	for output in kms.outputs() {
+		if output.render_size() == output.resolution() {
+			log::info!("Driving output {} at {:?}", output.name(), output.resolution());
+		} else {
+			log::info!("Driving output {} at {:?}, rendering at {:?}", output.name(), output.resolution(), output.render_size());
+		}
+	}
+
+	log::info!(
+		"Presenting through {:?}{}",
+		options.backend, if kms.is_atomic() { "" } else { " with legacy modesetting" }
+	);
+	let mut presenter = Presenter::new(&kms, &egl, options.backend, None).expect("Failed to create presenter");
+
+	let mut overlay = options.overlay.map(
+		|blend| Overlay::new(&kms, &egl, blend).expect("Failed to create overlay")
+	);
+
+	let mut pointer = options.cursor.then(Pointer::new);
+
+	let mut feedback_log = FeedbackLog::new(options.feedback_log.as_deref()).expect("Failed to create feedback log");
+
+	// set while no output can be configured after an unplug
+	let mut paused = false;
+	run_frames(
+		|current_frame| {
+			kms.poll_session().context("Failed to poll session events")?;
+			if kms.is_paused() {
+				// hotplug uevents stay queued until the session is resumed, if they overflow the connectors are probed again
+				std::thread::sleep(std::time::Duration::from_millis(100));
+				return Ok(())
+			}
+
+			let events = kms.poll_hotplug().context("Failed to poll hotplug events")?;
+			let reconfigured = kms.is_affected_by(&events);
+			if reconfigured {
+				match kms.reconfigure(&options.kms) {
+					Ok(()) => {
+						let old = std::mem::replace(&mut presenter, Presenter::Swapchain(Vec::new()));
+						presenter = Presenter::new(&kms, &egl, options.backend, Some(old)).context("Failed to recreate presenter")?;
+						paused = false;
+					}
+					Err(err) => {
+						log::warn!("Waiting for a usable output: {:#}", err);
+						paused = true;
+					}
+				}
+			}
+
+			if paused {
+				std::thread::sleep(std::time::Duration::from_millis(100));
+				return Ok(())
+			}
+
+			if let Some(ref mut overlay) = overlay {
+				overlay.update(&mut kms, current_frame, reconfigured).context("Failed to update overlay")?;
+			}
+			if let Some(ref mut pointer) = pointer {
+				pointer.update(&mut kms, current_frame, reconfigured).context("Failed to update pointer")?;
+			}
+			for target in 0 .. presenter.targets() {
+				presenter.bind(&egl, target).context("Failed to bind render target")?;
+				render_frame(current_frame);
+				if let Some(ref overlay) = overlay {
+					overlay.compose(target, &kms.outputs()[0], current_frame);
+				}
+				presenter.finish(&egl, target).context("Failed to finish rendering")?;
+			}
+			feedback_log.push(presenter.present(&kms).context("Failed to present")?);
+			feedback_log.collect()
+		}
+	).expect("Failed to run frames");
+
+	kms.wait_for_flip().expect("Failed to wait for last frame");
+	feedback_log.finish().expect("Failed to finish feedback log");
+	// before the presenter destroys the scanned out framebuffers
+	kms.restore().expect("Failed to restore display state");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ppm_rows_are_flipped() {
+		// 2x2 pixels as read back by GL, bottom row first
+		let pixels = [
+			1, 2, 3, 255, 4, 5, 6, 255,
+			7, 8, 9, 0, 10, 11, 12, 0
+		];
+
+		let mut ppm = Vec::new();
+		write_ppm(&mut ppm, [2, 2], &pixels).unwrap();
+		assert_eq!(ppm, b"P6\n2 2\n255\n\x07\x08\x09\x0a\x0b\x0c\x01\x02\x03\x04\x05\x06");
+
+		assert!(write_ppm(&mut Vec::new(), [2, 2], &pixels[.. 12]).is_err());
+	}
 }